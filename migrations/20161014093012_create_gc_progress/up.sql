@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS gc_progress (
+	id		INTEGER PRIMARY KEY,
+	hash_cursor	INTEGER NOT NULL
+);
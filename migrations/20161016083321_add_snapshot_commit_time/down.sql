@@ -0,0 +1,12 @@
+CREATE TABLE snapshots_old (
+	id		INTEGER PRIMARY KEY,
+	tag		INTEGER,
+	family_id	INTEGER,
+        snapshot_id	INTEGER,
+        msg		BLOB,
+	hash		BLOB,
+	tree_ref	BLOB
+);
+INSERT INTO snapshots_old SELECT id, tag, family_id, snapshot_id, msg, hash, tree_ref FROM snapshots;
+DROP TABLE snapshots;
+ALTER TABLE snapshots_old RENAME TO snapshots;
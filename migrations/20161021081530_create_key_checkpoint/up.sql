@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS key_checkpoint (
+	id		INTEGER PRIMARY KEY,
+	modified	BIGINT
+);
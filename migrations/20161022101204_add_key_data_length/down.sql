@@ -0,0 +1,24 @@
+CREATE TABLE keys_old (
+	id             INTEGER PRIMARY KEY,
+	parent         INTEGER,
+	name           BLOB,
+
+	created        Integer,
+	modified       Integer,
+	accessed       Integer,
+
+	permissions    Integer,
+	user_id        Integer,
+	group_id       Integer,
+
+	hash           BLOB,
+	persistent_ref BLOB,
+
+	link_target    BLOB,
+	hardlink_of    INTEGER,
+	xattrs         BLOB
+);
+INSERT INTO keys_old SELECT id, parent, name, created, modified, accessed, permissions, user_id, group_id, hash, persistent_ref, link_target, hardlink_of, xattrs FROM keys;
+DROP TABLE keys;
+ALTER TABLE keys_old RENAME TO keys;
+CREATE UNIQUE INDEX Keys_UniqueParentName ON keys(parent, name);
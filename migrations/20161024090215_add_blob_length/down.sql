@@ -0,0 +1,10 @@
+CREATE TABLE blobs_old (
+	id	INTEGER PRIMARY KEY,
+	name	BLOB,
+        tag	INT,
+	digest	BLOB
+);
+INSERT INTO blobs_old SELECT id, name, tag, digest FROM blobs;
+DROP TABLE blobs;
+ALTER TABLE blobs_old RENAME TO blobs;
+CREATE UNIQUE INDEX IF NOT EXISTS Blobs_UniqueName ON blobs(name);
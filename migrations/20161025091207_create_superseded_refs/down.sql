@@ -0,0 +1 @@
+DROP TABLE superseded_refs;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS superseded_refs (
+	id		INTEGER PRIMARY KEY,
+	hash_id		INTEGER,
+	blob_name	BLOB
+);
+
+CREATE INDEX IF NOT EXISTS SupersededRefs_HashId ON superseded_refs(hash_id);
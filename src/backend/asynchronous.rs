@@ -0,0 +1,156 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backends that complete their calls asynchronously, and adapters to and from `StoreBackend`.
+
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+use util::FnBox;
+
+
+/// Called once with the result of an asynchronous backend call.
+pub type Callback<T> = Box<FnBox<Result<T, BackendError>, ()>>;
+
+/// A backend whose calls return right away and report their result through a callback, e.g. from
+/// an event loop driving network requests. Several calls may be in flight at once.
+///
+/// Each call must eventually invoke its callback exactly once, possibly from another thread.
+/// Dropping a callback without calling it is reported as a failure of the call.
+pub trait AsyncStoreBackend: Sync + Send + 'static {
+    fn store(&self, name: &[u8], data: CipherText, done: Callback<()>);
+    fn retrieve(&self, name: &[u8], done: Callback<Option<Vec<u8>>>);
+    fn delete(&self, name: &[u8], done: Callback<()>);
+    fn flush(&self, done: Callback<()>);
+}
+
+
+/// Use an `AsyncStoreBackend` where a `StoreBackend` is expected. Each call blocks its thread
+/// until the callback is invoked, so the blob stores and gc can stay synchronous.
+pub struct BlockingAdapter<A> {
+    backend: A,
+}
+
+impl<A: AsyncStoreBackend> BlockingAdapter<A> {
+    pub fn new(backend: A) -> BlockingAdapter<A> {
+        BlockingAdapter { backend: backend }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.backend
+    }
+}
+
+/// Make a callback and wait for its result.
+fn wait<T, F>(call: F) -> Result<T, BackendError>
+    where T: Send + 'static,
+          F: FnOnce(Callback<T>)
+{
+    let (sender, receiver) = mpsc::channel();
+    call(Box::new(move |res: Result<T, BackendError>| {
+        // The waiting thread is gone if it panicked; nobody needs the result then.
+        let _ = sender.send(res);
+    }));
+    match receiver.recv() {
+        Ok(res) => res,
+        Err(_) => Err(From::from("Asynchronous backend dropped the call without completing it")),
+    }
+}
+
+impl<A: AsyncStoreBackend> StoreBackend for BlockingAdapter<A> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        wait(|done| self.backend.store(name, CipherText::new(data.to_vec()), done))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        wait(|done| self.backend.retrieve(name, done))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        wait(|done| self.backend.delete(name, done))
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        wait(|done| self.backend.flush(done))
+    }
+}
+
+
+type Job<B> = Box<FnBox<Arc<B>, ()>>;
+
+/// Use a `StoreBackend` where an `AsyncStoreBackend` is expected. Calls are queued and run on a
+/// fixed number of worker threads, which exit when the adapter is dropped.
+pub struct AsyncAdapter<B> {
+    jobs: Mutex<mpsc::Sender<Job<B>>>,
+    backend: Arc<B>,
+}
+
+impl<B: StoreBackend> AsyncAdapter<B> {
+    /// Run the calls of `backend` on `threads` workers.
+    pub fn new(backend: Arc<B>, threads: usize) -> AsyncAdapter<B> {
+        assert!(threads > 0);
+        let (sender, receiver) = mpsc::channel::<Job<B>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            let backend = backend.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job.call(backend.clone()),
+                        Err(_) => return,
+                    }
+                }
+            });
+        }
+        AsyncAdapter {
+            jobs: Mutex::new(sender),
+            backend: backend,
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    fn run<F>(&self, f: F)
+        where F: FnOnce(Arc<B>) + Send + 'static
+    {
+        self.jobs.lock().unwrap().send(Box::new(f)).expect("Backend workers have stopped");
+    }
+}
+
+impl<B: StoreBackend> AsyncStoreBackend for AsyncAdapter<B> {
+    fn store(&self, name: &[u8], data: CipherText, done: Callback<()>) {
+        let name = name.to_vec();
+        self.run(move |backend| done.call(backend.store(&name[..], &data)));
+    }
+
+    fn retrieve(&self, name: &[u8], done: Callback<Option<Vec<u8>>>) {
+        let name = name.to_vec();
+        self.run(move |backend| done.call(backend.retrieve(&name[..])));
+    }
+
+    fn delete(&self, name: &[u8], done: Callback<()>) {
+        let name = name.to_vec();
+        self.run(move |backend| done.call(backend.delete(&name[..])));
+    }
+
+    fn flush(&self, done: Callback<()>) {
+        self.run(move |backend| done.call(backend.flush()));
+    }
+}
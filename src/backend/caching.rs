@@ -0,0 +1,163 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keep recently retrieved blobs in memory.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+
+
+/// Least recently used blobs, within a budget of bytes.
+struct Lru {
+    max_bytes: usize,
+    bytes: usize,
+    next_use: u64,
+    // Blob name to last use and contents.
+    blobs: BTreeMap<Vec<u8>, (u64, Vec<u8>)>,
+    // Last use to blob name, oldest first.
+    uses: BTreeMap<u64, Vec<u8>>,
+    // Number of invalidations so far; see `CachingBackend::retrieve`.
+    invalidations: u64,
+}
+
+impl Lru {
+    fn get(&mut self, name: &[u8]) -> Option<Vec<u8>> {
+        let use_ = self.next_use;
+        match self.blobs.get_mut(name) {
+            None => None,
+            Some(&mut (ref mut last_use, ref data)) => {
+                self.uses.remove(last_use);
+                self.uses.insert(use_, name.to_vec());
+                *last_use = use_;
+                self.next_use += 1;
+                Some(data.clone())
+            }
+        }
+    }
+
+    fn insert(&mut self, name: Vec<u8>, data: Vec<u8>) {
+        if data.len() > self.max_bytes {
+            return;
+        }
+        self.remove(&name);
+        while self.bytes + data.len() > self.max_bytes {
+            let oldest = self.uses.keys().next().cloned().expect("cache accounting is off");
+            let oldest_name = self.uses[&oldest].clone();
+            self.remove(&oldest_name);
+        }
+
+        self.bytes += data.len();
+        self.uses.insert(self.next_use, name.clone());
+        self.blobs.insert(name, (self.next_use, data));
+        self.next_use += 1;
+    }
+
+    fn remove(&mut self, name: &[u8]) {
+        if let Some((last_use, data)) = self.blobs.remove(name) {
+            self.uses.remove(&last_use);
+            self.bytes -= data.len();
+        }
+    }
+
+    fn invalidate(&mut self, name: &[u8]) {
+        self.remove(name);
+        self.invalidations += 1;
+    }
+}
+
+/// Wraps a backend and serves repeated retrieves of the same blob from memory.
+///
+/// Stores and deletes go to the wrapped backend and drop the blob from the cache.
+pub struct CachingBackend<B> {
+    backend: B,
+    cache: Mutex<Lru>,
+}
+
+impl<B: StoreBackend> CachingBackend<B> {
+    /// Cache up to `max_bytes` of blob data.
+    pub fn new(backend: B, max_bytes: usize) -> CachingBackend<B> {
+        CachingBackend {
+            backend: backend,
+            cache: Mutex::new(Lru {
+                max_bytes: max_bytes,
+                bytes: 0,
+                next_use: 0,
+                blobs: BTreeMap::new(),
+                uses: BTreeMap::new(),
+                invalidations: 0,
+            }),
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for CachingBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let res = self.backend.store(name, data);
+        self.cache.lock().unwrap().invalidate(name);
+        res
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let invalidations = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(data) = cache.get(name) {
+                return Ok(Some(data));
+            }
+            cache.invalidations
+        };
+
+        let res = try!(self.backend.retrieve(name));
+        if let Some(ref data) = res {
+            let mut cache = self.cache.lock().unwrap();
+            // A store or delete racing with the retrieve may have made the data stale.
+            if cache.invalidations == invalidations {
+                cache.insert(name.to_vec(), data.clone());
+            }
+        }
+        Ok(res)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        let res = self.backend.delete(name);
+        self.cache.lock().unwrap().invalidate(name);
+        res
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.backend.flush()
+    }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, BackendError> {
+        if self.cache.lock().unwrap().blobs.contains_key(name) {
+            return Ok(true);
+        }
+        self.backend.exists(name)
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, BackendError> {
+        self.backend.list()
+    }
+
+    fn delete_batch(&self, names: &[Vec<u8>]) -> Vec<(Vec<u8>, BackendError)> {
+        let failed = self.backend.delete_batch(names);
+        let mut cache = self.cache.lock().unwrap();
+        for name in names.iter() {
+            cache.invalidate(&name[..]);
+        }
+        failed
+    }
+}
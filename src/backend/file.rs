@@ -12,20 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use rustc_serialize::hex::ToHex;
+use rand;
+use rustc_serialize::hex::{FromHex, ToHex};
 use std::collections::BTreeMap;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use backend::StoreBackend;
+use backend::{BackendError, StoreBackend};
 use crypto::CipherText;
+use util::Durability;
 
+/// Stores each blob as a file in a local directory.
+///
+/// Files are named after the hex encoding of the blob name and sharded into subdirectories by
+/// the first byte of the name (i.e. the first two hex characters), to avoid huge flat directories.
+/// Blobs stored directly in the root directory, by versions before the sharding, are still found.
+///
+/// Each blob is synced to disk as it is stored, unless configured otherwise with
+/// `with_durability`.
 pub struct FileBackend {
     root: PathBuf,
-    read_cache: Mutex<BTreeMap<Vec<u8>, Result<Option<Vec<u8>>, String>>>,
+    read_cache: Mutex<BTreeMap<Vec<u8>, Result<Option<Vec<u8>>, BackendError>>>,
     max_cache_size: usize,
+    tmp_counter: AtomicUsize,
+    durability: Durability,
+    // Blobs stored since the last flush that are not yet synced.
+    unsynced: Mutex<Vec<PathBuf>>,
 }
 
 impl FileBackend {
@@ -34,41 +49,156 @@ impl FileBackend {
             root: root,
             read_cache: Mutex::new(BTreeMap::new()),
             max_cache_size: 10,
+            tmp_counter: AtomicUsize::new(0),
+            durability: Durability::default(),
+            unsynced: Mutex::new(Vec::new()),
         }
     }
 
-    fn guarded_cache_get(&self, name: &[u8]) -> Option<Result<Option<Vec<u8>>, String>> {
+    /// Sync blobs as given by `durability`. With `Durability::PerCommit`, the blobs stored since
+    /// the last `flush()` are synced by it, which `Hat` calls when a snapshot is committed.
+    pub fn with_durability(mut self, durability: Durability) -> FileBackend {
+        self.durability = durability;
+        self
+    }
+
+    fn shard_dir(&self, name: &[u8]) -> PathBuf {
+        let mut p = self.root.clone();
+        if let Some(b) = name.first() {
+            p.push(&[*b].to_hex());
+        }
+        p
+    }
+
+    fn path(&self, name: &[u8]) -> PathBuf {
+        let mut p = self.shard_dir(name);
+        p.push(&name.to_hex());
+        p
+    }
+
+    // Where blobs were stored before they were sharded.
+    fn flat_path(&self, name: &[u8]) -> PathBuf {
+        let mut p = self.root.clone();
+        p.push(&name.to_hex());
+        p
+    }
+
+    fn tmp_path(&self, name: &[u8]) -> PathBuf {
+        // The counter separates concurrent stores within this process; the random part separates
+        // processes sharing the same directory.
+        let mut p = self.shard_dir(name);
+        p.push(format!(".{}.{}-{:x}.tmp",
+                       name.to_hex(),
+                       self.tmp_counter.fetch_add(1, Ordering::SeqCst),
+                       rand::random::<u64>()));
+        p
+    }
+
+    fn guarded_cache_get(&self, name: &[u8]) -> Option<Result<Option<Vec<u8>>, BackendError>> {
         match self.read_cache.lock() {
-            Err(e) => Some(Err(e.to_string())),
+            Err(e) => Some(Err(From::from(e.to_string()))),
             Ok(cache) => cache.get(name).map(|v| v.clone()),
         }
     }
 
-    fn get(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
-        // Read key:
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
+    fn get(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let file = match fs::File::open(&self.path(name)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::File::open(&self.flat_path(name))
+            }
+            file => file,
         };
-
-        match fs::File::open(&path) {
-            Err(_) => Ok(None),
+        match file {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(From::from(e.to_string())),
             Ok(mut fd) => {
                 let mut buf = Vec::new();
                 match fd.read_to_end(&mut buf) {
                     Ok(_) => Ok(Some(buf)),
-                    Err(e) => Err(e.to_string()),
+                    Err(e) => Err(From::from(e.to_string())),
+                }
+            }
+        }
+    }
+
+    // Create a temporary file to store `name` in, in its shard directory.
+    fn create_tmp(&self, name: &[u8]) -> io::Result<(PathBuf, fs::File)> {
+        let mut attempts = 0;
+        loop {
+            try!(fs::create_dir_all(self.shard_dir(name)));
+            let tmp_path = self.tmp_path(name);
+            match fs::File::create(&tmp_path) {
+                // A concurrent delete removed the shard directory as it emptied.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && attempts < 3 => {
+                    attempts += 1
+                }
+                Err(e) => return Err(e),
+                Ok(file) => return Ok((tmp_path, file)),
+            }
+        }
+    }
+
+    // Store `name` as the contents `write` gives a temporary file, which then replaces the blob.
+    fn put<F>(&self, name: &[u8], write: F) -> io::Result<()>
+        where F: FnOnce(&mut fs::File) -> io::Result<()>
+    {
+        // The file keeps the shard directory from being removed until it is renamed.
+        let (tmp_path, mut file) = try!(self.create_tmp(name));
+        let res = write(&mut file).and_then(|()| match self.durability {
+            Durability::PerBlob => file.sync_all(),
+            Durability::PerCommit | Durability::None => Ok(()),
+        });
+
+        let path = self.path(name);
+        match res.and_then(|()| fs::rename(&tmp_path, &path)) {
+            Ok(()) => {
+                if self.durability == Durability::PerCommit {
+                    self.unsynced.lock().unwrap().push(path);
                 }
+                Ok(())
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(e)
             }
         }
     }
 
+    fn list_shards(&self) -> io::Result<Vec<Vec<u8>>> {
+        let mut names = vec![];
+        for shard in try!(fs::read_dir(&self.root)) {
+            let shard = try!(shard);
+            if !try!(shard.file_type()).is_dir() {
+                continue;
+            }
+            for file in try!(fs::read_dir(shard.path())) {
+                // Skip temporary files (which start with a '.') and anything else not ours.
+                if let Some(Ok(name)) = try!(file).file_name().to_str().map(|n| n.from_hex()) {
+                    names.push(name);
+                }
+            }
+        }
+
+        // Blobs stored before the sharding, some of which may since have been stored again.
+        for file in try!(fs::read_dir(&self.root)) {
+            let file = try!(file);
+            if !try!(file.file_type()).is_file() {
+                continue;
+            }
+            if let Some(Ok(name)) = file.file_name().to_str().map(|n| n.from_hex()) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
     fn guarded_cache_delete(&self, name: &[u8]) {
         self.read_cache.lock().unwrap().remove(name);
     }
 
-    fn guarded_cache_put(&self, name: Vec<u8>, result: Result<Option<Vec<u8>>, String>) {
+    fn guarded_cache_put(&self, name: Vec<u8>, result: Result<Option<Vec<u8>>, BackendError>) {
         let mut cache = self.read_cache.lock().unwrap();
         if cache.len() >= self.max_cache_size {
             cache.clear();
@@ -78,24 +208,40 @@ impl FileBackend {
 }
 
 impl StoreBackend for FileBackend {
-    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
-        let mut path = self.root.clone();
-        path.push(&name.to_hex());
-
-        let mut file = match fs::File::create(&path) {
-            Err(e) => return Err(e.to_string()),
-            Ok(f) => f,
-        };
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.guarded_cache_delete(name);
+        let res = self.put(name, |file| {
+            for r in data.slices() {
+                try!(file.write_all(r));
+            }
+            Ok(())
+        });
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) => Err(From::from(e.to_string())),
+        }
+    }
 
-        for r in data.slices() {
-            if let Err(e) = file.write_all(r) {
-                return Err(e.to_string());
+    fn store_file(&self, name: &[u8], data: &fs::File, len: u64) -> Result<(), BackendError> {
+        self.guarded_cache_delete(name);
+        let res = self.put(name, |file| {
+            let mut data = data;
+            try!(data.seek(SeekFrom::Start(0)));
+            let copied = try!(io::copy(&mut data.take(len), file));
+            if copied == len {
+                Ok(())
+            } else {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                   format!("Blob file holds {} of {} bytes", copied, len)))
             }
+        });
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) => Err(From::from(e.to_string())),
         }
-        Ok(())
     }
 
-    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
         // Check for key in cache:
         let value_opt = self.guarded_cache_get(name);
         if let Some(r) = value_opt {
@@ -110,23 +256,58 @@ impl StoreBackend for FileBackend {
         res
     }
 
-    fn delete(&self, name: &[u8]) -> Result<(), String> {
-        let name = name.to_vec();
-        self.guarded_cache_delete(&name);
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.guarded_cache_delete(name);
 
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
-        };
+        for path in &[self.path(name), self.flat_path(name)] {
+            match fs::remove_file(path) {
+                // A blob may already be gone if a previous delete was interrupted.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(From::from(e.to_string())),
+                Ok(()) => (),
+            }
+        }
 
-        match fs::remove_file(&path) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string()),
+        // Remove the shard directory if this was its last blob; this fails harmlessly when the
+        // directory is not empty (or is the root itself).
+        if !name.is_empty() {
+            let _ = fs::remove_dir(self.shard_dir(name));
         }
+        Ok(())
     }
 
-    fn flush(&self) -> Result<(), String> {
+    fn flush(&self) -> Result<(), BackendError> {
+        let mut unsynced = self.unsynced.lock().unwrap();
+        while let Some(path) = unsynced.pop() {
+            match fs::File::open(&path).and_then(|file| file.sync_all()) {
+                // Deleted since it was stored.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => {
+                    // Try again on the next flush.
+                    unsynced.push(path);
+                    return Err(From::from(e.to_string()));
+                }
+                Ok(()) => (),
+            }
+        }
         Ok(())
     }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, BackendError> {
+        let metadata = match fs::metadata(&self.path(name)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::metadata(&self.flat_path(name))
+            }
+            metadata => metadata,
+        };
+        match metadata {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(From::from(e.to_string())),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, BackendError> {
+        self.list_shards().map_err(|e| From::from(e.to_string()))
+    }
 }
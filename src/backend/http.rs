@@ -0,0 +1,310 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Store blobs on a remote blob server, so that several clients can share one storage.
+//!
+//! `HttpBackend` is the client, and `HttpHandler` serves any local `StoreBackend` to it. They
+//! speak a small REST protocol, where a blob is addressed by the hex encoding of its name:
+//!
+//! * `PUT /blobs/<hex name>` stores the request body as the blob. Replies `204 No Content`.
+//! * `GET /blobs/<hex name>` replies `200 OK` with the blob as body, or `404 Not Found`.
+//! * `HEAD /blobs/<hex name>` replies `200 OK` if the blob is stored, or `404 Not Found`.
+//! * `DELETE /blobs/<hex name>` deletes the blob. Replies `204 No Content`, also if the blob
+//!   was missing.
+//! * `GET /blobs/` replies `200 OK` with the hex names of all stored blobs as body, one per line.
+//! * `POST /flush` flushes the served backend. Replies `204 No Content`.
+//!
+//! When the served backend fails, the server replies `503 Service Unavailable` if the call may be
+//! retried, and `500 Internal Server Error` with the error message as body otherwise. The client
+//! turns these back into `BackendError::Retry` and `BackendError::Message` respectively.
+
+use hyper;
+use hyper::client::{Client, Response, pool};
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{self, Handler, Request};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rustc_serialize::hex::{FromHex, ToHex};
+use std::io::Read;
+use std::sync::Arc;
+
+use backend::{BackendError, ConnectionPool, StoreBackend};
+use crypto::CipherText;
+use errors::RetryError;
+
+
+const BLOBS_PATH: &'static str = "/blobs/";
+const FLUSH_PATH: &'static str = "/flush";
+
+/// The number of connections to the blob server open at once, unless configured otherwise.
+const DEFAULT_MAX_CONNECTIONS: usize = 8;
+
+pub struct HttpBackend {
+    clients: ConnectionPool<Client>,
+    endpoint: hyper::Url,
+}
+
+/// Each client keeps a single connection alive, so that the pool bounds the connections.
+fn client_pool(max_connections: usize) -> ConnectionPool<Client> {
+    ConnectionPool::new(max_connections,
+                        || Ok(Client::with_pool_config(pool::Config { max_idle: 1 })))
+}
+
+impl HttpBackend {
+    /// Create a backend storing blobs on the blob server at `endpoint` (e.g.
+    /// `http://backup.example.com:8080`).
+    pub fn new(endpoint: &str) -> Result<HttpBackend, BackendError> {
+        let endpoint = match hyper::Url::parse(endpoint) {
+            Ok(url) => url,
+            Err(e) => return Err(From::from(format!("Invalid blob server endpoint: {}", e))),
+        };
+        if endpoint.host_str().is_none() {
+            return Err(From::from("Invalid blob server endpoint: missing host"));
+        }
+        Ok(HttpBackend {
+            clients: client_pool(DEFAULT_MAX_CONNECTIONS),
+            endpoint: endpoint,
+        })
+    }
+
+    /// Open at most `max_connections` connections at once (8 by default). Calls wait for a
+    /// connection when all of them are in use.
+    pub fn with_max_connections(mut self, max_connections: usize) -> HttpBackend {
+        self.clients = client_pool(max_connections);
+        self
+    }
+
+    /// Send a request, and handle its response with `read`. The connection is held until `read`
+    /// is done with the response, so that it is not shared while the response is being read.
+    fn send<T, F>(&self,
+                  method: Method,
+                  path: &str,
+                  payload: &[u8],
+                  read: F)
+                  -> Result<T, BackendError>
+        where F: FnOnce(Response) -> Result<T, BackendError>
+    {
+        let mut url = self.endpoint.clone();
+        url.set_path(path);
+        let client = try!(self.clients.get());
+        match client.request(method.clone(), url).body(payload).send() {
+            Ok(response) => read(response),
+            Err(hyper::Error::Io(e)) => {
+                warn!("Blob server {} request failed: {}", method, e);
+                Err(BackendError::Retry(RetryError))
+            }
+            Err(e) => Err(From::from(format!("Blob server {} request failed: {}", method, e))),
+        }
+    }
+}
+
+fn blob_path(name: &[u8]) -> String {
+    format!("{}{}", BLOBS_PATH, name.to_hex())
+}
+
+fn status_error(method: Method, mut response: Response) -> BackendError {
+    let mut body = String::new();
+    let _ = response.read_to_string(&mut body);
+    if response.status == StatusCode::ServiceUnavailable {
+        warn!("Blob server {} returned {}", method, response.status);
+        BackendError::Retry(RetryError)
+    } else {
+        From::from(format!("Blob server {} returned {}: {}", method, response.status, body))
+    }
+}
+
+impl StoreBackend for HttpBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let payload = data.to_vec();
+        self.send(Method::Put, &blob_path(name), &payload[..], |response| {
+            if response.status.is_success() {
+                Ok(())
+            } else {
+                Err(status_error(Method::Put, response))
+            }
+        })
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.send(Method::Get, &blob_path(name), &[], |mut response| {
+            if response.status == StatusCode::NotFound {
+                return Ok(None);
+            }
+            if !response.status.is_success() {
+                return Err(status_error(Method::Get, response));
+            }
+
+            let mut buf = Vec::new();
+            match response.read_to_end(&mut buf) {
+                Ok(_) => Ok(Some(buf)),
+                Err(e) => {
+                    warn!("Blob server GET response could not be read: {}", e);
+                    Err(BackendError::Retry(RetryError))
+                }
+            }
+        })
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.send(Method::Delete, &blob_path(name), &[], |response| {
+            // Deleting a missing blob is not an error.
+            if response.status.is_success() || response.status == StatusCode::NotFound {
+                Ok(())
+            } else {
+                Err(status_error(Method::Delete, response))
+            }
+        })
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.send(Method::Post, FLUSH_PATH, &[], |response| {
+            if response.status.is_success() {
+                Ok(())
+            } else {
+                Err(status_error(Method::Post, response))
+            }
+        })
+    }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, BackendError> {
+        self.send(Method::Head, &blob_path(name), &[], |response| {
+            if response.status == StatusCode::NotFound {
+                Ok(false)
+            } else if response.status.is_success() {
+                Ok(true)
+            } else {
+                Err(status_error(Method::Head, response))
+            }
+        })
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, BackendError> {
+        self.send(Method::Get, BLOBS_PATH, &[], |mut response| {
+            if !response.status.is_success() {
+                return Err(status_error(Method::Get, response));
+            }
+            let mut body = String::new();
+            if let Err(e) = response.read_to_string(&mut body) {
+                warn!("Blob server listing could not be read: {}", e);
+                return Err(BackendError::Retry(RetryError));
+            }
+            let mut names = vec![];
+            for line in body.lines().filter(|l| !l.is_empty()) {
+                match line.from_hex() {
+                    Ok(name) => names.push(name),
+                    Err(e) => return Err(From::from(format!("Invalid blob name listed: {}", e))),
+                }
+            }
+            Ok(names)
+        })
+    }
+}
+
+
+/// Serves the blobs of a local backend to `HttpBackend` clients, e.g. with
+/// `hyper::server::Server::http(addr).unwrap().handle(HttpHandler::new(backend))`.
+pub struct HttpHandler<B> {
+    backend: Arc<B>,
+}
+
+impl<B: StoreBackend> HttpHandler<B> {
+    pub fn new(backend: Arc<B>) -> HttpHandler<B> {
+        HttpHandler { backend: backend }
+    }
+
+    fn reply(&self, req: &mut Request) -> (StatusCode, Vec<u8>) {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref p) => p.clone(),
+            _ => return (StatusCode::BadRequest, b"Expected an absolute path".to_vec()),
+        };
+        let mut body = Vec::new();
+        if let Err(e) = req.read_to_end(&mut body) {
+            return (StatusCode::BadRequest, format!("Could not read request: {}", e).into_bytes());
+        }
+
+        let result = if path == FLUSH_PATH {
+            match req.method {
+                Method::Post => self.backend.flush().map(|()| (StatusCode::NoContent, vec![])),
+                _ => Ok((StatusCode::MethodNotAllowed, vec![])),
+            }
+        } else if path == BLOBS_PATH {
+            match req.method {
+                Method::Get => {
+                    self.backend.list().map(|names| {
+                        let lines: Vec<String> = names.iter().map(|n| n.to_hex()).collect();
+                        (StatusCode::Ok, lines.join("\n").into_bytes())
+                    })
+                }
+                _ => Ok((StatusCode::MethodNotAllowed, vec![])),
+            }
+        } else {
+            let name = match path_name(&path) {
+                Some(name) => name,
+                None => return (StatusCode::NotFound, vec![]),
+            };
+            match req.method {
+                Method::Get => {
+                    self.backend.retrieve(&name[..]).map(|data| match data {
+                        Some(data) => (StatusCode::Ok, data),
+                        None => (StatusCode::NotFound, vec![]),
+                    })
+                }
+                Method::Head => {
+                    self.backend.exists(&name[..]).map(|exists| if exists {
+                        (StatusCode::Ok, vec![])
+                    } else {
+                        (StatusCode::NotFound, vec![])
+                    })
+                }
+                Method::Put => {
+                    self.backend
+                        .store(&name[..], &CipherText::new(body))
+                        .map(|()| (StatusCode::NoContent, vec![]))
+                }
+                Method::Delete => {
+                    self.backend.delete(&name[..]).map(|()| (StatusCode::NoContent, vec![]))
+                }
+                _ => Ok((StatusCode::MethodNotAllowed, vec![])),
+            }
+        };
+
+        match result {
+            Ok(reply) => reply,
+            Err(BackendError::Retry(_)) => (StatusCode::ServiceUnavailable, vec![]),
+            Err(BackendError::Message(msg)) => {
+                warn!("Blob server {} {} failed: {}", req.method, path, msg);
+                (StatusCode::InternalServerError, msg.into_owned().into_bytes())
+            }
+        }
+    }
+}
+
+/// The blob name addressed by `path`, if it is of the form `/blobs/<hex name>`.
+fn path_name(path: &str) -> Option<Vec<u8>> {
+    if !path.starts_with(BLOBS_PATH) {
+        return None;
+    }
+    path[BLOBS_PATH.len()..].from_hex().ok()
+}
+
+impl<B: StoreBackend> Handler for HttpHandler<B> {
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: server::Response<'a, Fresh>) {
+        let (status, body) = self.reply(&mut req);
+        *res.status_mut() = status;
+        if let Err(e) = res.send(&body[..]) {
+            warn!("Blob server could not send its reply: {}", e);
+        }
+    }
+}
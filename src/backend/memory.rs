@@ -16,7 +16,7 @@ use crypto::CipherText;
 use std::collections::BTreeMap;
 use std::sync::Mutex;
 
-use backend::StoreBackend;
+use backend::{BackendError, StoreBackend};
 
 pub struct MemoryBackend {
     files: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
@@ -27,23 +27,36 @@ impl MemoryBackend {
         MemoryBackend { files: Mutex::new(BTreeMap::new()) }
     }
 
-    fn guarded_insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String> {
+    /// Modify a stored value in place (used by tests to simulate corruption).
+    #[cfg(test)]
+    pub fn modify<F: FnOnce(&mut Vec<u8>)>(&self, key: &[u8], f: F) {
+        let mut guarded_files = self.files.lock().unwrap();
+        f(guarded_files.get_mut(key).expect("Key does not exist"));
+    }
+
+    /// Total size of all stored values (used by tests to measure reclaimed space).
+    #[cfg(test)]
+    pub fn total_bytes(&self) -> usize {
+        self.files.lock().unwrap().values().fold(0, |sum, v| sum + v.len())
+    }
+
+    fn guarded_insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), BackendError> {
         let mut guarded_files = self.files.lock().unwrap();
         if guarded_files.contains_key(&key) {
-            return Err(format!("Key already exists: '{:?}'", key));
+            return Err(From::from(format!("Key already exists: '{:?}'", key)));
         }
         guarded_files.insert(key, value);
         Ok(())
     }
 
-    fn guarded_retrieve(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn guarded_retrieve(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
         match self.files.lock() {
-            Err(e) => Err(e.to_string()),
+            Err(e) => Err(From::from(e.to_string())),
             Ok(map) => Ok(map.get(key).map(|v| v.clone())),
         }
     }
 
-    fn guarded_delete(&self, key: &[u8]) -> Result<(), String> {
+    fn guarded_delete(&self, key: &[u8]) -> Result<(), BackendError> {
         let mut guarded_files = self.files.lock().unwrap();
         guarded_files.remove(key);
         Ok(())
@@ -51,19 +64,33 @@ impl MemoryBackend {
 }
 
 impl StoreBackend for MemoryBackend {
-    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
         self.guarded_insert(name.to_vec(), data.to_vec())
     }
 
-    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
         self.guarded_retrieve(name)
     }
 
-    fn delete(&self, name: &[u8]) -> Result<(), String> {
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
         self.guarded_delete(name)
     }
 
-    fn flush(&self) -> Result<(), String> {
+    fn flush(&self) -> Result<(), BackendError> {
         Ok(())
     }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, BackendError> {
+        match self.files.lock() {
+            Err(e) => Err(From::from(e.to_string())),
+            Ok(map) => Ok(map.contains_key(name)),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, BackendError> {
+        match self.files.lock() {
+            Err(e) => Err(From::from(e.to_string())),
+            Ok(map) => Ok(map.keys().cloned().collect()),
+        }
+    }
 }
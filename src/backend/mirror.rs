@@ -0,0 +1,163 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Write every blob to two backends.
+
+use std::collections::BTreeMap;
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+
+
+/// Combine the results of calling both backends; an error names the backend that failed. If
+/// every failure is transient, so is the combined one: calling both backends again is harmless.
+fn both(what: &str,
+        primary: Result<(), BackendError>,
+        secondary: Result<(), BackendError>)
+        -> Result<(), BackendError> {
+    match (primary, secondary) {
+        (Ok(()), Ok(())) => Ok(()),
+        (Err(BackendError::Retry(e)), Ok(())) |
+        (Err(BackendError::Retry(_)), Err(BackendError::Retry(e))) => {
+            warn!("{} failed on the primary backend, and may be retried", what);
+            Err(BackendError::Retry(e))
+        }
+        (Ok(()), Err(BackendError::Retry(e))) => {
+            warn!("{} failed on the secondary backend, and may be retried", what);
+            Err(BackendError::Retry(e))
+        }
+        (Err(e), Ok(())) => {
+            Err(From::from(format!("{} succeeded on the secondary backend only; primary failed: {}",
+                                   what,
+                                   e)))
+        }
+        (Ok(()), Err(e)) => {
+            Err(From::from(format!("{} succeeded on the primary backend only; secondary failed: {}",
+                                   what,
+                                   e)))
+        }
+        (Err(p), Err(s)) => {
+            Err(From::from(format!("{} failed on both backends; primary: {}, secondary: {}",
+                                   what,
+                                   p,
+                                   s)))
+        }
+    }
+}
+
+/// Stores to and deletes from both backends, and retrieves from the primary backend, falling
+/// back to the secondary backend when the primary does not have the blob or fails.
+///
+/// A store only succeeds if both backends stored the blob.
+pub struct MirrorBackend<A, B> {
+    primary: A,
+    secondary: B,
+}
+
+impl<A: StoreBackend, B: StoreBackend> MirrorBackend<A, B> {
+    pub fn new(primary: A, secondary: B) -> MirrorBackend<A, B> {
+        MirrorBackend {
+            primary: primary,
+            secondary: secondary,
+        }
+    }
+
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &B {
+        &self.secondary
+    }
+}
+
+impl<A: StoreBackend, B: StoreBackend> StoreBackend for MirrorBackend<A, B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        both("Store", self.primary.store(name, data), self.secondary.store(name, data))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let primary_err = match self.primary.retrieve(name) {
+            Ok(Some(data)) => return Ok(Some(data)),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Primary backend failed to retrieve blob, trying secondary: {}", e);
+                Some(e)
+            }
+        };
+        match (self.secondary.retrieve(name), primary_err) {
+            (Ok(Some(data)), _) => Ok(Some(data)),
+            // The blob may well exist in the primary backend; do not report it as missing.
+            (Ok(None), Some(e)) => Err(e),
+            (res, _) => res,
+        }
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        both("Delete", self.primary.delete(name), self.secondary.delete(name))
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        both("Flush", self.primary.flush(), self.secondary.flush())
+    }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, BackendError> {
+        // Like `retrieve`, the blob exists if either backend has it.
+        match self.primary.exists(name) {
+            Ok(true) => Ok(true),
+            Ok(false) => self.secondary.exists(name),
+            Err(e) => {
+                warn!("Primary backend failed to check for blob, trying secondary: {}", e);
+                match self.secondary.exists(name) {
+                    Ok(false) => Err(e),
+                    res => res,
+                }
+            }
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, BackendError> {
+        // Like `retrieve`, a blob is stored if either backend has it.
+        let mut names = match (self.primary.list(), self.secondary.list()) {
+            (Ok(mut primary), Ok(secondary)) => {
+                primary.extend(secondary);
+                primary
+            }
+            (Ok(names), Err(e)) | (Err(e), Ok(names)) => {
+                warn!("One of the mirrored backends failed to list blobs: {}", e);
+                names
+            }
+            (Err(e), Err(_)) => return Err(e),
+        };
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn delete_batch(&self, names: &[Vec<u8>]) -> Vec<(Vec<u8>, BackendError)> {
+        let mut primary: BTreeMap<_, _> = self.primary.delete_batch(names).into_iter().collect();
+        let mut secondary: BTreeMap<_, _> =
+            self.secondary.delete_batch(names).into_iter().collect();
+
+        let mut failed = Vec::new();
+        for name in names.iter() {
+            let p = primary.remove(name).map_or(Ok(()), Err);
+            let s = secondary.remove(name).map_or(Ok(()), Err);
+            if let Err(e) = both("Delete", p, s) {
+                failed.push((name.clone(), e));
+            }
+        }
+        failed
+    }
+}
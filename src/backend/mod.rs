@@ -12,19 +12,101 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod asynchronous;
+mod caching;
 mod devnull;
 mod file;
+mod http;
 mod memory;
+mod mirror;
+mod pool;
+mod retry;
+mod s3;
+mod throttled;
+#[cfg(test)]
+pub mod tests;
+
+use std::borrow::Cow;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
 
 use crypto::CipherText;
+use errors::RetryError;
 
+pub use self::asynchronous::{AsyncAdapter, AsyncStoreBackend, BlockingAdapter, Callback};
+pub use self::caching::CachingBackend;
 pub use self::devnull::DevNullBackend;
 pub use self::file::FileBackend;
+pub use self::http::{HttpBackend, HttpHandler};
 pub use self::memory::MemoryBackend;
+pub use self::mirror::MirrorBackend;
+pub use self::pool::{ConnectionPool, PooledConnection};
+pub use self::retry::RetryBackend;
+pub use self::s3::{S3Backend, S3Credentials};
+pub use self::throttled::ThrottledBackend;
+
+
+error_type! {
+    #[derive(Clone, Debug)]
+    pub enum BackendError {
+        /// A transient failure (e.g. a timeout or a 5xx reply); the call may be retried.
+        Retry(RetryError) {
+            cause;
+        },
+        /// A permanent failure; retrying the call will not help.
+        Message(Cow<'static, str>) {
+            desc (e) &**e;
+            from (s: &'static str) s.into();
+            from (s: String) s.into();
+        },
+    }
+}
 
 pub trait StoreBackend: Sync + Send + 'static {
-    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String>;
-    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String>;
-    fn delete(&self, name: &[u8]) -> Result<(), String>;
-    fn flush(&self) -> Result<(), String>;
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError>;
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError>;
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError>;
+    fn flush(&self) -> Result<(), BackendError>;
+
+    /// Store the first `len` bytes of `file` as the blob `name`. Reads from the start of the file,
+    /// whatever its position, so that a failed call can be repeated.
+    ///
+    /// Reads the bytes into memory and stores them with `store()` by default; backends that can
+    /// write the file out as they read it may override this, so that a large blob is never held
+    /// in memory.
+    fn store_file(&self, name: &[u8], file: &fs::File, len: u64) -> Result<(), BackendError> {
+        let mut file = file;
+        let mut data = Vec::with_capacity(len as usize);
+        let read = file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.take(len).read_to_end(&mut data));
+        match read {
+            Ok(n) if n as u64 == len => self.store(name, &CipherText::new(data)),
+            Ok(n) => Err(From::from(format!("Blob file holds {} of {} bytes", n, len))),
+            Err(e) => Err(From::from(e.to_string())),
+        }
+    }
+
+    /// Whether the blob `name` is stored, without needing its contents.
+    ///
+    /// Retrieves the blob by default; backends that can check for a blob more cheaply (e.g. with
+    /// a HEAD request) may override this.
+    fn exists(&self, name: &[u8]) -> Result<bool, BackendError> {
+        Ok(try!(self.retrieve(name)).is_some())
+    }
+
+    /// The names of all stored blobs, in no particular order.
+    ///
+    /// Needed only to rebuild a lost index from the backend; fails by default.
+    fn list(&self) -> Result<Vec<Vec<u8>>, BackendError> {
+        Err(From::from("Backend cannot list its blobs"))
+    }
+
+    /// Delete several blobs. Returns the names that could not be deleted, with their errors.
+    ///
+    /// Deletes one blob at a time by default; backends supporting bulk deletes may override this.
+    fn delete_batch(&self, names: &[Vec<u8>]) -> Vec<(Vec<u8>, BackendError)> {
+        names.iter()
+            .filter_map(|name| self.delete(&name[..]).err().map(|e| (name.clone(), e)))
+            .collect()
+    }
 }
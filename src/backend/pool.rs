@@ -0,0 +1,114 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Share a bounded number of connections between the calls to a network backend.
+
+use std::ops;
+use std::sync::{Condvar, Mutex};
+
+use backend::BackendError;
+
+
+/// Opens connections as they are needed, up to `max_connections`, and keeps them for reuse.
+/// Callers wait for a connection to be returned when all of them are in use.
+pub struct ConnectionPool<C> {
+    max_connections: usize,
+    connect: Box<Fn() -> Result<C, BackendError> + Send + Sync>,
+    // The idle connections, and the number of connections open in total.
+    state: Mutex<(Vec<C>, usize)>,
+    returned: Condvar,
+}
+
+/// A connection taken from a `ConnectionPool`. It is returned to the pool when dropped.
+pub struct PooledConnection<'a, C: 'a> {
+    pool: &'a ConnectionPool<C>,
+    conn: Option<C>,
+}
+
+impl<C> ConnectionPool<C> {
+    /// Create a pool opening connections with `connect`. Nothing is opened up front.
+    pub fn new<F>(max_connections: usize, connect: F) -> ConnectionPool<C>
+        where F: Fn() -> Result<C, BackendError> + Send + Sync + 'static
+    {
+        assert!(max_connections > 0);
+        ConnectionPool {
+            max_connections: max_connections,
+            connect: Box::new(connect),
+            state: Mutex::new((Vec::new(), 0)),
+            returned: Condvar::new(),
+        }
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Take an idle connection, or open a new one if there are fewer than `max_connections`.
+    /// Otherwise, wait for a connection to be returned.
+    pub fn get(&self) -> Result<PooledConnection<C>, BackendError> {
+        {
+            let mut state = self.state.lock().unwrap();
+            loop {
+                let idle = state.0.pop();
+                if let Some(conn) = idle {
+                    return Ok(PooledConnection {
+                        pool: self,
+                        conn: Some(conn),
+                    });
+                }
+                if state.1 < self.max_connections {
+                    state.1 += 1;
+                    break;
+                }
+                state = self.returned.wait(state).unwrap();
+            }
+        }
+
+        // Connect without holding the lock, so that idle connections can be taken meanwhile.
+        match (self.connect)() {
+            Ok(conn) => {
+                Ok(PooledConnection {
+                    pool: self,
+                    conn: Some(conn),
+                })
+            }
+            Err(e) => {
+                self.state.lock().unwrap().1 -= 1;
+                self.returned.notify_one();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'a, C> ops::Deref for PooledConnection<'a, C> {
+    type Target = C;
+    fn deref(&self) -> &C {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl<'a, C> ops::DerefMut for PooledConnection<'a, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl<'a, C> ops::Drop for PooledConnection<'a, C> {
+    fn drop(&mut self) {
+        let conn = self.conn.take().unwrap();
+        self.pool.state.lock().unwrap().0.push(conn);
+        self.pool.returned.notify_one();
+    }
+}
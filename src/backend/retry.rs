@@ -0,0 +1,149 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retry transient backend failures with exponential backoff.
+
+use rand;
+use std::cmp;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+
+
+/// Wraps a backend and retries calls failing with `BackendError::Retry`.
+///
+/// Permanent errors (`BackendError::Message`) are returned immediately.
+pub struct RetryBackend<B> {
+    backend: B,
+    max_attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl<B: StoreBackend> RetryBackend<B> {
+    /// Retry up to 5 attempts, starting with a delay of 100ms.
+    pub fn new(backend: B) -> RetryBackend<B> {
+        RetryBackend::with_backoff(backend, 5, Duration::from_millis(100), Duration::from_secs(30))
+    }
+
+    /// Make at most `max_attempts` calls. The delay before a retry starts at `initial_delay` and
+    /// doubles with every failure, up to `max_delay`.
+    pub fn with_backoff(backend: B,
+                        max_attempts: u32,
+                        initial_delay: Duration,
+                        max_delay: Duration)
+                        -> RetryBackend<B> {
+        RetryBackend {
+            backend: backend,
+            max_attempts: cmp::max(1, max_attempts),
+            initial_delay: initial_delay,
+            max_delay: max_delay,
+        }
+    }
+
+    /// Delay before the retry following `attempt` failed calls; a random value between half and
+    /// all of the backoff, so that concurrent callers do not retry in lockstep.
+    fn delay(&self, attempt: u32) -> Duration {
+        let shift = cmp::min(attempt - 1, 31);
+        let backoff = self.initial_delay
+            .checked_mul(1 << shift)
+            .map_or(self.max_delay, |d| cmp::min(d, self.max_delay));
+        let millis = backoff.as_secs() * 1000 + backoff.subsec_nanos() as u64 / 1_000_000;
+        Duration::from_millis(millis / 2 + rand::random::<u64>() % (millis / 2 + 1))
+    }
+
+    fn retry<T, F>(&self, what: &str, f: F) -> Result<T, BackendError>
+        where F: Fn(&B) -> Result<T, BackendError>
+    {
+        let mut attempt = 1;
+        loop {
+            match f(&self.backend) {
+                Err(BackendError::Retry(e)) if attempt < self.max_attempts => {
+                    let delay = self.delay(attempt);
+                    warn!("Backend {} failed ({}), attempt {} of {}; retrying in {:?}",
+                          what,
+                          e,
+                          attempt,
+                          self.max_attempts,
+                          delay);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for RetryBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.retry("store", |b| b.store(name, data))
+    }
+
+    fn store_file(&self, name: &[u8], file: &fs::File, len: u64) -> Result<(), BackendError> {
+        self.retry("store", |b| b.store_file(name, file, len))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.retry("retrieve", |b| b.retrieve(name))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.retry("delete", |b| b.delete(name))
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.retry("flush", |b| b.flush())
+    }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, BackendError> {
+        self.retry("exists", |b| b.exists(name))
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, BackendError> {
+        self.retry("list", |b| b.list())
+    }
+
+    fn delete_batch(&self, names: &[Vec<u8>]) -> Vec<(Vec<u8>, BackendError)> {
+        let mut failed = self.backend.delete_batch(names);
+        let mut attempt = 1;
+        while attempt < self.max_attempts {
+            // Only retry the names that failed transiently.
+            let (retry, permanent): (Vec<_>, Vec<_>) =
+                failed.into_iter().partition(|&(_, ref e)| match *e {
+                    BackendError::Retry(_) => true,
+                    BackendError::Message(_) => false,
+                });
+            if retry.is_empty() {
+                return permanent;
+            }
+            let delay = self.delay(attempt);
+            warn!("Backend delete failed for {} blobs, attempt {} of {}; retrying in {:?}",
+                  retry.len(),
+                  attempt,
+                  self.max_attempts,
+                  delay);
+            thread::sleep(delay);
+            attempt += 1;
+
+            let names: Vec<Vec<u8>> = retry.into_iter().map(|(name, _)| name).collect();
+            failed = permanent;
+            failed.extend(self.backend.delete_batch(&names[..]));
+        }
+        failed
+    }
+}
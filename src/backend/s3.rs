@@ -0,0 +1,495 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Store blobs as objects in an S3 bucket, using the S3 REST API.
+
+use hyper;
+use hyper::client::{Body, Client, RequestBuilder, Response, pool};
+use hyper::header::{Headers, Host};
+use hyper::status::{StatusClass, StatusCode};
+use rustc_serialize::hex::{FromHex, ToHex};
+use libsodium_sys;
+use sodiumoxide::crypto::hash::sha256;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::mem;
+use time;
+
+use backend::{BackendError, ConnectionPool, StoreBackend};
+use crypto::CipherText;
+use errors::RetryError;
+
+
+const SIGNED_HEADERS: &'static str = "host;x-amz-content-sha256;x-amz-date";
+const HMAC_BLOCK_SIZE: usize = 64;
+/// The size of the pieces in which files are read to be hashed.
+const FILE_READ_SIZE: usize = 64 * 1024;
+
+/// The number of connections to the S3 service open at once, unless configured otherwise.
+const DEFAULT_MAX_CONNECTIONS: usize = 8;
+
+#[derive(Clone)]
+pub struct S3Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+pub struct S3Backend {
+    clients: ConnectionPool<Client>,
+    endpoint: hyper::Url,
+    region: String,
+    bucket: String,
+    prefix: String,
+    credentials: S3Credentials,
+}
+
+/// The body of a request.
+enum Payload<'a> {
+    Bytes(&'a [u8]),
+    /// Bytes read from a file, and their digest as given by `hex_sha256_file()`.
+    File(io::Take<&'a fs::File>, String),
+}
+
+#[derive(Clone, Copy)]
+enum Method {
+    Get,
+    Put,
+    Delete,
+    Head,
+}
+
+impl Method {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Method::Get => "GET",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Head => "HEAD",
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], msg: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; HMAC_BLOCK_SIZE];
+    if key.len() > HMAC_BLOCK_SIZE {
+        let sha256::Digest(digest) = sha256::hash(key);
+        block[..digest.len()].copy_from_slice(&digest);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(msg);
+    let sha256::Digest(inner_digest) = sha256::hash(&inner[..]);
+
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&inner_digest);
+    let sha256::Digest(digest) = sha256::hash(&outer[..]);
+    digest.to_vec()
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    let sha256::Digest(digest) = sha256::hash(data);
+    digest.to_hex()
+}
+
+/// Like `hex_sha256()`, of the `len` bytes read from `file` in pieces.
+fn hex_sha256_file(file: &fs::File, len: u64) -> io::Result<String> {
+    let mut state: libsodium_sys::crypto_hash_sha256_state = unsafe { mem::uninitialized() };
+    unsafe {
+        libsodium_sys::crypto_hash_sha256_init(&mut state);
+    }
+    let mut reader = file.take(len);
+    let mut buf = vec![0; FILE_READ_SIZE];
+    let mut read = 0;
+    loop {
+        let n = match reader.read(&mut buf[..]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        unsafe {
+            libsodium_sys::crypto_hash_sha256_update(&mut state, buf.as_ptr(), n as u64);
+        }
+        read += n as u64;
+    }
+    if read != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  format!("Blob file holds {} of {} bytes", read, len)));
+    }
+
+    let mut digest = [0; libsodium_sys::crypto_hash_sha256_BYTES];
+    unsafe {
+        libsodium_sys::crypto_hash_sha256_final(&mut state, &mut digest);
+    }
+    Ok(digest.to_hex())
+}
+
+/// Percent-encode `s` as AWS Signature Version 4 expects in query strings.
+fn uri_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// The query string of `params`, encoded and sorted as AWS Signature Version 4 expects.
+fn canonical_query(params: &[(&str, String)]) -> String {
+    let mut pairs: Vec<String> = params.iter()
+        .map(|&(key, ref value)| format!("{}={}", uri_encode(key), uri_encode(value)))
+        .collect();
+    pairs.sort();
+    pairs.join("&")
+}
+
+/// The text of every `<tag>` element in `xml`, still escaped.
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open[..]) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close[..]) {
+            Some(end) => {
+                values.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    values
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Each client keeps a single connection alive, so that the pool bounds the connections.
+fn client_pool(max_connections: usize) -> ConnectionPool<Client> {
+    ConnectionPool::new(max_connections,
+                        || Ok(Client::with_pool_config(pool::Config { max_idle: 1 })))
+}
+
+impl S3Backend {
+    /// Create a backend storing blobs in `bucket` under `prefix`.
+    ///
+    /// `endpoint` is the base URL of the S3 service (e.g. `https://s3.amazonaws.com`); objects are
+    /// addressed path-style as `<endpoint>/<bucket>/<prefix><hex name>`.
+    pub fn new(endpoint: &str,
+               region: String,
+               bucket: String,
+               prefix: String,
+               credentials: S3Credentials)
+               -> Result<S3Backend, BackendError> {
+        let endpoint = match hyper::Url::parse(endpoint) {
+            Ok(url) => url,
+            Err(e) => return Err(From::from(format!("Invalid S3 endpoint: {}", e))),
+        };
+        if endpoint.host_str().is_none() {
+            return Err(From::from("Invalid S3 endpoint: missing host"));
+        }
+        Ok(S3Backend {
+            clients: client_pool(DEFAULT_MAX_CONNECTIONS),
+            endpoint: endpoint,
+            region: region,
+            bucket: bucket,
+            prefix: prefix,
+            credentials: credentials,
+        })
+    }
+
+    /// Open at most `max_connections` connections at once (8 by default). Calls wait for a
+    /// connection when all of them are in use.
+    pub fn with_max_connections(mut self, max_connections: usize) -> S3Backend {
+        self.clients = client_pool(max_connections);
+        self
+    }
+
+    fn object_path(&self, name: &[u8]) -> String {
+        format!("/{}/{}{}", self.bucket, self.prefix, name.to_hex())
+    }
+
+    fn host(&self) -> Host {
+        Host {
+            hostname: self.endpoint.host_str().unwrap().to_owned(),
+            port: self.endpoint.port(),
+        }
+    }
+
+    /// Build the AWS Signature Version 4 headers for a request of `path`, with the canonical
+    /// query string `query`.
+    fn signed_headers(&self,
+                      method: Method,
+                      path: &str,
+                      query: &str,
+                      payload_hash: &str)
+                      -> Headers {
+        let now = time::now_utc();
+        let amz_date = now.strftime("%Y%m%dT%H%M%SZ").unwrap().to_string();
+        let date = now.strftime("%Y%m%d").unwrap().to_string();
+
+        let host = self.host();
+        let host_value = match host.port {
+            Some(port) => format!("{}:{}", host.hostname, port),
+            None => host.hostname.clone(),
+        };
+        let canonical_request = format!("{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\n\
+                                         x-amz-date:{}\n\n{}\n{}",
+                                        method.as_str(),
+                                        path,
+                                        query,
+                                        host_value,
+                                        payload_hash,
+                                        amz_date,
+                                        SIGNED_HEADERS,
+                                        payload_hash);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                                     amz_date,
+                                     scope,
+                                     hex_sha256(canonical_request.as_bytes()));
+
+        let secret = format!("AWS4{}", self.credentials.secret_key);
+        let key = hmac_sha256(secret.as_bytes(), date.as_bytes());
+        let key = hmac_sha256(&key[..], self.region.as_bytes());
+        let key = hmac_sha256(&key[..], b"s3");
+        let key = hmac_sha256(&key[..], b"aws4_request");
+        let signature = hmac_sha256(&key[..], string_to_sign.as_bytes()).to_hex();
+
+        let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, \
+                                     Signature={}",
+                                    self.credentials.access_key,
+                                    scope,
+                                    SIGNED_HEADERS,
+                                    signature);
+
+        let mut headers = Headers::new();
+        headers.set(host);
+        headers.set_raw("x-amz-date", vec![amz_date.into_bytes()]);
+        headers.set_raw("x-amz-content-sha256", vec![payload_hash.as_bytes().to_vec()]);
+        headers.set_raw("Authorization", vec![authorization.into_bytes()]);
+        headers
+    }
+
+    /// Send a request for the object `name`, and handle its response with `read`.
+    fn send<T, F>(&self,
+                  method: Method,
+                  name: &[u8],
+                  payload: &[u8],
+                  read: F)
+                  -> Result<T, BackendError>
+        where F: FnOnce(Response) -> Result<T, BackendError>
+    {
+        self.send_request(method, &self.object_path(name), "", Payload::Bytes(payload), read)
+    }
+
+    /// Send a request of `path` with the canonical query string `query`, and handle its response
+    /// with `read`. The connection is held until `read` is done with the response, so that it is
+    /// not shared while the response is being read.
+    fn send_request<T, F>(&self,
+                          method: Method,
+                          path: &str,
+                          query: &str,
+                          mut payload: Payload,
+                          read: F)
+                          -> Result<T, BackendError>
+        where F: FnOnce(Response) -> Result<T, BackendError>
+    {
+        let mut url = self.endpoint.clone();
+        url.set_path(path);
+        if !query.is_empty() {
+            url.set_query(Some(query));
+        }
+        let headers = {
+            let payload_hash = match payload {
+                Payload::Bytes(bytes) => hex_sha256(bytes),
+                Payload::File(_, ref hash) => hash.clone(),
+            };
+            self.signed_headers(method, path, query, &payload_hash)
+        };
+        let client = try!(self.clients.get());
+        let request: RequestBuilder = match method {
+            Method::Get => client.get(url),
+            Method::Put => {
+                match payload {
+                    Payload::Bytes(bytes) => client.put(url).body(bytes),
+                    Payload::File(ref mut reader, _) => {
+                        let len = reader.limit();
+                        client.put(url).body(Body::SizedBody(reader, len))
+                    }
+                }
+            }
+            Method::Delete => client.delete(url),
+            Method::Head => client.head(url),
+        };
+        match request.headers(headers).send() {
+            Ok(response) => read(response),
+            Err(hyper::Error::Io(e)) => {
+                warn!("S3 {} request failed: {}", method.as_str(), e);
+                Err(BackendError::Retry(RetryError))
+            }
+            Err(e) => Err(From::from(format!("S3 {} request failed: {}", method.as_str(), e))),
+        }
+    }
+
+    fn status_error(&self, method: Method, mut response: Response) -> BackendError {
+        let mut body = String::new();
+        let _ = response.read_to_string(&mut body);
+        if response.status.class() == StatusClass::ServerError {
+            warn!("S3 {} returned {}: {}", method.as_str(), response.status, body);
+            BackendError::Retry(RetryError)
+        } else {
+            From::from(format!("S3 {} returned {}: {}", method.as_str(), response.status, body))
+        }
+    }
+}
+
+impl StoreBackend for S3Backend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let payload = data.to_vec();
+        self.send(Method::Put, name, &payload[..], |response| {
+            if response.status.is_success() {
+                Ok(())
+            } else {
+                Err(self.status_error(Method::Put, response))
+            }
+        })
+    }
+
+    fn store_file(&self, name: &[u8], file: &fs::File, len: u64) -> Result<(), BackendError> {
+        // The request is signed with the digest of the file, so it is read twice.
+        let mut file = file;
+        let digest = file.seek(SeekFrom::Start(0))
+            .and_then(|_| hex_sha256_file(file, len))
+            .and_then(|digest| file.seek(SeekFrom::Start(0)).map(|_| digest));
+        let digest = match digest {
+            Ok(digest) => digest,
+            Err(e) => return Err(From::from(e.to_string())),
+        };
+        let payload = Payload::File(file.take(len), digest);
+        self.send_request(Method::Put, &self.object_path(name), "", payload, |response| {
+            if response.status.is_success() {
+                Ok(())
+            } else {
+                Err(self.status_error(Method::Put, response))
+            }
+        })
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.send(Method::Get, name, &[], |mut response| {
+            if response.status == StatusCode::NotFound {
+                return Ok(None);
+            }
+            if !response.status.is_success() {
+                return Err(self.status_error(Method::Get, response));
+            }
+
+            let mut buf = Vec::new();
+            match response.read_to_end(&mut buf) {
+                Ok(_) => Ok(Some(buf)),
+                Err(e) => {
+                    warn!("S3 GET response could not be read: {}", e);
+                    Err(BackendError::Retry(RetryError))
+                }
+            }
+        })
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.send(Method::Delete, name, &[], |response| {
+            // Deleting a missing object is not an error.
+            if response.status.is_success() || response.status == StatusCode::NotFound {
+                Ok(())
+            } else {
+                Err(self.status_error(Method::Delete, response))
+            }
+        })
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, BackendError> {
+        self.send(Method::Head, name, &[], |response| {
+            if response.status == StatusCode::NotFound {
+                Ok(false)
+            } else if response.status.is_success() {
+                Ok(true)
+            } else {
+                Err(self.status_error(Method::Head, response))
+            }
+        })
+    }
+
+    /// Lists the objects under the prefix with ListObjectsV2, a page at a time. Objects under
+    /// the prefix whose names are not hex-encoded are not blobs, and are skipped.
+    fn list(&self) -> Result<Vec<Vec<u8>>, BackendError> {
+        let path = format!("/{}", self.bucket);
+        let mut names = vec![];
+        let mut token: Option<String> = None;
+        loop {
+            let mut params = vec![("list-type", "2".to_owned()), ("prefix", self.prefix.clone())];
+            if let Some(ref token) = token {
+                params.push(("continuation-token", token.clone()));
+            }
+            let page = try!(self.send_request(Method::Get,
+                                              &path,
+                                              &canonical_query(&params[..]),
+                                              Payload::Bytes(&[]),
+                                              |mut response| {
+                if !response.status.is_success() {
+                    return Err(self.status_error(Method::Get, response));
+                }
+                let mut body = String::new();
+                match response.read_to_string(&mut body) {
+                    Ok(_) => Ok(body),
+                    Err(e) => {
+                        warn!("S3 listing could not be read: {}", e);
+                        Err(BackendError::Retry(RetryError))
+                    }
+                }
+            }));
+
+            for key in xml_values(&page, "Key").into_iter().map(xml_unescape) {
+                if key.starts_with(&self.prefix[..]) {
+                    if let Ok(name) = key[self.prefix.len()..].from_hex() {
+                        names.push(name);
+                    }
+                }
+            }
+            if xml_values(&page, "IsTruncated").first() != Some(&"true") {
+                return Ok(names);
+            }
+            token = xml_values(&page, "NextContinuationToken").first().map(|t| xml_unescape(t));
+            if token.is_none() {
+                return Err(From::from("S3 listing is truncated, but has no continuation token"));
+            }
+        }
+    }
+}
@@ -0,0 +1,1044 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{AsyncAdapter, AsyncStoreBackend, BackendError, BlockingAdapter, CachingBackend,
+              Callback, ConnectionPool, DevNullBackend, FileBackend, HttpBackend, HttpHandler,
+              MemoryBackend, MirrorBackend, RetryBackend, S3Backend, S3Credentials, StoreBackend,
+              ThrottledBackend};
+use crypto::CipherText;
+use errors::RetryError;
+use util::Durability;
+
+use hyper::method::Method;
+use hyper::server::{Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rand;
+use rustc_serialize::hex::FromHex;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Number of keys the mock S3 lists per page, so that listings take several pages.
+const MOCK_S3_PAGE_SIZE: usize = 2;
+
+/// A minimal in-memory imitation of the S3 object API (GET, PUT and DELETE by path, and
+/// ListObjectsV2).
+pub struct MockS3 {
+    listening: Listening,
+    /// Number of upcoming requests to fail with "503 Service Unavailable".
+    pub fail_next: Arc<AtomicUsize>,
+}
+
+impl MockS3 {
+    pub fn start() -> MockS3 {
+        let objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>> = Arc::new(Mutex::new(BTreeMap::new()));
+        let fail_next = Arc::new(AtomicUsize::new(0));
+
+        let fail = fail_next.clone();
+        let handler = move |mut req: Request, mut res: Response| {
+            let path = match req.uri {
+                RequestUri::AbsolutePath(ref p) => p.clone(),
+                _ => panic!("Unexpected request URI"),
+            };
+            let mut body = Vec::new();
+            req.read_to_end(&mut body).unwrap();
+
+            let reply = if fail.load(Ordering::SeqCst) > 0 {
+                fail.fetch_sub(1, Ordering::SeqCst);
+                (StatusCode::ServiceUnavailable, vec![])
+            } else if !req.headers.has_raw("Authorization") {
+                (StatusCode::Forbidden, vec![])
+            } else {
+                let mut objects = objects.lock().unwrap();
+                match req.method {
+                    Method::Get if path.contains('?') => list_objects(&objects, &path),
+                    Method::Get => {
+                        match objects.get(&path) {
+                            Some(data) => (StatusCode::Ok, data.clone()),
+                            None => (StatusCode::NotFound, vec![]),
+                        }
+                    }
+                    Method::Put => {
+                        objects.insert(path, body);
+                        (StatusCode::Ok, vec![])
+                    }
+                    Method::Delete => {
+                        match objects.remove(&path) {
+                            Some(_) => (StatusCode::NoContent, vec![]),
+                            None => (StatusCode::NotFound, vec![]),
+                        }
+                    }
+                    Method::Head => {
+                        match objects.get(&path) {
+                            Some(_) => (StatusCode::Ok, vec![]),
+                            None => (StatusCode::NotFound, vec![]),
+                        }
+                    }
+                    _ => (StatusCode::MethodNotAllowed, vec![]),
+                }
+            };
+
+            *res.status_mut() = reply.0;
+            res.send(&reply.1[..]).unwrap();
+        };
+
+        let listening = Server::http("127.0.0.1:0").unwrap().handle(handler).unwrap();
+        MockS3 {
+            listening: listening,
+            fail_next: fail_next,
+        }
+    }
+
+    pub fn backend(&self, prefix: &str) -> S3Backend {
+        let endpoint = format!("http://{}", self.listening.socket);
+        S3Backend::new(&endpoint,
+                       "us-east-1".to_owned(),
+                       "bucket".to_owned(),
+                       prefix.to_owned(),
+                       S3Credentials {
+                           access_key: "access".to_owned(),
+                           secret_key: "secret".to_owned(),
+                       })
+            .unwrap()
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < s.len() {
+        if s.as_bytes()[i] == b'%' && i + 3 <= s.len() {
+            out.extend(s[i + 1..i + 3].from_hex().unwrap());
+            i += 3;
+        } else {
+            out.push(s.as_bytes()[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).unwrap()
+}
+
+/// Reply to a ListObjectsV2 request of `path`, e.g. `/bucket?list-type=2&prefix=blobs%2F`. The
+/// continuation token is the last key listed so far.
+fn list_objects(objects: &BTreeMap<String, Vec<u8>>, path: &str) -> (StatusCode, Vec<u8>) {
+    let (bucket, query) = path.split_at(path.find('?').unwrap());
+    let mut params = HashMap::new();
+    for pair in query[1..].split('&') {
+        let mut kv = pair.splitn(2, '=');
+        let key = kv.next().unwrap().to_owned();
+        params.insert(key, percent_decode(kv.next().unwrap_or("")));
+    }
+    if params.get("list-type").map(|t| &t[..]) != Some("2") {
+        return (StatusCode::BadRequest, vec![]);
+    }
+
+    let prefix = format!("{}/{}", bucket, params.get("prefix").map_or("", |p| &p[..]));
+    let after = params.get("continuation-token").map(|t| format!("{}/{}", bucket, t));
+    let keys: Vec<&String> = objects.keys()
+        .filter(|k| k.starts_with(&prefix[..]))
+        .filter(|k| after.as_ref().map_or(true, |after| *k > after))
+        .take(MOCK_S3_PAGE_SIZE + 1)
+        .collect();
+    let truncated = keys.len() > MOCK_S3_PAGE_SIZE;
+
+    let mut xml = "<ListBucketResult>".to_owned();
+    for key in keys.iter().take(MOCK_S3_PAGE_SIZE) {
+        xml.push_str(&format!("<Contents><Key>{}</Key></Contents>", &key[bucket.len() + 1..]));
+    }
+    xml.push_str(&format!("<IsTruncated>{}</IsTruncated>", truncated));
+    if truncated {
+        xml.push_str(&format!("<NextContinuationToken>{}</NextContinuationToken>",
+                              &keys[MOCK_S3_PAGE_SIZE - 1][bucket.len() + 1..]));
+    }
+    xml.push_str("</ListBucketResult>");
+    (StatusCode::Ok, xml.into_bytes())
+}
+
+impl Drop for MockS3 {
+    fn drop(&mut self) {
+        // Dropping hyper's `Listening` waits for its accept loop, which never ends. Closing it
+        // first detaches the loop instead, which serves any remaining clients until the run exits.
+        self.listening.close().unwrap();
+    }
+}
+
+/// Start a mock S3 endpoint that lives for the rest of the test run, and return a backend for it.
+pub fn mock_s3_backend() -> S3Backend {
+    let mock = MockS3::start();
+    mock.backend("blobs/")
+}
+
+#[test]
+fn s3_store_retrieve_delete() {
+    let backend = mock_s3_backend();
+    let data = vec![1u8, 2, 3, 4];
+
+    assert_eq!(backend.retrieve(b"name").unwrap(), None);
+
+    backend.store(b"name", &CipherText::new(data.clone())).unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), Some(data));
+
+    backend.delete(b"name").unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), None);
+
+    // Deleting a missing object is fine.
+    backend.delete(b"name").unwrap();
+}
+
+#[test]
+fn s3_prefixes_are_disjoint() {
+    let mock = MockS3::start();
+    let first = mock.backend("first/");
+    let second = mock.backend("second/");
+
+    first.store(b"name", &CipherText::new(vec![1])).unwrap();
+    assert_eq!(second.retrieve(b"name").unwrap(), None);
+    assert_eq!(first.retrieve(b"name").unwrap(), Some(vec![1]));
+}
+
+#[test]
+fn s3_server_errors_are_retryable() {
+    let mock = MockS3::start();
+    let backend = mock.backend("");
+    let fail_next = mock.fail_next.clone();
+
+    fail_next.store(1, Ordering::SeqCst);
+    match backend.store(b"name", &CipherText::new(vec![1])) {
+        Err(BackendError::Retry(_)) => (),
+        other => panic!("Expected a retryable error, got: {:?}", other),
+    }
+
+    // The request succeeds once the server recovers.
+    backend.store(b"name", &CipherText::new(vec![1])).unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), Some(vec![1]));
+}
+
+#[test]
+fn s3_concurrent_calls_share_connections() {
+    let mock = MockS3::start();
+    let backend = Arc::new(mock.backend("").with_max_connections(2));
+
+    let threads: Vec<_> = (0..8u8)
+        .map(|i| {
+            let backend = backend.clone();
+            thread::spawn(move || {
+                for j in 0..8u8 {
+                    backend.store(&[j, i], &CipherText::new(vec![i; 1024])).unwrap();
+                    assert_eq!(backend.retrieve(&[j, i]).unwrap(), Some(vec![i; 1024]));
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+/// Counts the connections open and in use, and the most seen at once.
+#[derive(Default)]
+struct ConnectionCounts {
+    opened: usize,
+    open: usize,
+    max_open: usize,
+    in_use: usize,
+    max_in_use: usize,
+}
+
+struct MockConnection {
+    counts: Arc<Mutex<ConnectionCounts>>,
+}
+
+impl Drop for MockConnection {
+    fn drop(&mut self) {
+        self.counts.lock().unwrap().open -= 1;
+    }
+}
+
+/// Serve `backend` as a blob server on a local socket for the rest of the test run, and return a
+/// client for it.
+fn serve_http<B: StoreBackend>(backend: Arc<B>) -> HttpBackend {
+    let mut listening =
+        Server::http("127.0.0.1:0").unwrap().handle(HttpHandler::new(backend)).unwrap();
+    let client = HttpBackend::new(&format!("http://{}", listening.socket)).unwrap();
+    // As for `MockS3`, closing detaches the accept loop so that dropping `listening` returns.
+    listening.close().unwrap();
+    client
+}
+
+/// Start a blob server over a `MemoryBackend` that lives for the rest of the test run, and return
+/// a client for it.
+pub fn mock_http_backend() -> HttpBackend {
+    serve_http(Arc::new(MemoryBackend::new()))
+}
+
+#[test]
+fn http_store_retrieve_delete() {
+    let served = Arc::new(MemoryBackend::new());
+    let backend = serve_http(served.clone());
+    let data = vec![1u8, 2, 3, 4];
+
+    assert_eq!(backend.retrieve(b"name").unwrap(), None);
+
+    // Blobs round-trip through the server into the served backend.
+    backend.store(b"name", &CipherText::new(data.clone())).unwrap();
+    assert_eq!(served.retrieve(b"name").unwrap(), Some(data.clone()));
+    assert_eq!(backend.retrieve(b"name").unwrap(), Some(data));
+    backend.flush().unwrap();
+
+    // Any bytes may be part of a name.
+    backend.store(&[0, 47, 255], &CipherText::new(vec![5])).unwrap();
+    assert_eq!(backend.retrieve(&[0, 47, 255]).unwrap(), Some(vec![5]));
+
+    backend.delete(b"name").unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), None);
+    assert_eq!(served.retrieve(b"name").unwrap(), None);
+
+    // Deleting a missing blob is fine.
+    backend.delete(b"name").unwrap();
+}
+
+#[test]
+fn http_server_keeps_error_kinds() {
+    let backend = serve_http(Arc::new(FlakyBackend::new(1, false)));
+    match backend.store(b"name", &CipherText::new(vec![1])) {
+        Err(BackendError::Retry(_)) => (),
+        other => panic!("Expected a retryable error, got: {:?}", other),
+    }
+    backend.store(b"name", &CipherText::new(vec![1])).unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), Some(vec![1]));
+
+    let backend = serve_http(Arc::new(FlakyBackend::new(1, true)));
+    match backend.retrieve(b"name") {
+        Err(BackendError::Message(msg)) => assert!(msg.contains("Access denied")),
+        other => panic!("Expected a permanent error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn connection_pool_never_exceeds_limit() {
+    let counts = Arc::new(Mutex::new(ConnectionCounts::default()));
+    let local_counts = counts.clone();
+    let pool = Arc::new(ConnectionPool::new(3, move || {
+        let mut counts = local_counts.lock().unwrap();
+        counts.opened += 1;
+        counts.open += 1;
+        counts.max_open = cmp::max(counts.open, counts.max_open);
+        Ok(MockConnection { counts: local_counts.clone() })
+    }));
+    assert_eq!(pool.max_connections(), 3);
+
+    let threads: Vec<_> = (0..16)
+        .map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    let conn = pool.get().unwrap();
+                    {
+                        let mut counts = conn.counts.lock().unwrap();
+                        counts.in_use += 1;
+                        counts.max_in_use = cmp::max(counts.in_use, counts.max_in_use);
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                    conn.counts.lock().unwrap().in_use -= 1;
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let counts = counts.lock().unwrap();
+    // The connections are reused rather than opened for each call.
+    assert!(counts.opened <= 3);
+    assert!(counts.max_open <= 3);
+    assert!(counts.max_in_use <= 3);
+    assert!(counts.max_in_use > 1);
+}
+
+#[test]
+fn connection_pool_failed_connect_frees_slot() {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let local_attempts = attempts.clone();
+    let pool = ConnectionPool::new(1, move || {
+        if local_attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            Err(BackendError::Retry(RetryError))
+        } else {
+            Ok(())
+        }
+    });
+
+    assert!(pool.get().is_err());
+    // The failed attempt does not count against the limit, or this would wait forever.
+    pool.get().unwrap();
+    pool.get().unwrap();
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+}
+
+fn file_backend_root() -> PathBuf {
+    let mut root = env::temp_dir();
+    root.push(format!("hat-file-backend-{:x}", rand::random::<u64>()));
+    root
+}
+
+#[test]
+fn file_store_retrieve_delete() {
+    let root = file_backend_root();
+    let backend = FileBackend::new(root.clone());
+
+    assert_eq!(backend.retrieve(&[0xab, 1]).unwrap(), None);
+
+    backend.store(&[0xab, 1], &CipherText::new(vec![1, 2, 3])).unwrap();
+    backend.store(&[0xab, 2], &CipherText::new(vec![4, 5, 6])).unwrap();
+    assert!(root.join("ab").join("ab01").is_file());
+    assert_eq!(backend.retrieve(&[0xab, 1]).unwrap(), Some(vec![1, 2, 3]));
+    assert_eq!(backend.retrieve(&[0xab, 2]).unwrap(), Some(vec![4, 5, 6]));
+
+    // The shard directory is kept while it has blobs, and removed with the last one.
+    backend.delete(&[0xab, 1]).unwrap();
+    assert_eq!(backend.retrieve(&[0xab, 1]).unwrap(), None);
+    assert!(root.join("ab").is_dir());
+    backend.delete(&[0xab, 2]).unwrap();
+    assert!(!root.join("ab").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn file_reads_blobs_stored_before_sharding() {
+    let root = file_backend_root();
+    fs::create_dir_all(&root).unwrap();
+    fs::File::create(root.join("ab01")).unwrap().write_all(&[1, 2, 3]).unwrap();
+    let backend = FileBackend::new(root.clone());
+
+    assert!(backend.exists(&[0xab, 1]).unwrap());
+    assert_eq!(backend.retrieve(&[0xab, 1]).unwrap(), Some(vec![1, 2, 3]));
+    assert_eq!(backend.list().unwrap(), vec![vec![0xab, 1]]);
+
+    backend.delete(&[0xab, 1]).unwrap();
+    assert!(!backend.exists(&[0xab, 1]).unwrap());
+    assert!(!root.join("ab01").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn file_stores_race_deletes_in_the_same_shard() {
+    let root = file_backend_root();
+    let backend = Arc::new(FileBackend::new(root.clone()));
+
+    // Each delete empties the shard, and removes it under the other thread's stores.
+    let threads: Vec<_> = (0..2u8)
+        .map(|i| {
+            let backend = backend.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    backend.store(&[0xab, i], &CipherText::new(vec![i])).unwrap();
+                    backend.delete(&[0xab, i]).unwrap();
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn file_store_with_weaker_durability() {
+    for &durability in &[Durability::PerCommit, Durability::None] {
+        let root = file_backend_root();
+        let backend = FileBackend::new(root.clone()).with_durability(durability);
+
+        backend.store(&[0xab, 1], &CipherText::new(vec![1, 2, 3])).unwrap();
+        backend.store(&[0xab, 2], &CipherText::new(vec![4, 5, 6])).unwrap();
+        assert_eq!(backend.retrieve(&[0xab, 1]).unwrap(), Some(vec![1, 2, 3]));
+
+        // Blobs deleted before they are synced are skipped.
+        backend.delete(&[0xab, 2]).unwrap();
+        backend.flush().unwrap();
+        backend.flush().unwrap();
+
+        let reopened = FileBackend::new(root.clone());
+        assert_eq!(reopened.retrieve(&[0xab, 1]).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(reopened.retrieve(&[0xab, 2]).unwrap(), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+#[test]
+fn file_concurrent_stores() {
+    let root = file_backend_root();
+    let backend = Arc::new(FileBackend::new(root.clone()));
+
+    let threads: Vec<_> = (0..8u8)
+        .map(|i| {
+            let backend = backend.clone();
+            thread::spawn(move || {
+                for j in 0..32u8 {
+                    backend.store(&[j, i], &CipherText::new(vec![i; 1024])).unwrap();
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    for i in 0..8u8 {
+        for j in 0..32u8 {
+            assert_eq!(backend.retrieve(&[j, i]).unwrap(), Some(vec![i; 1024]));
+        }
+    }
+
+    // No temporary files are left behind.
+    for shard in fs::read_dir(&root).unwrap() {
+        for file in fs::read_dir(shard.unwrap().path()).unwrap() {
+            let name = file.unwrap().file_name();
+            assert!(!name.to_string_lossy().ends_with(".tmp"));
+        }
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// Fails the next `failures` calls, then passes calls on to a `MemoryBackend`.
+struct FlakyBackend {
+    inner: MemoryBackend,
+    failures: AtomicUsize,
+    permanent: bool,
+}
+
+impl FlakyBackend {
+    fn new(failures: usize, permanent: bool) -> FlakyBackend {
+        FlakyBackend {
+            inner: MemoryBackend::new(),
+            failures: AtomicUsize::new(failures),
+            permanent: permanent,
+        }
+    }
+
+    fn fail(&self) -> Result<(), BackendError> {
+        if self.failures.load(Ordering::SeqCst) == 0 {
+            return Ok(());
+        }
+        self.failures.fetch_sub(1, Ordering::SeqCst);
+        if self.permanent {
+            Err(From::from("Access denied"))
+        } else {
+            Err(BackendError::Retry(RetryError))
+        }
+    }
+}
+
+impl StoreBackend for FlakyBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        try!(self.fail());
+        self.inner.store(name, data)
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        try!(self.fail());
+        self.inner.retrieve(name)
+    }
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        try!(self.fail());
+        self.inner.delete(name)
+    }
+    fn flush(&self) -> Result<(), BackendError> {
+        self.inner.flush()
+    }
+}
+
+fn retry_backend(inner: FlakyBackend) -> RetryBackend<FlakyBackend> {
+    RetryBackend::with_backoff(inner, 3, Duration::from_millis(1), Duration::from_millis(10))
+}
+
+#[test]
+fn retry_transient_failures() {
+    let backend = retry_backend(FlakyBackend::new(2, false));
+    backend.store(b"name", &CipherText::new(vec![1])).unwrap();
+
+    let backend = retry_backend(FlakyBackend::new(2, false));
+    assert_eq!(backend.retrieve(b"name").unwrap(), None);
+
+    // Giving up after the last attempt.
+    let backend = retry_backend(FlakyBackend::new(3, false));
+    match backend.store(b"name", &CipherText::new(vec![1])) {
+        Err(BackendError::Retry(_)) => (),
+        other => panic!("Expected a retryable error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn retry_not_on_permanent_failures() {
+    // Had the call been retried, the second attempt would have succeeded.
+    let backend = retry_backend(FlakyBackend::new(1, true));
+    match backend.delete(b"name") {
+        Err(BackendError::Message(_)) => (),
+        other => panic!("Expected a permanent error, got: {:?}", other),
+    }
+    backend.delete(b"name").unwrap();
+}
+
+#[test]
+fn throttled_store_is_shared_by_threads() {
+    let rate = 20000;
+    let backend = Arc::new(ThrottledBackend::new(MemoryBackend::new(), rate));
+
+    // Four stores of half the rate each; the first second worth of bytes is the initial burst.
+    let start = Instant::now();
+    let threads: Vec<_> = (0..2u8)
+        .map(|i| {
+            let backend = backend.clone();
+            thread::spawn(move || {
+                for j in 0..2u8 {
+                    backend.store(&[i, j], &CipherText::new(vec![0; rate as usize / 2])).unwrap();
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(990));
+
+    assert_eq!(backend.retrieve(&[1, 1]).unwrap(), Some(vec![0; rate as usize / 2]));
+}
+
+/// Counts the retrieves passed on to a `MemoryBackend`.
+struct CountingBackend {
+    inner: MemoryBackend,
+    retrieves: Arc<AtomicUsize>,
+}
+
+impl StoreBackend for CountingBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.inner.store(name, data)
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.retrieves.fetch_add(1, Ordering::SeqCst);
+        self.inner.retrieve(name)
+    }
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.inner.delete(name)
+    }
+    fn flush(&self) -> Result<(), BackendError> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn caching_retrieve() {
+    let retrieves = Arc::new(AtomicUsize::new(0));
+    let inner = CountingBackend {
+        inner: MemoryBackend::new(),
+        retrieves: retrieves.clone(),
+    };
+    let backend = CachingBackend::new(inner, 10);
+
+    backend.store(b"a", &CipherText::new(vec![1; 6])).unwrap();
+    backend.store(b"b", &CipherText::new(vec![2; 6])).unwrap();
+
+    assert_eq!(backend.retrieve(b"a").unwrap(), Some(vec![1; 6]));
+    assert_eq!(backend.retrieve(b"a").unwrap(), Some(vec![1; 6]));
+    assert_eq!(retrieves.load(Ordering::SeqCst), 1);
+
+    // Only one of the blobs fits the budget.
+    assert_eq!(backend.retrieve(b"b").unwrap(), Some(vec![2; 6]));
+    assert_eq!(backend.retrieve(b"a").unwrap(), Some(vec![1; 6]));
+    assert_eq!(retrieves.load(Ordering::SeqCst), 3);
+
+    // Deleting drops the cached blob.
+    backend.delete(b"a").unwrap();
+    assert_eq!(backend.retrieve(b"a").unwrap(), None);
+    assert_eq!(retrieves.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn mirror_store_retrieve_delete() {
+    let backend = MirrorBackend::new(MemoryBackend::new(), MemoryBackend::new());
+
+    backend.store(b"name", &CipherText::new(vec![1, 2, 3])).unwrap();
+    assert_eq!(backend.primary().retrieve(b"name").unwrap(), Some(vec![1, 2, 3]));
+    assert_eq!(backend.secondary().retrieve(b"name").unwrap(), Some(vec![1, 2, 3]));
+
+    // Falls back to the secondary backend.
+    backend.primary().delete(b"name").unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), Some(vec![1, 2, 3]));
+
+    backend.delete(b"name").unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), None);
+}
+
+#[test]
+fn mirror_reports_partial_store() {
+    let backend = MirrorBackend::new(MemoryBackend::new(), FlakyBackend::new(1, true));
+
+    match backend.store(b"name", &CipherText::new(vec![1])) {
+        Err(BackendError::Message(msg)) => assert!(msg.contains("primary backend only")),
+        other => panic!("Expected a partial store error, got: {:?}", other),
+    }
+    assert_eq!(backend.primary().retrieve(b"name").unwrap(), Some(vec![1]));
+}
+
+#[test]
+fn mirror_keeps_transient_failures_retryable() {
+    let backend = MirrorBackend::new(MemoryBackend::new(), FlakyBackend::new(1, false));
+    match backend.store(b"name", &CipherText::new(vec![1])) {
+        Err(BackendError::Retry(_)) => (),
+        other => panic!("Expected a transient error, got: {:?}", other),
+    }
+
+    // Retrying stores the blob on both backends.
+    let backend = RetryBackend::with_backoff(MirrorBackend::new(FlakyBackend::new(1, false),
+                                                                FlakyBackend::new(2, false)),
+                                             5,
+                                             Duration::from_millis(1),
+                                             Duration::from_millis(1));
+    backend.store(b"name", &CipherText::new(vec![1])).unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), Some(vec![1]));
+}
+
+#[test]
+fn exists() {
+    fn check<B: StoreBackend>(backend: &B) {
+        backend.store(b"present", &CipherText::new(vec![1, 2, 3])).unwrap();
+        assert!(backend.exists(b"present").unwrap());
+        assert!(!backend.exists(b"absent").unwrap());
+        backend.delete(b"present").unwrap();
+        assert!(!backend.exists(b"present").unwrap());
+    }
+
+    check(&MemoryBackend::new());
+    check(&mock_s3_backend());
+    check(&mock_http_backend());
+
+    let root = file_backend_root();
+    check(&FileBackend::new(root.clone()));
+    let _ = fs::remove_dir_all(&root);
+
+    // Backends without a cheaper check retrieve the blob.
+    let retrieves = Arc::new(AtomicUsize::new(0));
+    check(&CountingBackend {
+        inner: MemoryBackend::new(),
+        retrieves: retrieves.clone(),
+    });
+    assert_eq!(retrieves.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn list() {
+    fn check<B: StoreBackend>(backend: &B) {
+        assert_eq!(backend.list().unwrap(), Vec::<Vec<u8>>::new());
+        for name in &[&b"first"[..], &b"second"[..], &[0, 255][..]] {
+            backend.store(name, &CipherText::new(vec![1, 2, 3])).unwrap();
+        }
+        backend.delete(b"second").unwrap();
+        let mut names = backend.list().unwrap();
+        names.sort();
+        assert_eq!(names, vec![vec![0, 255], b"first".to_vec()]);
+    }
+
+    check(&MemoryBackend::new());
+    check(&MirrorBackend::new(MemoryBackend::new(), MemoryBackend::new()));
+    check(&mock_s3_backend());
+    check(&mock_http_backend());
+
+    let root = file_backend_root();
+    check(&FileBackend::new(root.clone()));
+    let _ = fs::remove_dir_all(&root);
+
+    // Listing is optional, also for the backend served to a blob server.
+    assert!(DevNullBackend.list().is_err());
+    assert!(serve_http(Arc::new(DevNullBackend)).list().is_err());
+}
+
+#[test]
+fn s3_list_pages() {
+    let mock = MockS3::start();
+    let backend = mock.backend("blobs/");
+    let other = mock.backend("other/");
+    other.store(b"other", &CipherText::new(vec![1])).unwrap();
+
+    // The mock lists a few keys at a time, so the listing takes several pages.
+    let expected: Vec<Vec<u8>> = (0..MOCK_S3_PAGE_SIZE as u8 * 3 + 1).map(|i| vec![i]).collect();
+    for name in expected.iter() {
+        backend.store(&name[..], &CipherText::new(vec![2])).unwrap();
+    }
+    let mut names = backend.list().unwrap();
+    names.sort();
+    assert_eq!(names, expected);
+
+    // Only the blobs under each backend's prefix are listed.
+    assert_eq!(other.list().unwrap(), vec![b"other".to_vec()]);
+}
+
+/// Deletes batches in a single call, as e.g. S3's multi-object delete does.
+struct BatchBackend {
+    inner: MemoryBackend,
+    batches: AtomicUsize,
+}
+
+impl StoreBackend for BatchBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.inner.store(name, data)
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.inner.retrieve(name)
+    }
+    fn delete(&self, _name: &[u8]) -> Result<(), BackendError> {
+        panic!("Single delete used instead of batch delete");
+    }
+    fn flush(&self) -> Result<(), BackendError> {
+        self.inner.flush()
+    }
+    fn delete_batch(&self, names: &[Vec<u8>]) -> Vec<(Vec<u8>, BackendError)> {
+        self.batches.fetch_add(1, Ordering::SeqCst);
+        self.inner.delete_batch(names)
+    }
+}
+
+#[test]
+fn delete_batch() {
+    let names: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i]).collect();
+
+    let backend = MemoryBackend::new();
+    for name in names.iter() {
+        backend.store(&name[..], &CipherText::new(vec![1])).unwrap();
+    }
+    assert!(backend.delete_batch(&names[..]).is_empty());
+    for name in names.iter() {
+        assert_eq!(backend.retrieve(&name[..]).unwrap(), None);
+    }
+
+    let backend = BatchBackend {
+        inner: MemoryBackend::new(),
+        batches: AtomicUsize::new(0),
+    };
+    for name in names.iter() {
+        backend.store(&name[..], &CipherText::new(vec![1])).unwrap();
+    }
+    assert!(backend.delete_batch(&names[..]).is_empty());
+    assert_eq!(backend.batches.load(Ordering::SeqCst), 1);
+    for name in names.iter() {
+        assert_eq!(backend.retrieve(&name[..]).unwrap(), None);
+    }
+}
+
+#[test]
+fn delete_batch_reports_failures() {
+    // The first two deletes fail.
+    let backend = FlakyBackend::new(2, true);
+    let names: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i]).collect();
+
+    let failed: Vec<Vec<u8>> =
+        backend.delete_batch(&names[..]).into_iter().map(|(name, _)| name).collect();
+    assert_eq!(failed, vec![vec![0], vec![1]]);
+}
+
+enum AsyncRequest {
+    Put(Vec<u8>, Vec<u8>, Callback<()>),
+    Get(Vec<u8>, Callback<Option<Vec<u8>>>),
+    Delete(Vec<u8>, Callback<()>),
+}
+
+fn unavailable<T>() -> Result<T, BackendError> {
+    Err(BackendError::Retry(RetryError))
+}
+
+/// An in-memory imitation of an asynchronous S3 client: calls are queued and answered by a single
+/// event loop thread, however many are in flight.
+struct AsyncMockS3 {
+    requests: Mutex<mpsc::Sender<AsyncRequest>>,
+    /// Number of upcoming requests to fail as "503 Service Unavailable".
+    fail_next: Arc<AtomicUsize>,
+}
+
+impl AsyncMockS3 {
+    fn start() -> AsyncMockS3 {
+        let (sender, receiver) = mpsc::channel();
+        let fail_next = Arc::new(AtomicUsize::new(0));
+        let fail = fail_next.clone();
+        thread::spawn(move || {
+            let mut objects = BTreeMap::new();
+            for request in receiver.iter() {
+                let failed = fail.load(Ordering::SeqCst) > 0;
+                if failed {
+                    fail.fetch_sub(1, Ordering::SeqCst);
+                }
+                match request {
+                    AsyncRequest::Put(name, data, done) => {
+                        if failed {
+                            done.call(unavailable())
+                        } else {
+                            objects.insert(name, data);
+                            done.call(Ok(()))
+                        }
+                    }
+                    AsyncRequest::Get(name, done) => {
+                        if failed {
+                            done.call(unavailable())
+                        } else {
+                            done.call(Ok(objects.get(&name).cloned()))
+                        }
+                    }
+                    AsyncRequest::Delete(name, done) => {
+                        if failed {
+                            done.call(unavailable())
+                        } else {
+                            objects.remove(&name);
+                            done.call(Ok(()))
+                        }
+                    }
+                }
+            }
+        });
+        AsyncMockS3 {
+            requests: Mutex::new(sender),
+            fail_next: fail_next,
+        }
+    }
+
+    fn send(&self, request: AsyncRequest) {
+        self.requests.lock().unwrap().send(request).unwrap();
+    }
+}
+
+impl AsyncStoreBackend for AsyncMockS3 {
+    fn store(&self, name: &[u8], data: CipherText, done: Callback<()>) {
+        self.send(AsyncRequest::Put(name.to_vec(), data.to_vec(), done));
+    }
+    fn retrieve(&self, name: &[u8], done: Callback<Option<Vec<u8>>>) {
+        self.send(AsyncRequest::Get(name.to_vec(), done));
+    }
+    fn delete(&self, name: &[u8], done: Callback<()>) {
+        self.send(AsyncRequest::Delete(name.to_vec(), done));
+    }
+    fn flush(&self, done: Callback<()>) {
+        done.call(Ok(()));
+    }
+}
+
+#[test]
+fn blocking_adapter_over_async_backend() {
+    let backend = Arc::new(BlockingAdapter::new(AsyncMockS3::start()));
+
+    assert_eq!(backend.retrieve(b"name").unwrap(), None);
+    backend.store(b"name", &CipherText::new(vec![1, 2, 3])).unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), Some(vec![1, 2, 3]));
+    backend.delete(b"name").unwrap();
+    assert_eq!(backend.retrieve(b"name").unwrap(), None);
+    backend.flush().unwrap();
+
+    // Errors are passed through as they are, so that they can still be retried.
+    backend.inner().fail_next.store(1, Ordering::SeqCst);
+    match backend.store(b"name", &CipherText::new(vec![1])) {
+        Err(BackendError::Retry(_)) => (),
+        other => panic!("Expected a retryable error, got: {:?}", other),
+    }
+
+    // Threads blocked on the adapter share the single event loop.
+    let threads: Vec<_> = (0..4u8)
+        .map(|i| {
+            let backend = backend.clone();
+            thread::spawn(move || {
+                for j in 0..16u8 {
+                    backend.store(&[i, j], &CipherText::new(vec![i, j])).unwrap();
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    for i in 0..4u8 {
+        for j in 0..16u8 {
+            assert_eq!(backend.retrieve(&[i, j]).unwrap(), Some(vec![i, j]));
+        }
+    }
+}
+
+#[test]
+fn async_adapter_over_blocking_backend() {
+    let backend = AsyncAdapter::new(Arc::new(MemoryBackend::new()), 4);
+
+    // Issue all stores before waiting for any of them.
+    let (sender, receiver) = mpsc::channel();
+    for i in 0..32u8 {
+        let sender = sender.clone();
+        let done = move |res: Result<(), BackendError>| sender.send((i, res)).unwrap();
+        backend.store(&[i], CipherText::new(vec![i]), Box::new(done));
+    }
+    let mut stored: Vec<u8> = receiver.iter()
+        .take(32)
+        .map(|(i, res)| {
+            res.unwrap();
+            i
+        })
+        .collect();
+    stored.sort();
+    assert_eq!(stored, (0..32u8).collect::<Vec<_>>());
+    assert_eq!(backend.inner().retrieve(&[7]).unwrap(), Some(vec![7]));
+
+    // Round-trip through both adapters.
+    let backend = BlockingAdapter::new(backend);
+    assert_eq!(backend.retrieve(&[7]).unwrap(), Some(vec![7]));
+    backend.delete(&[7]).unwrap();
+    assert_eq!(backend.retrieve(&[7]).unwrap(), None);
+}
+
+#[test]
+fn store_file() {
+    fn check<B: StoreBackend>(backend: &B) {
+        // Only the first bytes of the file are stored, however far it has been read.
+        let path = file_backend_root();
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        let data: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+        file.write_all(&data[..]).unwrap();
+        file.write_all(b"trailing").unwrap();
+
+        backend.store_file(b"name", &file, data.len() as u64).unwrap();
+        assert_eq!(backend.retrieve(b"name").unwrap(), Some(data.clone()));
+
+        // A file shorter than its given length is not stored.
+        assert!(backend.store_file(b"short", &file, 200000).is_err());
+        assert_eq!(backend.retrieve(b"short").unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    check(&MemoryBackend::new());
+    check(&mock_s3_backend());
+    check(&RetryBackend::new(mock_s3_backend()));
+
+    let root = file_backend_root();
+    check(&FileBackend::new(root.clone()));
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,123 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cap the bandwidth used by a backend.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+
+
+/// A token bucket holding up to one second worth of bytes.
+///
+/// Callers take their bytes up front, possibly leaving the bucket in debt, and then wait for the
+/// debt to be paid off. Concurrent callers thereby queue up behind each other.
+struct TokenBucket {
+    bytes_per_sec: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_sec: u64) -> TokenBucket {
+        assert!(bytes_per_sec > 0);
+        TokenBucket {
+            bytes_per_sec: bytes_per_sec,
+            state: Mutex::new((bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    fn take(&self, bytes: usize) {
+        let rate = self.bytes_per_sec as f64;
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let &mut (ref mut tokens, ref mut last) = &mut *state;
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(*last);
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            *tokens = (*tokens + elapsed * rate).min(rate) - bytes as f64;
+            *last = now;
+
+            if *tokens < 0.0 { -*tokens / rate } else { 0.0 }
+        };
+
+        if wait > 0.0 {
+            thread::sleep(Duration::new(wait as u64, (wait.fract() * 1e9) as u32));
+        }
+    }
+}
+
+/// Wraps a backend and limits the number of bytes per second it stores (and, optionally,
+/// retrieves). The limit is shared by all concurrent calls.
+pub struct ThrottledBackend<B> {
+    backend: B,
+    store: TokenBucket,
+    retrieve: Option<TokenBucket>,
+}
+
+impl<B: StoreBackend> ThrottledBackend<B> {
+    /// Limit stores to `store_bytes_per_sec`, while leaving retrieves unlimited.
+    pub fn new(backend: B, store_bytes_per_sec: u64) -> ThrottledBackend<B> {
+        ThrottledBackend {
+            backend: backend,
+            store: TokenBucket::new(store_bytes_per_sec),
+            retrieve: None,
+        }
+    }
+
+    /// Also limit retrieves, independently of stores, to `bytes_per_sec`.
+    pub fn with_retrieve_limit(mut self, bytes_per_sec: u64) -> ThrottledBackend<B> {
+        self.retrieve = Some(TokenBucket::new(bytes_per_sec));
+        self
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for ThrottledBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.store.take(data.len());
+        self.backend.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let res = try!(self.backend.retrieve(name));
+        // The size is only known once retrieved, so the bytes are paid for afterwards.
+        if let (Some(bucket), Some(data)) = (self.retrieve.as_ref(), res.as_ref()) {
+            bucket.take(data.len());
+        }
+        Ok(res)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.backend.delete(name)
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.backend.flush()
+    }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, BackendError> {
+        self.backend.exists(name)
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, BackendError> {
+        self.backend.list()
+    }
+
+    fn delete_batch(&self, names: &[Vec<u8>]) -> Vec<(Vec<u8>, BackendError)> {
+        self.backend.delete_batch(names)
+    }
+}
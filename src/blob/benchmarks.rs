@@ -34,6 +34,9 @@ fn dummy_hashref() -> HashRef {
             kind: Kind::TreeLeaf,
             packing: None,
             key: None,
+            raw_length: None,
+            nonce: None,
+            inline: None,
         },
     }
 }
@@ -45,9 +48,9 @@ fn insert_128_kb_chunks(bench: &mut Bencher) {
     let mut href = dummy_hashref();
     let chunk = [0u8; CHUNKSIZE];
     bench.iter(|| {
-        if let Err(()) = b.try_append(&chunk[..], &mut href) {
+        if !b.try_append(&chunk[..], &mut href).unwrap() {
             b = Blob::new(BLOBSIZE);
-            b.try_append(&chunk[..], &mut href).unwrap();
+            assert!(b.try_append(&chunk[..], &mut href).unwrap());
         }
     });
     bench.bytes = CHUNKSIZE as u64;
@@ -59,9 +62,9 @@ fn insert_256_kb_chunks(bench: &mut Bencher) {
     let mut b = Blob::new(BLOBSIZE);
     let mut href = dummy_hashref();
     bench.iter(|| {
-        if let Err(()) = b.try_append(&chunk[..], &mut href) {
-            b.to_ciphertext();
-            b.try_append(&chunk[..], &mut href).unwrap();
+        if !b.try_append(&chunk[..], &mut href).unwrap() {
+            b.to_ciphertext().unwrap();
+            assert!(b.try_append(&chunk[..], &mut href).unwrap());
         }
     });
     bench.bytes = 2 * CHUNKSIZE as u64;
@@ -12,28 +12,82 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use backend::{BackendError, StoreBackend};
 use crypto;
 use crypto::{CipherText, CipherTextRef, PlainTextRef};
-use hash::Hash;
+use hash::{Hash, HashWriter};
 use hash::tree::HashRef;
 
 use super::BlobError;
-use super::ChunkRef;
+use super::{Cipher, ChunkRef, CompressionLevel, NonceStrategy};
 
+use rand;
+use std::cmp;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem;
 
+/// The most random padding generated at once when sealing a spilled blob.
+const SPILLED_PAD_SIZE: usize = 64 * 1024;
+
+
+/// A blob sealed by `Blob::seal()`, ready to be stored.
+pub enum SealedBlob {
+    /// The blob, held in memory.
+    Memory(CipherText),
+    /// The blob, in the temporary file it was spilled to; the file goes away with its handle.
+    File {
+        file: fs::File,
+        len: usize,
+        digest: Hash,
+    },
+}
+
+impl SealedBlob {
+    pub fn len(&self) -> usize {
+        match *self {
+            SealedBlob::Memory(ref ct) => ct.len(),
+            SealedBlob::File { len, .. } => len,
+        }
+    }
+
+    /// The hash of the whole blob, as recorded to detect its corruption once stored.
+    pub fn digest(&self) -> Hash {
+        match *self {
+            SealedBlob::Memory(ref ct) => Hash::new(&ct.to_vec()[..]),
+            SealedBlob::File { ref digest, .. } => digest.clone(),
+        }
+    }
+
+    /// Store the blob as `name` in `backend`, streaming it from its file if it has one.
+    pub fn store<B: StoreBackend>(&self, backend: &B, name: &[u8]) -> Result<(), BackendError> {
+        match *self {
+            SealedBlob::Memory(ref ct) => backend.store(name, ct),
+            SealedBlob::File { ref file, len, .. } => backend.store_file(name, file, len as u64),
+        }
+    }
+}
 
 pub struct Blob {
     master_key: crypto::FixedKey,
     chunks: CipherText,
+    // The chunks moved out of memory, the hash of them so far, and their length. They precede
+    // those in `chunks`.
+    spilled: Option<(fs::File, HashWriter)>,
+    spilled_len: usize,
+    spill_threshold: Option<usize>,
     footer: Vec<u8>,
     overhead: usize,
     max_len: usize,
+    compression_level: Option<CompressionLevel>,
+    cipher: Cipher,
+    nonce_strategy: NonceStrategy,
 }
 
 impl Blob {
+    /// A blob sealed with the built-in key until `set_master_key()` is called.
     pub fn new(max_len: usize) -> Blob {
-        // TODO(jos): Plug an actual crypto key through somehow.
         let pubkey = crypto::sealed::desc::PublicKey::from_slice(&[215, 136, 80, 128, 158, 109,
                                                                    227, 141, 219, 63, 118, 91,
                                                                    123, 97, 1, 97, 65, 237, 62,
@@ -52,39 +106,110 @@ impl Blob {
         Blob {
             master_key: master_key,
             chunks: CipherText::empty(),
+            spilled: None,
+            spilled_len: 0,
+            spill_threshold: None,
             footer: Vec::with_capacity(max_len / 2),
             overhead: crypto::sealed::desc::overhead(),
             max_len: max_len,
+            compression_level: None,
+            cipher: Cipher::default(),
+            nonce_strategy: NonceStrategy::default(),
         }
     }
 
+    /// Set the level used when packing chunks appended from now on.
+    pub fn set_compression_level(&mut self, level: Option<CompressionLevel>) {
+        self.compression_level = level;
+    }
+
+    /// Set the cipher used to encrypt chunks appended from now on.
+    pub fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = cipher;
+    }
+
+    /// Set how the nonces of chunks appended from now on are chosen.
+    pub fn set_nonce_strategy(&mut self, strategy: NonceStrategy) {
+        self.nonce_strategy = strategy;
+    }
+
+    /// Seal the footers of blobs from now on, and read them back, with `key` instead of the
+    /// built-in key. The footer holds the keys of the blob's chunks.
+    pub fn set_master_key(&mut self, key: crypto::FixedKey) {
+        self.master_key = key;
+    }
+
+    /// Move the chunks to a temporary file whenever more than `threshold` bytes of them are held
+    /// in memory. `None` keeps all of them in memory.
+    pub fn set_spill_threshold(&mut self, threshold: Option<usize>) {
+        self.spill_threshold = threshold;
+    }
+
     pub fn read_chunk(blob: &[u8], hash: &Hash, cref: &ChunkRef) -> Result<Vec<u8>, BlobError> {
         let ct = crypto::CipherTextRef::new(blob);
-        Ok(try!(crypto::RefKey::unseal(hash, cref, ct)).into_vec())
+        let pt = try!(crypto::RefKey::unseal(hash, cref, ct)).into_vec();
+        match cref.packing {
+            None => Ok(pt),
+            Some(ref packing) => packing.unpack(&pt[..]),
+        }
+    }
+
+    /// The length of the chunks appended so far, in memory or not.
+    fn chunks_len(&self) -> usize {
+        self.spilled_len + self.chunks.len()
     }
 
     pub fn upperbound_len(&self) -> usize {
-        if self.chunks.len() == 0 {
+        if self.chunks_len() == 0 {
             0
         } else {
-            self.chunks.len() + self.footer.len() + self.overhead
+            self.chunks_len() + self.footer.len() + self.overhead
         }
     }
 
-    pub fn try_append(&mut self, chunk: &[u8], mut href: &mut HashRef) -> Result<(), ()> {
-        let ct = crypto::RefKey::seal(&mut href, PlainTextRef::new(&chunk));
+    /// Append a chunk and its reference to this blob. Returns `false` without appending if the
+    /// blob cannot hold the chunk.
+    pub fn try_append(&mut self, chunk: &[u8], href: &mut HashRef) -> Result<bool, BlobError> {
+        let cipher = self.cipher;
+        self.try_append_with_cipher(chunk, href, cipher)
+    }
+
+    /// Like `try_append()`, but encrypts the chunk with `cipher` instead of the blob's cipher.
+    pub fn try_append_with_cipher(&mut self,
+                                  chunk: &[u8],
+                                  mut href: &mut HashRef,
+                                  cipher: Cipher)
+                                  -> Result<bool, BlobError> {
+        let packed = match href.persistent_ref.packing {
+            None => None,
+            Some(ref packing) => Some(try!(packing.pack(chunk, self.compression_level))),
+        };
+        let ct = {
+            let plain = match packed {
+                None => chunk,
+                Some(ref p) => &p[..],
+            };
+            crypto::RefKey::seal(&mut href,
+                                 PlainTextRef::new(plain),
+                                 cipher,
+                                 self.nonce_strategy)
+        };
+        // Spilling first leaves the blob as it was if that fails.
+        if self.spill_threshold.map_or(false, |t| self.chunks.len() > t) {
+            try!(self.spill());
+        }
 
-        href.persistent_ref.offset = self.chunks.len();
-        let mut href_bytes = href.as_bytes();
+        href.persistent_ref.offset = self.chunks_len();
+        let mut href_bytes = try!(href.as_bytes());
         assert!(href_bytes.len() < 255);
 
         if self.upperbound_len() + 1 + href_bytes.len() + ct.len() >= self.max_len {
-            if self.chunks.len() == 0 {
+            if self.chunks_len() == 0 {
                 panic!("Can never fit chunk of size {} in blob of size {}",
                        chunk.len(),
                        self.max_len);
             }
-            return Err(());
+            return Ok(false);
         }
 
         self.chunks.append(ct);
@@ -93,13 +218,64 @@ impl Blob {
         self.footer.push(href_bytes.len() as u8);
         self.footer.append(&mut href_bytes);
 
+        Ok(true)
+    }
+
+    /// Append the chunks held in memory to the temporary file, creating it if needed.
+    fn spill(&mut self) -> Result<(), io::Error> {
+        if self.spilled.is_none() {
+            let path = env::temp_dir().join(format!("hat-blob-{:x}", rand::random::<u64>()));
+            let file = try!(fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path));
+            // The file is only reached through its handle, and goes away with it.
+            try!(fs::remove_file(&path));
+            self.spilled = Some((file, HashWriter::new()));
+        }
+
+        {
+            let &mut (ref mut file, ref mut digest) = self.spilled.as_mut().unwrap();
+            try!(file.seek(SeekFrom::Start(self.spilled_len as u64)));
+            for slice in self.chunks.slices() {
+                try!(file.write_all(slice));
+            }
+            // Only hashed once written, so that a failed spill leaves the hash as it was.
+            for slice in self.chunks.slices() {
+                try!(digest.write_all(slice));
+            }
+        }
+        self.spilled_len += self.chunks.len();
+        self.chunks = CipherText::empty();
+        Ok(())
+    }
+
+    /// Read the spilled chunks back into memory, ahead of those held there.
+    fn unspill(&mut self) -> Result<(), io::Error> {
+        let mut file = match self.spilled.take() {
+            None => return Ok(()),
+            Some((file, _)) => file,
+        };
+        let mut data = Vec::with_capacity(self.spilled_len);
+        try!(file.seek(SeekFrom::Start(0)));
+        // A failed spill may have left a partial write beyond the spilled chunks.
+        try!((&mut file).take(self.spilled_len as u64).read_to_end(&mut data));
+        assert_eq!(data.len(), self.spilled_len);
+        self.spilled_len = 0;
+
+        let mut chunks = CipherText::new(data);
+        chunks.append(mem::replace(&mut self.chunks, CipherText::empty()));
+        self.chunks = chunks;
         Ok(())
     }
 
-    pub fn to_ciphertext(&mut self) -> Option<CipherText> {
-        if self.chunks.len() == 0 {
-            return None;
+    /// Seal the blob, and reset it for the next chunks. Returns `None` if it holds no chunks.
+    pub fn to_ciphertext(&mut self) -> Result<Option<CipherText>, BlobError> {
+        if self.chunks_len() == 0 {
+            return Ok(None);
         }
+        try!(self.unspill());
 
         let footer = self.master_key.seal(PlainTextRef::new(&self.footer[..]));
         self.footer.truncate(0);
@@ -110,10 +286,49 @@ impl Blob {
         out.append(footer);
 
         // Everything has been reset. We are ready to go again.
-        assert_eq!(0, self.chunks.len());
+        assert_eq!(0, self.chunks_len());
         assert_eq!(0, self.footer.len());
 
-        Some(out)
+        Ok(Some(out))
+    }
+
+    /// Like `to_ciphertext()`, but a blob larger than the spill threshold is sealed in its
+    /// temporary file, to be stored from there without reading it back into memory.
+    pub fn seal(&mut self) -> Result<Option<SealedBlob>, BlobError> {
+        if self.chunks_len() == 0 {
+            return Ok(None);
+        }
+        // Padding fills every blob up to its maximum length.
+        if self.spilled.is_none() && self.spill_threshold.map_or(true, |t| self.max_len <= t) {
+            return Ok(try!(self.to_ciphertext()).map(SealedBlob::Memory));
+        }
+
+        let footer = self.master_key.seal(PlainTextRef::new(&self.footer[..]));
+        self.footer.truncate(0);
+        assert!(self.chunks_len() + footer.len() <= self.max_len);
+
+        // The padding is spilled as it is generated, in pieces, followed by the footer.
+        let mut padding = self.max_len - footer.len() - self.chunks_len();
+        loop {
+            try!(self.spill());
+            if padding == 0 {
+                break;
+            }
+            let size = cmp::min(padding, SPILLED_PAD_SIZE);
+            self.chunks = CipherText::random_pad(size);
+            padding -= size;
+        }
+        self.chunks = footer;
+        try!(self.spill());
+
+        let (file, digest) = self.spilled.take().unwrap();
+        let len = mem::replace(&mut self.spilled_len, 0);
+        assert_eq!(len, self.max_len);
+        Ok(Some(SealedBlob::File {
+            file: file,
+            len: len,
+            digest: digest.finish(),
+        }))
     }
 
     pub fn refs_from_bytes(&self, bytes: &[u8]) -> Result<Vec<HashRef>, BlobError> {
@@ -127,7 +342,9 @@ impl Blob {
         let mut hrefs = Vec::new();
         while footer_pos.len() > 0 {
             let len = footer_pos[0] as usize;
-            assert!(footer_pos.len() > len);
+            if footer_pos.len() <= len {
+                return Err(From::from("Blob footer is truncated"));
+            }
 
             hrefs.push(try!(HashRef::from_bytes(&mut &footer_pos[1..1 + len])));
             footer_pos = &footer_pos[len + 1..];
@@ -12,20 +12,187 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use flate2;
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{self, Json};
+use snap;
+use sodiumoxide::crypto::aead::chacha20poly1305_ietf;
 use sodiumoxide::crypto::secretbox::xsalsa20poly1305;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use zstd;
+
 use capnp;
 use root_capnp;
 
+use super::BlobError;
+
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Packing {
     GZip,
     Snappy,
+    Zstd,
+}
+
+/// Compression level used when packing chunks; higher levels trade CPU time for space.
+///
+/// Valid levels are 1-19 for zstd and 0-9 for gzip (values outside are clamped). Snappy does not
+/// have levels. The level only affects compression, so it is not recorded in the `ChunkRef`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CompressionLevel(pub i32);
+
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+/// Number of leading bytes of a chunk sampled to decide whether to pack it.
+pub const ENTROPY_SAMPLE_LEN: usize = 4096;
+
+/// Samples with more bits of entropy per byte than this are not worth compressing. Random data
+/// (e.g. already compressed media) comes close to 8.
+const MAX_COMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+/// Estimate whether packing `data` is likely to make it smaller, from the byte entropy of its
+/// first `ENTROPY_SAMPLE_LEN` bytes.
+pub fn likely_compressible(data: &[u8]) -> bool {
+    let sample = &data[..cmp::min(data.len(), ENTROPY_SAMPLE_LEN)];
+    if sample.len() < 256 {
+        // Too short to tell, and cheap to compress anyway.
+        return true;
+    }
+
+    let mut counts = [0usize; 256];
+    for b in sample {
+        counts[*b as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy = counts.iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / len;
+            -p * p.log2()
+        })
+        .fold(0.0, |a, b| a + b);
+    entropy <= MAX_COMPRESSIBLE_ENTROPY
+}
+
+/// Number of leading bytes of a chunk compressed with each candidate by `choose_packing`.
+pub const PACKING_SAMPLE_LEN: usize = 16 * 1024;
+
+/// GZip is only chosen if it packs the sample to at most this fraction of Snappy's output, as
+/// it is much slower to compress and decompress.
+const MIN_GZIP_GAIN: f64 = 0.9;
+
+/// Choose between GZip, Snappy and no packing for `data` by packing its first
+/// `PACKING_SAMPLE_LEN` bytes with both: GZip if it is clearly smaller than Snappy, Snappy if
+/// that makes the sample smaller at all. Data that does not look compressible is not sampled.
+pub fn choose_packing(data: &[u8], level: Option<CompressionLevel>) -> Option<Packing> {
+    if !likely_compressible(data) {
+        return None;
+    }
+    let sample = &data[..cmp::min(data.len(), PACKING_SAMPLE_LEN)];
+    let snappy = match Packing::Snappy.pack(sample, None) {
+        Ok(packed) => packed.len(),
+        Err(_) => return None,
+    };
+    let gzip = match Packing::GZip.pack(sample, level) {
+        Ok(packed) => packed.len(),
+        Err(_) => return None,
+    };
+
+    if gzip as f64 <= snappy as f64 * MIN_GZIP_GAIN {
+        Some(Packing::GZip)
+    } else if snappy < sample.len() {
+        Some(Packing::Snappy)
+    } else {
+        None
+    }
+}
+
+impl Packing {
+    /// Compress `data` with this packing, using the packing's default level if none is given.
+    pub fn pack(&self,
+                data: &[u8],
+                level: Option<CompressionLevel>)
+                -> Result<Vec<u8>, BlobError> {
+        match *self {
+            Packing::GZip => {
+                let compression = match level {
+                    None => flate2::Compression::default(),
+                    Some(CompressionLevel(l)) => {
+                        flate2::Compression::new(cmp::max(0, cmp::min(9, l)) as u32)
+                    }
+                };
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), compression);
+                try!(encoder.write_all(data));
+                Ok(try!(encoder.finish()))
+            }
+            Packing::Snappy => {
+                snap::Encoder::new().compress_vec(data).map_err(|e| From::from(e.to_string()))
+            }
+            Packing::Zstd => {
+                let level = match level {
+                    None => ZSTD_DEFAULT_LEVEL,
+                    Some(CompressionLevel(l)) => cmp::max(1, cmp::min(19, l)),
+                };
+                Ok(try!(zstd::encode_all(data, level)))
+            }
+        }
+    }
+
+    /// Decompress `data` that was compressed with this packing.
+    pub fn unpack(&self, data: &[u8]) -> Result<Vec<u8>, BlobError> {
+        match *self {
+            Packing::GZip => {
+                let mut decoder = flate2::read::GzDecoder::new(data);
+                let mut out = Vec::new();
+                try!(decoder.read_to_end(&mut out));
+                Ok(out)
+            }
+            Packing::Snappy => {
+                snap::Decoder::new().decompress_vec(data).map_err(|e| From::from(e.to_string()))
+            }
+            Packing::Zstd => Ok(try!(zstd::decode_all(data))),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Key {
     XSalsa20Poly1305(xsalsa20poly1305::Key),
+    ChaCha20Poly1305(chacha20poly1305_ietf::Key),
+}
+
+/// Cipher used to encrypt new chunks. The cipher of an existing chunk is given by its `Key`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Cipher {
+    XSalsa20Poly1305,
+    ChaCha20Poly1305,
+    /// Store chunks as they are, without a key; for testing, or for backends that already
+    /// encrypt what they store. The blob footers listing the chunks are still encrypted.
+    Unencrypted,
+}
+
+impl Default for Cipher {
+    fn default() -> Cipher {
+        Cipher::XSalsa20Poly1305
+    }
+}
+
+/// How the nonce of a new encrypted chunk is chosen.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NonceStrategy {
+    /// Draw a random nonce for every chunk, and record it in the chunk's `ChunkRef`.
+    Random,
+    /// Use the leading bytes of the chunk's hash, so that nothing needs to be recorded. This is
+    /// only safe because every chunk is encrypted with a fresh key.
+    FromHash,
+}
+
+impl Default for NonceStrategy {
+    fn default() -> NonceStrategy {
+        NonceStrategy::Random
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -42,6 +209,40 @@ pub struct ChunkRef {
     pub kind: Kind,
     pub packing: Option<Packing>,
     pub key: Option<Key>,
+    /// Length of the chunk before packing, if the store was asked to record it.
+    pub raw_length: Option<usize>,
+    /// The nonce the chunk was encrypted with, if drawn at random. Otherwise the nonce is the
+    /// start of the chunk's hash.
+    pub nonce: Option<Vec<u8>>,
+    /// The chunk itself, if it was small enough to be kept in its reference instead of a blob
+    /// (see `StoreOptions::inline_threshold`). Like references to empty chunks, these have no
+    /// blob and a zero offset and length.
+    pub inline: Option<Vec<u8>>,
+}
+
+fn incorrect_key_size(len: usize) -> capnp::Error {
+    capnp::Error::failed(format!("Incorrect key-size in chunk reference: {} bytes", len))
+}
+
+fn json_field<'a>(obj: &'a json::Object, name: &str) -> Result<&'a Json, BlobError> {
+    obj.get(name).ok_or_else(|| From::from(format!("Missing '{}' in chunk reference", name)))
+}
+
+fn json_string<'a>(obj: &'a json::Object, name: &str) -> Result<&'a str, BlobError> {
+    try!(json_field(obj, name))
+        .as_string()
+        .ok_or_else(|| From::from(format!("Expected a string for '{}'", name)))
+}
+
+fn json_usize(obj: &json::Object, name: &str) -> Result<usize, BlobError> {
+    try!(json_field(obj, name))
+        .as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| From::from(format!("Expected a number for '{}'", name)))
+}
+
+fn json_hex(obj: &json::Object, name: &str) -> Result<Vec<u8>, BlobError> {
+    try!(json_string(obj, name)).from_hex().map_err(|e| From::from(format!("{}: {}", name, e)))
 }
 
 impl ChunkRef {
@@ -53,7 +254,7 @@ impl ChunkRef {
         Ok(try!(ChunkRef::read_msg(&root)))
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
+    pub fn as_bytes(&self) -> Result<Vec<u8>, capnp::Error> {
         let mut message = ::capnp::message::Builder::new_default();
         {
             let mut root = message.init_root::<root_capnp::chunk_ref::Builder>();
@@ -61,9 +262,9 @@ impl ChunkRef {
         }
 
         let mut out = Vec::new();
-        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        try!(capnp::serialize_packed::write_message(&mut out, &message));
 
-        out
+        Ok(out)
     }
 
     pub fn populate_msg(&self, mut msg: root_capnp::chunk_ref::Builder) {
@@ -75,16 +276,36 @@ impl ChunkRef {
             Kind::TreeBranch => msg.borrow().init_kind().set_tree_branch(()),
         }
 
-        if let Some(Key::XSalsa20Poly1305(ref salsa)) = self.key {
-            msg.borrow().init_key().set_xsalsa20_poly1305(salsa.0.as_ref());
-        } else {
-            msg.borrow().init_key().set_none(());
+        match self.key {
+            None => msg.borrow().init_key().set_none(()),
+            Some(Key::XSalsa20Poly1305(ref salsa)) => {
+                msg.borrow().init_key().set_xsalsa20_poly1305(salsa.0.as_ref())
+            }
+            Some(Key::ChaCha20Poly1305(ref chacha)) => {
+                msg.borrow().init_key().set_chacha20_poly1305(chacha.0.as_ref())
+            }
         }
 
         match self.packing {
             None => msg.borrow().init_packing().set_none(()),
             Some(Packing::GZip) => msg.borrow().init_packing().set_gzip(()),
             Some(Packing::Snappy) => msg.borrow().init_packing().set_snappy(()),
+            Some(Packing::Zstd) => msg.borrow().init_packing().set_zstd(()),
+        }
+
+        match self.raw_length {
+            None => msg.borrow().init_raw_length().set_unknown(()),
+            Some(len) => msg.borrow().init_raw_length().set_length(len as i64),
+        }
+
+        match self.nonce {
+            None => msg.borrow().init_nonce().set_from_hash(()),
+            Some(ref nonce) => msg.borrow().init_nonce().set_random(&nonce[..]),
+        }
+
+        match self.inline {
+            None => msg.borrow().init_inline().set_none(()),
+            Some(ref data) => msg.borrow().init_inline().set_data(&data[..]),
         }
     }
 
@@ -101,14 +322,145 @@ impl ChunkRef {
                 root_capnp::chunk_ref::packing::None(()) => None,
                 root_capnp::chunk_ref::packing::Gzip(()) => Some(Packing::GZip),
                 root_capnp::chunk_ref::packing::Snappy(()) => Some(Packing::Snappy),
+                root_capnp::chunk_ref::packing::Zstd(()) => Some(Packing::Zstd),
             },
             key: match try!(msg.get_key().which()) {
                 root_capnp::chunk_ref::key::None(()) => None,
                 root_capnp::chunk_ref::key::Xsalsa20Poly1305(res) => {
-                    Some(Key::XSalsa20Poly1305(xsalsa20poly1305::Key::from_slice(try!(res))
-                        .expect("Incorrect key-size")))
+                    let key = try!(res);
+                    match xsalsa20poly1305::Key::from_slice(key) {
+                        Some(key) => Some(Key::XSalsa20Poly1305(key)),
+                        None => return Err(incorrect_key_size(key.len())),
+                    }
+                }
+                root_capnp::chunk_ref::key::Chacha20Poly1305(res) => {
+                    let key = try!(res);
+                    match chacha20poly1305_ietf::Key::from_slice(key) {
+                        Some(key) => Some(Key::ChaCha20Poly1305(key)),
+                        None => return Err(incorrect_key_size(key.len())),
+                    }
                 }
             },
+            raw_length: match try!(msg.get_raw_length().which()) {
+                root_capnp::chunk_ref::raw_length::Unknown(()) => None,
+                root_capnp::chunk_ref::raw_length::Length(len) => Some(len as usize),
+            },
+            nonce: match try!(msg.get_nonce().which()) {
+                root_capnp::chunk_ref::nonce::FromHash(()) => None,
+                root_capnp::chunk_ref::nonce::Random(res) => Some(try!(res).to_owned()),
+            },
+            inline: match try!(msg.get_inline().which()) {
+                root_capnp::chunk_ref::inline::None(()) => None,
+                root_capnp::chunk_ref::inline::Data(res) => Some(try!(res).to_owned()),
+            },
+        })
+    }
+
+    /// Render this reference as JSON, to inspect it when debugging. Binary fields (the blob ID,
+    /// the key, the nonce and inline data) are hex-encoded; note that this includes the chunk's
+    /// key. Chunk references are only ever stored with `as_bytes`.
+    pub fn to_json(&self) -> String {
+        let mut obj = BTreeMap::new();
+        obj.insert("blob_id".to_owned(), Json::String(self.blob_id.to_hex()));
+        obj.insert("offset".to_owned(), Json::U64(self.offset as u64));
+        obj.insert("length".to_owned(), Json::U64(self.length as u64));
+        let kind = match self.kind {
+            Kind::TreeBranch => "tree_branch",
+            Kind::TreeLeaf => "tree_leaf",
+        };
+        obj.insert("kind".to_owned(), Json::String(kind.to_owned()));
+        let packing = match self.packing {
+            None => Json::Null,
+            Some(Packing::GZip) => Json::String("gzip".to_owned()),
+            Some(Packing::Snappy) => Json::String("snappy".to_owned()),
+            Some(Packing::Zstd) => Json::String("zstd".to_owned()),
+        };
+        obj.insert("packing".to_owned(), packing);
+        let key = match self.key {
+            None => Json::Null,
+            Some(ref key) => {
+                let (cipher, bytes) = match *key {
+                    Key::XSalsa20Poly1305(ref salsa) => ("xsalsa20_poly1305", salsa.0.to_hex()),
+                    Key::ChaCha20Poly1305(ref chacha) => ("chacha20_poly1305", chacha.0.to_hex()),
+                };
+                let mut key_obj = BTreeMap::new();
+                key_obj.insert(cipher.to_owned(), Json::String(bytes));
+                Json::Object(key_obj)
+            }
+        };
+        obj.insert("key".to_owned(), key);
+        obj.insert("raw_length".to_owned(),
+                   self.raw_length.map_or(Json::Null, |len| Json::U64(len as u64)));
+        obj.insert("nonce".to_owned(),
+                   self.nonce.as_ref().map_or(Json::Null, |nonce| Json::String(nonce.to_hex())));
+        let inline = self.inline.as_ref().map_or(Json::Null, |data| Json::String(data.to_hex()));
+        obj.insert("inline".to_owned(), inline);
+        Json::Object(obj).to_string()
+    }
+
+    /// Parse a reference rendered by `to_json`.
+    pub fn from_json(s: &str) -> Result<ChunkRef, BlobError> {
+        let json = try!(Json::from_str(s).map_err(|e| BlobError::from(e.to_string())));
+        let obj = try!(json.as_object().ok_or("Expected a JSON object for a chunk reference"));
+
+        let kind = match try!(json_string(obj, "kind")) {
+            "tree_branch" => Kind::TreeBranch,
+            "tree_leaf" => Kind::TreeLeaf,
+            other => return Err(From::from(format!("Unknown chunk kind: {}", other))),
+        };
+        let packing = match try!(json_field(obj, "packing")) {
+            &Json::Null => None,
+            _ => {
+                match try!(json_string(obj, "packing")) {
+                    "gzip" => Some(Packing::GZip),
+                    "snappy" => Some(Packing::Snappy),
+                    "zstd" => Some(Packing::Zstd),
+                    other => return Err(From::from(format!("Unknown packing: {}", other))),
+                }
+            }
+        };
+        let key = match try!(json_field(obj, "key")) {
+            &Json::Null => None,
+            &Json::Object(ref key_obj) if key_obj.contains_key("xsalsa20_poly1305") => {
+                let bytes = try!(json_hex(key_obj, "xsalsa20_poly1305"));
+                match xsalsa20poly1305::Key::from_slice(&bytes[..]) {
+                    Some(key) => Some(Key::XSalsa20Poly1305(key)),
+                    None => return Err(From::from(incorrect_key_size(bytes.len()))),
+                }
+            }
+            &Json::Object(ref key_obj) if key_obj.contains_key("chacha20_poly1305") => {
+                let bytes = try!(json_hex(key_obj, "chacha20_poly1305"));
+                match chacha20poly1305_ietf::Key::from_slice(&bytes[..]) {
+                    Some(key) => Some(Key::ChaCha20Poly1305(key)),
+                    None => return Err(From::from(incorrect_key_size(bytes.len()))),
+                }
+            }
+            _ => return Err(From::from("Unknown key in chunk reference")),
+        };
+        let raw_length = match try!(json_field(obj, "raw_length")) {
+            &Json::Null => None,
+            _ => Some(try!(json_usize(obj, "raw_length"))),
+        };
+        let nonce = match try!(json_field(obj, "nonce")) {
+            &Json::Null => None,
+            _ => Some(try!(json_hex(obj, "nonce"))),
+        };
+        // Older renderings have no inline data.
+        let inline = match obj.get("inline") {
+            None | Some(&Json::Null) => None,
+            Some(_) => Some(try!(json_hex(obj, "inline"))),
+        };
+
+        Ok(ChunkRef {
+            blob_id: try!(json_hex(obj, "blob_id")),
+            offset: try!(json_usize(obj, "offset")),
+            length: try!(json_usize(obj, "length")),
+            kind: kind,
+            packing: packing,
+            key: key,
+            raw_length: raw_length,
+            nonce: nonce,
+            inline: inline,
         })
     }
 }
@@ -14,6 +14,8 @@
 
 //! Local state for external blobs and their states.
 
+use std::cmp;
+use std::collections::HashSet;
 use std::sync::{Mutex, MutexGuard};
 
 use diesel;
@@ -23,12 +25,49 @@ use diesel::sqlite::SqliteConnection;
 use sodiumoxide::randombytes::randombytes;
 
 use errors::DieselError;
+use hash::Hash;
 use tags;
 use util;
 
 use super::schema;
 
 
+/// The state of a blob, as recorded in the `tag` column of the blob index.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BlobStatus {
+    /// The blob is being uploaded, and may or may not exist in the backend. Nothing refers to it
+    /// yet.
+    InProgress,
+    /// The blob is stored in the backend and its chunks may be referenced.
+    Committed,
+    /// Garbage collection found live chunks in the blob.
+    Marked,
+    /// The blob is no longer referenced and is about to be deleted from the backend. During
+    /// garbage collection, this also marks stored blobs not yet found to hold live chunks.
+    Deletable,
+}
+
+impl BlobStatus {
+    fn from_tag(tag: tags::Tag) -> Option<BlobStatus> {
+        match tag {
+            tags::Tag::InProgress => Some(BlobStatus::InProgress),
+            tags::Tag::Done => Some(BlobStatus::Committed),
+            tags::Tag::Reserved => Some(BlobStatus::Marked),
+            tags::Tag::WillDelete => Some(BlobStatus::Deletable),
+            _ => None,
+        }
+    }
+
+    fn to_tag(self) -> tags::Tag {
+        match self {
+            BlobStatus::InProgress => tags::Tag::InProgress,
+            BlobStatus::Committed => tags::Tag::Done,
+            BlobStatus::Marked => tags::Tag::Reserved,
+            BlobStatus::Deletable => tags::Tag::WillDelete,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct BlobDesc {
     pub name: Vec<u8>,
@@ -38,18 +77,26 @@ pub struct BlobDesc {
 pub struct InternalBlobIndex {
     conn: SqliteConnection,
     next_id: i64,
+    // The sum of the recorded blob lengths, once counted.
+    stored_bytes: Option<u64>,
+    // Names reserved by content, but not yet recorded by `in_air`.
+    reserved_names: HashSet<Vec<u8>>,
 }
 
 pub struct BlobIndex(Mutex<InternalBlobIndex>);
 
 
 impl InternalBlobIndex {
-    pub fn new(path: &str) -> Result<InternalBlobIndex, DieselError> {
-        let conn = try!(SqliteConnection::establish(path));
+    pub fn new(path: &str,
+               options: &util::IndexOptions)
+               -> Result<InternalBlobIndex, DieselError> {
+        let conn = try!(util::sqlite::establish(path, options));
 
         let mut bi = InternalBlobIndex {
             conn: conn,
             next_id: -1,
+            stored_bytes: None,
+            reserved_names: HashSet::new(),
         };
 
         let dir = try!(diesel::migrations::find_migrations_directory());
@@ -61,13 +108,40 @@ impl InternalBlobIndex {
         Ok(bi)
     }
 
-    fn new_blob_desc(&mut self) -> BlobDesc {
+    /// Reserve the name of the next blob to fill: a random name, or one derived from the hash of
+    /// the blob's first chunk if given.
+    ///
+    /// The name cannot be derived from all of the blob's contents: chunk references, which
+    /// include the name, are handed out as chunks are added, and end up inside the blob's own
+    /// footer and in the tree nodes stored after them. The hash of the stored contents is
+    /// recorded separately, as the blob's digest (see `set_digest`).
+    fn new_blob_desc(&mut self, first_chunk: Option<&Hash>) -> BlobDesc {
+        let name = match first_chunk {
+            None => randombytes(24),
+            Some(hash) => self.content_name(hash),
+        };
         BlobDesc {
-            name: randombytes(24),
+            name: name,
             id: self.next_id(),
         }
     }
 
+    // The first of the names derived from `first_chunk` that no other blob has. The same chunk
+    // may start several blobs, e.g. when it is stored again after being deleted.
+    fn content_name(&mut self, first_chunk: &Hash) -> Vec<u8> {
+        let mut attempt = 0u64;
+        loop {
+            let mut text = first_chunk.bytes.clone();
+            text.extend((0..8).map(|i| (attempt >> (8 * i)) as u8));
+            let name = Hash::new(&text[..]).bytes[..24].to_vec();
+            if !self.reserved_names.contains(&name) && self.find_id(&name[..]).is_none() {
+                self.reserved_names.insert(name.clone());
+                return name;
+            }
+            attempt += 1;
+        }
+    }
+
     pub fn refresh_next_id(&mut self) {
         use diesel::expression::max;
         use super::schema::blobs::dsl::*;
@@ -107,13 +181,15 @@ impl InternalBlobIndex {
         blob
     }
 
-    fn reserve(&mut self) -> BlobDesc {
-        self.new_blob_desc()
+    fn reserve(&mut self, first_chunk: Option<&Hash>) -> BlobDesc {
+        self.new_blob_desc(first_chunk)
     }
 
     fn in_air(&mut self, blob: &BlobDesc) {
         use super::schema::blobs::dsl::*;
 
+        self.reserved_names.remove(&blob.name);
+
         let new = schema::NewBlob {
             id: blob.id,
             name: &blob.name,
@@ -142,6 +218,52 @@ impl InternalBlobIndex {
         self.new_transaction();
     }
 
+    fn set_digest(&mut self, blob: &BlobDesc, digest_: &[u8]) {
+        use super::schema::blobs::dsl::*;
+
+        diesel::update(blobs.find(blob.id))
+            .set(digest.eq(digest_))
+            .execute(&self.conn)
+            .expect("Error updating blob");
+    }
+
+    fn set_length(&mut self, blob: &BlobDesc, length_: u64) {
+        use super::schema::blobs::dsl::*;
+
+        diesel::update(blobs.find(blob.id))
+            .set(length.eq(length_ as i64))
+            .execute(&self.conn)
+            .expect("Error updating blob");
+        if let Some(ref mut bytes) = self.stored_bytes {
+            *bytes += length_;
+        }
+    }
+
+    fn stored_bytes(&mut self) -> u64 {
+        use super::schema::blobs::dsl::*;
+
+        if let Some(bytes) = self.stored_bytes {
+            return bytes;
+        }
+        let bytes = blobs.select(length)
+            .load::<Option<i64>>(&self.conn)
+            .expect("Error reading blob lengths")
+            .into_iter()
+            .fold(0, |sum, l| sum + l.unwrap_or(0) as u64);
+        self.stored_bytes = Some(bytes);
+        bytes
+    }
+
+    fn digest(&mut self, name_: &[u8]) -> Option<Vec<u8>> {
+        use super::schema::blobs::dsl::*;
+        blobs.filter(name.eq(name_))
+            .select(digest)
+            .first::<Option<Vec<u8>>>(&self.conn)
+            .optional()
+            .expect("Error reading blob")
+            .and_then(|d| d)
+    }
+
     fn find_id(&mut self, name_: &[u8]) -> Option<i64> {
         use super::schema::blobs::dsl::*;
         blobs.filter(name.eq(name_))
@@ -176,16 +298,37 @@ impl InternalBlobIndex {
         };
     }
 
-    fn delete_by_tag(&mut self, tag_: tags::Tag) {
+    fn retag(&mut self, name_: Option<&[u8]>, from: tags::Tag, to: tags::Tag) {
         use super::schema::blobs::dsl::*;
-        diesel::delete(blobs.filter(tag.eq(tag_ as i32)))
+        match name_ {
+            None => {
+                diesel::update(blobs.filter(tag.eq(from as i32)))
+                    .set(tag.eq(to as i32))
+                    .execute(&self.conn)
+                    .expect("Error updating blob tags")
+            }
+            Some(name_) => {
+                diesel::update(blobs.filter(name.eq(name_)).filter(tag.eq(from as i32)))
+                    .set(tag.eq(to as i32))
+                    .execute(&self.conn)
+                    .expect("Error updating blob tags")
+            }
+        };
+    }
+
+    fn delete(&mut self, blob: &BlobDesc) {
+        use super::schema::blobs::dsl::*;
+        diesel::delete(blobs.find(blob.id))
             .execute(&self.conn)
-            .expect("Error deleting blobs");
+            .expect("Error deleting blob");
+        // Counted again when next needed.
+        self.stored_bytes = None;
     }
 
-    fn list_by_tag(&mut self, tag_: tags::Tag) -> Vec<BlobDesc> {
+    fn list_by_tag(&mut self, tag_: tags::Tag, limit: usize) -> Vec<BlobDesc> {
         use super::schema::blobs::dsl::*;
         blobs.filter(tag.eq(tag_ as i32))
+            .limit(cmp::min(limit, i64::max_value() as usize) as i64)
             .load::<schema::Blob>(&self.conn)
             .expect("Error listing blobs")
             .into_iter()
@@ -197,11 +340,37 @@ impl InternalBlobIndex {
             })
             .collect()
     }
+
+    fn count_by_tag(&mut self, tag_: tags::Tag) -> usize {
+        use super::schema::blobs::dsl::*;
+        use diesel::expression::count_star;
+
+        blobs.filter(tag.eq(tag_ as i32))
+            .select(count_star())
+            .first::<i64>(&self.conn)
+            .expect("Error counting blobs") as usize
+    }
+
+    fn tag_of(&mut self, name_: &[u8]) -> Option<tags::Tag> {
+        use super::schema::blobs::dsl::*;
+        blobs.filter(name.eq(name_))
+            .select(tag)
+            .first::<i32>(&self.conn)
+            .optional()
+            .expect("Error reading blob")
+            .and_then(|t| tags::tag_from_num(t as i64))
+    }
 }
 
 impl BlobIndex {
     pub fn new(path: &str) -> Result<BlobIndex, DieselError> {
-        InternalBlobIndex::new(path).map(|index| BlobIndex(Mutex::new(index)))
+        BlobIndex::with_options(path, &util::IndexOptions::default())
+    }
+
+    pub fn with_options(path: &str,
+                        options: &util::IndexOptions)
+                        -> Result<BlobIndex, DieselError> {
+        InternalBlobIndex::new(path, options).map(|index| BlobIndex(Mutex::new(index)))
     }
 
     #[cfg(test)]
@@ -215,7 +384,14 @@ impl BlobIndex {
 
     /// Reserve an internal `BlobDesc` for a new blob.
     pub fn reserve(&self) -> BlobDesc {
-        self.lock().reserve()
+        self.lock().reserve(None)
+    }
+
+    /// Reserve an internal `BlobDesc` for a new blob starting with the chunk hashed `first_chunk`,
+    /// named after that hash (see `BlobNaming::Content`). Given the same blobs in the index, the
+    /// same first chunk gets the same name.
+    pub fn reserve_for(&self, first_chunk: &Hash) -> BlobDesc {
+        self.lock().reserve(Some(first_chunk))
     }
 
     /// Report that this blob is in the process of being committed to persistent storage. If a
@@ -231,6 +407,28 @@ impl BlobIndex {
         self.lock().commit_blob(blob)
     }
 
+    /// Record the digest of this blob's stored bytes.
+    pub fn set_digest(&self, blob: &BlobDesc, digest: &[u8]) {
+        self.lock().set_digest(blob, digest)
+    }
+
+    /// Record the number of bytes this blob takes up in the backend.
+    pub fn set_length(&self, blob: &BlobDesc, length: u64) {
+        self.lock().set_length(blob, length)
+    }
+
+    /// The total size of the blobs in the index, including blobs still being uploaded. Blobs
+    /// stored before lengths were recorded, and recovered blobs, are not counted.
+    pub fn stored_bytes(&self) -> u64 {
+        self.lock().stored_bytes()
+    }
+
+    /// The digest recorded for the blob named `name`, if any. Blobs stored before digests were
+    /// recorded, and recovered blobs, have none.
+    pub fn digest(&self, name: &[u8]) -> Option<Vec<u8>> {
+        self.lock().digest(name)
+    }
+
     /// Reinstall blob recovered by from external storage.
     /// Creates a new blob by a known external name.
     pub fn recover(&self, name: Vec<u8>) -> BlobDesc {
@@ -245,12 +443,34 @@ impl BlobIndex {
         self.lock().tag(tag, None)
     }
 
-    pub fn list_by_tag(&self, tag: tags::Tag) -> Vec<BlobDesc> {
-        self.lock().list_by_tag(tag)
+    /// Give the blobs tagged `from` the tag `to` instead, or only the blob called `name` if given.
+    /// Blobs with other tags are left alone.
+    pub fn retag(&self, name: Option<&[u8]>, from: tags::Tag, to: tags::Tag) {
+        self.lock().retag(name, from, to)
+    }
+
+    /// List up to `limit` blobs with the given tag.
+    pub fn list_by_tag(&self, tag: tags::Tag, limit: usize) -> Vec<BlobDesc> {
+        self.lock().list_by_tag(tag, limit)
+    }
+
+    /// Count the blobs with the given status.
+    pub fn count_by_status(&self, status: BlobStatus) -> usize {
+        self.lock().count_by_tag(status.to_tag())
+    }
+
+    /// List up to `limit` blobs with the given status.
+    pub fn list_by_status(&self, status: BlobStatus, limit: usize) -> Vec<BlobDesc> {
+        self.lock().list_by_tag(status.to_tag(), limit)
+    }
+
+    /// The status of the blob named `name`, or `None` if the index does not know it.
+    pub fn status(&self, name: &[u8]) -> Option<BlobStatus> {
+        self.lock().tag_of(name).and_then(BlobStatus::from_tag)
     }
 
-    pub fn delete_by_tag(&self, tag: tags::Tag) {
-        self.lock().delete_by_tag(tag)
+    pub fn delete(&self, blob: &BlobDesc) {
+        self.lock().delete(blob)
     }
 
     pub fn flush(&self) {
@@ -15,23 +15,28 @@
 //! Combines data chunks into larger blobs to be stored externally.
 
 use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::io;
 use std::mem;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 
-use backend::StoreBackend;
+use backend::{BackendError, StoreBackend};
 use capnp;
+use crypto;
 use errors;
 use hash::Hash;
 use hash::tree::HashRef;
+use metrics::{Metrics, NoMetrics};
 use tags;
-use util::FnBox;
+use util::{FnBox, LruCache};
 
 
 mod chunk;
 mod blob;
 mod index;
 mod schema;
+mod upload;
 #[cfg(test)]
 pub mod tests;
 
@@ -39,9 +44,11 @@ pub mod tests;
 mod benchmarks;
 
 
-pub use self::chunk::{ChunkRef, Key, Kind, Packing};
-pub use self::blob::Blob;
-pub use self::index::{BlobDesc, BlobIndex};
+pub use self::chunk::{Cipher, ChunkRef, CompressionLevel, Key, Kind, NonceStrategy, Packing};
+use self::chunk::{choose_packing, likely_compressible};
+pub use self::blob::{Blob, SealedBlob};
+pub use self::index::{BlobDesc, BlobIndex, BlobStatus};
+use self::upload::Uploads;
 
 
 error_type! {
@@ -55,69 +62,279 @@ error_type! {
         CryptoError(errors::CryptoError) {
             cause;
         },
+        Integrity(errors::IntegrityError) {
+            cause;
+        },
+        NotFound(errors::BlobNotFoundError) {
+            cause;
+        },
+        Quota(errors::QuotaError) {
+            cause;
+        },
         DataSerialization(capnp::Error) {
             cause;
+        },
+        Backend(BackendError) {
+            cause;
+        },
+        IO(io::Error) {
+            cause;
         }
     }
 }
 
+/// Number of blobs a store uploads concurrently, unless configured otherwise.
+pub const DEFAULT_UPLOAD_CONCURRENCY: usize = 4;
+
+/// Number of blobs a store keeps after reading them, so that reading several chunks of a blob in
+/// a row fetches and checks it once.
+const READ_CACHE_BLOBS: usize = 2;
+
+/// Number of recent random nonces a store checks new ones against in debug builds.
+const DEBUG_NONCE_WINDOW: usize = 1 << 16;
+
+/// Limits for the maximum size of a blob. A blob must at least fit the largest chunk.
+pub const MIN_BLOB_SIZE: usize = 256 * 1024;
+pub const MAX_BLOB_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Checks that `size` is within `MIN_BLOB_SIZE` and `MAX_BLOB_SIZE`.
+///
+/// The maximum size only affects writing: a chunk is read back from anywhere in a blob of any
+/// size, as the blob's footer is located from its end.
+pub fn check_max_blob_size(size: usize) -> Result<(), BlobError> {
+    if size < MIN_BLOB_SIZE || size > MAX_BLOB_SIZE {
+        return Err(From::from(format!("Maximum blob size must be between {} and {} bytes, not {}",
+                                      MIN_BLOB_SIZE,
+                                      MAX_BLOB_SIZE,
+                                      size)));
+    }
+    Ok(())
+}
+
+/// Settings for how a blob store packs and encrypts the chunks it writes.
+#[derive(Debug, Clone, Default)]
+pub struct StoreOptions {
+    pub packing: Option<Packing>,
+    /// Choose GZip, Snappy or no packing for each chunk by compressing its first 16 KiB with
+    /// both, instead of using `packing`. The choice is recorded in the chunk's `ChunkRef`.
+    pub auto_packing: bool,
+    pub compression_level: Option<CompressionLevel>,
+    pub cipher: Cipher,
+    /// How nonces are chosen for encrypted chunks. Defaults to `NonceStrategy::Random`.
+    pub nonce_strategy: NonceStrategy,
+    /// Maximum number of blobs being stored to the backend at once. Each holds up to a full
+    /// blob in memory. Defaults to `DEFAULT_UPLOAD_CONCURRENCY`.
+    pub upload_concurrency: Option<usize>,
+    /// Maximum size of the blobs written, if different from the repository's. Must pass
+    /// `check_max_blob_size`.
+    pub max_blob_size: Option<usize>,
+    /// Record the length of each chunk before packing in its `ChunkRef`, to diagnose how well
+    /// chunks compress. Off by default, as it makes every reference slightly larger.
+    pub record_raw_length: bool,
+    /// Maximum total size in bytes of the blobs in the index, counting the blobs of all stores
+    /// sharing it. Storing a chunk that would grow them beyond this fails with
+    /// `BlobError::Quota`. Unlimited by default.
+    pub quota: Option<u64>,
+    /// Once more than this many bytes of the blob being filled are held in memory, move them to
+    /// a temporary file until the blob is full. Blobs larger than this are sealed in such a file
+    /// and stored from it with `StoreBackend::store_file`, which backends able to stream it (e.g.
+    /// `FileBackend` and `S3Backend`) do without reading it back into memory. By default the
+    /// whole blob is kept in memory.
+    pub spill_threshold: Option<usize>,
+    /// Keep file data chunks of up to this many bytes in their `ChunkRef` instead of a blob, so
+    /// that tiny files need no backend object (see `BlobStore::store_file_data`). Such chunks are
+    /// neither packed nor encrypted, and are read back from the directory listing holding their
+    /// reference, which is always stored encrypted in a blob. Off by default.
+    pub inline_threshold: Option<usize>,
+    /// How blobs are named. Defaults to `BlobNaming::Random`.
+    pub blob_naming: BlobNaming,
+}
+
+/// Overrides how chunks are packed and encrypted, in place of the store's `StoreOptions`, e.g. to
+/// store data that is already compressed and encrypted as it is. The choice is recorded in each
+/// chunk's `ChunkRef`, so chunks stored with any policy are read back alike.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChunkPolicy {
+    /// How to pack chunks; `None` stores them unpacked. Chunks that look incompressible are
+    /// never packed.
+    pub packing: Option<Packing>,
+    /// How to encrypt chunks; `Cipher::Unencrypted` stores them without a key.
+    pub cipher: Cipher,
+}
+
+/// How a store names the blobs it writes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BlobNaming {
+    /// Give every blob a random name.
+    Random,
+    /// Derive the name of a blob from the hash of the first chunk stored in it, so that storing
+    /// the same chunks into the same index names the blobs alike (see `BlobIndex::reserve_for`).
+    /// Blobs whose derived name is taken by another blob in the index get the next one derived.
+    Content,
+}
+
+impl Default for BlobNaming {
+    fn default() -> BlobNaming {
+        BlobNaming::Random
+    }
+}
+
 pub struct BlobStore<B>(Arc<Mutex<StoreInner<B>>>);
 
+// A blob read from the backend, and whether it no longer matches its recorded digest.
+struct FetchedBlob {
+    data: Vec<u8>,
+    damaged: bool,
+}
+
+// The last nonces inserted, up to a fixed number of them; older ones are forgotten first.
+struct NonceFilter {
+    seen: HashSet<Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+    capacity: usize,
+}
+
+impl NonceFilter {
+    fn new(capacity: usize) -> NonceFilter {
+        assert!(capacity > 0);
+        NonceFilter {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            capacity: capacity,
+        }
+    }
+
+    // Remember `nonce`. Returns false if it is among the nonces remembered already.
+    fn insert(&mut self, nonce: &[u8]) -> bool {
+        if self.seen.contains(nonce) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(nonce.to_vec());
+        self.order.push_back(nonce.to_vec());
+        true
+    }
+}
+
 pub struct StoreInner<B> {
     backend: Arc<B>,
     max_blob_size: usize,
+    options: StoreOptions,
 
     blob_index: Arc<BlobIndex>,
     blob_desc: BlobDesc,
     blob_refs: Vec<(HashRef, Box<FnBox<HashRef, ()>>)>,
     blob: Blob,
+    uploads: Uploads,
+    metrics: Arc<Metrics>,
+    master_key: Option<crypto::FixedKey>,
+    // In debug builds: the random nonces recently drawn for this store's chunks, across blobs, to
+    // catch one being drawn twice.
+    random_nonces: NonceFilter,
+    // The blobs read last, checked against their digest.
+    read_cache: LruCache<Vec<u8>, Arc<FetchedBlob>>,
 }
 
 impl<B: StoreBackend> StoreInner<B> {
-    fn new(index: Arc<BlobIndex>, backend: Arc<B>, max_blob_size: usize) -> StoreInner<B> {
+    fn new(index: Arc<BlobIndex>,
+           backend: Arc<B>,
+           max_blob_size: usize,
+           options: StoreOptions)
+           -> StoreInner<B> {
+        let mut blob = Blob::new(max_blob_size);
+        blob.set_compression_level(options.compression_level);
+        blob.set_cipher(options.cipher);
+        blob.set_nonce_strategy(options.nonce_strategy);
+        blob.set_spill_threshold(options.spill_threshold);
+        let uploads = Uploads::new(options.upload_concurrency
+            .unwrap_or(DEFAULT_UPLOAD_CONCURRENCY));
         let mut bs = StoreInner {
             backend: backend,
             blob_index: index,
             blob_desc: Default::default(),
             blob_refs: Vec::new(),
             max_blob_size: max_blob_size,
-            blob: Blob::new(max_blob_size),
+            options: options,
+            blob: blob,
+            uploads: uploads,
+            metrics: Arc::new(NoMetrics),
+            master_key: None,
+            random_nonces: NonceFilter::new(DEBUG_NONCE_WINDOW),
+            read_cache: LruCache::new(READ_CACHE_BLOBS),
         };
         bs.reserve_new_blob();
         bs
     }
 
     fn reserve_new_blob(&mut self) -> BlobDesc {
-        mem::replace(&mut self.blob_desc, self.blob_index.reserve())
+        let next = match self.options.blob_naming {
+            BlobNaming::Random => self.blob_index.reserve(),
+            // Reserved by `name_blob` once its first chunk is known.
+            BlobNaming::Content => Default::default(),
+        };
+        mem::replace(&mut self.blob_desc, next)
     }
 
-    fn flush(&mut self) {
-        let ct = match self.blob.to_ciphertext() {
-            None => return,
-            Some(ct) => ct,
+    // Name the blob being filled after `first_chunk` if it is still empty, and blobs are named by
+    // their content.
+    fn name_blob(&mut self, first_chunk: &Hash) {
+        if self.options.blob_naming == BlobNaming::Content && self.blob.upperbound_len() == 0 {
+            self.blob_desc = self.blob_index.reserve_for(first_chunk);
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), BlobError> {
+        let sealed = match try!(self.blob.seal()) {
+            None => return Ok(()),
+            Some(sealed) => sealed,
         };
 
         // Replace blob id
         let old_blob_desc = self.reserve_new_blob();
 
         self.blob_index.in_air(&old_blob_desc);
-        self.backend.store(&old_blob_desc.name[..], &ct)
-                    .expect("Store operation failed");
-        self.blob_index.commit_done(&old_blob_desc);
-
-        // Go through callbacks
-        while let Some((href, callback)) = self.blob_refs.pop() {
-            callback.call(href);
-        }
+        self.blob_index.set_length(&old_blob_desc, sealed.len() as u64);
+        // Recorded to detect corruption of the stored blob before attempting to decrypt it.
+        self.blob_index.set_digest(&old_blob_desc, &sealed.digest().bytes[..]);
+
+        let backend = self.backend.clone();
+        let blob_index = self.blob_index.clone();
+        let metrics = self.metrics.clone();
+        let mut blob_refs = mem::replace(&mut self.blob_refs, Vec::new());
+        debug!("Storing blob {:?} of {} bytes holding {} chunks",
+               old_blob_desc.name,
+               sealed.len(),
+               blob_refs.len());
+        self.uploads.spawn(move || {
+            try!(sealed.store(&*backend, &old_blob_desc.name[..]));
+            metrics.bytes_stored(sealed.len());
+            blob_index.commit_done(&old_blob_desc);
+
+            // Go through callbacks; the references are only safe to use once stored.
+            while let Some((href, callback)) = blob_refs.pop() {
+                callback.call(href);
+            }
+            Ok(())
+        });
+        Ok(())
     }
 
     fn store(&mut self,
              chunk: &[u8],
              hash: Hash,
              kind: Kind,
+             policy: Option<&ChunkPolicy>,
+             file_data: bool,
              callback: Box<FnBox<HashRef, ()>>)
-             -> HashRef {
-        if chunk.is_empty() {
+             -> Result<HashRef, BlobError> {
+        // Empty and tiny chunks are kept in their reference, without a blob.
+        let inline = file_data &&
+                     self.options.inline_threshold.map_or(false, |max| chunk.len() <= max);
+        if chunk.is_empty() || inline {
             let href = HashRef {
                 hash: hash,
                 persistent_ref: ChunkRef {
@@ -127,78 +344,137 @@ impl<B: StoreBackend> StoreInner<B> {
                     kind: kind,
                     packing: None,
                     key: None,
+                    raw_length: None,
+                    nonce: None,
+                    inline: if chunk.is_empty() { None } else { Some(chunk.to_vec()) },
                 },
             };
             let local_href = href.clone();
             thread::spawn(move || callback.call(local_href));
-            return href;
+            return Ok(href);
         }
 
+        if let Some(quota) = self.options.quota {
+            let needed = self.blob_index.stored_bytes() + self.blob.upperbound_len() as u64 +
+                         chunk.len() as u64;
+            if needed > quota {
+                return Err(BlobError::Quota(errors::QuotaError {
+                    quota: quota,
+                    needed: needed,
+                }));
+            }
+        }
+
+        // Compressing data that looks random wastes time, and may even grow the chunk.
+        let packing = match policy {
+            Some(policy) => {
+                match policy.packing {
+                    Some(ref packing) if likely_compressible(chunk) => Some(packing.clone()),
+                    _ => None,
+                }
+            }
+            None if self.options.auto_packing => {
+                choose_packing(chunk, self.options.compression_level)
+            }
+            None => {
+                match self.options.packing {
+                    Some(ref packing) if likely_compressible(chunk) => Some(packing.clone()),
+                    _ => None,
+                }
+            }
+        };
+        let cipher = policy.map_or(self.options.cipher, |p| p.cipher);
+
+        self.name_blob(&hash);
         let mut href = HashRef {
             hash: hash,
             persistent_ref: ChunkRef {
                 blob_id: self.blob_desc.name.clone(),
                 kind: kind,
-                packing: None,
+                packing: packing,
                 // updated by try_append:
                 offset: 0,
                 length: 0,
                 key: None,
+                raw_length: if self.options.record_raw_length { Some(chunk.len()) } else { None },
+                nonce: None,
+                inline: None,
             },
         };
 
-        if let Err(()) = self.blob.try_append(&chunk, &mut href) {
-            self.flush();
+        if !try!(self.blob.try_append_with_cipher(&chunk, &mut href, cipher)) {
+            try!(self.flush());
 
+            self.name_blob(&href.hash);
             href.persistent_ref.blob_id = self.blob_desc.name.clone();
-            self.blob.try_append(&chunk, &mut href).unwrap();
+            let appended = try!(self.blob.try_append_with_cipher(&chunk, &mut href, cipher));
+            assert!(appended, "Chunk does not fit in an empty blob");
+        }
+        if cfg!(debug_assertions) {
+            if let Some(ref nonce) = href.persistent_ref.nonce {
+                // A nonce drawn twice means the random number generator is broken.
+                assert!(self.random_nonces.insert(nonce),
+                        "Random nonce {:?} was drawn twice",
+                        nonce);
+            }
         }
         self.blob_refs.push((href.clone(), callback));
+        debug!("Added chunk of {} bytes to blob {:?}", chunk.len(), href.persistent_ref.blob_id);
 
         // To avoid unnecessary blocking, we reply with the ID *before* possibly flushing.
-        href
+        Ok(href)
     }
 
-    fn retrieve(&mut self, hash: &Hash, cref: &ChunkRef) -> Result<Option<Vec<u8>>, BlobError> {
-        if cref.offset == 0 && cref.length == 0 {
-            return Ok(Some(Vec::new()));
-        }
-        match self.backend.retrieve(&cref.blob_id[..]) {
-            Ok(Some(blob)) => Ok(Some(try!(Blob::read_chunk(&blob, hash, cref)))),
-            Ok(None) => Ok(None),
-            Err(e) => Err(e.into()),
+    fn set_master_key(&mut self, key: crypto::FixedKey) {
+        self.blob.set_master_key(key.clone());
+        self.master_key = Some(key);
+    }
+
+    // A blob to seal or unseal a named blob with; see `BlobStore::store_named`.
+    fn named_blob(&self, sealed: bool) -> Blob {
+        let mut blob = Blob::new(self.max_blob_size);
+        if let (true, Some(key)) = (sealed, self.master_key.clone()) {
+            blob.set_master_key(key);
         }
+        blob
     }
 
-    fn store_named(&mut self, name: &str, data: &[u8]) -> Result<(), String> {
+    fn store_named(&mut self, name: &str, data: &[u8], sealed: bool) -> Result<(), BlobError> {
         assert!(data.len() < self.max_blob_size);
         let hash = Hash::new(&data[..]);
-        let mut blob = Blob::new(self.max_blob_size);
-        blob.try_append(&data,
-                        &mut HashRef {
-                            hash: hash,
-                            persistent_ref: ChunkRef {
-                                blob_id: name.as_bytes().to_owned(),
-                                offset: 0,
-                                length: 0,
-                                kind: Kind::TreeLeaf,
-                                packing: None,
-                                key: None,
-                            },
-                        })
-            .unwrap();
+        let mut blob = self.named_blob(sealed);
+        let mut href = HashRef {
+            hash: hash,
+            persistent_ref: ChunkRef {
+                blob_id: name.as_bytes().to_owned(),
+                offset: 0,
+                length: 0,
+                kind: Kind::TreeLeaf,
+                packing: None,
+                key: None,
+                raw_length: None,
+                nonce: None,
+                inline: None,
+            },
+        };
+        let appended = try!(blob.try_append(&data, &mut href));
+        assert!(appended);
 
-        let ct = blob.to_ciphertext().unwrap();
+        let ct = try!(blob.to_ciphertext()).unwrap();
 
+        // Named blobs may refer to data in blobs still being uploaded.
+        self.uploads.wait();
         try!(self.backend.store(name.as_bytes(), &ct));
+        self.metrics.bytes_stored(ct.len());
         Ok(())
     }
 
-    fn retrieve_named(&mut self, name: &str) -> Result<Option<Vec<u8>>, BlobError> {
+    fn retrieve_named(&mut self, name: &str, sealed: bool) -> Result<Option<Vec<u8>>, BlobError> {
         match try!(self.backend.retrieve(name.as_bytes())) {
             None => Ok(None),
             Some(ct) => {
-                let hrefs = try!(self.blob.refs_from_bytes(&ct));
+                self.metrics.bytes_retrieved(ct.len());
+                let hrefs = try!(self.named_blob(sealed).refs_from_bytes(&ct));
                 assert_eq!(hrefs.len(), 1);
                 let href = &hrefs[0];
                 assert_eq!(name.as_bytes(), &href.persistent_ref.blob_id[..]);
@@ -209,7 +485,7 @@ impl<B: StoreBackend> StoreInner<B> {
 
     fn recover(&mut self, chunk: HashRef) {
         if chunk.persistent_ref.offset == 0 && chunk.persistent_ref.length == 0 {
-            // This chunk is empty, so there is no blob to recover.
+            // This chunk is empty or inline, so there is no blob to recover.
             return;
         }
         self.blob_index.recover(chunk.persistent_ref.blob_id);
@@ -227,19 +503,57 @@ impl<B: StoreBackend> StoreInner<B> {
         self.blob_index.tag_all(tag);
     }
 
-    fn delete_by_tag(&mut self, tag: tags::Tag) -> Result<(), String> {
-        let blobs = self.blob_index.list_by_tag(tag);
-        for b in blobs.iter() {
-            try!(self.backend.delete(&b.name));
+    fn delete_by_tag(&mut self, tag: tags::Tag, limit: usize) -> Result<usize, BlobError> {
+        let blobs = self.blob_index.list_by_tag(tag, limit);
+        let names: Vec<Vec<u8>> = blobs.iter().map(|b| b.name.clone()).collect();
+        let failed = self.backend.delete_batch(&names[..]);
+
+        // Blobs that could not be deleted keep their tag, so the next run tries them again.
+        let failed_names: HashSet<&[u8]> = failed.iter().map(|&(ref name, _)| &name[..]).collect();
+        for b in blobs.iter().filter(|b| !failed_names.contains(&b.name[..])) {
+            self.blob_index.delete(b);
+            self.read_cache.remove(&b.name);
         }
-        self.blob_index.delete_by_tag(tag);
-        Ok(())
+        self.metrics.blobs_deleted(blobs.len() - failed_names.len());
+        if let Some(&(_, ref e)) = failed.first() {
+            warn!("Could not delete {} of {} blobs: {}", failed.len(), blobs.len(), e);
+            return Err(From::from(format!("Could not delete {} of {} blobs: {}",
+                                          failed.len(),
+                                          blobs.len(),
+                                          e)));
+        }
+        Ok(blobs.len())
     }
 }
 
 impl<B: StoreBackend> BlobStore<B> {
     pub fn new(index: Arc<BlobIndex>, backend: Arc<B>, max_blob_size: usize) -> BlobStore<B> {
-        BlobStore(Arc::new(Mutex::new(StoreInner::new(index, backend, max_blob_size))))
+        BlobStore::with_options(index, backend, max_blob_size, Default::default())
+    }
+
+    pub fn with_options(index: Arc<BlobIndex>,
+                        backend: Arc<B>,
+                        max_blob_size: usize,
+                        options: StoreOptions)
+                        -> BlobStore<B> {
+        BlobStore(Arc::new(Mutex::new(StoreInner::new(index, backend, max_blob_size, options))))
+    }
+
+    /// Report the traffic of this store to `metrics` from now on.
+    pub fn set_metrics(&self, metrics: Arc<Metrics>) {
+        self.lock().metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.lock().metrics.clone()
+    }
+
+    /// Whether the chunk at `cref` was stored unencrypted, while this store encrypts chunks (as
+    /// given by `policy`, if any), so that reusing it would leave data stored with this store
+    /// unencrypted. Empty and inline chunks are held by their (encrypted) parent instead.
+    pub fn needs_encrypted_copy(&self, cref: &ChunkRef, policy: Option<&ChunkPolicy>) -> bool {
+        let cipher = policy.map_or(self.lock().options.cipher, |p| p.cipher);
+        cipher != Cipher::Unencrypted && cref.key.is_none() && (cref.offset, cref.length) != (0, 0)
     }
 
     fn lock(&self) -> MutexGuard<StoreInner<B>> {
@@ -254,24 +568,149 @@ impl<B: StoreBackend> BlobStore<B> {
                  hash: Hash,
                  kind: Kind,
                  callback: Box<FnBox<HashRef, ()>>)
-                 -> HashRef {
+                 -> Result<HashRef, BlobError> {
+        self.store_with_policy(chunk, hash, kind, None, callback)
+    }
+
+    /// Like `store()`, but packs and encrypts the chunk as given by `policy` instead of the
+    /// store's options, if it is given.
+    pub fn store_with_policy(&self,
+                             chunk: &[u8],
+                             hash: Hash,
+                             kind: Kind,
+                             policy: Option<&ChunkPolicy>,
+                             callback: Box<FnBox<HashRef, ()>>)
+                             -> Result<HashRef, BlobError> {
+        let mut guard = self.lock();
+        guard.store(&chunk, hash, kind, policy, false, callback)
+    }
+
+    /// Like `store_with_policy()`, for a chunk of file data (a leaf of a file's hash tree). Only
+    /// these are kept in their reference if they are small enough (see
+    /// `StoreOptions::inline_threshold`), as the reference is then stored in an encrypted
+    /// directory listing.
+    pub fn store_file_data(&self,
+                           chunk: &[u8],
+                           hash: Hash,
+                           policy: Option<&ChunkPolicy>,
+                           callback: Box<FnBox<HashRef, ()>>)
+                           -> Result<HashRef, BlobError> {
         let mut guard = self.lock();
-        guard.store(&chunk, hash, kind, callback)
+        guard.store(&chunk, hash, Kind::TreeLeaf, policy, true, callback)
+    }
+
+    /// Retrieve the data chunk identified by `ChunkRef`. Fails with `BlobError::NotFound` if the
+    /// backend does not have its blob. A blob that does not match its recorded digest fails with
+    /// `BlobError::Integrity` only for the chunks of it that no longer verify.
+    ///
+    /// The store is not locked while the blob is read from the backend, so several chunks can be
+    /// retrieved concurrently. The last blobs read are kept, along with the result of checking
+    /// their digest, so that their other chunks are read without fetching them again.
+    pub fn retrieve(&self, hash: &Hash, cref: &ChunkRef) -> Result<Vec<u8>, BlobError> {
+        self.retrieve_from(hash, cref, true)
+    }
+
+    /// Like `retrieve()`, but always reads the blob from the backend, e.g. to verify what the
+    /// backend holds now.
+    pub fn retrieve_uncached(&self, hash: &Hash, cref: &ChunkRef) -> Result<Vec<u8>, BlobError> {
+        self.retrieve_from(hash, cref, false)
+    }
+
+    fn retrieve_from(&self,
+                     hash: &Hash,
+                     cref: &ChunkRef,
+                     cached: bool)
+                     -> Result<Vec<u8>, BlobError> {
+        if cref.offset == 0 && cref.length == 0 {
+            return Ok(cref.inline.clone().unwrap_or_else(Vec::new));
+        }
+        let blob = try!(self.fetch_blob(&cref.blob_id, cached));
+
+        // A damaged blob only loses the chunks that no longer authenticate.
+        match Blob::read_chunk(&blob.data, hash, cref) {
+            // Unencrypted chunks are not authenticated, so check them by their hash.
+            Ok(ref chunk) if blob.damaged && cref.key.is_none() && !hash.verify(&chunk[..]) => {
+                Err(BlobError::Integrity(errors::IntegrityError { blob_id: cref.blob_id.clone() }))
+            }
+            Ok(chunk) => {
+                if blob.damaged {
+                    warn!("Blob {:?} is damaged, but its chunk at offset {} is intact",
+                          cref.blob_id,
+                          cref.offset);
+                }
+                Ok(chunk)
+            }
+            Err(_) if blob.damaged => {
+                Err(BlobError::Integrity(errors::IntegrityError { blob_id: cref.blob_id.clone() }))
+            }
+            Err(e) => Err(e),
+        }
     }
 
-    /// Retrieve the data chunk identified by `ChunkRef`.
-    pub fn retrieve(&self, hash: &Hash, cref: &ChunkRef) -> Result<Option<Vec<u8>>, BlobError> {
-        self.lock().retrieve(hash, cref)
+    // Read the blob `blob_id` and check it against its recorded digest, before any of its chunks
+    // are decrypted. With `cached`, a blob read recently is reused as it was checked then.
+    fn fetch_blob(&self, blob_id: &[u8], cached: bool) -> Result<Arc<FetchedBlob>, BlobError> {
+        let (backend, blob_index, metrics) = {
+            let mut guard = self.lock();
+            if cached {
+                if let Some(blob) = guard.read_cache.get(&blob_id.to_vec()) {
+                    return Ok(blob.clone());
+                }
+            }
+            (guard.backend.clone(), guard.blob_index.clone(), guard.metrics.clone())
+        };
+        let data = match backend.retrieve(blob_id) {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                return Err(BlobError::NotFound(errors::BlobNotFoundError {
+                    blob_id: blob_id.to_vec(),
+                }));
+            }
+            Err(e) => {
+                warn!("Could not retrieve blob {:?}: {}", blob_id, e);
+                return Err(e.into());
+            }
+        };
+        metrics.bytes_retrieved(data.len());
+        let damaged = match blob_index.digest(blob_id) {
+            Some(digest) => Hash::new(&data[..]).bytes != digest,
+            None => false,
+        };
+        let blob = Arc::new(FetchedBlob {
+            data: data,
+            damaged: damaged,
+        });
+        if cached {
+            self.lock().read_cache.insert(blob_id.to_vec(), blob.clone());
+        }
+        Ok(blob)
     }
 
     /// Store a full named blob (used for writing root).
-    pub fn store_named(&self, name: &str, data: &[u8]) -> Result<(), String> {
-        self.lock().store_named(name, &data)
+    pub fn store_named(&self, name: &str, data: &[u8]) -> Result<(), BlobError> {
+        self.lock().store_named(name, &data, true)
     }
 
     /// Retrieve full named blob.
     pub fn retrieve_named(&self, name: &str) -> Result<Option<Vec<u8>>, BlobError> {
-        self.lock().retrieve_named(name)
+        self.lock().retrieve_named(name, true)
+    }
+
+    /// Like `store_named()`, but always sealed with the built-in key, for what must be readable
+    /// before the master key is known.
+    pub fn store_named_unsealed(&self, name: &str, data: &[u8]) -> Result<(), BlobError> {
+        self.lock().store_named(name, &data, false)
+    }
+
+    /// Retrieve a named blob stored with `store_named_unsealed()`.
+    pub fn retrieve_named_unsealed(&self, name: &str) -> Result<Option<Vec<u8>>, BlobError> {
+        self.lock().retrieve_named(name, false)
+    }
+
+    /// Seal the footers of the blobs stored from now on, which hold the keys of their chunks, and
+    /// named blobs with `key` instead of the built-in key (see `crypto::FixedKey`).
+    pub fn set_master_key(&self, key: crypto::FixedKey) {
+        self.lock().set_master_key(key)
     }
 
     /// Reinstall a blob recovered from external storage.
@@ -287,14 +726,17 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().tag_all(tag)
     }
 
-    pub fn delete_by_tag(&self, tag: tags::Tag) -> Result<(), String> {
-        self.lock().delete_by_tag(tag)
+    /// Delete up to `limit` blobs with the given tag. Returns the number of blobs deleted.
+    pub fn delete_by_tag(&self, tag: tags::Tag, limit: usize) -> Result<usize, BlobError> {
+        self.lock().delete_by_tag(tag, limit)
     }
 
-    /// Flush the current blob, independent of its size.
-    pub fn flush(&self) {
+    /// Flush the current blob, independent of its size, and wait for all uploads to finish.
+    pub fn flush(&self) -> Result<(), BlobError> {
         let mut guard = self.lock();
-        guard.flush();
+        try!(guard.flush());
+        guard.uploads.wait();
         guard.blob_index.flush();
+        Ok(())
     }
 }
@@ -19,6 +19,8 @@ table! {
         id -> BigInt,
         name -> Binary,
         tag -> Integer,
+        digest -> Nullable<Binary>,
+        length -> Nullable<BigInt>,
     }
 }
 
@@ -30,6 +32,8 @@ pub struct Blob {
     pub id: i64,
     pub name: Vec<u8>,
     pub tag: i32,
+    pub digest: Option<Vec<u8>>,
+    pub length: Option<i64>,
 }
 
 #[insertable_into(blobs)]
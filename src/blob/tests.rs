@@ -12,13 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License
 
-use blob::{Blob, BlobError, BlobIndex, BlobStore, ChunkRef, Kind};
-use backend::{MemoryBackend, StoreBackend};
+use blob::{Blob, BlobError, BlobIndex, BlobNaming, BlobStatus, BlobStore, ChunkRef, Cipher,
+           CompressionLevel, Key, Kind, NonceStrategy, Packing, StoreOptions};
+use backend::{BackendError, MemoryBackend, StoreBackend};
+use crypto::CipherText;
 use hash;
+use metrics::MemoryMetrics;
+use tags;
 
+use std::cmp;
 use std::collections::HashSet;
-use std::sync::Arc;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use quickcheck;
+use rand;
 
 #[test]
 fn identity() {
@@ -33,11 +43,11 @@ fn identity() {
             ids.push((bs_p.store(&chunk[..],
                                  hash::Hash::new(chunk),
                                  Kind::TreeLeaf,
-                                 Box::new(move |_| {})),
+                                 Box::new(move |_| {})).unwrap(),
                       chunk));
         }
 
-        bs_p.flush();
+        bs_p.flush().unwrap();
 
         // Non-empty chunks must be in the backend now:
         for &(ref id, chunk) in ids.iter() {
@@ -51,9 +61,7 @@ fn identity() {
 
         // All chunks must be available through the blob store:
         for &(ref id, chunk) in ids.iter() {
-            assert_eq!(bs_p.retrieve(&id.hash, &id.persistent_ref)
-                           .unwrap()
-                           .unwrap(),
+            assert_eq!(bs_p.retrieve(&id.hash, &id.persistent_ref).unwrap(),
                        &chunk[..]);
         }
 
@@ -75,13 +83,11 @@ fn identity_with_excessive_flushing() {
             ids.push((bs_p.store(&chunk[..],
                                  hash::Hash::new(chunk),
                                  Kind::TreeLeaf,
-                                 Box::new(move |_| {})),
+                                 Box::new(move |_| {})).unwrap(),
                       chunk));
-            bs_p.flush();
+            bs_p.flush().unwrap();
             let &(ref id, chunk) = ids.last().unwrap();
-            assert_eq!(bs_p.retrieve(&id.hash, &id.persistent_ref)
-                           .unwrap()
-                           .unwrap(),
+            assert_eq!(bs_p.retrieve(&id.hash, &id.persistent_ref).unwrap(),
                        &chunk[..]);
         }
 
@@ -97,9 +103,7 @@ fn identity_with_excessive_flushing() {
 
         // All chunks must be available through the blob store:
         for &(ref id, chunk) in ids.iter() {
-            assert_eq!(bs_p.retrieve(&id.hash, &id.persistent_ref)
-                           .unwrap()
-                           .unwrap(),
+            assert_eq!(bs_p.retrieve(&id.hash, &id.persistent_ref).unwrap(),
                        &chunk[..]);
         }
 
@@ -118,13 +122,791 @@ fn blobid_identity() {
             kind: Kind::TreeBranch,
             packing: None,
             key: None,
+            raw_length: None,
+            nonce: None,
+            inline: None,
         };
-        let blob_id_bytes = blob_id.as_bytes();
+        let blob_id_bytes = blob_id.as_bytes().unwrap();
         ChunkRef::from_bytes(&mut &blob_id_bytes[..]).unwrap() == blob_id
     }
     quickcheck::quickcheck(prop as fn(Vec<u8>, usize, usize) -> bool);
 }
 
+#[test]
+fn blobid_packing_identity() {
+    for packing in vec![None, Some(Packing::GZip), Some(Packing::Snappy), Some(Packing::Zstd)] {
+        let blob_id = ChunkRef {
+            blob_id: vec![1, 2, 3],
+            offset: 10,
+            length: 20,
+            kind: Kind::TreeLeaf,
+            packing: packing,
+            key: None,
+            raw_length: None,
+            nonce: None,
+            inline: None,
+        };
+        let blob_id_bytes = blob_id.as_bytes().unwrap();
+        assert_eq!(ChunkRef::from_bytes(&mut &blob_id_bytes[..]).unwrap(), blob_id);
+    }
+}
+
+#[test]
+fn blobid_key_identity() {
+    use sodiumoxide::crypto::aead::chacha20poly1305_ietf;
+    use sodiumoxide::crypto::secretbox::xsalsa20poly1305;
+
+    for key in vec![None,
+                    Some(Key::XSalsa20Poly1305(xsalsa20poly1305::gen_key())),
+                    Some(Key::ChaCha20Poly1305(chacha20poly1305_ietf::gen_key()))] {
+        let blob_id = ChunkRef {
+            blob_id: vec![1, 2, 3],
+            offset: 10,
+            length: 20,
+            kind: Kind::TreeLeaf,
+            packing: None,
+            key: key,
+            raw_length: None,
+            nonce: None,
+            inline: None,
+        };
+        let blob_id_bytes = blob_id.as_bytes().unwrap();
+        assert_eq!(ChunkRef::from_bytes(&mut &blob_id_bytes[..]).unwrap(), blob_id);
+    }
+}
+
+#[test]
+fn chunk_ref_bytes_identity() {
+    use sodiumoxide::crypto::aead::chacha20poly1305_ietf;
+
+    let cref = ChunkRef {
+        blob_id: vec![4, 5, 6, 7],
+        offset: 1 << 20,
+        length: 4096,
+        kind: Kind::TreeBranch,
+        packing: Some(Packing::Zstd),
+        key: Some(Key::ChaCha20Poly1305(chacha20poly1305_ietf::gen_key())),
+        raw_length: Some(10000),
+        nonce: None,
+        inline: None,
+    };
+    let bytes = match cref.as_bytes() {
+        Ok(bytes) => bytes,
+        Err(e) => panic!("Could not serialize chunk reference: {}", e),
+    };
+    assert!(!bytes.is_empty());
+    assert_eq!(ChunkRef::from_bytes(&mut &bytes[..]).unwrap(), cref);
+}
+
+#[test]
+fn chunk_ref_json_identity() {
+    use sodiumoxide::crypto::aead::chacha20poly1305_ietf;
+    use sodiumoxide::crypto::secretbox::xsalsa20poly1305;
+
+    let mut cref = ChunkRef {
+        blob_id: vec![4, 5, 6, 7],
+        offset: 1 << 20,
+        length: 4096,
+        kind: Kind::TreeBranch,
+        packing: Some(Packing::Snappy),
+        key: Some(Key::ChaCha20Poly1305(chacha20poly1305_ietf::gen_key())),
+        raw_length: Some(10000),
+        nonce: Some(vec![9; 12]),
+        inline: None,
+    };
+    assert_eq!(ChunkRef::from_json(&cref.to_json()).unwrap(), cref);
+
+    cref.kind = Kind::TreeLeaf;
+    cref.packing = None;
+    cref.key = Some(Key::XSalsa20Poly1305(xsalsa20poly1305::gen_key()));
+    cref.raw_length = None;
+    cref.nonce = None;
+    assert_eq!(ChunkRef::from_json(&cref.to_json()).unwrap(), cref);
+
+    cref.key = None;
+    assert_eq!(ChunkRef::from_json(&cref.to_json()).unwrap(), cref);
+
+    cref.inline = Some(b"tiny".to_vec());
+    assert_eq!(ChunkRef::from_json(&cref.to_json()).unwrap(), cref);
+    let bytes = cref.as_bytes().unwrap();
+    assert_eq!(ChunkRef::from_bytes(&mut &bytes[..]).unwrap(), cref);
+
+    assert!(ChunkRef::from_json("{}").is_err());
+    assert!(ChunkRef::from_json("not json").is_err());
+}
+
+#[test]
+fn chunk_ref_rejects_wrong_key_size() {
+    use capnp;
+    use root_capnp;
+
+    let cref = ChunkRef {
+        blob_id: vec![1, 2, 3],
+        offset: 10,
+        length: 20,
+        kind: Kind::TreeLeaf,
+        packing: None,
+        key: None,
+        raw_length: None,
+        nonce: None,
+        inline: None,
+    };
+    for key_len in vec![0, 16, 31, 33, 64] {
+        let key = vec![7u8; key_len];
+        for chacha in vec![false, true] {
+            let mut message = capnp::message::Builder::new_default();
+            {
+                let mut root = message.init_root::<root_capnp::chunk_ref::Builder>();
+                cref.populate_msg(root.borrow());
+                if chacha {
+                    root.borrow().init_key().set_chacha20_poly1305(&key[..]);
+                } else {
+                    root.borrow().init_key().set_xsalsa20_poly1305(&key[..]);
+                }
+            }
+            let mut bytes = Vec::new();
+            capnp::serialize_packed::write_message(&mut bytes, &message).unwrap();
+
+            assert!(ChunkRef::from_bytes(&mut &bytes[..]).is_err());
+        }
+    }
+}
+
+#[test]
+fn packing_identity() {
+    fn prop(chunk: Vec<u8>) -> bool {
+        for packing in vec![Packing::GZip, Packing::Snappy, Packing::Zstd] {
+            let packed = packing.pack(&chunk[..], None).unwrap();
+            if packing.unpack(&packed[..]).unwrap() != chunk {
+                return false;
+            }
+        }
+        true
+    }
+    quickcheck::quickcheck(prop as fn(Vec<u8>) -> bool);
+}
+
+fn compressible_text(len: usize) -> Vec<u8> {
+    // Words picked by a simple LCG, so higher levels have something to find.
+    let words = ["backup ", "snapshot ", "family ", "blob ", "chunk ", "hash ", "tree ", "index "];
+    let mut state: u32 = 1;
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        out.extend_from_slice(words[(state >> 16) as usize % words.len()].as_bytes());
+    }
+    out.truncate(len);
+    out
+}
+
+#[test]
+fn packing_levels() {
+    let chunk = compressible_text(100000);
+    for &(ref packing, low, high) in &[(Packing::Zstd, 1, 19), (Packing::GZip, 1, 9)] {
+        let fast = packing.pack(&chunk[..], Some(CompressionLevel(low))).unwrap();
+        let small = packing.pack(&chunk[..], Some(CompressionLevel(high))).unwrap();
+        assert!(small.len() != fast.len());
+        assert_eq!(chunk, packing.unpack(&fast[..]).unwrap());
+        assert_eq!(chunk, packing.unpack(&small[..]).unwrap());
+    }
+}
+
+#[test]
+fn blob_store_compression_level() {
+    let chunk = compressible_text(100000);
+    let mut lengths = Vec::new();
+    for level in vec![1, 19] {
+        let backend = Arc::new(MemoryBackend::new());
+        let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+        let options = StoreOptions {
+            packing: Some(Packing::Zstd),
+            compression_level: Some(CompressionLevel(level)),
+            ..StoreOptions::default()
+        };
+        let bs_p = BlobStore::with_options(blob_index, backend, 1024 * 1024, options);
+
+        let href = bs_p.store(&chunk[..],
+                              hash::Hash::new(&chunk[..]),
+                              Kind::TreeLeaf,
+                              Box::new(move |_| {})).unwrap();
+        bs_p.flush().unwrap();
+
+        // The level is not recorded in the reference.
+        assert_eq!(Some(Packing::Zstd), href.persistent_ref.packing);
+        assert_eq!(bs_p.retrieve(&href.hash, &href.persistent_ref).unwrap(),
+                   chunk);
+        lengths.push(href.persistent_ref.length);
+    }
+    assert!(lengths[0] != lengths[1]);
+}
+
+#[test]
+fn blob_store_skips_packing_incompressible() {
+    let random: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    let text = compressible_text(100000);
+
+    let backend = Arc::new(MemoryBackend::new());
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let options = StoreOptions { packing: Some(Packing::Zstd), ..StoreOptions::default() };
+    let bs_p = BlobStore::with_options(blob_index, backend, 1024 * 1024, options);
+
+    let mut hrefs = Vec::new();
+    for chunk in vec![&random, &text] {
+        hrefs.push(bs_p.store(&chunk[..],
+                              hash::Hash::new(&chunk[..]),
+                              Kind::TreeLeaf,
+                              Box::new(move |_| {})).unwrap());
+    }
+    bs_p.flush().unwrap();
+
+    assert_eq!(None, hrefs[0].persistent_ref.packing);
+    assert_eq!(Some(Packing::Zstd), hrefs[1].persistent_ref.packing);
+    assert_eq!(bs_p.retrieve(&hrefs[0].hash, &hrefs[0].persistent_ref).unwrap(),
+               random);
+    assert_eq!(bs_p.retrieve(&hrefs[1].hash, &hrefs[1].persistent_ref).unwrap(),
+               text);
+}
+
+#[test]
+fn blob_store_auto_packing() {
+    let random: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    let zeros = vec![0; 100000];
+
+    let backend = Arc::new(MemoryBackend::new());
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let options = StoreOptions { auto_packing: true, ..StoreOptions::default() };
+    let bs_p = BlobStore::with_options(blob_index, backend, 1024 * 1024, options);
+
+    let mut hrefs = Vec::new();
+    for chunk in vec![&random, &zeros] {
+        hrefs.push(bs_p.store(&chunk[..],
+                              hash::Hash::new(&chunk[..]),
+                              Kind::TreeLeaf,
+                              Box::new(move |_| {})).unwrap());
+    }
+    bs_p.flush().unwrap();
+
+    assert!(hrefs[0].persistent_ref.packing != Some(Packing::GZip));
+    assert_eq!(Some(Packing::GZip), hrefs[1].persistent_ref.packing);
+    assert_eq!(bs_p.retrieve(&hrefs[0].hash, &hrefs[0].persistent_ref).unwrap(),
+               random);
+    assert_eq!(bs_p.retrieve(&hrefs[1].hash, &hrefs[1].persistent_ref).unwrap(),
+               zeros);
+}
+
+#[test]
+fn blob_store_records_raw_length() {
+    let chunk = compressible_text(100000);
+    let mut hrefs = Vec::new();
+    for record in vec![false, true] {
+        let backend = Arc::new(MemoryBackend::new());
+        let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+        let options = StoreOptions {
+            packing: Some(Packing::Zstd),
+            record_raw_length: record,
+            ..StoreOptions::default()
+        };
+        let bs_p = BlobStore::with_options(blob_index, backend, 1024 * 1024, options);
+        hrefs.push(bs_p.store(&chunk[..],
+                              hash::Hash::new(&chunk[..]),
+                              Kind::TreeLeaf,
+                              Box::new(move |_| {})).unwrap());
+        bs_p.flush().unwrap();
+    }
+
+    assert_eq!(None, hrefs[0].persistent_ref.raw_length);
+
+    let cref = &hrefs[1].persistent_ref;
+    assert_eq!(Some(chunk.len()), cref.raw_length);
+    assert!(chunk.len() > cref.length);
+    assert_eq!(cref, &ChunkRef::from_bytes(&mut &cref.as_bytes().unwrap()[..]).unwrap());
+}
+
+#[test]
+fn blob_store_without_encryption() {
+    let backend = Arc::new(MemoryBackend::new());
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let store = |cipher| {
+        let options = StoreOptions { cipher: cipher, ..StoreOptions::default() };
+        BlobStore::with_options(blob_index.clone(), backend.clone(), 1024 * 1024, options)
+    };
+
+    let plain = store(Cipher::Unencrypted);
+    let chunk: Vec<u8> = (0..10000).map(|i| (i * 7) as u8).collect();
+    let href = plain.store(&chunk[..],
+                           hash::Hash::new(&chunk[..]),
+                           Kind::TreeLeaf,
+                           Box::new(move |_| {})).unwrap();
+    plain.flush().unwrap();
+    let cref = &href.persistent_ref;
+    assert_eq!(None, cref.key);
+
+    // The chunk is stored as is.
+    let stored = backend.retrieve(&cref.blob_id[..]).unwrap().unwrap();
+    assert_eq!(&chunk[..], &stored[cref.offset..cref.offset + cref.length]);
+
+    // Another store reads both unencrypted and encrypted chunks.
+    let encrypted = store(Cipher::default());
+    let other: Vec<u8> = (0..10000).map(|i| (i * 13) as u8).collect();
+    let other_href = encrypted.store(&other[..],
+                                     hash::Hash::new(&other[..]),
+                                     Kind::TreeLeaf,
+                                     Box::new(move |_| {})).unwrap();
+    encrypted.flush().unwrap();
+    assert!(other_href.persistent_ref.key.is_some());
+    assert_eq!(encrypted.retrieve(&href.hash, cref).unwrap(), chunk);
+    assert_eq!(encrypted.retrieve(&other_href.hash, &other_href.persistent_ref).unwrap(),
+               other);
+}
+
+#[test]
+fn blob_store_detects_corruption() {
+    let backend = Arc::new(MemoryBackend::new());
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let bs_p = BlobStore::new(blob_index, backend.clone(), 1024);
+
+    let chunk = vec![1; 100];
+    let href = bs_p.store(&chunk[..],
+                          hash::Hash::new(&chunk[..]),
+                          Kind::TreeLeaf,
+                          Box::new(move |_| {})).unwrap();
+    let other = vec![2; 100];
+    let other_href = bs_p.store(&other[..],
+                                hash::Hash::new(&other[..]),
+                                Kind::TreeLeaf,
+                                Box::new(move |_| {})).unwrap();
+    bs_p.flush().unwrap();
+    assert_eq!(href.persistent_ref.blob_id, other_href.persistent_ref.blob_id);
+    assert_eq!(bs_p.retrieve(&href.hash, &href.persistent_ref).unwrap(), chunk);
+
+    // Flip a bit in the encrypted chunk.
+    backend.modify(&href.persistent_ref.blob_id[..],
+                   |blob| blob[href.persistent_ref.offset] ^= 1);
+    match bs_p.retrieve_uncached(&href.hash, &href.persistent_ref) {
+        Err(BlobError::Integrity(e)) => assert_eq!(e.blob_id, href.persistent_ref.blob_id),
+        other => panic!("Expected an integrity error, got: {:?}", other),
+    }
+
+    // The other chunk of the damaged blob still authenticates.
+    assert_eq!(bs_p.retrieve_uncached(&other_href.hash, &other_href.persistent_ref).unwrap(),
+               other);
+
+    // The blob as read before it was damaged is still cached.
+    assert_eq!(bs_p.retrieve(&href.hash, &href.persistent_ref).unwrap(), chunk);
+}
+
+#[test]
+fn blob_store_fetches_blob_once_for_its_chunks() {
+    let backend = Arc::new(MemoryBackend::new());
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let bs_p = BlobStore::new(blob_index, backend.clone(), 1024);
+    let metrics = Arc::new(MemoryMetrics::new());
+    bs_p.set_metrics(metrics.clone());
+
+    let chunks: Vec<Vec<u8>> = (0..3).map(|i| vec![i; 50]).collect();
+    let hrefs: Vec<_> = chunks.iter()
+        .map(|chunk| {
+            bs_p.store(&chunk[..],
+                       hash::Hash::new(&chunk[..]),
+                       Kind::TreeLeaf,
+                       Box::new(move |_| {}))
+                .unwrap()
+        })
+        .collect();
+    bs_p.flush().unwrap();
+    let blob_id = hrefs[0].persistent_ref.blob_id.clone();
+    assert!(hrefs.iter().all(|h| h.persistent_ref.blob_id == blob_id));
+    let blob_len = backend.retrieve(&blob_id[..]).unwrap().unwrap().len();
+
+    // The blob is fetched and checked against its digest once, for all of its chunks.
+    for (href, chunk) in hrefs.iter().zip(chunks.iter()) {
+        assert_eq!(&bs_p.retrieve(&href.hash, &href.persistent_ref).unwrap(), chunk);
+    }
+    assert_eq!(metrics.counts().bytes_retrieved, blob_len);
+
+    // Deleted blobs are no longer read from the cache.
+    bs_p.tag(hrefs[0].persistent_ref.clone(), tags::Tag::WillDelete);
+    bs_p.delete_by_tag(tags::Tag::WillDelete, 10).unwrap();
+    match bs_p.retrieve(&hrefs[0].hash, &hrefs[0].persistent_ref) {
+        Err(BlobError::NotFound(_)) => (),
+        other => panic!("Expected a missing blob, got: {:?}", other),
+    }
+}
+
+/// Stores slowly and records the order in which stores complete.
+struct SlowBackend {
+    inner: MemoryBackend,
+    stored: Mutex<Vec<Vec<u8>>>,
+    // Number of stores in progress, and the most seen at once.
+    in_flight: Mutex<(usize, usize)>,
+}
+
+impl StoreBackend for SlowBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight.0 += 1;
+            in_flight.1 = cmp::max(in_flight.0, in_flight.1);
+        }
+        thread::sleep(Duration::from_millis(20));
+        let res = self.inner.store(name, data);
+        self.stored.lock().unwrap().push(name.to_vec());
+        self.in_flight.lock().unwrap().0 -= 1;
+        res
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.inner.retrieve(name)
+    }
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.inner.delete(name)
+    }
+    fn flush(&self) -> Result<(), BackendError> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn blob_store_concurrent_uploads() {
+    let backend = Arc::new(SlowBackend {
+        inner: MemoryBackend::new(),
+        stored: Mutex::new(Vec::new()),
+        in_flight: Mutex::new((0, 0)),
+    });
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let options = StoreOptions { upload_concurrency: Some(4), ..StoreOptions::default() };
+    let bs_p = BlobStore::with_options(blob_index, backend.clone(), 1024, options);
+
+    let done = Arc::new(Mutex::new(Vec::new()));
+    let mut hrefs = Vec::new();
+    for i in 0..32u8 {
+        // Each chunk fills most of a blob, so every store starts an upload.
+        let chunk = vec![i; 800];
+        let local_backend = backend.clone();
+        let local_done = done.clone();
+        hrefs.push((bs_p.store(&chunk[..],
+                               hash::Hash::new(&chunk[..]),
+                               Kind::TreeLeaf,
+                               Box::new(move |href: hash::tree::HashRef| {
+                                   // The blob must be stored before its references are used.
+                                   let stored = local_backend.stored.lock().unwrap();
+                                   assert!(stored.contains(&href.persistent_ref.blob_id));
+                                   local_done.lock().unwrap().push(href.hash);
+                               })).unwrap(),
+                    chunk));
+    }
+    bs_p.flush().unwrap();
+
+    assert_eq!(done.lock().unwrap().len(), hrefs.len());
+    assert_eq!(backend.stored.lock().unwrap().len(), hrefs.len());
+    let max_in_flight = backend.in_flight.lock().unwrap().1;
+    assert!(max_in_flight > 1 && max_in_flight <= 4);
+
+    for &(ref href, ref chunk) in hrefs.iter() {
+        assert_eq!(&bs_p.retrieve(&href.hash, &href.persistent_ref).unwrap(),
+                   chunk);
+    }
+}
+
+#[test]
+fn blob_store_spills_large_blobs() {
+    let chunks: Vec<Vec<u8>> = (0..200)
+        .map(|_| (0..1000).map(|_| rand::random::<u8>()).collect())
+        .collect();
+
+    let mut refs = Vec::new();
+    for threshold in vec![None, Some(4096)] {
+        let backend = Arc::new(MemoryBackend::new());
+        let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+        let options = StoreOptions { spill_threshold: threshold, ..StoreOptions::default() };
+        let bs_p = BlobStore::with_options(blob_index, backend.clone(), 64 * 1024, options);
+
+        let hrefs: Vec<_> = chunks.iter()
+            .map(|chunk| {
+                bs_p.store(&chunk[..],
+                           hash::Hash::new(&chunk[..]),
+                           Kind::TreeLeaf,
+                           Box::new(move |_| {}))
+                    .unwrap()
+            })
+            .collect();
+        bs_p.flush().unwrap();
+
+        // The chunks span several blobs, each of which is spilled many times over.
+        let blob_ids: HashSet<_> = hrefs.iter().map(|h| h.persistent_ref.blob_id.clone()).collect();
+        assert!(blob_ids.len() > 2);
+
+        for (href, chunk) in hrefs.iter().zip(chunks.iter()) {
+            assert_eq!(&bs_p.retrieve(&href.hash, &href.persistent_ref).unwrap(), chunk);
+        }
+        for name in blob_ids {
+            let blob = backend.retrieve(&name[..]).unwrap().unwrap();
+            assert_eq!(Blob::new(64 * 1024).refs_from_bytes(&blob[..]).unwrap().len(),
+                       hrefs.iter().filter(|h| h.persistent_ref.blob_id == name).count());
+        }
+        refs.push(hrefs.into_iter()
+            .map(|h| (h.persistent_ref.offset, h.persistent_ref.length))
+            .collect::<Vec<_>>());
+    }
+
+    // Spilling does not move the chunks within their blobs.
+    assert_eq!(refs[0], refs[1]);
+}
+
+/// Keeps blobs in a `MemoryBackend`, but refuses to be handed any larger than `max_in_memory` in
+/// memory; larger blobs must come from a file.
+struct StreamingBackend {
+    blobs: MemoryBackend,
+    max_in_memory: usize,
+    files_stored: Mutex<usize>,
+}
+
+impl StoreBackend for StreamingBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        if data.len() > self.max_in_memory {
+            return Err(From::from(format!("Blob of {} bytes held in memory", data.len())));
+        }
+        self.blobs.store(name, data)
+    }
+
+    fn store_file(&self, name: &[u8], file: &fs::File, len: u64) -> Result<(), BackendError> {
+        *self.files_stored.lock().unwrap() += 1;
+        let mut file = file;
+        let mut data = vec![];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.take(len).read_to_end(&mut data).unwrap();
+        self.blobs.store(name, &CipherText::new(data))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.blobs.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.blobs.delete(name)
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.blobs.flush()
+    }
+}
+
+#[test]
+fn blob_store_streams_spilled_blobs() {
+    let backend = Arc::new(StreamingBackend {
+        blobs: MemoryBackend::new(),
+        max_in_memory: 8192,
+        files_stored: Mutex::new(0),
+    });
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let options = StoreOptions { spill_threshold: Some(4096), ..StoreOptions::default() };
+    let bs_p = BlobStore::with_options(blob_index, backend.clone(), 64 * 1024, options);
+
+    let chunks: Vec<Vec<u8>> = (0..200)
+        .map(|_| (0..1000).map(|_| rand::random::<u8>()).collect())
+        .collect();
+    let hrefs: Vec<_> = chunks.iter()
+        .map(|chunk| {
+            bs_p.store(&chunk[..],
+                       hash::Hash::new(&chunk[..]),
+                       Kind::TreeLeaf,
+                       Box::new(move |_| {}))
+                .unwrap()
+        })
+        .collect();
+    bs_p.flush().unwrap();
+
+    // No blob was handed to the backend in memory, and each was stored whole, matching the digest
+    // it is read back with.
+    let blob_ids: HashSet<_> = hrefs.iter().map(|h| h.persistent_ref.blob_id.clone()).collect();
+    assert_eq!(*backend.files_stored.lock().unwrap(), blob_ids.len());
+    for name in blob_ids {
+        assert_eq!(backend.retrieve(&name[..]).unwrap().unwrap().len(), 64 * 1024);
+    }
+    for (href, chunk) in hrefs.iter().zip(chunks.iter()) {
+        assert_eq!(&bs_p.retrieve(&href.hash, &href.persistent_ref).unwrap(), chunk);
+    }
+}
+
+#[test]
+fn blob_store_quota() {
+    let backend = Arc::new(MemoryBackend::new());
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let options = StoreOptions { quota: Some(2000), ..StoreOptions::default() };
+    let bs_p = BlobStore::with_options(blob_index.clone(), backend, 1024, options);
+    let store = |chunk: &[u8]| {
+        bs_p.store(chunk,
+                   hash::Hash::new(chunk),
+                   Kind::TreeLeaf,
+                   Box::new(|_: hash::tree::HashRef| {}))
+    };
+
+    // Each chunk fills most of a blob, so the blobs before it are stored and counted.
+    store(&[1; 800][..]).unwrap();
+    store(&[2; 800][..]).unwrap();
+    bs_p.flush().unwrap();
+    assert!(blob_index.stored_bytes() > 1600);
+    match store(&[3; 800][..]) {
+        Err(BlobError::Quota(e)) => assert_eq!(e.quota, 2000),
+        other => panic!("Expected a quota error, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(blob_index.count_by_status(BlobStatus::Committed), 2);
+
+    // Deleted blobs no longer count.
+    for blob in blob_index.list_by_status(BlobStatus::Committed, 1) {
+        blob_index.delete(&blob);
+    }
+    store(&[3; 800][..]).unwrap();
+}
+
+#[test]
+fn blob_zstd_identity() {
+    let chunk: Vec<u8> = "the quick brown fox jumps over the lazy dog "
+        .bytes()
+        .cycle()
+        .take(10000)
+        .collect();
+    let mut href = hash::tree::HashRef {
+        hash: hash::Hash::new(&chunk[..]),
+        persistent_ref: ChunkRef {
+            blob_id: Vec::new(),
+            offset: 0,
+            length: 0,
+            kind: Kind::TreeLeaf,
+            packing: Some(Packing::Zstd),
+            key: None,
+            raw_length: None,
+            nonce: None,
+            inline: None,
+        },
+    };
+
+    let mut b = Blob::new(20000);
+    assert!(b.try_append(&chunk[..], &mut href).unwrap());
+    assert!(href.persistent_ref.length < chunk.len());
+
+    let out = b.to_ciphertext().unwrap().unwrap().to_vec();
+    assert_eq!(chunk, Blob::read_chunk(&out, &href.hash, &href.persistent_ref).unwrap());
+
+    // The packing survives a round-trip through the blob footer.
+    let hrefs = Blob::new(20000).refs_from_bytes(&out).unwrap();
+    assert_eq!(Some(Packing::Zstd), hrefs[0].persistent_ref.packing);
+    assert_eq!(chunk, Blob::read_chunk(&out, &hrefs[0].hash, &hrefs[0].persistent_ref).unwrap());
+}
+
+#[test]
+fn blob_chacha20poly1305_identity() {
+    let chunk: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    let mut href = hash::tree::HashRef {
+        hash: hash::Hash::new(&chunk[..]),
+        persistent_ref: ChunkRef {
+            blob_id: Vec::new(),
+            offset: 0,
+            length: 0,
+            kind: Kind::TreeLeaf,
+            packing: None,
+            key: None,
+            raw_length: None,
+            nonce: None,
+            inline: None,
+        },
+    };
+
+    let mut b = Blob::new(20000);
+    b.set_cipher(Cipher::ChaCha20Poly1305);
+    assert!(b.try_append(&chunk[..], &mut href).unwrap());
+    match href.persistent_ref.key {
+        Some(Key::ChaCha20Poly1305(_)) => (),
+        ref other => panic!("Expected a ChaCha20Poly1305 key, got: {:?}", other),
+    }
+
+    let out = b.to_ciphertext().unwrap().unwrap().to_vec();
+    assert_eq!(chunk, Blob::read_chunk(&out, &href.hash, &href.persistent_ref).unwrap());
+
+    // The key survives a round-trip through the blob footer.
+    let hrefs = Blob::new(20000).refs_from_bytes(&out).unwrap();
+    assert_eq!(href.persistent_ref.key, hrefs[0].persistent_ref.key);
+    assert_eq!(chunk, Blob::read_chunk(&out, &hrefs[0].hash, &hrefs[0].persistent_ref).unwrap());
+}
+
+#[test]
+fn blob_random_nonce_per_chunk() {
+    let chunk: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    for cipher in vec![Cipher::XSalsa20Poly1305, Cipher::ChaCha20Poly1305] {
+        let mut b = Blob::new(100000);
+        b.set_cipher(cipher);
+
+        // The same chunk twice, so that the nonces differ only if they are random.
+        let mut hrefs = Vec::new();
+        for _ in 0..2 {
+            let mut href = hash::tree::HashRef {
+                hash: hash::Hash::new(&chunk[..]),
+                persistent_ref: ChunkRef {
+                    blob_id: Vec::new(),
+                    offset: 0,
+                    length: 0,
+                    kind: Kind::TreeLeaf,
+                    packing: None,
+                    key: None,
+                    raw_length: None,
+                    nonce: None,
+                    inline: None,
+                },
+            };
+            assert!(b.try_append(&chunk[..], &mut href).unwrap());
+            hrefs.push(href);
+        }
+        let nonces: Vec<Vec<u8>> =
+            hrefs.iter().map(|h| h.persistent_ref.nonce.clone().unwrap()).collect();
+        assert!(nonces[0] != nonces[1]);
+
+        let out = b.to_ciphertext().unwrap().unwrap().to_vec();
+        for href in hrefs.iter() {
+            assert_eq!(chunk, Blob::read_chunk(&out, &href.hash, &href.persistent_ref).unwrap());
+        }
+
+        // The nonces survive a round-trip through the blob footer.
+        let read = Blob::new(100000).refs_from_bytes(&out).unwrap();
+        let read_nonces: Vec<Vec<u8>> =
+            read.iter().map(|h| h.persistent_ref.nonce.clone().unwrap()).collect();
+        assert_eq!(read_nonces, nonces);
+    }
+
+    // Nonces taken from the hash are not recorded.
+    let mut b = Blob::new(100000);
+    b.set_nonce_strategy(NonceStrategy::FromHash);
+    let mut href = hash::tree::HashRef {
+        hash: hash::Hash::new(&chunk[..]),
+        persistent_ref: ChunkRef {
+            blob_id: Vec::new(),
+            offset: 0,
+            length: 0,
+            kind: Kind::TreeLeaf,
+            packing: None,
+            key: None,
+            raw_length: None,
+            nonce: None,
+            inline: None,
+        },
+    };
+    assert!(b.try_append(&chunk[..], &mut href).unwrap());
+    assert_eq!(href.persistent_ref.nonce, None);
+    let out = b.to_ciphertext().unwrap().unwrap().to_vec();
+    assert_eq!(chunk, Blob::read_chunk(&out, &href.hash, &href.persistent_ref).unwrap());
+}
+
+#[test]
+fn nonce_filter_forgets_oldest() {
+    let mut filter = super::NonceFilter::new(2);
+    assert!(filter.insert(&[1]));
+    assert!(filter.insert(&[2]));
+    assert!(!filter.insert(&[1]));
+
+    // Remembering a third nonce forgets the first.
+    assert!(filter.insert(&[3]));
+    assert!(filter.insert(&[1]));
+    assert!(!filter.insert(&[3]));
+}
+
 #[test]
 fn blob_reuse() {
     let mut c1 = hash::tree::HashRef {
@@ -136,15 +918,18 @@ fn blob_reuse() {
             kind: Kind::TreeLeaf,
             packing: None,
             key: None,
+            raw_length: None,
+            nonce: None,
+            inline: None,
         },
     };
     let mut c2 = c1.clone();
 
     let mut b = Blob::new(1000);
-    b.try_append(&[1, 2, 3], &mut c1).unwrap();
-    b.try_append(&[4, 5, 6], &mut c2).unwrap();
+    assert!(b.try_append(&[1, 2, 3], &mut c1).unwrap());
+    assert!(b.try_append(&[4, 5, 6], &mut c2).unwrap());
 
-    let out = b.to_ciphertext().unwrap().to_vec();
+    let out = b.to_ciphertext().unwrap().unwrap().to_vec();
 
     assert_eq!(vec![1, 2, 3],
                Blob::read_chunk(&out, &c1.hash, &c1.persistent_ref).unwrap());
@@ -153,11 +938,11 @@ fn blob_reuse() {
 
     let mut c3 = c2.clone();
 
-    b.try_append(&[1, 2], &mut c1).unwrap();
-    b.try_append(&[1, 2], &mut c2).unwrap();
-    b.try_append(&[1, 2], &mut c3).unwrap();
+    assert!(b.try_append(&[1, 2], &mut c1).unwrap());
+    assert!(b.try_append(&[1, 2], &mut c2).unwrap());
+    assert!(b.try_append(&[1, 2], &mut c3).unwrap());
 
-    let out = b.to_ciphertext().unwrap().to_vec();
+    let out = b.to_ciphertext().unwrap().unwrap().to_vec();
     assert_eq!(vec![1, 2],
                Blob::read_chunk(&out, &c1.hash, &c1.persistent_ref).unwrap());
     assert_eq!(vec![1, 2],
@@ -166,6 +951,69 @@ fn blob_reuse() {
                Blob::read_chunk(&out, &c3.hash, &c3.persistent_ref).unwrap());
 }
 
+#[test]
+fn blob_index_status() {
+    let bi = BlobIndex::new_for_testing().unwrap();
+
+    // A reserved blob is not recorded until its upload starts.
+    let b1 = bi.reserve();
+    let b2 = bi.reserve();
+    assert_eq!(None, bi.status(&b1.name[..]));
+
+    bi.in_air(&b1);
+    bi.in_air(&b2);
+    assert_eq!(Some(BlobStatus::InProgress), bi.status(&b1.name[..]));
+    assert_eq!(2, bi.count_by_status(BlobStatus::InProgress));
+
+    bi.commit_done(&b1);
+    assert_eq!(Some(BlobStatus::Committed), bi.status(&b1.name[..]));
+    assert_eq!(Some(BlobStatus::InProgress), bi.status(&b2.name[..]));
+    assert_eq!(1, bi.count_by_status(BlobStatus::Committed));
+    let in_progress = bi.list_by_status(BlobStatus::InProgress, 10);
+    assert_eq!(1, in_progress.len());
+    assert_eq!(b2.name, in_progress[0].name);
+
+    bi.tag(&b1, tags::Tag::WillDelete);
+    assert_eq!(Some(BlobStatus::Deletable), bi.status(&b1.name[..]));
+    assert_eq!(0, bi.count_by_status(BlobStatus::Committed));
+}
+
+#[test]
+fn blob_store_names_blobs_by_content() {
+    let chunks: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 1000]).collect();
+    let store_all = |blob_index: Arc<BlobIndex>| {
+        let backend = Arc::new(MemoryBackend::new());
+        let options = StoreOptions { blob_naming: BlobNaming::Content, ..StoreOptions::default() };
+        let bs_p = BlobStore::with_options(blob_index, backend, 4096, options);
+        let hrefs: Vec<_> = chunks.iter()
+            .map(|chunk| {
+                bs_p.store(&chunk[..],
+                           hash::Hash::new(&chunk[..]),
+                           Kind::TreeLeaf,
+                           Box::new(move |_| {}))
+                    .unwrap()
+            })
+            .collect();
+        bs_p.flush().unwrap();
+        for (href, chunk) in hrefs.iter().zip(chunks.iter()) {
+            assert_eq!(&bs_p.retrieve(&href.hash, &href.persistent_ref).unwrap(), chunk);
+        }
+        hrefs.into_iter().map(|h| h.persistent_ref.blob_id).collect::<Vec<_>>()
+    };
+
+    // The same chunks stored into separate indexes end up in blobs named alike.
+    let first = store_all(Arc::new(BlobIndex::new_for_testing().unwrap()));
+    let second = store_all(Arc::new(BlobIndex::new_for_testing().unwrap()));
+    assert_eq!(first, second);
+    assert!(first.iter().collect::<HashSet<_>>().len() > 2);
+
+    // Stored again into the same index, they do not take the names of the blobs already there.
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let first = store_all(blob_index.clone());
+    let again = store_all(blob_index);
+    assert!(again.iter().all(|name| !first.contains(name)));
+}
+
 #[test]
 fn blob_identity() {
     fn prop(chunks: Vec<Vec<u8>>) -> bool {
@@ -182,16 +1030,20 @@ fn blob_identity() {
                     kind: Kind::TreeLeaf,
                     packing: None,
                     key: None,
+                    raw_length: None,
+                    nonce: None,
+                    inline: None,
                 },
             };
-            if let Err(_) = b.try_append(&chunk[..], &mut cref) {
-                assert!(b.upperbound_len() + chunk.len() + cref.as_bytes().len() + 50 >= max_size);
+            if !b.try_append(&chunk[..], &mut cref).unwrap() {
+                let cref_len = cref.as_bytes().unwrap().len();
+                assert!(b.upperbound_len() + chunk.len() + cref_len + 50 >= max_size);
                 break;
             }
             n = n + 1;
         }
 
-        let out = match b.to_ciphertext() {
+        let out = match b.to_ciphertext().unwrap() {
             None => {
                 assert_eq!(n, 0);
                 return true;
@@ -229,14 +1081,16 @@ fn empty_blocks_blob_ciphertext(blob: &mut Blob, blocksize: usize) -> Vec<u8> {
                 kind: Kind::TreeLeaf,
                 packing: None,
                 key: None,
+                raw_length: None,
+                nonce: None,
+                inline: None,
             },
         };
-        match blob.try_append(&block[..], &mut cref) {
-            Ok(()) => continue,
-            Err(_) => break,
+        if !blob.try_append(&block[..], &mut cref).unwrap() {
+            break;
         }
     }
-    blob.to_ciphertext().unwrap().to_vec()
+    blob.to_ciphertext().unwrap().unwrap().to_vec()
 }
 
 #[test]
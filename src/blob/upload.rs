@@ -0,0 +1,97 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A bounded set of blob uploads running concurrently.
+
+use std::cmp;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use backend::BackendError;
+
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+    failure: Option<String>,
+}
+
+pub struct Uploads {
+    max_in_flight: usize,
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+/// Marks an upload as finished when dropped, also when the upload panicked.
+struct Finish(Arc<(Mutex<State>, Condvar)>);
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        let &(ref lock, ref cond) = &*self.0;
+        let mut state = lock.lock().unwrap();
+        state.in_flight -= 1;
+        if thread::panicking() && state.failure.is_none() {
+            state.failure = Some("upload thread panicked".to_owned());
+        }
+        cond.notify_all();
+    }
+}
+
+impl Uploads {
+    pub fn new(max_in_flight: usize) -> Uploads {
+        Uploads {
+            max_in_flight: cmp::max(1, max_in_flight),
+            state: Arc::new((Mutex::new(State::default()), Condvar::new())),
+        }
+    }
+
+    /// Run `upload` on its own thread. Blocks while the maximum number of uploads is in flight,
+    /// which caps the memory held by blobs waiting to be stored.
+    pub fn spawn<F>(&self, upload: F)
+        where F: FnOnce() -> Result<(), BackendError> + Send + 'static
+    {
+        {
+            let &(ref lock, ref cond) = &*self.state;
+            let mut state = lock.lock().unwrap();
+            while state.in_flight >= self.max_in_flight && state.failure.is_none() {
+                state = cond.wait(state).unwrap();
+            }
+            if let Some(ref e) = state.failure {
+                panic!("Store operation failed: {}", e);
+            }
+            state.in_flight += 1;
+        }
+
+        let finish = Finish(self.state.clone());
+        thread::spawn(move || {
+            if let Err(e) = upload() {
+                error!("Could not store blob: {}", e);
+                let mut state = (finish.0).0.lock().unwrap();
+                state.failure = Some(e.to_string());
+            }
+            drop(finish);
+        });
+    }
+
+    /// Wait until every upload has finished. Panics if any of them failed.
+    pub fn wait(&self) {
+        let &(ref lock, ref cond) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.in_flight > 0 {
+            state = cond.wait(state).unwrap();
+        }
+        if let Some(ref e) = state.failure {
+            panic!("Store operation failed: {}", e);
+        }
+    }
+}
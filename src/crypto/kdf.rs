@@ -0,0 +1,147 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Derive the master key from a passphrase with Argon2id.
+
+use libc::{c_char, c_int, c_ulonglong, size_t};
+use sodiumoxide::randombytes::randombytes;
+use sodiumoxide::version;
+
+use capnp;
+use root_capnp;
+
+use crypto::authed;
+use errors::CryptoError;
+
+
+// Provided by libsodium, which `libsodium_sys` links. `sodiumoxide` has no binding for it yet.
+// Argon2id is only supported from libsodium 1.0.13 on; see `has_argon2id()`.
+extern "C" {
+    fn crypto_pwhash(out: *mut u8,
+                     outlen: c_ulonglong,
+                     passwd: *const c_char,
+                     passwdlen: c_ulonglong,
+                     salt: *const u8,
+                     opslimit: c_ulonglong,
+                     memlimit: size_t,
+                     alg: c_int)
+                     -> c_int;
+}
+
+const ALG_ARGON2ID13: c_int = 2;
+
+pub const SALTBYTES: usize = 16;
+
+/// The smallest costs Argon2id accepts.
+pub const OPSLIMIT_MIN: u64 = 1;
+pub const MEMLIMIT_MIN: u64 = 8192;
+
+/// libsodium's costs for interactive use.
+pub const OPSLIMIT_INTERACTIVE: u64 = 2;
+pub const MEMLIMIT_INTERACTIVE: u64 = 64 * 1024 * 1024;
+
+
+/// Everything but the passphrase needed to derive the master key again.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Params {
+    pub salt: Vec<u8>,
+    /// Argon2id time cost (number of passes).
+    pub ops_limit: u64,
+    /// Argon2id memory cost in bytes.
+    pub mem_limit: u64,
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params::new(OPSLIMIT_INTERACTIVE, MEMLIMIT_INTERACTIVE)
+    }
+}
+
+impl Params {
+    /// New parameters with the given costs and a random salt.
+    pub fn new(ops_limit: u64, mem_limit: u64) -> Params {
+        Params {
+            salt: randombytes(SALTBYTES),
+            ops_limit: ops_limit,
+            mem_limit: mem_limit,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let mut root = message.init_root::<root_capnp::kdf_params::Builder>();
+            root.set_salt(&self.salt[..]);
+            root.set_ops_limit(self.ops_limit);
+            root.set_mem_limit(self.mem_limit);
+        }
+        let mut out = Vec::new();
+        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Params, CryptoError> {
+        let options = capnp::message::ReaderOptions::new();
+        let reader = try!(capnp::serialize_packed::read_message(&mut &bytes[..], options)
+            .map_err(|_| "Could not read key derivation parameters"));
+        let msg = try!(reader.get_root::<root_capnp::kdf_params::Reader>()
+            .map_err(|_| "Could not read key derivation parameters"));
+        let salt = try!(msg.get_salt().map_err(|_| "Could not read key derivation salt"));
+        Ok(Params {
+            salt: salt.to_vec(),
+            ops_limit: msg.get_ops_limit(),
+            mem_limit: msg.get_mem_limit(),
+        })
+    }
+}
+
+// Whether the linked libsodium supports `ALG_ARGON2ID13`.
+fn has_argon2id() -> bool {
+    let release: Vec<u32> = version::version_string()
+        .split('.')
+        .map(|n| n.parse().unwrap_or(0))
+        .collect();
+    release >= vec![1, 0, 13]
+}
+
+/// Derive a key from `passphrase`; the same passphrase and parameters always give the same key.
+/// This needs libsodium 1.0.13 or later.
+pub fn derive_key(passphrase: &[u8], params: &Params) -> Result<authed::desc::Key, CryptoError> {
+    if !has_argon2id() {
+        return Err(From::from("Key derivation needs libsodium 1.0.13 or later"));
+    }
+    if params.salt.len() != SALTBYTES {
+        return Err(From::from("Key derivation salt has the wrong length"));
+    }
+    if params.ops_limit < OPSLIMIT_MIN || params.mem_limit < MEMLIMIT_MIN {
+        return Err(From::from("Key derivation costs are below the minimum"));
+    }
+
+    let mut key = vec![0; authed::desc::KEYBYTES];
+    let res = unsafe {
+        crypto_pwhash(key.as_mut_ptr(),
+                      key.len() as c_ulonglong,
+                      passphrase.as_ptr() as *const c_char,
+                      passphrase.len() as c_ulonglong,
+                      params.salt.as_ptr(),
+                      params.ops_limit as c_ulonglong,
+                      params.mem_limit as size_t,
+                      ALG_ARGON2ID13)
+    };
+    if res != 0 {
+        // Most likely out of memory.
+        return Err(From::from("Key derivation failed"));
+    }
+    Ok(authed::desc::Key::from_slice(&key[..]).unwrap())
+}
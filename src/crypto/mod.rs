@@ -13,12 +13,15 @@
 // limitations under the License.
 
 use errors::CryptoError;
+use sodiumoxide::crypto::scalarmult::curve25519::{Scalar, scalarmult_base};
 use sodiumoxide::crypto::stream;
 use hash::Hash;
 use hash::tree::HashRef;
-use blob::{ChunkRef, Key};
+use blob::{Cipher, ChunkRef, Key, NonceStrategy};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
+pub mod kdf;
+
 pub struct PlainText(Vec<u8>);
 pub struct PlainTextRef<'a>(&'a [u8]);
 pub struct CipherText{
@@ -38,6 +41,18 @@ pub mod authed {
     }
 }
 
+pub mod aead {
+    pub mod desc {
+        pub use sodiumoxide::crypto::aead::chacha20poly1305_ietf::{KEYBYTES, Key, NONCEBYTES,
+                                                                   Nonce};
+    }
+
+    pub mod imp {
+        pub use sodiumoxide::crypto::aead::chacha20poly1305_ietf::{gen_key, gen_nonce, open,
+                                                                   seal};
+    }
+}
+
 pub mod sealed {
     pub mod desc {
         pub use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::{MACBYTES, PUBLICKEYBYTES,
@@ -69,10 +84,6 @@ pub mod sealed {
 
 
 
-fn wrap_key(key: authed::desc::Key) -> Key {
-    Key::XSalsa20Poly1305(key)
-}
-
 impl<'a> PlainTextRef<'a> {
     pub fn new(bytes: &[u8]) -> PlainTextRef {
         PlainTextRef(bytes)
@@ -86,6 +97,12 @@ impl<'a> PlainTextRef<'a> {
                          -> CipherText {
         CipherText::new(authed::imp::seal(&self.0, &nonce, &key))
     }
+    pub fn to_aead_ciphertext(&self,
+                              nonce: &aead::desc::Nonce,
+                              key: &aead::desc::Key)
+                              -> CipherText {
+        CipherText::new(aead::imp::seal(&self.0, None, &nonce, &key))
+    }
     pub fn to_sealed_ciphertext(&self, pubkey: &sealed::desc::PublicKey) -> CipherText {
         CipherText::new(sealed::imp::seal(&self.0, &pubkey))
     }
@@ -169,6 +186,13 @@ impl<'a> CipherTextRef<'a> {
         Ok((PlainText::new(try!(authed::imp::open(&self.0, &nonce, &key)
             .map_err(|()| "Crypto failed to open authenticated message")))))
     }
+    pub fn to_aead_plaintext(&self,
+                             nonce: &aead::desc::Nonce,
+                             key: &aead::desc::Key)
+                             -> Result<PlainText, CryptoError> {
+        Ok(PlainText::new(try!(aead::imp::open(&self.0, None, &nonce, &key)
+            .map_err(|()| "Crypto failed to open authenticated message"))))
+    }
     pub fn to_sealed_plaintext(&self,
                                pubkey: &sealed::desc::PublicKey,
                                seckey: &sealed::desc::SecretKey)
@@ -182,14 +206,64 @@ impl<'a> CipherTextRef<'a> {
 pub struct RefKey {}
 
 
-impl RefKey {
-    pub fn seal(href: &mut HashRef, pt: PlainTextRef) -> CipherText {
-        let key = authed::imp::gen_key();
-        href.persistent_ref.key = Some(wrap_key(key.clone()));
+// The nonce a chunk was encrypted with: the one recorded in its reference, or else the start of
+// its hash.
+fn chunk_nonce<'a>(hash: &'a Hash, cref: &'a ChunkRef, len: usize) -> &'a [u8] {
+    match cref.nonce {
+        Some(ref nonce) => &nonce[..],
+        None => &hash.bytes[..len],
+    }
+}
 
-        let nonce = authed::desc::Nonce::from_slice(&href.hash.bytes[..authed::desc::NONCEBYTES])
-            .unwrap();
-        let ct = pt.to_ciphertext(&nonce, &key);
+impl RefKey {
+    /// Encrypt `pt` with a fresh key, and record the key (and with `NonceStrategy::Random`, the
+    /// nonce) in `href`.
+    pub fn seal(href: &mut HashRef,
+                pt: PlainTextRef,
+                cipher: Cipher,
+                strategy: NonceStrategy)
+                -> CipherText {
+        href.persistent_ref.nonce = None;
+        let ct = match cipher {
+            Cipher::XSalsa20Poly1305 => {
+                let key = authed::imp::gen_key();
+                href.persistent_ref.key = Some(Key::XSalsa20Poly1305(key.clone()));
+
+                let nonce = match strategy {
+                    NonceStrategy::Random => {
+                        let nonce = authed::imp::gen_nonce();
+                        href.persistent_ref.nonce = Some(nonce.0.to_vec());
+                        nonce
+                    }
+                    NonceStrategy::FromHash => {
+                        let len = authed::desc::NONCEBYTES;
+                        authed::desc::Nonce::from_slice(&href.hash.bytes[..len]).unwrap()
+                    }
+                };
+                pt.to_ciphertext(&nonce, &key)
+            }
+            Cipher::ChaCha20Poly1305 => {
+                let key = aead::imp::gen_key();
+                href.persistent_ref.key = Some(Key::ChaCha20Poly1305(key.clone()));
+
+                let nonce = match strategy {
+                    NonceStrategy::Random => {
+                        let nonce = aead::imp::gen_nonce();
+                        href.persistent_ref.nonce = Some(nonce.0.to_vec());
+                        nonce
+                    }
+                    NonceStrategy::FromHash => {
+                        let len = aead::desc::NONCEBYTES;
+                        aead::desc::Nonce::from_slice(&href.hash.bytes[..len]).unwrap()
+                    }
+                };
+                pt.to_aead_ciphertext(&nonce, &key)
+            }
+            Cipher::Unencrypted => {
+                href.persistent_ref.key = None;
+                CipherText::new(pt.0.to_vec())
+            }
+        };
         href.persistent_ref.length = ct.len();
 
         ct
@@ -203,16 +277,24 @@ impl RefKey {
         let ct = ct.slice(cref.offset, cref.offset + cref.length);
         match cref.key {
             Some(Key::XSalsa20Poly1305(ref key)) => {
-                let nonce =
-                    authed::desc::Nonce::from_slice(&hash.bytes[..authed::desc::NONCEBYTES])
-                        .unwrap();
+                let nonce = chunk_nonce(hash, cref, authed::desc::NONCEBYTES);
+                let nonce = try!(authed::desc::Nonce::from_slice(nonce)
+                    .ok_or("Incorrect nonce size in chunk reference"));
                 Ok(try!(ct.to_plaintext(&nonce, &key)))
             }
-            _ => panic!("Unknown blob key type"),
+            Some(Key::ChaCha20Poly1305(ref key)) => {
+                let nonce = chunk_nonce(hash, cref, aead::desc::NONCEBYTES);
+                let nonce = try!(aead::desc::Nonce::from_slice(nonce)
+                    .ok_or("Incorrect nonce size in chunk reference"));
+                Ok(try!(ct.to_aead_plaintext(&nonce, &key)))
+            }
+            // Stored without encryption.
+            None => Ok(PlainText::new(ct.0.to_vec())),
         }
     }
 }
 
+#[derive(Clone)]
 pub struct FixedKey {
     pubkey: sealed::desc::PublicKey,
     seckey: Option<sealed::desc::SecretKey>,
@@ -228,6 +310,14 @@ impl FixedKey {
         }
     }
 
+    /// The key pair whose secret key is `master_key`, e.g. as derived by `kdf::derive_key`.
+    pub fn from_master_key(master_key: &authed::desc::Key) -> FixedKey {
+        let seckey = sealed::desc::SecretKey::from_slice(&master_key.0[..]).unwrap();
+        let point = scalarmult_base(&Scalar::from_slice(&master_key.0[..]).unwrap());
+        let pubkey = sealed::desc::PublicKey::from_slice(&point.0[..]).unwrap();
+        FixedKey::new(pubkey, Some(seckey))
+    }
+
     pub fn seal(&self, pt: PlainTextRef) -> CipherText {
         let mut ct = pt.to_sealed_ciphertext(&self.pubkey);
 
@@ -250,6 +340,9 @@ impl FixedKey {
         let seckey = self.seckey.as_ref().expect("unseal requires access to read-key");
 
         // Read sealed ciphertext length and unseal it.
+        if ct.len() < sealed::desc::footer_cipher_bytes() {
+            return Err(From::from("Ciphertext is too short to have a footer"));
+        }
         let (rest, foot_ct) = ct.split_from_right(sealed::desc::footer_cipher_bytes());
         let foot_pt = try!(foot_ct.to_sealed_plaintext(&self.pubkey, &seckey));
         assert_eq!(foot_pt.len(), sealed::desc::footer_plain_bytes());
@@ -258,6 +351,9 @@ impl FixedKey {
         let ct_len = foot_pt.as_bytes().read_u64::<LittleEndian>().unwrap();
 
         // Read and unseal original ciphertext.
+        if (rest.len() as u64) < ct_len {
+            return Err(From::from("Ciphertext is shorter than its footer says"));
+        }
         let (rest, ct) = rest.split_from_right(ct_len as usize);
         Ok((rest, try!(ct.to_sealed_plaintext(&self.pubkey, &seckey))))
     }
@@ -33,6 +33,123 @@ impl error::Error for RetryError {
     }
 }
 
+/// The operation was stopped early through its `CancelToken`.
+#[derive(Clone, Copy, Debug)]
+pub struct CancelledError;
+
+impl fmt::Display for CancelledError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Operation was cancelled")
+    }
+}
+
+impl error::Error for CancelledError {
+    fn description(&self) -> &str {
+        "Operation cancelled"
+    }
+}
+
+/// A blob read from the backend does not match the digest recorded when it was stored.
+#[derive(Clone, Debug)]
+pub struct IntegrityError {
+    pub blob_id: Vec<u8>,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Blob {:?} does not match its recorded digest", self.blob_id)
+    }
+}
+
+impl error::Error for IntegrityError {
+    fn description(&self) -> &str {
+        "Blob integrity check failed"
+    }
+}
+
+/// A chunk refers to a blob that the backend does not have, e.g. because it was deleted.
+#[derive(Clone, Debug)]
+pub struct BlobNotFoundError {
+    pub blob_id: Vec<u8>,
+}
+
+impl fmt::Display for BlobNotFoundError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Blob {:?} was not found in the backend", self.blob_id)
+    }
+}
+
+impl error::Error for BlobNotFoundError {
+    fn description(&self) -> &str {
+        "Blob not found"
+    }
+}
+
+/// A hasher made a digest of a length that `Hash::with_hasher` cannot tell apart from a BLAKE2b
+/// hash once prefixed by the hasher's id.
+#[derive(Clone, Copy, Debug)]
+pub struct HasherError {
+    pub hasher_id: u8,
+    pub digest_len: usize,
+}
+
+impl fmt::Display for HasherError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f,
+               "Hasher {} made a digest of {} bytes, which reads as a BLAKE2b hash",
+               self.hasher_id,
+               self.digest_len)
+    }
+}
+
+impl error::Error for HasherError {
+    fn description(&self) -> &str {
+        "Unsupported hasher digest length"
+    }
+}
+
+/// SQLite gave up waiting for another connection to release a local index, e.g. another process
+/// using the same repository.
+#[derive(Debug)]
+pub struct IndexLockedError(pub DieselError);
+
+impl fmt::Display for IndexLockedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Index is locked by another connection: {}", self.0)
+    }
+}
+
+impl error::Error for IndexLockedError {
+    fn description(&self) -> &str {
+        "Index locked"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.0)
+    }
+}
+
+/// Storing more data would grow the blobs in the store beyond the configured quota.
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaError {
+    /// The configured quota in bytes.
+    pub quota: u64,
+    /// The bytes the store would have held with the rejected data.
+    pub needed: u64,
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Storing {} bytes would exceed the quota of {} bytes", self.needed, self.quota)
+    }
+}
+
+impl error::Error for QuotaError {
+    fn description(&self) -> &str {
+        "Storage quota exceeded"
+    }
+}
+
 mod hat_error {
     use std::{io, str};
     use std::borrow::Cow;
@@ -40,6 +157,7 @@ mod hat_error {
     use capnp;
     use void;
 
+    use backend::BackendError;
     use blob;
     use key;
 
@@ -63,18 +181,92 @@ mod hat_error {
                 from (s: &'static str) s.into();
                 from (s: String) s.into();
             },
-            DieselError(super::DieselError) {
+            DieselError(Box<super::DieselError>) {
                 cause;
             },
             Crypto(super::CryptoError) {
                 cause;
             },
-            Blob(blob::BlobError) {
+            Blob(Box<blob::BlobError>) {
+                cause;
+            },
+            Integrity(super::IntegrityError) {
+                cause;
+            },
+            BlobNotFound(super::BlobNotFoundError) {
+                cause;
+            },
+            BackendUnavailable(super::RetryError) {
+                cause;
+            },
+            IndexLocked(super::IndexLockedError) {
+                cause;
+            },
+            Cancelled(super::CancelledError) {
+                cause;
+            },
+            Quota(super::QuotaError) {
+                cause;
+            },
+            Hasher(super::HasherError) {
                 cause;
             },
         }
     }
 
+    impl HatError {
+        /// Sorts the blob errors that callers may want to recover from (missing and damaged
+        /// blobs, an unavailable backend, the quota) into their own variants. `From` does the
+        /// same.
+        pub fn from_blob_error(e: blob::BlobError) -> HatError {
+            match e {
+                blob::BlobError::Integrity(e) => HatError::Integrity(e),
+                blob::BlobError::NotFound(e) => HatError::BlobNotFound(e),
+                blob::BlobError::Quota(e) => HatError::Quota(e),
+                blob::BlobError::Backend(BackendError::Retry(e)) => HatError::BackendUnavailable(e),
+                e => HatError::Blob(Box::new(e)),
+            }
+        }
+
+        /// Like `from_blob_error`, for errors of the backend itself.
+        pub fn from_backend_error(e: BackendError) -> HatError {
+            HatError::from_blob_error(blob::BlobError::Backend(e))
+        }
+
+        /// Like `From`, but keeps the blob errors of a key store apart as `from_blob_error` does,
+        /// and its index errors as `from_diesel_error` does.
+        pub fn from_key_error(e: key::MsgError) -> HatError {
+            match e {
+                key::MsgError::Blob(e) => HatError::from_blob_error(e),
+                key::MsgError::DieselError(e) => HatError::from_diesel_error(e),
+                key::MsgError::Cancelled(e) => HatError::Cancelled(e),
+                key::MsgError::Hasher(e) => HatError::Hasher(e),
+                e => HatError::Keys(e),
+            }
+        }
+
+        /// Reports an index held by another connection as `IndexLocked`. `From` does the same.
+        pub fn from_diesel_error(e: super::DieselError) -> HatError {
+            if e.is_locked() {
+                HatError::IndexLocked(super::IndexLockedError(e))
+            } else {
+                HatError::DieselError(Box::new(e))
+            }
+        }
+    }
+
+    impl From<blob::BlobError> for HatError {
+        fn from(e: blob::BlobError) -> HatError {
+            HatError::from_blob_error(e)
+        }
+    }
+
+    impl From<super::DieselError> for HatError {
+        fn from(e: super::DieselError) -> HatError {
+            HatError::from_diesel_error(e)
+        }
+    }
+
     impl From<void::Void> for HatError {
         fn from(val: void::Void) -> HatError {
             void::unreachable(val)
@@ -84,6 +276,7 @@ mod hat_error {
 
 mod diesel_error {
     use diesel;
+    use diesel::result;
 
     error_type! {
         #[derive(Debug)]
@@ -102,6 +295,20 @@ mod diesel_error {
             },
         }
     }
+
+    impl DieselError {
+        /// Whether SQLite gave up waiting for another connection to release the database (see
+        /// `IndexOptions::busy_timeout_ms`).
+        pub fn is_locked(&self) -> bool {
+            match *self {
+                DieselError::SqlExecute(result::Error::DatabaseError(_, ref info)) => {
+                    let msg = info.message();
+                    msg.contains("database is locked") || msg.contains("database table is locked")
+                }
+                _ => false,
+            }
+        }
+    }
 }
 
 mod crypto_error {
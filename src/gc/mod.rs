@@ -63,6 +63,7 @@ pub trait GcBackend {
     fn reverse_refs(&self, hash_id: Id) -> Result<Vec<Id>, Self::Err>;
 
     fn list_ids_by_tag(&self, tag: tags::Tag) -> Result<mpsc::Receiver<Id>, Self::Err>;
+    fn list_all_ids(&self) -> Result<mpsc::Receiver<Id>, Self::Err>;
 
     fn manual_commit(&mut self) -> Result<(), Self::Err>;
 }
@@ -114,6 +115,9 @@ pub trait Gc<B> {
 
     fn list_unused_ids(&mut self, refs: mpsc::Sender<Id>) -> Result<(), Self::Err>;
 
+    /// Like `list_unused_ids`, but without changing any state (e.g. tags) in the backend.
+    fn list_unused_ids_dry_run(&self, refs: mpsc::Sender<Id>) -> Result<(), Self::Err>;
+
     fn status(&mut self, final_ref: Id) -> Result<Option<Status>, Self::Err>;
 }
 
@@ -283,6 +287,19 @@ impl GcBackend for SafeMemoryBackend {
         Ok(receiver)
     }
 
+    fn list_all_ids(&self) -> Result<mpsc::Receiver<Id>, Self::Err> {
+        let backend = self.backend.lock().unwrap();
+        let mut ids: Vec<Id> =
+            backend.snapshot_refs.values().flat_map(|refs| refs.clone()).collect();
+        ids.sort();
+        ids.dedup();
+
+        let (sender, receiver) = mpsc::channel();
+        ids.iter().map(|id| sender.send(*id)).last();
+
+        Ok(receiver)
+    }
+
     fn manual_commit(&mut self) -> Result<(), Self::Err> {
         self.commit();
         Ok(())
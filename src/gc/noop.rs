@@ -69,6 +69,10 @@ impl<B: gc::GcBackend> gc::Gc<B> for GcNoop {
         Ok(())
     }
 
+    fn list_unused_ids_dry_run(&self, _refs: mpsc::Sender<gc::Id>) -> Result<(), Self::Err> {
+        Ok(())
+    }
+
     fn status(&mut self, _final_ref: gc::Id) -> Result<Option<gc::Status>, Self::Err> {
         Ok(Some(gc::Status::Complete))
     }
@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::mpsc;
+use std::cmp;
+use std::collections::HashSet;
+use std::sync::{Mutex, mpsc};
+
+use scoped_pool;
 
 use hash::GcData;
 use snapshot;
@@ -24,11 +28,111 @@ use tags;
 // Instead this constant family ID is always used.
 const DATA_FAMILY: i64 = 0;
 
+// Stored as `GcData.bytes` once an ID has been registered by a snapshot. It is kept when the
+// snapshot is deregistered, so unused IDs can be told apart from ones never committed at all.
+const REGISTERED: &'static [u8] = b"r";
+
 
 pub struct GcRc<B> {
     backend: B,
 }
 
+impl<B> GcRc<B>
+    where B: gc::GcBackend + Sync,
+          B::Err: Send
+{
+    /// Like `list_unused_ids`, but walks the trees of used IDs with up to `workers` threads.
+    ///
+    /// Used IDs are collected into one set, so the result (and the tags left behind) are the same
+    /// as for `list_unused_ids`.
+    pub fn list_unused_ids_parallel(&mut self,
+                                    refs: mpsc::Sender<gc::Id>,
+                                    workers: usize)
+                                    -> Result<(), B::Err> {
+        let workers = cmp::max(workers, 1);
+        let all: Vec<gc::Id> = try!(self.backend.list_all_ids()).iter().collect();
+        let used = Mutex::new(HashSet::new());
+        let error = Mutex::new(None);
+        {
+            let backend = &self.backend;
+            let (used, error) = (&used, &error);
+            let pool = scoped_pool::Pool::new(workers);
+            pool.scoped(|scope| {
+                for roots in all.chunks(cmp::max(all.len() / workers, 1)) {
+                    scope.execute(move || {
+                        if let Err(e) = mark_used(backend, roots, used) {
+                            *error.lock().unwrap() = Some(e);
+                        }
+                    });
+                }
+            });
+            pool.shutdown();
+        }
+        if let Some(e) = error.into_inner().unwrap() {
+            return Err(e);
+        }
+
+        // Leave the same tags behind as `list_unused_ids`.
+        try!(self.backend.set_all_tags(tags::Tag::Done));
+        for &r in used.lock().unwrap().iter() {
+            try!(self.backend.set_tag(r, tags::Tag::Reserved));
+        }
+        for r in try!(self.backend.list_ids_by_tag(tags::Tag::Done)).iter() {
+            if let Err(_) = refs.send(r) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the IDs among `unused` that were once part of a registered snapshot: the ones
+    /// registered themselves, and the unused IDs they reference. The others were never committed,
+    /// e.g. because their snapshot was aborted before it was registered.
+    pub fn list_registered_ids(&self, unused: &[gc::Id]) -> Result<HashSet<gc::Id>, B::Err> {
+        let unused: HashSet<gc::Id> = unused.iter().cloned().collect();
+        let mut registered = HashSet::new();
+        for &r in unused.iter() {
+            let data = try!(self.backend.get_data(r, DATA_FAMILY));
+            if data.bytes.as_slice() == REGISTERED && registered.insert(r) {
+                let mut todo = vec![r];
+                while let Some(id) = todo.pop() {
+                    for child in try!(self.backend.reverse_refs(id)) {
+                        if unused.contains(&child) && registered.insert(child) {
+                            todo.push(child);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(registered)
+    }
+}
+
+// Add the used IDs among `roots`, and everything they reference, to `used`.
+fn mark_used<B>(backend: &B,
+                roots: &[gc::Id],
+                used: &Mutex<HashSet<gc::Id>>)
+                -> Result<(), B::Err>
+    where B: gc::GcBackend
+{
+    for &r in roots {
+        let data = try!(backend.get_data(r, DATA_FAMILY));
+        assert!(data.num >= 0);
+        if data.num > 0 && used.lock().unwrap().insert(r) {
+            let mut todo = vec![r];
+            while let Some(id) = todo.pop() {
+                for child in try!(backend.reverse_refs(id)) {
+                    if used.lock().unwrap().insert(child) {
+                        todo.push(child);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 impl<B: gc::GcBackend> gc::Gc<B> for GcRc<B> {
     type Err = B::Err;
 
@@ -51,10 +155,10 @@ impl<B: gc::GcBackend> gc::Gc<B> for GcRc<B> {
 
         // Increment counters.
         for r in refs.iter() {
-            try!(self.backend.update_data(r, DATA_FAMILY, move |GcData { num, bytes }| {
+            try!(self.backend.update_data(r, DATA_FAMILY, move |GcData { num, .. }| {
                 Some(GcData {
                     num: num + 1,
-                    bytes: bytes,
+                    bytes: REGISTERED.to_vec(),
                 })
             }));
         }
@@ -67,10 +171,10 @@ impl<B: gc::GcBackend> gc::Gc<B> for GcRc<B> {
                       ref_final: gc::Id)
                       -> Result<(), Self::Err> {
         // Increment final counter and tag it as ready.
-        try!(self.backend.update_data(ref_final, DATA_FAMILY, move |GcData { num, bytes }| {
+        try!(self.backend.update_data(ref_final, DATA_FAMILY, move |GcData { num, .. }| {
             Some(GcData {
                 num: num + 1,
-                bytes: bytes,
+                bytes: REGISTERED.to_vec(),
             })
         }));
         try!(self.backend.set_tag(ref_final, tags::Tag::InProgress));
@@ -133,6 +237,33 @@ impl<B: gc::GcBackend> gc::Gc<B> for GcRc<B> {
         Ok(())
     }
 
+    fn list_unused_ids_dry_run(&self, refs: mpsc::Sender<gc::Id>) -> Result<(), Self::Err> {
+        // Same as `list_unused_ids`, but marks used IDs in memory instead of with tags.
+        let all: Vec<gc::Id> = try!(self.backend.list_all_ids()).iter().collect();
+        let mut used = HashSet::new();
+        for &r in all.iter() {
+            let data = try!(self.backend.get_data(r, DATA_FAMILY));
+            assert!(data.num >= 0);
+            if data.num > 0 && used.insert(r) {
+                let mut todo = vec![r];
+                while let Some(id) = todo.pop() {
+                    for child in try!(self.backend.reverse_refs(id)) {
+                        if used.insert(child) {
+                            todo.push(child);
+                        }
+                    }
+                }
+            }
+        }
+        for r in all.into_iter().filter(|r| !used.contains(r)) {
+            if let Err(_) = refs.send(r) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
     fn status(&mut self, final_ref: gc::Id) -> Result<Option<gc::Status>, Self::Err> {
         Ok(match try!(self.backend.get_tag(final_ref)) {
             Some(tags::Tag::Complete) |
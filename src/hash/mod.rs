@@ -14,6 +14,10 @@
 
 //! Local state for known hashes and their external location (blob reference).
 
+use std::cmp;
+use std::collections::HashSet;
+use std::io;
+use std::ptr;
 use std::sync::{Mutex, MutexGuard};
 use time::Duration;
 
@@ -22,16 +26,19 @@ use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
 
 use libsodium_sys;
+use sodiumoxide::crypto::hash::sha256;
 
 use blob;
-use util::{Counter, InfoWriter, PeriodicTimer, UniquePriorityQueue};
+use util::{self, Counter, IndexOptions, InfoWriter, LruCache, PeriodicTimer,
+           UniquePriorityQueue};
 use tags;
-use errors::{DieselError, RetryError};
+use errors::{DieselError, HasherError, RetryError};
 
 use capnp;
 use root_capnp;
 
 mod schema;
+mod pool;
 pub mod tree;
 
 #[cfg(test)]
@@ -39,9 +46,18 @@ mod tests;
 #[cfg(all(test, feature = "benchmarks"))]
 mod benchmarks;
 
+pub use self::pool::{HashPool, HashQueue};
+
 
 pub struct HashIndex(Mutex<InternalHashIndex>);
 
+/// Number of entries found in the index that are kept in memory, unless configured otherwise.
+pub const DEFAULT_CACHE_SIZE: usize = 16 * 1024;
+
+/// The GC and key rotation progress tables each hold at most this one row.
+const GC_PROGRESS_ID: i64 = 1;
+const KEY_ROTATION_PROGRESS_ID: i64 = 1;
+
 
 fn encode_childs(childs: &[i64]) -> Vec<u8> {
     let mut message = capnp::message::Builder::new_default();
@@ -87,9 +103,34 @@ pub struct GcData {
 pub trait UpdateFn: FnOnce(GcData) -> Option<GcData> {}
 impl<T> UpdateFn for T where T: FnOnce(GcData) -> Option<GcData> {}
 
-impl Hash {
-    /// Computes `hash(text)` and stores this digest as the `bytes` field in a new `Hash` structure.
-    pub fn new(text: &[u8]) -> Hash {
+/// A hash function used to name chunks.
+///
+/// The id of the hasher is recorded in every hash it makes (see `Hash::with_hasher`), so chunks
+/// hashed with different algorithms can live side by side in the same store, and only chunks
+/// hashed with the same algorithm are deduplicated against each other.
+pub trait Hasher: Send + Sync {
+    /// Identifies the algorithm; must be unique among hashers and stable across versions.
+    fn id(&self) -> u8;
+
+    /// Computes the digest of `text`.
+    fn digest(&self, text: &[u8]) -> Vec<u8>;
+}
+
+/// BLAKE2b with 512-bit digests; the default hasher.
+pub struct Blake2b;
+
+/// SHA-256.
+pub struct Sha256;
+
+pub const BLAKE2B_ID: u8 = 0;
+pub const SHA256_ID: u8 = 1;
+
+impl Hasher for Blake2b {
+    fn id(&self) -> u8 {
+        BLAKE2B_ID
+    }
+
+    fn digest(&self, text: &[u8]) -> Vec<u8> {
         let digest_len = libsodium_sys::crypto_generichash_blake2b_BYTES_MAX;
         let mut digest = vec![0; digest_len];
         unsafe {
@@ -100,10 +141,141 @@ impl Hash {
                                                       vec![].as_ptr(),
                                                       0);
         }
+        digest
+    }
+}
+
+impl Hasher for Sha256 {
+    fn id(&self) -> u8 {
+        SHA256_ID
+    }
+
+    fn digest(&self, text: &[u8]) -> Vec<u8> {
+        let sha256::Digest(digest) = sha256::hash(text);
+        digest.to_vec()
+    }
+}
+
+/// The built-in hasher with the given id, if any.
+pub fn hasher_by_id(id: u8) -> Option<&'static Hasher> {
+    static BLAKE2B: Blake2b = Blake2b;
+    static SHA256: Sha256 = Sha256;
+    match id {
+        BLAKE2B_ID => Some(&BLAKE2B),
+        SHA256_ID => Some(&SHA256),
+        _ => None,
+    }
+}
+
+impl Hash {
+    /// Computes `hash(text)` and stores this digest as the `bytes` field in a new `Hash` structure.
+    pub fn new(text: &[u8]) -> Hash {
+        Hash { bytes: Blake2b.digest(text) }
+    }
+
+    /// Computes the hash of `text` with `hasher`.
+    ///
+    /// Default (BLAKE2b) hashes are the bare digest, as in stores created before the hasher was
+    /// configurable. Other hashes are the digest prefixed by the hasher's id; a digest of 63
+    /// bytes would then read as a BLAKE2b hash, and is rejected with an error.
+    pub fn with_hasher(hasher: &Hasher, text: &[u8]) -> Result<Hash, HasherError> {
+        let digest = hasher.digest(text);
+        if hasher.id() == BLAKE2B_ID {
+            return Ok(Hash { bytes: digest });
+        }
+        if digest.len() + 1 == libsodium_sys::crypto_generichash_blake2b_BYTES_MAX {
+            return Err(HasherError {
+                hasher_id: hasher.id(),
+                digest_len: digest.len(),
+            });
+        }
+        let mut bytes = Vec::with_capacity(digest.len() + 1);
+        bytes.push(hasher.id());
+        bytes.extend_from_slice(&digest[..]);
+        Ok(Hash { bytes: bytes })
+    }
+
+    /// The id of the hasher that made this hash.
+    pub fn hasher_id(&self) -> u8 {
+        if self.bytes.len() == libsodium_sys::crypto_generichash_blake2b_BYTES_MAX {
+            BLAKE2B_ID
+        } else {
+            self.bytes.get(0).cloned().unwrap_or(BLAKE2B_ID)
+        }
+    }
+
+    /// Checks that this is the hash of `text`, using the algorithm that made it.
+    pub fn verify(&self, text: &[u8]) -> bool {
+        match hasher_by_id(self.hasher_id()) {
+            Some(hasher) => Hash::with_hasher(hasher, text).ok().as_ref() == Some(self),
+            None => {
+                warn!("Cannot verify hash made by unknown hasher {}", self.hasher_id());
+                false
+            }
+        }
+    }
+}
+
+/// Computes the hash of text written to it in pieces, as `Hash::new` computes it of the text as a
+/// whole, e.g. to hash a file without reading all of it into memory.
+pub struct HashWriter {
+    // Room for libsodium's BLAKE2b state, which must be 64-byte aligned, starting at `offset`.
+    state: Vec<u8>,
+    offset: usize,
+}
+
+impl HashWriter {
+    pub fn new() -> HashWriter {
+        let size = unsafe { libsodium_sys::crypto_generichash_statebytes() };
+        let state = vec![0; size + 64];
+        let offset = (64 - state.as_ptr() as usize % 64) % 64;
+        let mut writer = HashWriter {
+            state: state,
+            offset: offset,
+        };
+        let digest_len = libsodium_sys::crypto_generichash_blake2b_BYTES_MAX;
+        unsafe {
+            libsodium_sys::crypto_generichash_blake2b_init(writer.state_ptr(),
+                                                           ptr::null(),
+                                                           0,
+                                                           digest_len);
+        }
+        writer
+    }
+
+    fn state_ptr(&mut self) -> *mut libsodium_sys::crypto_generichash_blake2b_state {
+        // The vector is never resized, so the state stays where it was initialized.
+        self.state[self.offset..].as_mut_ptr() as *mut _
+    }
+
+    /// The hash of all text written.
+    pub fn finish(mut self) -> Hash {
+        let digest_len = libsodium_sys::crypto_generichash_blake2b_BYTES_MAX;
+        let mut digest = vec![0; digest_len];
+        unsafe {
+            libsodium_sys::crypto_generichash_blake2b_final(self.state_ptr(),
+                                                            digest.as_mut_ptr(),
+                                                            digest_len);
+        }
         Hash { bytes: digest }
     }
 }
 
+impl io::Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        unsafe {
+            libsodium_sys::crypto_generichash_blake2b_update(self.state_ptr(),
+                                                             buf.as_ptr(),
+                                                             buf.len() as u64);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 
 /// An entry that can be inserted into the hash index.
 #[derive(Clone)]
@@ -144,20 +316,50 @@ pub struct InternalHashIndex {
 
     queue: UniquePriorityQueue<i64, Vec<u8>, QueueEntry>,
 
+    // Recently located entries of the index. Only entries that exist in the index are cached, so
+    // a hash missing here is always looked up in the index.
+    cache: LruCache<Vec<u8>, QueueEntry>,
+
     flush_timer: PeriodicTimer,
     flush_periodically: bool,
+
+    // Hashes found again by `reserve()` since the last `unpin_all()`; see `delete_unpinned()`.
+    pinned: HashSet<i64>,
+}
+
+fn entry_from_row(row: schema::Hash) -> Entry {
+    Entry {
+        hash: Hash { bytes: row.hash },
+        level: row.height,
+        childs: row.childs.as_ref().and_then(|p| {
+            if p.is_empty() {
+                None
+            } else {
+                Some(decode_childs(p).unwrap())
+            }
+        }),
+        persistent_ref: row.blob_ref.and_then(|b| {
+            if b.is_empty() {
+                None
+            } else {
+                Some(blob::ChunkRef::from_bytes(&mut &b[..]).unwrap())
+            }
+        }),
+    }
 }
 
 impl InternalHashIndex {
-    fn new(path: &str) -> Result<InternalHashIndex, DieselError> {
-        let conn = try!(SqliteConnection::establish(path));
+    fn new(path: &str, options: &IndexOptions) -> Result<InternalHashIndex, DieselError> {
+        let conn = try!(util::sqlite::establish(path, options));
 
         let mut hi = InternalHashIndex {
             conn: conn,
             id_counter: Counter::new(0),
             queue: UniquePriorityQueue::new(),
+            cache: LruCache::new(DEFAULT_CACHE_SIZE),
             flush_timer: PeriodicTimer::new(Duration::seconds(10)),
             flush_periodically: true,
+            pinned: HashSet::new(),
         };
 
         let dir = try!(diesel::migrations::find_migrations_directory());
@@ -204,8 +406,17 @@ impl InternalHashIndex {
     }
 
     fn locate(&mut self, hash: &Hash) -> Option<QueueEntry> {
-        let result_opt = self.queue.find_value_of_key(&hash.bytes).map(|x| x.clone());
-        result_opt.or_else(|| self.index_locate(hash))
+        if let Some(entry) = self.queue.find_value_of_key(&hash.bytes) {
+            return Some(entry.clone());
+        }
+        if let Some(entry) = self.cache.get(&hash.bytes) {
+            return Some(entry.clone());
+        }
+        let result_opt = self.index_locate(hash);
+        if let Some(ref entry) = result_opt {
+            self.cache.insert(hash.bytes.clone(), entry.clone());
+        }
+        result_opt
     }
 
     fn locate_by_id(&mut self, id_: i64) -> Option<Entry> {
@@ -291,7 +502,13 @@ impl InternalHashIndex {
         }
     }
 
-    fn insert_completed_in_order(&mut self) {
+    fn unreserve(&mut self, hash: &Hash) -> Result<(), capnp::Error> {
+        assert!(self.queue.remove_key(&hash.bytes).is_some(), "hash was reserved");
+        // Later entries may have been waiting for this one.
+        self.insert_completed_in_order()
+    }
+
+    fn insert_completed_in_order(&mut self) -> Result<(), capnp::Error> {
         use self::schema::hashes::dsl::*;
 
         loop {
@@ -300,7 +517,10 @@ impl InternalHashIndex {
                 Some((id_, hash_bytes, queue_entry)) => {
                     assert_eq!(id_, queue_entry.id);
 
-                    let persistent_ref_bytes = queue_entry.persistent_ref.map(|c| c.as_bytes());
+                    let persistent_ref_bytes = match queue_entry.persistent_ref {
+                        Some(c) => Some(try!(c.as_bytes())),
+                        None => None,
+                    };
                     let childs_ = queue_entry.childs.as_ref().map(|v| encode_childs(&v[..]));
                     let new = schema::NewHash {
                         id: id_,
@@ -318,6 +538,7 @@ impl InternalHashIndex {
                 }
             }
         }
+        Ok(())
     }
 
     fn set_tag(&mut self, id_opt: Option<i64>, tag_: tags::Tag) {
@@ -452,7 +673,7 @@ impl InternalHashIndex {
             .expect("Error deleting GC metadata");
     }
 
-    fn commit(&mut self, hash: &Hash, chunk_ref: blob::ChunkRef) {
+    fn commit(&mut self, hash: &Hash, chunk_ref: blob::ChunkRef) -> Result<(), capnp::Error> {
         // Update persistent reference for ready hash
         let queue_entry = self.locate(hash).expect("hash was committed");
         self.queue.update_value(&hash.bytes, |old_qe| {
@@ -460,9 +681,10 @@ impl InternalHashIndex {
         });
         self.queue.set_ready(&queue_entry.id);
 
-        self.insert_completed_in_order();
+        try!(self.insert_completed_in_order());
 
         self.maybe_flush();
+        Ok(())
     }
 
     fn list(&mut self) -> Vec<Entry> {
@@ -470,29 +692,160 @@ impl InternalHashIndex {
         hashes.load::<schema::Hash>(&self.conn)
             .expect("Error listing hashes")
             .into_iter()
-            .map(|hash_| {
-                Entry {
-                    hash: Hash { bytes: hash_.hash },
-                    level: hash_.height,
-                    childs: hash_.childs.as_ref().and_then(|p| {
-                        if p.is_empty() {
-                            None
-                        } else {
-                            Some(decode_childs(p).unwrap())
-                        }
-                    }),
-                    persistent_ref: hash_.blob_ref.and_then(|b| {
-                        if b.is_empty() {
-                            None
-                        } else {
-                            Some(blob::ChunkRef::from_bytes(&mut &b[..]).unwrap())
-                        }
-                    }),
-                }
-            })
+            .map(entry_from_row)
             .collect()
     }
 
+    fn list_ids(&mut self) -> Vec<i64> {
+        use self::schema::hashes::dsl::*;
+        hashes.select(id)
+            .load::<i64>(&self.conn)
+            .expect("Error listing hashes")
+    }
+
+    fn queued_refs(&self) -> Vec<blob::ChunkRef> {
+        self.queue.values().into_iter().filter_map(|qe| qe.persistent_ref.clone()).collect()
+    }
+
+    fn list_from(&mut self, after_id: i64, limit: usize) -> Vec<(i64, Entry)> {
+        use self::schema::hashes::dsl::*;
+        hashes.filter(id.gt(after_id))
+            .order(id.asc())
+            .limit(cmp::min(limit, i64::max_value() as usize) as i64)
+            .load::<schema::Hash>(&self.conn)
+            .expect("Error listing hashes")
+            .into_iter()
+            .map(|hash_| (hash_.id, entry_from_row(hash_)))
+            .collect()
+    }
+
+    fn count_with_persistent_ref(&mut self) -> i64 {
+        use self::schema::hashes::dsl::*;
+        use diesel::expression::count_star;
+
+        hashes.filter(blob_ref.is_not_null())
+            .select(count_star())
+            .first::<i64>(&self.conn)
+            .expect("Error counting hashes")
+    }
+
+    fn gc_mark_cursor(&mut self) -> Option<i64> {
+        use self::schema::gc_progress::dsl::*;
+
+        gc_progress.find(GC_PROGRESS_ID)
+            .select(hash_cursor)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading GC progress")
+    }
+
+    fn set_gc_mark_cursor(&mut self, cursor: Option<i64>) {
+        use self::schema::gc_progress::dsl::*;
+
+        diesel::delete(gc_progress.find(GC_PROGRESS_ID))
+            .execute(&self.conn)
+            .expect("Error clearing GC progress");
+
+        if let Some(cursor) = cursor {
+            let new = schema::NewGcProgress {
+                id: GC_PROGRESS_ID,
+                hash_cursor: cursor,
+            };
+            diesel::insert(&new)
+                .into(gc_progress)
+                .execute(&self.conn)
+                .expect("Error inserting GC progress");
+        }
+    }
+
+    fn key_rotation_cursor(&mut self) -> Option<i64> {
+        use self::schema::key_rotation_progress::dsl::*;
+
+        key_rotation_progress.find(KEY_ROTATION_PROGRESS_ID)
+            .select(hash_cursor)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading key rotation progress")
+    }
+
+    fn set_key_rotation_cursor(&mut self, cursor: Option<i64>) {
+        use self::schema::key_rotation_progress::dsl::*;
+
+        diesel::delete(key_rotation_progress.find(KEY_ROTATION_PROGRESS_ID))
+            .execute(&self.conn)
+            .expect("Error clearing key rotation progress");
+
+        if let Some(cursor) = cursor {
+            let new = schema::NewKeyRotationProgress {
+                id: KEY_ROTATION_PROGRESS_ID,
+                hash_cursor: cursor,
+            };
+            diesel::insert(&new)
+                .into(key_rotation_progress)
+                .execute(&self.conn)
+                .expect("Error inserting key rotation progress");
+        }
+    }
+
+    fn update_persistent_ref(&mut self,
+                             hash_: &Hash,
+                             chunk_ref: blob::ChunkRef)
+                             -> Result<(), capnp::Error> {
+        use self::schema::hashes::dsl::*;
+        let chunk_ref_bytes = try!(chunk_ref.as_bytes());
+        let count = diesel::update(hashes.filter(hash.eq(&hash_.bytes)))
+            .set(blob_ref.eq(&chunk_ref_bytes[..]))
+            .execute(&self.conn)
+            .expect("Error updating persistent reference");
+        assert_eq!(count, 1);
+        self.cache.remove(&hash_.bytes);
+        Ok(())
+    }
+
+    fn insert_superseded(&mut self, hash_id_: i64, blob_name_: &[u8]) {
+        use self::schema::superseded_refs::dsl::*;
+        let new = schema::NewSupersededRef {
+            hash_id: hash_id_,
+            blob_name: blob_name_,
+        };
+        diesel::insert(&new)
+            .into(superseded_refs)
+            .execute(&self.conn)
+            .expect("Error inserting superseded reference");
+    }
+
+    fn upgrade_persistent_ref(&mut self,
+                              hash: &Hash,
+                              chunk_ref: blob::ChunkRef)
+                              -> Result<(), capnp::Error> {
+        let queued_id = self.queue.find_value_of_key(&hash.bytes).map(|entry| entry.id);
+        match queued_id {
+            // Not yet in the index, where the first copy would overwrite it; keep the new copy's
+            // blob for as long as the hash instead.
+            Some(id) => {
+                if (chunk_ref.offset, chunk_ref.length) != (0, 0) {
+                    self.insert_superseded(id, &chunk_ref.blob_id[..]);
+                }
+            }
+            None => {
+                let (id, old_ref) = match self.index_locate(hash) {
+                    Some(entry) => (entry.id, entry.persistent_ref),
+                    None => return Ok(()),
+                };
+                // Tree nodes and listings stored earlier still name the old copy, so its blob is
+                // kept for as long as this hash is.
+                if let Some(old_ref) = old_ref {
+                    if old_ref.blob_id != chunk_ref.blob_id &&
+                       (old_ref.offset, old_ref.length) != (0, 0) {
+                        self.insert_superseded(id, &old_ref.blob_id[..]);
+                    }
+                }
+                try!(self.update_persistent_ref(hash, chunk_ref));
+            }
+        }
+        Ok(())
+    }
+
     fn delete(&mut self, id_: i64) {
         {
             use self::schema::hashes::dsl::*;
@@ -501,6 +854,8 @@ impl InternalHashIndex {
                 .expect("Error deleting hash");
             assert!(hash_count <= 1);
         }
+        // The cache is keyed by hash; it is refilled as hashes are located again.
+        self.cache.clear();
 
         {
             use self::schema::gc_metadata::dsl::*;
@@ -508,6 +863,21 @@ impl InternalHashIndex {
                 .execute(&self.conn)
                 .expect("Error deleting GC metadata");
         }
+
+        {
+            use self::schema::superseded_refs::dsl::*;
+            diesel::delete(superseded_refs.filter(hash_id.eq(id_)))
+                .execute(&self.conn)
+                .expect("Error deleting superseded references");
+        }
+    }
+
+    fn superseded_blobs(&mut self) -> Vec<Vec<u8>> {
+        use self::schema::superseded_refs::dsl::*;
+        superseded_refs.select(blob_name)
+            .distinct()
+            .load::<Vec<u8>>(&self.conn)
+            .expect("Error listing superseded references")
     }
 
     fn maybe_flush(&mut self) {
@@ -525,7 +895,11 @@ impl InternalHashIndex {
 
 impl HashIndex {
     pub fn new(path: &str) -> Result<HashIndex, DieselError> {
-        InternalHashIndex::new(path).map(|index| HashIndex(Mutex::new((index))))
+        HashIndex::with_options(path, &IndexOptions::default())
+    }
+
+    pub fn with_options(path: &str, options: &IndexOptions) -> Result<HashIndex, DieselError> {
+        InternalHashIndex::new(path, options).map(|index| HashIndex(Mutex::new((index))))
     }
 
     #[cfg(test)]
@@ -537,6 +911,12 @@ impl HashIndex {
         self.0.lock().expect("Hash index was poisoned")
     }
 
+    /// Keep up to `entries` recently located entries of the index in memory, to save looking up
+    /// chunks that are seen again (e.g. when snapshotting unchanged files). 0 disables the cache.
+    pub fn set_cache_size(&self, entries: usize) {
+        self.lock().cache.set_capacity(entries);
+    }
+
     /// Locate the local ID of this hash.
     pub fn get_id(&self, hash: &Hash) -> Option<i64> {
         assert!(!hash.bytes.is_empty());
@@ -579,7 +959,10 @@ impl HashIndex {
         // through and delete uncommitted entries.
         let mut guard = self.lock();
         match guard.locate(&hash_entry.hash) {
-            Some(entry) => ReserveResult::HashKnown(entry.id),
+            Some(entry) => {
+                guard.pinned.insert(entry.id);
+                ReserveResult::HashKnown(entry.id)
+            }
             None => {
                 let id = guard.reserve(hash_entry);
                 ReserveResult::ReserveOk(id)
@@ -595,11 +978,18 @@ impl HashIndex {
         self.lock().update_reserved(hash_entry);
     }
 
+    /// Drop the reservation of a `Hash` whose content could not be stored, e.g. as the store is
+    /// over its quota. It is as if the `Hash` was never reserved.
+    pub fn unreserve(&self, hash: &Hash) -> Result<(), capnp::Error> {
+        assert!(!hash.bytes.is_empty());
+        self.lock().unreserve(hash)
+    }
+
     /// A `Hash` is committed when it has been `finalized` in the external storage. `Commit`
     /// includes the persistent reference that the content is available at.
-    pub fn commit(&self, hash: &Hash, persistent_ref: blob::ChunkRef) {
+    pub fn commit(&self, hash: &Hash, persistent_ref: blob::ChunkRef) -> Result<(), capnp::Error> {
         assert!(!hash.bytes.is_empty());
-        self.lock().commit(hash, persistent_ref);
+        self.lock().commit(hash, persistent_ref)
     }
 
     /// List all hash entries.
@@ -607,11 +997,105 @@ impl HashIndex {
         self.lock().list()
     }
 
+    /// List the IDs of all hash entries.
+    pub fn list_ids(&self) -> Vec<i64> {
+        self.lock().list_ids()
+    }
+
+    /// The ID of the hash reserved last. Hashes reserved later always get higher IDs.
+    pub fn last_id(&self) -> i64 {
+        self.lock().id_counter.current()
+    }
+
+    /// The persistent references of hashes reserved but not yet written to the index, which waits
+    /// for the chunks of all hashes reserved before to be stored.
+    pub fn queued_refs(&self) -> Vec<blob::ChunkRef> {
+        self.lock().queued_refs()
+    }
+
+    /// List up to `limit` hash entries with IDs above `after_id`, ordered by ID.
+    pub fn list_from(&self, after_id: i64, limit: usize) -> Vec<(i64, Entry)> {
+        self.lock().list_from(after_id, limit)
+    }
+
+    /// Count the hash entries that have a persistent reference.
+    pub fn count_with_persistent_ref(&self) -> i64 {
+        self.lock().count_with_persistent_ref()
+    }
+
+    /// The ID of the last hash marked by an unfinished GC run, if any.
+    pub fn gc_mark_cursor(&self) -> Option<i64> {
+        self.lock().gc_mark_cursor()
+    }
+
+    /// Record (or with `None`, clear) the progress of the GC mark phase.
+    pub fn set_gc_mark_cursor(&self, cursor: Option<i64>) {
+        self.lock().set_gc_mark_cursor(cursor)
+    }
+
+    /// The ID of the last hash rotated by an unfinished key rotation, if any.
+    pub fn key_rotation_cursor(&self) -> Option<i64> {
+        self.lock().key_rotation_cursor()
+    }
+
+    /// Record (or with `None`, clear) the progress of a key rotation.
+    pub fn set_key_rotation_cursor(&self, cursor: Option<i64>) {
+        self.lock().set_key_rotation_cursor(cursor)
+    }
+
+    /// Point an already stored hash at a new copy of its data. The old copy is not kept for it:
+    /// reads of references to it fall back to the hash index (see `key::HashStoreBackend`).
+    pub fn update_persistent_ref(&self,
+                                 hash: &Hash,
+                                 persistent_ref: blob::ChunkRef)
+                                 -> Result<(), capnp::Error> {
+        assert!(!hash.bytes.is_empty());
+        self.lock().update_persistent_ref(hash, persistent_ref)
+    }
+
+    /// Names of the blobs holding other copies of live hashes, moved by
+    /// `upgrade_persistent_ref`. Stored tree nodes and listings may still name these copies.
+    pub fn superseded_blobs(&self) -> Vec<Vec<u8>> {
+        self.lock().superseded_blobs()
+    }
+
+    /// Point `hash` at a copy of its chunk stored again with stronger encryption, like
+    /// `update_persistent_ref()`. A hash still waiting to be written to the index keeps its first
+    /// copy, and the new copy's blob is kept along with it.
+    pub fn upgrade_persistent_ref(&self,
+                                  hash: &Hash,
+                                  persistent_ref: blob::ChunkRef)
+                                  -> Result<(), capnp::Error> {
+        assert!(!hash.bytes.is_empty());
+        self.lock().upgrade_persistent_ref(hash, persistent_ref)
+    }
+
     /// Permanently delete hash by its ID.
     pub fn delete(&self, id: i64) {
         self.lock().delete(id)
     }
 
+    /// Like `delete()`, but spare the hash if `reserve()` found it since the last `unpin_all()`,
+    /// as data stored meanwhile may refer to it. Returns whether the hash was deleted.
+    pub fn delete_unpinned(&self, id: i64) -> bool {
+        let mut guard = self.lock();
+        if guard.pinned.contains(&id) {
+            return false;
+        }
+        guard.delete(id);
+        true
+    }
+
+    /// Whether `delete_unpinned()` would spare the hash.
+    pub fn is_pinned(&self, id: i64) -> bool {
+        self.lock().pinned.contains(&id)
+    }
+
+    /// Forget which hashes `reserve()` found so far; see `delete_unpinned()`.
+    pub fn unpin_all(&self) {
+        self.lock().pinned.clear();
+    }
+
     /// API related to tagging, which is useful to indicate state during operation stages.
     /// It operates directly on the underlying IDs.
     pub fn set_tag(&self, id: i64, tag: tags::Tag) {
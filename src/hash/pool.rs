@@ -0,0 +1,100 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hash chunks on a pool of worker threads.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use errors::HasherError;
+use hash::{Hash, Hasher};
+
+
+type Hashed = (Vec<u8>, Result<Hash, HasherError>);
+type Job = (Vec<u8>, mpsc::Sender<Hashed>);
+
+/// A fixed number of threads hashing chunks with the same hasher. The threads exit when the pool
+/// is dropped.
+pub struct HashPool {
+    jobs: Mutex<mpsc::Sender<Job>>,
+    queue_depth: usize,
+}
+
+impl HashPool {
+    /// Start `threads` workers. Each queue of the pool holds up to two chunks per worker.
+    pub fn new(hasher: Arc<Hasher>, threads: usize) -> HashPool {
+        assert!(threads > 0);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            let hasher = hasher.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok((chunk, reply)) => {
+                            let hash = Hash::with_hasher(&*hasher, &chunk[..]);
+                            // The queue may have been dropped; its results are not needed then.
+                            let _ = reply.send((chunk, hash));
+                        }
+                        Err(_) => return,
+                    }
+                }
+            });
+        }
+        HashPool {
+            jobs: Mutex::new(sender),
+            queue_depth: 2 * threads,
+        }
+    }
+
+    /// Create a queue for hashing a sequence of chunks in order.
+    pub fn queue(&self) -> HashQueue {
+        HashQueue {
+            jobs: self.jobs.lock().unwrap().clone(),
+            pending: VecDeque::new(),
+            depth: self.queue_depth,
+        }
+    }
+}
+
+/// Chunks being hashed by a `HashPool`, returned with their hash in the order they were pushed.
+pub struct HashQueue {
+    jobs: mpsc::Sender<Job>,
+    pending: VecDeque<mpsc::Receiver<Hashed>>,
+    depth: usize,
+}
+
+impl HashQueue {
+    /// Start hashing `chunk`. Once the queue is full, this waits for the oldest chunk and
+    /// returns it with its hash.
+    pub fn push(&mut self, chunk: Vec<u8>) -> Option<Hashed> {
+        let (sender, receiver) = mpsc::channel();
+        self.jobs.send((chunk, sender)).expect("Hash pool has stopped");
+        self.pending.push_back(receiver);
+        if self.pending.len() > self.depth {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Wait for the oldest chunk and return it with its hash. Returns `None` if the queue is
+    /// empty.
+    pub fn pop(&mut self) -> Option<Hashed> {
+        self.pending.pop_front().map(|receiver| receiver.recv().expect("Hash worker died"))
+    }
+}
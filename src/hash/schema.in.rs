@@ -35,6 +35,28 @@ table! {
     }
 }
 
+table! {
+    gc_progress {
+        id -> BigInt,
+        hash_cursor -> BigInt,
+    }
+}
+
+table! {
+    key_rotation_progress {
+        id -> BigInt,
+        hash_cursor -> BigInt,
+    }
+}
+
+table! {
+    superseded_refs {
+        id -> BigInt,
+        hash_id -> BigInt,
+        blob_name -> Binary,
+    }
+}
+
 
 // Rust models.
 
@@ -74,3 +96,21 @@ pub struct NewGcMetadata<'a> {
     pub gc_int: i64,
     pub gc_vec: &'a [u8],
 }
+
+#[insertable_into(gc_progress)]
+pub struct NewGcProgress {
+    pub id: i64,
+    pub hash_cursor: i64,
+}
+
+#[insertable_into(key_rotation_progress)]
+pub struct NewKeyRotationProgress {
+    pub id: i64,
+    pub hash_cursor: i64,
+}
+
+#[insertable_into(superseded_refs)]
+pub struct NewSupersededRef<'a> {
+    pub hash_id: i64,
+    pub blob_name: &'a [u8],
+}
@@ -17,12 +17,17 @@ use hash::tree::*;
 use std::sync::{Arc, Mutex};
 
 use blob::{ChunkRef, Kind};
-use hash::Hash;
+use hash::{Hash, HashWriter};
 use key;
 
 use std::borrow::Cow;
+use std::cmp;
 use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io::Write;
 use quickcheck;
+use rand;
 
 #[derive(Clone)]
 pub struct MemoryBackend {
@@ -78,6 +83,9 @@ impl HashTreeBackend for MemoryBackend {
                     },
                     packing: None,
                     key: None,
+                    raw_length: None,
+                    nonce: None,
+                    inline: None,
                 })
             }
             None => None,
@@ -112,6 +120,9 @@ impl HashTreeBackend for MemoryBackend {
                 },
                 packing: None,
                 key: None,
+                raw_length: None,
+                nonce: None,
+                inline: None,
             },
         })))
     }
@@ -272,3 +283,88 @@ fn identity_1_short_of_flush() {
         assert_eq!(bytes, chunk);
     }
 }
+
+#[test]
+fn identity_different_orders() {
+    let blocks: Vec<Vec<u8>> = (0..1000u32)
+        .map(|i| vec![(i % 256) as u8, (i / 256) as u8])
+        .collect();
+
+    let mut node_counts = vec![];
+    for order in vec![4, 64] {
+        let backend = MemoryBackend::new();
+        let mut ht = SimpleHashTreeWriter::new(order, backend.clone());
+        for block in blocks.iter() {
+            ht.append(&block[..]).unwrap();
+        }
+        let (hash, hash_ref) = ht.hash().unwrap();
+        node_counts.push(backend.chunks.lock().unwrap().len() - blocks.len());
+
+        let it = SimpleHashTreeReader::open(backend, &hash, Some(hash_ref))
+            .unwrap()
+            .expect("tree not found");
+        assert_eq!(blocks, it.collect::<Vec<_>>());
+    }
+
+    // The wider tree needs fewer branch nodes.
+    assert!(node_counts[1] < node_counts[0]);
+}
+
+#[test]
+fn identity_spilled_levels() {
+    let blocks: Vec<Vec<u8>> = (0..1000u32)
+        .map(|i| vec![(i % 256) as u8, (i / 256) as u8])
+        .collect();
+    let mut dir = env::temp_dir();
+    dir.push(format!("hat-tree-spill-{:x}", rand::random::<u64>()));
+    fs::create_dir(&dir).unwrap();
+    let spilled_files = || fs::read_dir(&dir).unwrap().count();
+
+    let backend = MemoryBackend::new();
+    let mut ht = SimpleHashTreeWriter::new(64, backend.clone()).with_spill(dir.clone(), 4);
+    let mut max_files = 0;
+    for block in blocks.iter() {
+        ht.append(&block[..]).unwrap();
+        max_files = cmp::max(max_files, spilled_files());
+    }
+    assert!(max_files > 0);
+    let (hash, hash_ref) = ht.hash().unwrap();
+    assert_eq!(spilled_files(), 0);
+
+    // Spilling does not change the tree written.
+    let mut unspilled = SimpleHashTreeWriter::new(64, MemoryBackend::new());
+    for block in blocks.iter() {
+        unspilled.append(&block[..]).unwrap();
+    }
+    assert_eq!(unspilled.hash().unwrap().0, hash);
+
+    let it = SimpleHashTreeReader::open(backend, &hash, Some(hash_ref))
+        .unwrap()
+        .expect("tree not found");
+    assert_eq!(blocks, it.collect::<Vec<_>>());
+
+    // The files of a writer dropped unfinished are removed as well.
+    {
+        let mut ht = SimpleHashTreeWriter::new(64, MemoryBackend::new())
+            .with_spill(dir.clone(), 4);
+        for block in blocks[..100].iter() {
+            ht.append(&block[..]).unwrap();
+        }
+        assert!(spilled_files() > 0);
+    }
+    assert_eq!(spilled_files(), 0);
+    fs::remove_dir(&dir).unwrap();
+}
+
+#[test]
+fn hash_writer_matches_hash_new() {
+    let text: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    for piece_len in vec![1, 7, 1000, 20000] {
+        let mut writer = HashWriter::new();
+        for piece in text.chunks(piece_len) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(writer.finish(), Hash::new(&text[..]));
+    }
+    assert_eq!(HashWriter::new().finish(), Hash::new(&[]));
+}
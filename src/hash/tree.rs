@@ -17,7 +17,12 @@
 //! This module implements two structures for handling hash trees: A streaming hash-tree writer, and
 //! a streaming hash-tree reader.
 
+use rand;
 use std::fmt;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
 
 use capnp;
 use root_capnp;
@@ -59,7 +64,7 @@ impl HashRef {
         Ok(try!(HashRef::read_msg(&root)))
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
+    pub fn as_bytes(&self) -> Result<Vec<u8>, capnp::Error> {
         let mut message = ::capnp::message::Builder::new_default();
         {
             let mut root = message.init_root::<root_capnp::hash_ref::Builder>();
@@ -67,15 +72,15 @@ impl HashRef {
         }
 
         let mut out = Vec::new();
-        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        try!(capnp::serialize_packed::write_message(&mut out, &message));
 
-        out
+        Ok(out)
     }
 }
 
 
 pub trait HashTreeBackend: Clone {
-    type Err: fmt::Debug;
+    type Err: fmt::Debug + From<&'static str>;
 
     fn fetch_chunk(&self, &Hash, Option<ChunkRef>) -> Result<Option<Vec<u8>>, Self::Err>;
     fn fetch_childs(&self, &Hash) -> Option<Vec<i64>>;
@@ -86,6 +91,11 @@ pub trait HashTreeBackend: Clone {
                     Option<Vec<i64>>,
                     &[u8])
                     -> Result<(i64, HashRef), Self::Err>;
+
+    /// Hashes a chunk before it is inserted; backends may use a hasher other than the default.
+    fn hash(&self, chunk: &[u8]) -> Result<Hash, Self::Err> {
+        Ok(Hash::new(chunk))
+    }
 }
 
 
@@ -131,6 +141,9 @@ fn test_hash_refs_identity() {
             kind: Kind::TreeBranch,
             packing: None,
             key: None,
+            raw_length: None,
+            nonce: None,
+            inline: None,
         };
         let mut v = vec![];
         for _ in 0..count {
@@ -148,9 +161,131 @@ fn test_hash_refs_identity() {
 }
 
 
+// Little-endian bytes of `n`, of which there are `len`.
+fn to_le(n: u64, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (n >> (8 * i)) as u8).collect()
+}
+
+fn from_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, b| (n << 8) | *b as u64)
+}
+
+// A level of the rightmost path of a tree being written: the references not yet collapsed into
+// a node of the level above.
+struct Level {
+    refs: Vec<(i64, HashRef)>,
+    // The earlier references, moved to a file in the spill directory, their count and the
+    // length of the file they take up.
+    spilled: Option<(PathBuf, fs::File)>,
+    spilled_count: usize,
+    spilled_len: u64,
+}
+
+impl Level {
+    fn new() -> Level {
+        Level {
+            refs: Vec::new(),
+            spilled: None,
+            spilled_count: 0,
+            spilled_len: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.spilled_count + self.refs.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Append the references held in memory to this level's file in `dir`, creating it if needed.
+    fn spill(&mut self, dir: &Path) -> Result<(), String> {
+        let mut data = vec![];
+        for &(id, ref href) in self.refs.iter() {
+            let bytes = try!(href.as_bytes().map_err(|e| e.to_string()));
+            data.extend(to_le(id as u64, 8));
+            data.extend(to_le(bytes.len() as u64, 4));
+            data.extend(bytes);
+        }
+
+        if self.spilled.is_none() {
+            let path = dir.join(format!("hat-tree-{:x}", rand::random::<u64>()));
+            let file = try!(fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .map_err(|e| e.to_string()));
+            self.spilled = Some((path, file));
+        }
+        {
+            let &mut (_, ref mut file) = self.spilled.as_mut().unwrap();
+            // A failed spill may have left a partial write beyond the spilled references.
+            try!(file.seek(SeekFrom::Start(self.spilled_len))
+                .and_then(|_| file.write_all(&data[..]))
+                .map_err(|e| e.to_string()));
+        }
+        self.spilled_count += self.refs.len();
+        self.spilled_len += data.len() as u64;
+        self.refs.clear();
+        Ok(())
+    }
+
+    // All references of this level, in the order they were added. Removes the level's file.
+    fn into_refs(mut self) -> Result<Vec<(i64, HashRef)>, String> {
+        let mut refs = Vec::with_capacity(self.len());
+        let spilled_len = self.spilled_len;
+        if let Some((_, ref mut file)) = self.spilled {
+            let mut data = Vec::with_capacity(spilled_len as usize);
+            try!(file.seek(SeekFrom::Start(0))
+                .and_then(|_| file.take(spilled_len).read_to_end(&mut data))
+                .map_err(|e| e.to_string()));
+            let mut pos = 0;
+            while pos < data.len() {
+                if data.len() < pos + 12 {
+                    return Err("Spilled references are truncated".to_owned());
+                }
+                let id = from_le(&data[pos..pos + 8]) as i64;
+                let len = from_le(&data[pos + 8..pos + 12]) as usize;
+                pos += 12;
+                if data.len() < pos + len {
+                    return Err("Spilled references are truncated".to_owned());
+                }
+                let href = try!(HashRef::from_bytes(&mut &data[pos..pos + len])
+                    .map_err(|e| e.to_string()));
+                refs.push((id, href));
+                pos += len;
+            }
+            assert_eq!(refs.len(), self.spilled_count);
+        }
+        refs.extend(mem::replace(&mut self.refs, Vec::new()));
+        Ok(refs)
+    }
+}
+
+impl Drop for Level {
+    fn drop(&mut self) {
+        if let Some((ref path, _)) = self.spilled {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Could not remove spilled hash-tree references {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+
+/// Node order (fan-out) of the hash-trees written by hat unless configured otherwise.
+pub const DEFAULT_ORDER: usize = 8;
+
 /// A simple implementation of a hash-tree stream writer.
 ///
 /// The hash-tree is "created" as append-only and is streamed from first to last data-block. The
+/// writer only keeps the rightmost path of the tree in memory, with fewer than `order` references
+/// per level, and stores every node as soon as it is complete. Memory use thus grows with the
+/// logarithm of the data's length, so data of any size is written without buffering it. For
+/// trees of a large order, the references held per level may also be moved to disk; see
+/// `with_spill()`.
 ///
 /// ```rust,ignore
 /// let mut tree = SimpleHashTreeWriter::new(order, backend);
@@ -163,27 +298,45 @@ fn test_hash_refs_identity() {
 pub struct SimpleHashTreeWriter<B> {
     backend: B,
     order: usize,
-    levels: Vec<Vec<(i64, HashRef)>>, // Representation of rightmost path to root
+    levels: Vec<Level>, // Representation of rightmost path to root
+    // The directory to move the references of a level to once it holds more than the given
+    // number of them in memory.
+    spill: Option<(PathBuf, usize)>,
 }
 
 
 impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
     /// Create a new hash-tree to be stored through 'backend' with node order 'order'.
+    ///
+    /// The order only affects how the tree is written; readers follow whatever structure was
+    /// stored, so trees of different orders can be read alike.
     pub fn new(order: usize, backend: B) -> SimpleHashTreeWriter<B> {
+        assert!(order >= 2, "hash-tree order must be at least 2");
         SimpleHashTreeWriter {
             backend: backend,
             order: order,
             levels: Vec::new(),
+            spill: None,
         }
     }
 
+    /// Move the references held for a level of the tree to a file in `dir` whenever more than
+    /// `threshold` of them are in memory, until the level is complete and collapsed into a node.
+    /// This bounds the memory used by trees of a large order. Each file is removed once read
+    /// back, or when the writer is dropped unfinished.
+    pub fn with_spill(mut self, dir: PathBuf, threshold: usize) -> SimpleHashTreeWriter<B> {
+        assert!(threshold >= 1, "spill threshold must be at least 1");
+        self.spill = Some((dir, threshold));
+        self
+    }
+
     fn top_level(&self) -> Option<usize> {
         self.levels.len().checked_sub(1)
     }
 
     fn grow_to(&mut self, level: usize) {
         while self.top_level() < Some(level) {
-            self.levels.push(Vec::new());
+            self.levels.push(Level::new());
         }
     }
 
@@ -197,12 +350,19 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
         self.append_at(0, &chunk, None)
     }
 
+    /// Like `append()`, for a data-block already hashed with the backend's `hash()` (e.g. while
+    /// reading the next blocks).
+    pub fn append_hashed(&mut self, chunk: &[u8], hash: Hash) -> Result<(), B::Err> {
+        let (id, hash_ref) = try!(self.backend.insert_chunk(&hash, 0, None, chunk));
+        self.append_hashref_at(0, id, hash_ref)
+    }
+
     fn append_at(&mut self,
                  level: usize,
                  data: &[u8],
                  childs: Option<Vec<i64>>)
                  -> Result<(), B::Err> {
-        let hash = Hash::new(&data[..]);
+        let hash = try!(self.backend.hash(&data[..]));
         let (id, hash_ref) = try!(self.backend.insert_chunk(&hash, level as i64, childs, &data));
         self.append_hashref_at(level, id, hash_ref)
     }
@@ -212,14 +372,21 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
         self.grow_to(level);
 
         let new_level_len = {
-            let level: &mut Vec<(i64, HashRef)> =
-                self.levels.get_mut(level).expect("len() >= level");
-            level.push((id, hashref));
+            let level: &mut Level = self.levels.get_mut(level).expect("len() >= level");
+            level.refs.push((id, hashref));
             level.len()
         };
 
         if new_level_len == self.order {
             try!(self.collapse_level(level));
+        } else if let Some((ref dir, threshold)) = self.spill {
+            let level = &mut self.levels[level];
+            if level.refs.len() > threshold {
+                if let Err(e) = level.spill(dir) {
+                    error!("Could not spill hash-tree references to {:?}: {}", dir, e);
+                    return Err(From::from("Could not spill hash-tree references"));
+                }
+            }
         }
 
         Ok(())
@@ -228,12 +395,18 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
     fn collapse_level(&mut self, level: usize) -> Result<(), B::Err> {
         // Extract-replace level with a new empty level
         assert!(self.levels.len() > level);
-        self.levels.push(Vec::new());
-        let level_v = self.levels.swap_remove(level);
+        let level_v = mem::replace(&mut self.levels[level], Level::new());
+        let refs = match level_v.into_refs() {
+            Ok(refs) => refs,
+            Err(e) => {
+                error!("Could not read back spilled hash-tree references: {}", e);
+                return Err(From::from("Could not read back spilled hash-tree references"));
+            }
+        };
 
         // All data from this level (hashes and references):
-        let ids: Vec<i64> = level_v.iter().map(|&(id, _)| id).collect();
-        let data = hash_refs_to_bytes(&level_v.into_iter().map(|(_, hr)| hr).collect());
+        let ids: Vec<i64> = refs.iter().map(|&(id, _)| id).collect();
+        let data = hash_refs_to_bytes(&refs.into_iter().map(|(_, hr)| hr).collect());
 
         self.append_at(level + 1, &data[..], Some(ids))
     }
@@ -267,7 +440,7 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
 
         // After this point, only root exists and root has exactly one entry:
         assert_eq!(self.levels.last().map(|x| x.len()), Some(1));
-        let &(_, ref hashref) = self.levels.last().and_then(|x| x.last()).expect("asserted");
+        let &(_, ref hashref) = self.levels.last().and_then(|x| x.refs.last()).expect("asserted");
 
         Ok((hashref.hash.clone(), hashref.persistent_ref.clone()))
     }
@@ -321,16 +494,16 @@ impl<B: HashTreeBackend> SimpleHashTreeReader<B> {
             return Ok(None);
         }
 
-        let pref = if let None = root_ref {
+        let pref = try!(if let None = root_ref {
                 backend.fetch_persistent_ref(root_hash)
             } else {
                 root_ref
             }
-            .expect("Could not find tree root hash");
+            .ok_or("Could not find tree root hash"));
         let kind = pref.kind.clone();
 
-        let data = try!(backend.fetch_chunk(root_hash, Some(pref)))
-            .expect("Could not find tree root hash.");
+        let data = try!(try!(backend.fetch_chunk(root_hash, Some(pref)))
+            .ok_or("Could not find tree root chunk"));
         match kind {
             // This is a raw data block.
             Kind::TreeLeaf => Ok(Some(ReaderResult::SingleBlock(data))),
@@ -399,6 +572,39 @@ impl<B: HashTreeBackend> SimpleHashTreeReader<B> {
         Ok((hashes, level))
     }
 
+    /// Skip the data blocks that end within `n` bytes, without fetching them. Tree nodes on the
+    /// way are still read. A block's length is its recorded raw length, or else `block_len`.
+    /// Returns the number of bytes skipped.
+    fn skip_bytes(&mut self, n: u64, block_len: u64) -> Result<u64, B::Err> {
+        let mut skipped = 0;
+        loop {
+            let child = match self.stack.pop() {
+                None => break,
+                Some(child) => child,
+            };
+            match child.persistent_ref.kind {
+                Kind::TreeLeaf => {
+                    let len = child.persistent_ref.raw_length.map_or(block_len, |l| l as u64);
+                    if skipped + len > n {
+                        self.stack.push(child);
+                        break;
+                    }
+                    skipped += len;
+                }
+                Kind::TreeBranch => {
+                    let data = try!(try!(self.backend
+                            .fetch_chunk(&child.hash, Some(child.persistent_ref.clone())))
+                        .ok_or("Could not find chunk for hash ref"));
+                    let mut new_childs = hash_refs_from_bytes(&data[..]).unwrap();
+                    new_childs.reverse();
+                    self.stack.extend(new_childs.into_iter());
+                }
+            }
+        }
+
+        Ok(skipped)
+    }
+
     fn extract(&mut self) -> Result<Option<Vec<u8>>, B::Err> {
         // Basic cycle detection to spot some programming mistakes.
         let mut cycle_start = None;
@@ -412,9 +618,9 @@ impl<B: HashTreeBackend> SimpleHashTreeReader<B> {
             }
 
             let kind = child.persistent_ref.kind.clone();
-            let data = try!(self.backend
+            let data = try!(try!(self.backend
                     .fetch_chunk(&child.hash, Some(child.persistent_ref)))
-                .expect("Invalid hash ref");
+                .ok_or("Could not find chunk for hash ref"));
 
             match kind {
                 Kind::TreeLeaf => return Ok(Some(data)),
@@ -431,14 +637,12 @@ impl<B: HashTreeBackend> SimpleHashTreeReader<B> {
 }
 
 
-impl<B: HashTreeBackend> Iterator for ReaderResult<B> {
-    type Item = Vec<u8>;
-
+impl<B: HashTreeBackend> ReaderResult<B> {
     /// Read the next block of the hash-tree.
-    /// This operation can be expensive, as it may require fetching a file through the backend.
-    fn next(&mut self) -> Option<Vec<u8>> {
+    /// Unlike `next()`, a missing or unreadable block is returned as an error.
+    pub fn try_next(&mut self) -> Result<Option<Vec<u8>>, B::Err> {
         let (force_empty, res) = match *self {
-            ReaderResult::Tree(ref mut it) => (false, it.extract().unwrap()),
+            ReaderResult::Tree(ref mut it) => (false, try!(it.extract())),
             ReaderResult::SingleBlock(ref b) => (true, Some(b.clone())),
             ReaderResult::Empty => (true, None),
         };
@@ -446,6 +650,39 @@ impl<B: HashTreeBackend> Iterator for ReaderResult<B> {
             *self = ReaderResult::Empty;
         }
 
-        res
+        Ok(res)
+    }
+
+    /// Skip the blocks of the hash-tree that end within `n` bytes without fetching their data.
+    /// Blocks without a recorded raw length are taken to be `block_len` bytes long.
+    /// Returns the number of bytes skipped.
+    pub fn skip_bytes(&mut self, n: u64, block_len: u64) -> Result<u64, B::Err> {
+        if n == 0 {
+            return Ok(0);
+        }
+        let (skipped, exhausted) = match *self {
+            ReaderResult::Tree(ref mut it) => {
+                let skipped = try!(it.skip_bytes(n, block_len));
+                (skipped, it.stack.is_empty())
+            }
+            ReaderResult::SingleBlock(ref b) if b.len() as u64 <= n => (b.len() as u64, true),
+            ReaderResult::SingleBlock(_) => (0, false),
+            ReaderResult::Empty => (0, true),
+        };
+        if exhausted {
+            *self = ReaderResult::Empty;
+        }
+
+        Ok(skipped)
+    }
+}
+
+impl<B: HashTreeBackend> Iterator for ReaderResult<B> {
+    type Item = Vec<u8>;
+
+    /// Read the next block of the hash-tree.
+    /// This operation can be expensive, as it may require fetching a file through the backend.
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.try_next().unwrap()
     }
 }
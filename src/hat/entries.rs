@@ -0,0 +1,97 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Walk the entries of a committed snapshot without restoring it.
+
+use backend::StoreBackend;
+use blob;
+use errors::HatError;
+use hash;
+use hat::family::{DirListing, Family};
+use key;
+
+
+type Listing<B> = DirListing<key::HashStoreBackend<B>>;
+
+/// The entries of a committed snapshot with their paths (names separated by `/`, relative to the
+/// snapshot's root), in depth-first order: each directory comes right before its contents.
+///
+/// A directory's listing is only fetched from the backend once the walk reaches it, one chunk at a
+/// time, so memory use grows with the depth of the tree rather than its size. Entries read back
+/// from a snapshot carry the id of their parent directory, if any.
+pub struct SnapshotEntries<B: StoreBackend> {
+    family: Family<B>,
+    backend: key::HashStoreBackend<B>,
+    // The directories being walked, innermost last, with their path prefix and id.
+    stack: Vec<(Vec<u8>, Option<u64>, Listing<B>)>,
+}
+
+impl<B: StoreBackend> SnapshotEntries<B> {
+    pub fn new(family: Family<B>,
+               backend: key::HashStoreBackend<B>,
+               root_hash: &hash::Hash,
+               root_ref: blob::ChunkRef)
+               -> Result<SnapshotEntries<B>, HatError> {
+        let root = try!(family.dir_listing(root_hash, root_ref, backend.clone()));
+        Ok(SnapshotEntries {
+            family: family,
+            backend: backend,
+            stack: vec![(vec![], None, root)],
+        })
+    }
+}
+
+impl<B: StoreBackend> Iterator for SnapshotEntries<B> {
+    type Item = Result<(Vec<u8>, key::Entry), HatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = match self.stack.last_mut() {
+                None => return None,
+                Some(&mut (ref prefix, ref parent_id, ref mut listing)) => {
+                    listing.next().map(|next| (prefix.clone(), *parent_id, next))
+                }
+            };
+            let (mut path, parent_id, (mut entry, content)) = match next {
+                Some((prefix, parent_id, Ok(elem))) => (prefix, parent_id, elem),
+                Some((_, _, Err(e))) => {
+                    // Give up on the rest of this directory.
+                    self.stack.pop();
+                    return Some(Err(e));
+                }
+                None => {
+                    // This directory is done; continue with its parent.
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            path.extend_from_slice(&entry.name[..]);
+            entry.parent_id = parent_id;
+
+            if entry.is_directory() {
+                if let Some((hash, pref)) = content {
+                    let backend = self.backend.clone();
+                    let listing = match self.family.dir_listing(&hash, pref, backend) {
+                        Ok(listing) => listing,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    let mut prefix = path.clone();
+                    prefix.push(b'/');
+                    self.stack.push((prefix, entry.id, listing));
+                }
+            }
+            return Some(Ok((path, entry)));
+        }
+    }
+}
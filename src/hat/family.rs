@@ -12,21 +12,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs as unix_fs;
 use std::path::PathBuf;
 use std::str;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, mpsc};
+use std::vec;
 use capnp;
+use time;
 
 use backend::StoreBackend;
 use blob;
 use hash;
 use key;
+use progress::{self, Progress, ProgressSender};
 use root_capnp;
-use util::{FileIterator, FnBox, PathHandler};
+use util::{CancelReader, CancelToken, FileIterator, FnBox, Glob, PathHandler, Semaphore};
+use util::sparse::SparseWriter;
+use util::tar::{self, TarReader};
 use errors::HatError;
+use hat::SnapshotSummary;
+use hat::hardlinks::HardLinks;
 use hat::insert_path_handler::InsertPathHandler;
+use hat::lease::SnapshotLeases;
 
 fn try_a_few_times_then_panic<F>(mut f: F, msg: &str)
     where F: FnMut() -> bool
@@ -39,10 +52,405 @@ fn try_a_few_times_then_panic<F>(mut f: F, msg: &str)
     panic!(msg.to_owned());
 }
 
+/// How an entry differs between two snapshots.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    /// The file contents changed.
+    Modified,
+    /// Only metadata (modification time, permissions or ownership) changed.
+    MetadataChanged,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiffEntry {
+    /// Path of the entry relative to the snapshot root, with `/` between names.
+    pub path: Vec<u8>,
+    pub kind: DiffKind,
+}
+
+type DirRoot = (hash::Hash, blob::ChunkRef);
+
+/// Streaming difference between two snapshot trees, returned by `Family::diff()`.
+///
+/// Directories are listed one pair at a time as the iterator advances, and subtrees with equal
+/// hashes are skipped without being read.
+pub struct Diff<B, HTB> {
+    family: Family<B>,
+    backend: HTB,
+    // Directory pairs still to compare, with their path prefix.
+    dirs: Vec<(Vec<u8>, Option<DirRoot>, Option<DirRoot>)>,
+    ready: VecDeque<DiffEntry>,
+}
+
+fn metadata_differs(a: &key::Entry, b: &key::Entry) -> bool {
+    a.modified != b.modified || a.permissions != b.permissions || a.user_id != b.user_id ||
+    a.group_id != b.group_id || a.xattrs != b.xattrs
+}
+
+impl<B, HTB> Diff<B, HTB>
+    where B: StoreBackend,
+          HTB: hash::tree::HashTreeBackend<Err = key::MsgError>
+{
+    fn list(&self,
+            dir: Option<DirRoot>)
+            -> Result<BTreeMap<Vec<u8>, (key::Entry, Option<DirRoot>)>, HatError> {
+        let mut out = BTreeMap::new();
+        if let Some((dir_hash, dir_ref)) = dir {
+            for (entry, content) in try!(self.family
+                .fetch_dir_data(&dir_hash, dir_ref, self.backend.clone())) {
+                out.insert(entry.name.clone(), (entry, content));
+            }
+        }
+        Ok(out)
+    }
+
+    fn compare_dirs(&mut self,
+                    prefix: Vec<u8>,
+                    a: Option<DirRoot>,
+                    b: Option<DirRoot>)
+                    -> Result<(), HatError> {
+        let mut before = try!(self.list(a));
+        let after = try!(self.list(b));
+
+        for (name, (entry, content)) in after.into_iter() {
+            let mut path = prefix.clone();
+            path.extend_from_slice(&name[..]);
+
+            match before.remove(&name) {
+                None => {
+                    self.added(path, &entry, content);
+                }
+                Some((old_entry, old_content)) => {
+                    if old_entry.is_directory() != entry.is_directory() ||
+                       old_entry.link_target.is_some() != entry.link_target.is_some() ||
+                       old_entry.hardlink_of.is_some() != entry.hardlink_of.is_some() {
+                        // Replaced by an entry of another type.
+                        self.removed(path.clone(), &old_entry, old_content);
+                        self.added(path, &entry, content);
+                        continue;
+                    }
+
+                    if old_entry.data_hash != entry.data_hash ||
+                       old_entry.link_target != entry.link_target ||
+                       old_entry.hardlink_of != entry.hardlink_of {
+                        self.push(path.clone(), DiffKind::Modified);
+                    } else if metadata_differs(&old_entry, &entry) {
+                        self.push(path.clone(), DiffKind::MetadataChanged);
+                    }
+                    let same_tree = old_content.as_ref().map(|c| &c.0) ==
+                                    content.as_ref().map(|c| &c.0);
+                    if entry.is_directory() && !same_tree {
+                        self.dirs.push((dir_prefix(path), old_content, content));
+                    }
+                }
+            }
+        }
+
+        for (_, (entry, content)) in before.into_iter() {
+            let mut path = prefix.clone();
+            path.extend_from_slice(&entry.name[..]);
+            self.removed(path, &entry, content);
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, path: Vec<u8>, kind: DiffKind) {
+        self.ready.push_back(DiffEntry {
+            path: path,
+            kind: kind,
+        });
+    }
+
+    fn added(&mut self, path: Vec<u8>, entry: &key::Entry, content: Option<DirRoot>) {
+        self.push(path.clone(), DiffKind::Added);
+        if entry.is_directory() {
+            // Everything below a new directory is new as well.
+            self.dirs.push((dir_prefix(path), None, content));
+        }
+    }
+
+    fn removed(&mut self, path: Vec<u8>, entry: &key::Entry, content: Option<DirRoot>) {
+        self.push(path.clone(), DiffKind::Removed);
+        if entry.is_directory() {
+            self.dirs.push((dir_prefix(path), content, None));
+        }
+    }
+}
+
+fn dir_prefix(mut path: Vec<u8>) -> Vec<u8> {
+    path.push(b'/');
+    path
+}
+
+impl<B, HTB> Iterator for Diff<B, HTB>
+    where B: StoreBackend,
+          HTB: hash::tree::HashTreeBackend<Err = key::MsgError>
+{
+    type Item = Result<DiffEntry, HatError>;
+
+    fn next(&mut self) -> Option<Result<DiffEntry, HatError>> {
+        loop {
+            if let Some(entry) = self.ready.pop_front() {
+                return Some(Ok(entry));
+            }
+            let (prefix, a, b) = match self.dirs.pop() {
+                None => return None,
+                Some(dirs) => dirs,
+            };
+            if let Err(e) = self.compare_dirs(prefix, a, b) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Reads the chunks sent by another thread, until the sender is dropped.
+struct ChannelReader {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// An entry for a tar member named `name`, with its metadata if known.
+fn tar_entry(name: Vec<u8>, meta: Option<&tar::Meta>) -> key::Entry {
+    key::Entry {
+        id: None,
+        parent_id: None,
+        name: name,
+        created: None,
+        modified: meta.map(|m| m.mtime as i64 * 1_000_000_000),
+        accessed: None,
+        permissions: meta.map(|m| m.mode as u64),
+        user_id: meta.map(|m| m.uid),
+        group_id: meta.map(|m| m.gid),
+        data_hash: None,
+        data_length: None,
+        link_target: None,
+        hardlink_of: None,
+        xattrs: None,
+        holes: None,
+    }
+}
+
+/// Split a path from a tar archive into its names, dropping empty and `.` names.
+fn tar_path_names(path: &[u8]) -> Result<Vec<&[u8]>, HatError> {
+    let names: Vec<&[u8]> =
+        path.split(|&b| b == b'/').filter(|n| !n.is_empty() && *n != b".").collect();
+    if names.iter().any(|n| *n == b"..") {
+        return Err(From::from(format!("Unsupported path in tar archive: {}",
+                                      String::from_utf8_lossy(path))));
+    }
+    Ok(names)
+}
+
+/// An entry of a directory listing, with the hash and reference of its contents, if any.
+pub type DirElem = (key::Entry, Option<(hash::Hash, blob::ChunkRef)>);
+
+/// The entries of a directory listing, decoded one chunk of the listing at a time.
+pub struct DirListing<HTB> {
+    // `None` once the listing is exhausted or failed.
+    tree: Option<hash::tree::ReaderResult<HTB>>,
+    entries: vec::IntoIter<DirElem>,
+}
+
+impl<HTB> Iterator for DirListing<HTB>
+    where HTB: hash::tree::HashTreeBackend<Err = key::MsgError>
+{
+    type Item = Result<DirElem, HatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(elem) = self.entries.next() {
+                return Some(Ok(elem));
+            }
+            let chunk = match self.tree {
+                Some(ref mut tree) => tree.try_next(),
+                None => return None,
+            };
+            match chunk {
+                Ok(Some(chunk)) => self.entries = parse_dir_chunk(&chunk[..]).into_iter(),
+                Ok(None) => {
+                    self.tree = None;
+                    return None;
+                }
+                Err(e) => {
+                    self.tree = None;
+                    return Some(Err(HatError::from_key_error(e)));
+                }
+            }
+        }
+    }
+}
+
+fn parse_dir_chunk(chunk: &[u8]) -> Vec<DirElem> {
+    let mut out = Vec::new();
+    if chunk.is_empty() {
+        return out;
+    }
+    let reader =
+        capnp::serialize_packed::read_message(&mut &chunk[..],
+                                              capnp::message::ReaderOptions::new())
+            .unwrap();
+
+    let list = reader.get_root::<root_capnp::file_list::Reader>().unwrap();
+    for f in list.get_files().unwrap().iter() {
+        if f.get_name().unwrap().len() == 0 {
+            // Empty entry at end.
+            // TODO(jos): Can we get rid of these?
+            break;
+        }
+        let entry = key::Entry {
+            id: Some(f.get_id()),
+            name: f.get_name().unwrap().to_owned(),
+            created: match f.get_created().which().unwrap() {
+                root_capnp::file::created::Unknown(()) => None,
+                root_capnp::file::created::Timestamp(ts) => Some(ts),
+            },
+            modified: match f.get_modified().which().unwrap() {
+                root_capnp::file::modified::Unknown(()) => None,
+                root_capnp::file::modified::Timestamp(ts) => Some(ts),
+            },
+            accessed: match f.get_accessed().which().unwrap() {
+                root_capnp::file::accessed::Unknown(()) => None,
+                root_capnp::file::accessed::Timestamp(ts) => Some(ts),
+            },
+            data_hash: match f.get_content().which().unwrap() {
+                root_capnp::file::content::Data(r) => {
+                    Some(r.unwrap().get_hash().unwrap().to_owned())
+                }
+                root_capnp::file::content::Directory(_) |
+                root_capnp::file::content::Symlink(_) |
+                root_capnp::file::content::Hardlink(_) => None,
+            },
+            permissions: match f.get_permissions().which().unwrap() {
+                root_capnp::file::permissions::Unknown(()) => None,
+                root_capnp::file::permissions::Mode(mode) => Some(mode as u64),
+            },
+            user_id: match f.get_user_id().which().unwrap() {
+                root_capnp::file::user_id::Unknown(()) => None,
+                root_capnp::file::user_id::Id(id) => Some(id as u64),
+            },
+            group_id: match f.get_group_id().which().unwrap() {
+                root_capnp::file::group_id::Unknown(()) => None,
+                root_capnp::file::group_id::Id(id) => Some(id as u64),
+            },
+            link_target: match f.get_content().which().unwrap() {
+                root_capnp::file::content::Symlink(target) => {
+                    Some(target.unwrap().to_owned())
+                }
+                _ => None,
+            },
+            hardlink_of: match f.get_content().which().unwrap() {
+                root_capnp::file::content::Hardlink(id) => Some(id),
+                _ => None,
+            },
+            xattrs: {
+                let attrs = f.get_extended_attributes().unwrap();
+                if attrs.len() == 0 {
+                    None
+                } else {
+                    Some(attrs.iter()
+                        .map(|a| {
+                            (a.get_name().unwrap().to_owned(),
+                             a.get_value().unwrap().to_owned())
+                        })
+                        .collect())
+                }
+            },
+            data_length: match f.get_data_length().which().unwrap() {
+                root_capnp::file::data_length::Unknown(()) => None,
+                root_capnp::file::data_length::Length(len) => Some(len),
+            },
+            holes: {
+                let holes = f.get_holes().unwrap();
+                if holes.len() == 0 {
+                    None
+                } else {
+                    Some(holes.iter().map(|h| (h.get_offset(), h.get_length())).collect())
+                }
+            },
+            // TODO(jos): Implement support for these remaining fields.
+            parent_id: None,
+        };
+        let hash_ref = match f.get_content().which().unwrap() {
+            root_capnp::file::content::Data(r) => Some(r.unwrap()),
+            root_capnp::file::content::Directory(d) => Some(d.unwrap()),
+            root_capnp::file::content::Symlink(_) |
+            root_capnp::file::content::Hardlink(_) => None,
+        };
+        let content = hash_ref.map(|r| {
+            (hash::Hash { bytes: r.get_hash().unwrap().to_owned() },
+             blob::ChunkRef::read_msg(&r.get_chunk_ref().unwrap()).unwrap())
+        });
+
+        out.push((entry, content));
+    }
+
+    out
+}
+
+/// How `Family::snapshot_direct_with` stores an entry. The defaults are those of
+/// `Family::snapshot_direct`.
+#[derive(Clone, Default)]
+pub struct SnapshotOptions {
+    /// Where to report progress as the contents are stored. Storing happens in the background;
+    /// all events have been sent once `Family::flush` returns.
+    pub progress: Option<ProgressSender>,
+    /// How to split the contents into chunks, e.g. large chunks for a database file. The profile
+    /// is not stored with the entry.
+    pub profile: key::ChunkProfile,
+    /// How to pack and encrypt the contents instead of as the family's store options say, e.g.
+    /// to store an archive that is already compressed and encrypted as it is. Chunks already
+    /// stored are reused as they are.
+    pub policy: Option<blob::ChunkPolicy>,
+    /// Fail with `HatError::Cancelled` once this token is cancelled. Contents still being read
+    /// when the token is cancelled are not stored, and the error is reported by the next call
+    /// into the family, e.g. `flush()`. A snapshot whose token was cancelled cannot be
+    /// committed: `Hat::commit` fails until it is discarded with `abort()`, which leaves its data
+    /// for `Hat::gc` to reclaim.
+    pub cancel: Option<CancelToken>,
+}
+
 pub struct Family<B> {
     pub name: String,
     pub key_store: key::Store<B>,
     pub key_store_process: Vec<key::StoreProcess<FileIterator, B>>,
+    pub dedup_stats: Arc<Mutex<key::DedupStats>>,
+    pub chunk_size_stats: Option<Arc<Mutex<key::ChunkSizeStats>>>,
+    /// IDs of the entries whose data changed while it was read; see `changed_entries()`.
+    pub changed_entries: Arc<Mutex<HashSet<u64>>>,
+    /// The snapshots in progress of the `Hat` this family was opened from.
+    pub snapshot_leases: Arc<SnapshotLeases>,
+    /// Bounds the source files open at once while snapshotting directories; shared with the
+    /// other families of the `Hat` (see `Hat::set_max_open_files`).
+    pub open_files: Semaphore,
+    /// Whether the family was opened from a read-only repository, and rejects snapshots.
+    pub read_only: bool,
+    /// The tokens passed to `snapshot_direct_with()` since the last commit or abort.
+    pub cancel_tokens: Arc<Mutex<Vec<CancelToken>>>,
+    /// Shared with the `Hat` this family was opened from, which counts its open families.
+    pub opened_from: Arc<()>,
 }
 impl<B: StoreBackend> Clone for Family<B> {
     fn clone(&self) -> Family<B> {
@@ -50,44 +458,355 @@ impl<B: StoreBackend> Clone for Family<B> {
             name: self.name.clone(),
             key_store: self.key_store.clone(),
             key_store_process: self.key_store_process.clone(),
+            dedup_stats: self.dedup_stats.clone(),
+            chunk_size_stats: self.chunk_size_stats.clone(),
+            changed_entries: self.changed_entries.clone(),
+            snapshot_leases: self.snapshot_leases.clone(),
+            open_files: self.open_files.clone(),
+            read_only: self.read_only,
+            cancel_tokens: self.cancel_tokens.clone(),
+            opened_from: self.opened_from.clone(),
         }
     }
 }
 
 impl<B: StoreBackend> Family<B> {
-    pub fn snapshot_dir(&self, dir: PathBuf) {
-        let handler = InsertPathHandler::new(self.key_store_process.clone());
+    /// Snapshot the tree below `dir`, skipping entries whose path relative to `dir` matches one of
+    /// the `exclude` patterns (see `util::Glob`). Excluded directories are not descended into.
+    pub fn snapshot_dir(&self, dir: PathBuf, exclude: &[String]) {
+        self.snapshot_dir_with_progress(dir, exclude, None, false)
+    }
+
+    /// Like `snapshot_dir()`, but reports progress to `progress` as the entries are stored.
+    ///
+    /// With `prescan`, the tree is walked once up front to count the entries and bytes to store,
+    /// which are reported first as `Progress::Total`; this allows showing a percentage or an ETA,
+    /// at the cost of an extra walk of the tree.
+    pub fn snapshot_dir_with_progress(&self,
+                                      dir: PathBuf,
+                                      exclude: &[String],
+                                      progress: Option<ProgressSender>,
+                                      prescan: bool) {
+        if let Err(e) = self.check_writable() {
+            error!("Not snapshotting {}: {}", dir.display(), e);
+            return;
+        }
+        info!("Snapshotting {} into family {}", dir.display(), self.name);
+        self.snapshot_leases.begin(&self.name);
+        let exclude = exclude.iter().map(|p| Glob::new(p)).collect();
+        let handler = InsertPathHandler::new(self.key_store_process.clone(), dir.clone(), exclude)
+            .with_progress(progress.clone())
+            .with_open_files(self.open_files.clone());
+        if prescan {
+            let (entries, bytes) = handler.scan();
+            progress::report(&progress,
+                             Progress::Total {
+                                 entries: entries,
+                                 bytes: bytes,
+                             });
+        }
         handler.recurse(PathBuf::from(&dir), None);
+        info!("Finished snapshotting {} into family {}", dir.display(), self.name);
     }
 
+    /// Snapshot `file` with the given contents. This may be called from several threads at once,
+    /// on clones of this family or on other families of the same `Hat`.
     pub fn snapshot_direct(&self,
                            file: key::Entry,
                            is_directory: bool,
                            contents: Option<FileIterator>)
                            -> Result<(), HatError> {
+        self.snapshot_direct_with(file, is_directory, contents, SnapshotOptions::default())
+    }
+
+    /// Like `snapshot_direct()`, but stores `file` as given by `options`.
+    pub fn snapshot_direct_with(&self,
+                                file: key::Entry,
+                                is_directory: bool,
+                                contents: Option<FileIterator>,
+                                options: SnapshotOptions)
+                                -> Result<(), HatError> {
+        let SnapshotOptions { progress, profile, policy, cancel } = options;
+        let contents = match cancel {
+            Some(cancel) => {
+                try!(cancel.check());
+                self.cancel_tokens.lock().unwrap().push(cancel.clone());
+                contents.map(|c| {
+                    FileIterator::from_reader(Box::new(CancelReader::new(c, cancel.clone())))
+                })
+            }
+            None => contents,
+        };
+
+        try!(self.check_writable());
+        self.snapshot_leases.begin(&self.name);
         let f = if is_directory {
             None
         } else {
             Some(Box::new(move |()| contents) as Box<FnBox<(), _>>)
         };
-        match try!(self.key_store_process[0].send_reply(key::Msg::Insert(file, f))) {
+        let msg = key::Msg::Insert(file, f, progress, profile, policy);
+        match try!(self.key_store_process[0].send_reply(msg).map_err(HatError::from_key_error)) {
             key::Reply::Id(..) => return Ok(()),
             _ => Err(From::from("Unexpected reply from key store")),
         }
     }
 
+    /// Snapshot the bytes of `stream` as a regular file called `name` in the family's root, e.g.
+    /// a database dump piped in. The stream is read until it ends; its length need not be known,
+    /// and is recorded once the stream is stored. The entry's modification time is the time of
+    /// this call, so a stream stored under the same name again is always read in full.
+    pub fn snapshot_stream<R>(&self, name: Vec<u8>, stream: R) -> Result<(), HatError>
+        where R: Read + Send + 'static
+    {
+        let now = time::get_time();
+        let file = key::Entry {
+            id: None,
+            parent_id: None,
+            name: name,
+            created: None,
+            modified: Some(now.sec * 1_000_000_000 + now.nsec as i64),
+            accessed: None,
+            permissions: None,
+            user_id: None,
+            group_id: None,
+            data_hash: None,
+            data_length: None,
+            link_target: None,
+            hardlink_of: None,
+            xattrs: None,
+            holes: None,
+        };
+        self.snapshot_direct(file, false, Some(FileIterator::from_reader(Box::new(stream))))
+    }
+
+    /// Store the bytes of `reader` as the data of the regular file `entry`, and return the root
+    /// hash of the data once it is stored, e.g. to read it back with `Hat::get_by_hash` without
+    /// going through a snapshot. Like other entries, the file is part of the family's next
+    /// snapshot, and keeps its stored data if it is unchanged since it was last stored (see
+    /// `snapshot_direct()`), in which case `reader` is not read.
+    pub fn put_file<R>(&self, entry: key::Entry, reader: R) -> Result<hash::Hash, HatError>
+        where R: Read + Send + 'static
+    {
+        let parent_id = entry.parent_id;
+        let name = entry.name.clone();
+        let contents = FileIterator::from_reader(Box::new(reader));
+        try!(self.snapshot_direct(entry, false, Some(contents)));
+
+        // The key store replies before the data is stored; wait for it.
+        try!(self.flush());
+        match try!(self.key_store.lookup(parent_id, name)) {
+            Some(key::Entry { data_hash: Some(bytes), .. }) => Ok(hash::Hash { bytes: bytes }),
+            _ => Err(From::from("File data was not stored")),
+        }
+    }
+
+    /// Snapshot the members of the tar archive read from `archive` into the family's root. Modes,
+    /// ownership and modification times are taken from the archive, and directories, symlinks
+    /// and hardlinks become entries of the same kind. Directories missing from the archive are
+    /// created without metadata. Devices and fifos are skipped.
+    ///
+    /// File data is handed to the key store as it is read, so members are never held in memory
+    /// in full.
+    pub fn import_tar<R: Read>(&self, archive: R) -> Result<(), HatError> {
+        try!(self.check_writable());
+        let mut archive = TarReader::new(archive);
+        let mut dirs: HashMap<Vec<u8>, u64> = HashMap::new();
+        let mut files: HashMap<Vec<u8>, u64> = HashMap::new();
+
+        while let Some(header) = try!(archive.next_entry()) {
+            let names = try!(tar_path_names(&header.path[..]));
+            let name = match names.last() {
+                Some(name) => name.to_vec(),
+                // The archive's root directory.
+                None => continue,
+            };
+            let path = names.join(&b'/');
+
+            // Find the parent, creating directories the archive does not list.
+            let mut parent_id = None;
+            for i in 1..names.len() {
+                let dir = names[..i].join(&b'/');
+                let id = match dirs.get(&dir) {
+                    Some(&id) => id,
+                    None => {
+                        let mut e = tar_entry(names[i - 1].to_vec(), None);
+                        e.parent_id = parent_id;
+                        try!(self.insert_entry(e, None))
+                    }
+                };
+                dirs.insert(dir, id);
+                parent_id = Some(id);
+            }
+
+            let mut entry = tar_entry(name, Some(&header.meta));
+            entry.parent_id = parent_id;
+            match header.kind {
+                tar::Kind::Dir => {
+                    let id = try!(self.insert_entry(entry, None));
+                    dirs.insert(path, id);
+                }
+                tar::Kind::Symlink => {
+                    entry.link_target = Some(header.link);
+                    try!(self.insert_entry(entry, None));
+                }
+                tar::Kind::Hardlink => {
+                    let target = try!(tar_path_names(&header.link[..])).join(&b'/');
+                    entry.hardlink_of = match files.get(&target) {
+                        Some(&id) => Some(id),
+                        None => {
+                            return Err(From::from(format!("Hardlink '{}' refers to unknown \
+                                                           file '{}'",
+                                                          String::from_utf8_lossy(&path[..]),
+                                                          String::from_utf8_lossy(&target[..]))))
+                        }
+                    };
+                    try!(self.insert_entry(entry, None));
+                }
+                tar::Kind::File => {
+                    entry.data_length = Some(header.size);
+                    let (sender, receiver) = mpsc::sync_channel(16);
+                    let reader = ChannelReader {
+                        chunks: receiver,
+                        chunk: vec![],
+                        pos: 0,
+                    };
+                    let contents = FileIterator::from_reader(Box::new(reader));
+                    let id = try!(self.insert_entry(entry, Some(contents)));
+
+                    let mut buf = vec![0; 64 * 1024];
+                    loop {
+                        let n = try!(archive.read(&mut buf[..]));
+                        // The key store drops the reader if it already has the data.
+                        if n == 0 || sender.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    drop(sender);
+                    files.insert(path, id);
+                }
+                tar::Kind::Other(_) => {
+                    warn!("Skipping '{}': not a file, directory or link",
+                          String::from_utf8_lossy(&path[..]));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Fail if the family was opened from a read-only repository.
+    fn check_writable(&self) -> Result<(), HatError> {
+        if self.read_only {
+            return Err(From::from(format!("Family {} is opened read-only", self.name)));
+        }
+        Ok(())
+    }
+
+    /// Insert `entry` into the key store, with `contents` if it is a regular file. Returns the
+    /// entry's id once it is known; the contents are read afterwards.
+    fn insert_entry(&self,
+                    entry: key::Entry,
+                    contents: Option<FileIterator>)
+                    -> Result<u64, HatError> {
+        self.snapshot_leases.begin(&self.name);
+        let f = contents.map(|c| Box::new(move |()| Some(c)) as Box<FnBox<(), _>>);
+        let msg = key::Msg::Insert(entry, f, None, key::ChunkProfile::default(), None);
+        match try!(self.key_store_process[0].send_reply(msg)) {
+            key::Reply::Id(id) => Ok(id),
+            _ => Err(From::from("Unexpected reply from key store")),
+        }
+    }
+
+    /// How well the file data read for the snapshot in progress was deduplicated, i.e. since this
+    /// family was opened or its last snapshot was committed or aborted, which start them over.
+    /// Files that were not read again (e.g. by an incremental family) are not counted.
+    pub fn dedup_stats(&self) -> key::DedupStats {
+        *self.dedup_stats.lock().unwrap()
+    }
+
+    /// The entries stored since this family was opened whose data changed while it was read: the
+    /// file's size or modification time after reading differs from what was recorded up front.
+    /// Their stored data may be inconsistent, e.g. for a database written to meanwhile. An entry
+    /// is no longer listed once its data is read again without changing.
+    pub fn changed_entries(&self) -> Result<Vec<key::Entry>, HatError> {
+        let changed = self.changed_entries.lock().unwrap().clone();
+        let mut entries = vec![];
+        if changed.is_empty() {
+            return Ok(entries);
+        }
+        let mut dirs = vec![None];
+        while let Some(dir_id) = dirs.pop() {
+            for (entry, _, _) in try!(self.list_from_key_store(dir_id)) {
+                if entry.is_directory() {
+                    dirs.push(entry.id);
+                } else if entry.id.map_or(false, |id| changed.contains(&id)) {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The sizes of the chunks written since this family was opened, if the hat was set to
+    /// record them (see `Hat::set_chunk_size_stats`).
+    pub fn chunk_size_stats(&self) -> Option<key::ChunkSizeStats> {
+        self.chunk_size_stats.as_ref().map(|stats| stats.lock().unwrap().clone())
+    }
+
+    /// Wait until all data snapshotted through this family is stored and indexed. Families being
+    /// snapshotted concurrently are not waited for.
     pub fn flush(&self) -> Result<(), HatError> {
+        try!(self.check_writable());
+        for ks in &self.key_store_process {
+            let reply = try!(ks.send_reply(key::Msg::Flush).map_err(HatError::from_key_error));
+            if let key::Reply::FlushOk = reply {
+                continue;
+            }
+            return Err(From::from("Unexpected reply from key store"));
+        }
+        Ok(())
+    }
+
+    /// Make the files snapshotted so far durable, so that a snapshot interrupted later on skips
+    /// them when restarted. This also happens periodically while snapshotting.
+    pub fn checkpoint(&self) -> Result<(), HatError> {
+        try!(self.check_writable());
+        for ks in &self.key_store_process {
+            if let key::Reply::FlushOk = try!(ks.send_reply(key::Msg::Checkpoint)) {
+                continue;
+            }
+            return Err(From::from("Unexpected reply from key store"));
+        }
+        Ok(())
+    }
+
+    /// Discard the snapshot in progress, whether or not it was flushed, as long as it was not
+    /// committed. The data stored for it is left unreferenced for `Hat::gc` to reclaim, and the
+    /// next snapshot of this family starts from scratch.
+    pub fn abort(&self) -> Result<(), HatError> {
+        try!(self.check_writable());
+        // All stores share the key index, so none may still be inserting when it is cleared.
+        try!(self.flush());
         for ks in &self.key_store_process {
-            if let key::Reply::FlushOk = try!(ks.send_reply(key::Msg::Flush)) {
+            if let key::Reply::FlushOk = try!(ks.send_reply(key::Msg::Abort)) {
                 continue;
             }
             return Err(From::from("Unexpected reply from key store"));
         }
+        self.snapshot_leases.end(&self.name);
+        self.cancel_tokens.lock().unwrap().clear();
+        *self.dedup_stats.lock().unwrap() = key::DedupStats::default();
         Ok(())
     }
 
-  pub fn write_file_chunks<HTB: hash::tree::HashTreeBackend<Err=key::MsgError>>(
-    &self, fd: &mut fs::File, tree: hash::tree::ReaderResult<HTB>)
+    /// Whether the snapshot in progress was cancelled, so that some of its contents may be
+    /// missing (see `SnapshotOptions::cancel`).
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_tokens.lock().unwrap().iter().any(|t| t.is_cancelled())
+    }
+
+  pub fn write_file_chunks<W: Write, HTB: hash::tree::HashTreeBackend<Err=key::MsgError>>(
+    &self, fd: &mut W, tree: hash::tree::ReaderResult<HTB>)
   {
         for chunk in tree {
             try_a_few_times_then_panic(|| fd.write_all(&chunk[..]).is_ok(),
@@ -100,23 +819,43 @@ impl<B: StoreBackend> Family<B> {
                            output_dir: PathBuf,
                            dir_id: Option<u64>)
                            -> Result<(), HatError> {
+        let mut links = HardLinks::new();
+        try!(self.checkout_in_dir_with_links(output_dir, dir_id, &mut links));
+        links.finish()
+    }
+
+    fn checkout_in_dir_with_links(&self,
+                                  output_dir: PathBuf,
+                                  dir_id: Option<u64>,
+                                  links: &mut HardLinks)
+                                  -> Result<(), HatError> {
         let mut path = output_dir;
         for (entry, _ref, read_fn_opt) in try!(self.list_from_key_store(dir_id)).into_iter() {
             // Extend directory with filename:
             path.push(str::from_utf8(&entry.name[..]).unwrap());
 
             match read_fn_opt {
+                None if entry.link_target.is_some() => {
+                    let target = entry.link_target.as_ref().unwrap();
+                    try!(unix_fs::symlink(OsStr::from_bytes(&target[..]), &path));
+                }
+                None if entry.hardlink_of.is_some() => {
+                    links.link(path.clone(), entry.hardlink_of.unwrap());
+                }
                 None => {
                     // This is a directory, recurse!
                     fs::create_dir_all(&path).unwrap();
-                    try!(self.checkout_in_dir(path.clone(), entry.id));
+                    try!(self.checkout_in_dir_with_links(path.clone(), entry.id, links));
                 }
                 Some(read_fn) => {
                     // This is a file, write it
-                    let mut fd = fs::File::create(&path).unwrap();
+                    let fd = fs::File::create(&path).unwrap();
+                    let mut out = SparseWriter::new(fd, entry.holes.clone());
                     if let Some(tree) = try!(read_fn.init()) {
-                        self.write_file_chunks(&mut fd, tree);
+                        self.write_file_chunks(&mut out, tree);
                     }
+                    try!(out.finish());
+                    links.file(entry.id.unwrap(), path.clone());
                 }
             }
 
@@ -127,6 +866,26 @@ impl<B: StoreBackend> Family<B> {
         Ok(())
     }
 
+    /// Compare the snapshot trees rooted at `a` and `b`, yielding entries that were added,
+    /// removed or changed going from `a` to `b`.
+    pub fn diff<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>(&self,
+                                                                  backend: HTB,
+                                                                  a: (hash::Hash, blob::ChunkRef),
+                                                                  b: (hash::Hash, blob::ChunkRef))
+                                                                  -> Diff<B, HTB> {
+        let dirs = if a.0 == b.0 {
+            vec![]
+        } else {
+            vec![(vec![], Some(a), Some(b))]
+        };
+        Diff {
+            family: self.clone(),
+            backend: backend,
+            dirs: dirs,
+            ready: VecDeque::new(),
+        }
+    }
+
     pub fn list_from_key_store(&self,
                                dir_id: Option<u64>)
                                -> Result<Vec<key::DirElem<B>>, HatError> {
@@ -136,82 +895,60 @@ impl<B: StoreBackend> Family<B> {
         }
     }
 
+    /// Count the entries in the key store, which the next commit makes a snapshot of, and the
+    /// bytes of data in their files.
+    pub fn summary(&self) -> Result<SnapshotSummary, HatError> {
+        let mut summary = SnapshotSummary::default();
+        let changed = self.changed_entries.lock().unwrap().clone();
+        let mut dirs = vec![None];
+        while let Some(dir_id) = dirs.pop() {
+            for (entry, _, _) in try!(self.list_from_key_store(dir_id)) {
+                summary.entries += 1;
+                if entry.is_file() {
+                    summary.bytes += entry.data_length.unwrap_or(0);
+                    if entry.id.map_or(false, |id| changed.contains(&id)) {
+                        summary.changed += 1;
+                    }
+                } else if entry.is_directory() {
+                    dirs.push(entry.id);
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// The entries of a directory, read from its listing in the backend. Prefer `dir_listing()`
+    /// for directories that may be large.
     pub fn fetch_dir_data<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>
         (&self,
          dir_hash: &hash::Hash,
          dir_ref: blob::ChunkRef,
          backend: HTB)
-         -> Result<Vec<(key::Entry, hash::Hash, blob::ChunkRef)>, HatError> {
+         -> Result<Vec<DirElem>, HatError> {
         let mut out = Vec::new();
-        let it = try!(hash::tree::SimpleHashTreeReader::open(backend, dir_hash, Some(dir_ref)))
-            .expect("unable to open dir");
-
-        for chunk in it {
-            if chunk.is_empty() {
-                continue;
-            }
-            let reader =
-                capnp::serialize_packed::read_message(&mut &chunk[..],
-                                                      capnp::message::ReaderOptions::new())
-                    .unwrap();
-
-            let list = reader.get_root::<root_capnp::file_list::Reader>().unwrap();
-            for f in list.get_files().unwrap().iter() {
-                if f.get_name().unwrap().len() == 0 {
-                    // Empty entry at end.
-                    // TODO(jos): Can we get rid of these?
-                    break;
-                }
-                let entry = key::Entry {
-                    id: Some(f.get_id()),
-                    name: f.get_name().unwrap().to_owned(),
-                    created: match f.get_created().which().unwrap() {
-                        root_capnp::file::created::Unknown(()) => None,
-                        root_capnp::file::created::Timestamp(ts) => Some(ts),
-                    },
-                    modified: match f.get_modified().which().unwrap() {
-                        root_capnp::file::modified::Unknown(()) => None,
-                        root_capnp::file::modified::Timestamp(ts) => Some(ts),
-                    },
-                    accessed: match f.get_accessed().which().unwrap() {
-                        root_capnp::file::accessed::Unknown(()) => None,
-                        root_capnp::file::accessed::Timestamp(ts) => Some(ts),
-                    },
-                    data_hash: match f.get_content().which().unwrap() {
-                        root_capnp::file::content::Data(r) => {
-                            Some(r.unwrap().get_hash().unwrap().to_owned())
-                        }
-                        root_capnp::file::content::Directory(_) => None,
-                    },
-                    // TODO(jos): Implement support for these remaining fields.
-                    user_id: None,
-                    group_id: None,
-                    permissions: None,
-                    data_length: None,
-                    parent_id: None,
-                };
-                let hash = match f.get_content().which().unwrap() {
-                    root_capnp::file::content::Data(r) => r.unwrap().get_hash().unwrap().to_owned(),
-                    root_capnp::file::content::Directory(d) => {
-                        d.unwrap().get_hash().unwrap().to_owned()
-                    }
-                };
-                let pref = match f.get_content().which().unwrap() {
-                    root_capnp::file::content::Data(r) => {
-                        blob::ChunkRef::read_msg(&r.unwrap().get_chunk_ref().unwrap()).unwrap()
-                    }
-                    root_capnp::file::content::Directory(d) => {
-                        blob::ChunkRef::read_msg(&d.unwrap().get_chunk_ref().unwrap()).unwrap()
-                    }
-                };
-
-                out.push((entry, hash::Hash { bytes: hash }, pref));
-            }
+        for elem in try!(self.dir_listing(dir_hash, dir_ref, backend)) {
+            out.push(try!(elem));
         }
-
         Ok(out)
     }
 
+    /// Like `fetch_dir_data()`, but reads the listing one chunk at a time as it is iterated, so
+    /// that only a bounded part of a large directory is held in memory.
+    pub fn dir_listing<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>
+        (&self,
+         dir_hash: &hash::Hash,
+         dir_ref: blob::ChunkRef,
+         backend: HTB)
+         -> Result<DirListing<HTB>, HatError> {
+        let tree = try!(hash::tree::SimpleHashTreeReader::open(backend, dir_hash, Some(dir_ref))
+                .map_err(HatError::from_key_error))
+            .expect("unable to open dir");
+        Ok(DirListing {
+            tree: Some(tree),
+            entries: Vec::new().into_iter(),
+        })
+    }
+
     pub fn commit(&mut self,
                   hash_ch: &mpsc::Sender<hash::Hash>)
                   -> Result<(hash::Hash, blob::ChunkRef), HatError> {
@@ -226,6 +963,7 @@ impl<B: StoreBackend> Family<B> {
                           dir_id: Option<u64>,
                           hash_ch: &mpsc::Sender<hash::Hash>)
                           -> Result<(), HatError> {
+        try!(self.check_writable());
 
         let files_at_a_time = 1024;
         let mut it = try!(self.list_from_key_store(dir_id)).into_iter();
@@ -264,6 +1002,45 @@ impl<B: StoreBackend> Family<B> {
                         Some(ts) => file_msg.borrow().init_accessed().set_timestamp(ts),
                     }
 
+                    match entry.permissions {
+                        None => file_msg.borrow().init_permissions().set_unknown(()),
+                        Some(mode) => file_msg.borrow().init_permissions().set_mode(mode as u32),
+                    }
+
+                    match entry.user_id {
+                        None => file_msg.borrow().init_user_id().set_unknown(()),
+                        Some(id) => file_msg.borrow().init_user_id().set_id(id as u32),
+                    }
+
+                    match entry.group_id {
+                        None => file_msg.borrow().init_group_id().set_unknown(()),
+                        Some(id) => file_msg.borrow().init_group_id().set_id(id as u32),
+                    }
+
+                    match entry.data_length {
+                        None => file_msg.borrow().init_data_length().set_unknown(()),
+                        Some(len) => file_msg.borrow().init_data_length().set_length(len),
+                    }
+
+                    if let Some(ref xattrs) = entry.xattrs {
+                        let mut attrs =
+                            file_msg.borrow().init_extended_attributes(xattrs.len() as u32);
+                        for (i, (name, value)) in xattrs.iter().enumerate() {
+                            let mut attr = attrs.borrow().get(i as u32);
+                            attr.set_name(&name[..]);
+                            attr.set_value(&value[..]);
+                        }
+                    }
+
+                    if let Some(ref holes) = entry.holes {
+                        let mut list = file_msg.borrow().init_holes(holes.len() as u32);
+                        for (i, &(offset, length)) in holes.iter().enumerate() {
+                            let mut hole = list.borrow().get(i as u32);
+                            hole.set_offset(offset);
+                            hole.set_length(length);
+                        }
+                    }
+
                     if let Some(hash_bytes) = entry.data_hash {
                         // This is a file, store its data hash:
                         let mut hash_ref_msg = capnp::message::Builder::new_default();
@@ -272,13 +1049,28 @@ impl<B: StoreBackend> Family<B> {
 
                         // Populate data hash and ChunkRef.
                         hash_ref_root.set_hash(&hash_bytes);
-                        data_ref.expect("has data")
-                            .populate_msg(hash_ref_root.borrow().init_chunk_ref());
+                        match data_ref {
+                            Some(data_ref) => {
+                                data_ref.populate_msg(hash_ref_root.borrow().init_chunk_ref())
+                            }
+                            // An empty file has no data, and keeps an empty ChunkRef.
+                            None => {
+                                hash_ref_root.borrow().init_chunk_ref();
+                            }
+                        }
                         // Set as file content.
                         try!(file_msg.borrow()
                             .init_content()
                             .set_data(hash_ref_root.as_reader()));
-                        hash_ch.send(hash::Hash { bytes: hash_bytes }).unwrap();
+                        if !hash_bytes.is_empty() {
+                            hash_ch.send(hash::Hash { bytes: hash_bytes }).unwrap();
+                        }
+                    } else if let Some(target) = entry.link_target {
+                        drop(data_ref);  // Symlinks have no data.
+                        file_msg.borrow().init_content().set_symlink(&target[..]);
+                    } else if let Some(id) = entry.hardlink_of {
+                        drop(data_ref);  // The data is stored with the linked entry.
+                        file_msg.borrow().init_content().set_hardlink(id);
                     } else {
                         drop(data_ref);  // May not use data reference without hash.
 
@@ -0,0 +1,143 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stream the contents of a single file out of a committed snapshot.
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use blob;
+use hash;
+use hash::tree::{HashTreeBackend, ReaderResult, SimpleHashTreeReader};
+use key;
+
+
+fn io_error<E: fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+}
+
+/// A file's contents, fetched chunk by chunk from the backend as they are read.
+///
+/// Seeking skips the chunks before the new position without fetching them. As the stored length
+/// of a chunk (in its `ChunkRef`) is that of the packed and encrypted data, this relies on the
+/// chunk's recorded raw length, or else on it being `key::MAX_CHUNK_LEN` bytes long.
+pub struct FileReader<B> {
+    backend: B,
+    hash: hash::Hash,
+    persistent_ref: blob::ChunkRef,
+    tree: Option<ReaderResult<B>>,
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+    pos: u64,
+}
+
+impl<B: HashTreeBackend> FileReader<B> {
+    pub fn new(backend: B,
+               hash: hash::Hash,
+               persistent_ref: blob::ChunkRef)
+               -> Result<FileReader<B>, B::Err> {
+        let tree = try!(SimpleHashTreeReader::open(backend.clone(),
+                                                   &hash,
+                                                   Some(persistent_ref.clone())));
+        Ok(FileReader {
+            backend: backend,
+            hash: hash,
+            persistent_ref: persistent_ref,
+            tree: tree,
+            chunk: Vec::new(),
+            chunk_pos: 0,
+            pos: 0,
+        })
+    }
+
+    fn seek_to(&mut self, pos: u64) -> io::Result<u64> {
+        if pos == self.pos {
+            return Ok(pos);
+        }
+
+        // Start over from the first chunk, as the tree can only be read forwards.
+        self.tree = try!(SimpleHashTreeReader::open(self.backend.clone(),
+                                                    &self.hash,
+                                                    Some(self.persistent_ref.clone()))
+            .map_err(io_error));
+        self.chunk = Vec::new();
+        self.chunk_pos = 0;
+
+        let chunk_len = key::MAX_CHUNK_LEN as u64;
+        self.pos = match self.tree {
+            None => 0,
+            Some(ref mut tree) => try!(tree.skip_bytes(pos, chunk_len).map_err(io_error)),
+        };
+
+        // Read up to the position within its chunk.
+        let mut rest = pos - self.pos;
+        let mut buf = vec![0; cmp::min(rest, chunk_len) as usize];
+        while rest > 0 {
+            let len = cmp::min(rest, buf.len() as u64) as usize;
+            match try!(self.read(&mut buf[..len])) {
+                0 => break,
+                n => rest -= n as u64,
+            }
+        }
+        Ok(self.pos)
+    }
+}
+
+impl<B: HashTreeBackend> Read for FileReader<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk_pos == self.chunk.len() {
+            let next = match self.tree {
+                None => None,
+                Some(ref mut tree) => try!(tree.try_next().map_err(io_error)),
+            };
+            match next {
+                None => return Ok(0),
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.chunk_pos = 0;
+                }
+            }
+        }
+
+        let len = cmp::min(buf.len(), self.chunk.len() - self.chunk_pos);
+        buf[..len].copy_from_slice(&self.chunk[self.chunk_pos..self.chunk_pos + len]);
+        self.chunk_pos += len;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<B: HashTreeBackend> Seek for FileReader<B> {
+    /// Seeking relative to the end is not supported, as the file size is not stored.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) if offset >= 0 => self.pos.checked_add(offset as u64),
+            SeekFrom::Current(offset) => self.pos.checked_sub(offset.wrapping_neg() as u64),
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Seeking from the end of a stored file is not \
+                                           supported"));
+            }
+        };
+        match target {
+            Some(target) => self.seek_to(target),
+            None => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "Invalid seek to a negative or overflowing position"))
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recreate hardlinks when writing a snapshot back to disk.
+//!
+//! A hardlinked entry refers to the id of the entry holding its data, which may be written
+//! before or after the link itself. Links are therefore collected while the tree is written, and
+//! created once all files are in place.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use errors::HatError;
+
+#[derive(Default)]
+pub struct HardLinks {
+    files: HashMap<u64, PathBuf>,
+    pending: Vec<(PathBuf, u64)>,
+    // With `on_demand()`: the ids that links have asked for so far.
+    wanted: Option<HashSet<u64>>,
+}
+
+impl HardLinks {
+    pub fn new() -> HardLinks {
+        HardLinks::default()
+    }
+
+    /// Like `new()`, but only remember the files that links have already asked for, so that
+    /// memory use does not grow with the number of files written. Links to files written before
+    /// them are left for `unresolved()`.
+    pub fn on_demand() -> HardLinks {
+        HardLinks { wanted: Some(HashSet::new()), ..HardLinks::default() }
+    }
+
+    /// Remember that the data of entry `id` was written to `path`.
+    pub fn file(&mut self, id: u64, path: PathBuf) {
+        let remember = match self.wanted {
+            Some(ref wanted) => wanted.contains(&id),
+            None => true,
+        };
+        if remember {
+            self.files.insert(id, path);
+        }
+    }
+
+    /// Request `path` to be a hardlink to the file written for entry `id`.
+    pub fn link(&mut self, path: PathBuf, id: u64) {
+        if let Some(ref mut wanted) = self.wanted {
+            wanted.insert(id);
+        }
+        self.pending.push((path, id));
+    }
+
+    /// The ids that links refer to whose files are not known, e.g. because they were written
+    /// before the first link to them. Report their paths with `file()` before calling `finish()`.
+    pub fn unresolved(&self) -> HashSet<u64> {
+        self.pending.iter().map(|&(_, id)| id).filter(|id| !self.files.contains_key(id)).collect()
+    }
+
+    /// Create all requested links.
+    pub fn finish(self) -> Result<(), HatError> {
+        for &(ref path, id) in &self.pending {
+            try!(self.create_link(path, id));
+        }
+        Ok(())
+    }
+
+    /// Like `finish()`, but create as many of the requested links as possible. Returns the links
+    /// that could not be created.
+    pub fn finish_partial(self) -> Vec<(PathBuf, HatError)> {
+        let mut failed = vec![];
+        for &(ref path, id) in &self.pending {
+            if let Err(e) = self.create_link(path, id) {
+                failed.push((path.clone(), e));
+            }
+        }
+        failed
+    }
+
+    fn create_link(&self, path: &PathBuf, id: u64) -> Result<(), HatError> {
+        match self.files.get(&id) {
+            Some(original) => Ok(try!(fs::hard_link(original, path))),
+            None => {
+                Err(From::from(format!("Hardlink '{}' refers to missing entry {}",
+                                       path.display(),
+                                       id)))
+            }
+        }
+    }
+}
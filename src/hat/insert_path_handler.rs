@@ -12,18 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::io;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 use std::str;
 use std::sync::{Mutex, atomic};
+use libc;
 use time;
 
 use backend::StoreBackend;
 use key;
-use util::{FileIterator, PathHandler, SyncPool};
+use progress::ProgressSender;
+use util::{FileIterator, Glob, PathHandler, Semaphore, SyncPool};
+use util::{sparse, xattr};
+
+/// Combine the two parts of a stat timestamp into nanoseconds since the epoch.
+fn timestamp_nanos(secs: i64, nsecs: i64) -> i64 {
+    secs * 1_000_000_000 + nsecs
+}
 
 struct FileEntry {
     key_entry: key::Entry,
@@ -33,7 +43,10 @@ struct FileEntry {
 }
 
 impl FileEntry {
-    fn new(full_path: PathBuf, parent: Option<u64>) -> Result<FileEntry, Box<Error>> {
+    fn new(full_path: PathBuf,
+           parent: Option<u64>,
+           open_files: &Option<Semaphore>)
+           -> Result<FileEntry, Box<Error>> {
         debug!("FileEntry::new({:?})", full_path);
 
         let filename_opt =
@@ -41,20 +54,46 @@ impl FileEntry {
 
         if filename_opt.is_some() {
             let md = try!(fs::symlink_metadata(&full_path));
-            let link_path = fs::read_link(&full_path).ok();
+            let link_path = if md.file_type().is_symlink() {
+                Some(try!(fs::read_link(&full_path)))
+            } else {
+                None
+            };
+            // Filesystems without extended attribute support have none to record.
+            let xattrs = match xattr::list(&full_path) {
+                Ok(ref attrs) if attrs.is_empty() => None,
+                Ok(attrs) => Some(attrs),
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => None,
+                Err(e) => return Err(From::from(e)),
+            };
+            // Only files taking up less space than their size can have holes.
+            let holes = if md.file_type().is_file() && md.blocks() * 512 < md.len() {
+                let _permit = open_files.as_ref().map(|s| s.acquire());
+                let fd = try!(fs::File::open(&full_path));
+                try!(sparse::holes(&fd, md.len()))
+            } else {
+                None
+            };
             Ok(FileEntry {
                 key_entry: key::Entry {
                     name: filename_opt.unwrap(),
-                    created: Some(md.ctime_nsec()),
-                    modified: Some(md.mtime_nsec()),
-                    accessed: Some(md.atime_nsec()),
+                    created: Some(timestamp_nanos(md.ctime(), md.ctime_nsec())),
+                    modified: Some(timestamp_nanos(md.mtime(), md.mtime_nsec())),
+                    accessed: Some(timestamp_nanos(md.atime(), md.atime_nsec())),
                     parent_id: parent,
-                    data_length: Some(md.len()),
+                    data_length: match link_path {
+                        Some(_) => None,
+                        None => Some(md.len()),
+                    },
                     data_hash: None,
+                    link_target: link_path.as_ref().map(|p| p.as_os_str().as_bytes().to_vec()),
+                    hardlink_of: None,
+                    xattrs: xattrs,
+                    holes: holes,
                     id: None,
-                    permissions: None,
-                    user_id: None,
-                    group_id: None,
+                    permissions: Some(md.mode() as u64),
+                    user_id: Some(md.uid() as u64),
+                    group_id: Some(md.gid() as u64),
                 },
                 metadata: md,
                 full_path: full_path,
@@ -77,14 +116,81 @@ pub struct InsertPathHandler<B: StoreBackend> {
     count: atomic::AtomicIsize,
     last_print: Mutex<time::Timespec>,
     key_store: SyncPool<key::StoreProcess<FileIterator, B>>,
+    // Key entry ids of hardlinked files seen so far, by (device, inode).
+    inodes: Mutex<HashMap<(u64, u64), u64>>,
+    root: PathBuf,
+    exclude: Vec<Glob>,
+    // Senders are not `Sync`, so each insert clones its own.
+    progress: Mutex<Option<ProgressSender>>,
+    // Bounds the files open at once to read their data, if set.
+    open_files: Option<Semaphore>,
 }
 
 impl<B: StoreBackend> InsertPathHandler<B> {
-    pub fn new(key_stores: Vec<key::StoreProcess<FileIterator, B>>) -> InsertPathHandler<B> {
+    pub fn new(key_stores: Vec<key::StoreProcess<FileIterator, B>>,
+               root: PathBuf,
+               exclude: Vec<Glob>)
+               -> InsertPathHandler<B> {
         InsertPathHandler {
             count: atomic::AtomicIsize::new(0),
             last_print: Mutex::new(time::now().to_timespec()),
             key_store: SyncPool::new(key_stores),
+            inodes: Mutex::new(HashMap::new()),
+            root: root,
+            exclude: exclude,
+            progress: Mutex::new(None),
+            open_files: None,
+        }
+    }
+
+    /// Report the progress of storing each entry to `progress`.
+    pub fn with_progress(self, progress: Option<ProgressSender>) -> InsertPathHandler<B> {
+        *self.progress.lock().unwrap() = progress;
+        self
+    }
+
+    /// Take a permit from `open_files` for each file opened, and hold it until the file is
+    /// closed. Files beyond the limit wait for a permit rather than fail to open.
+    pub fn with_open_files(mut self, open_files: Semaphore) -> InsertPathHandler<B> {
+        self.open_files = Some(open_files);
+        self
+    }
+
+    /// Count the entries below the root that `recurse` would store, and the bytes of data in
+    /// their files, without storing anything. The data of a hardlinked file is counted once.
+    pub fn scan(&self) -> (u64, u64) {
+        let (mut entries, mut bytes) = (0, 0);
+        let mut inodes = HashSet::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let listing = match fs::read_dir(&dir) {
+                Ok(listing) => listing,
+                Err(_) => continue,  // Skipped when snapshotting as well.
+            };
+            for path in listing.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+                if self.is_excluded(&path) {
+                    continue;
+                }
+                let md = match fs::symlink_metadata(&path) {
+                    Ok(md) => md,
+                    Err(_) => continue,
+                };
+                entries += 1;
+                if md.is_dir() {
+                    dirs.push(path);
+                } else if md.file_type().is_file() &&
+                          (md.nlink() <= 1 || inodes.insert((md.dev(), md.ino()))) {
+                    bytes += md.len();
+                }
+            }
+        }
+        (entries, bytes)
+    }
+
+    fn is_excluded(&self, path: &PathBuf) -> bool {
+        match path.strip_prefix(&self.root).ok().and_then(|p| p.to_str()) {
+            Some(relative) => self.exclude.iter().any(|g| g.matches(relative.as_bytes())),
+            None => false,
         }
     }
 }
@@ -98,6 +204,11 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
     }
 
     fn handle_path(&self, parent: &Option<u64>, path: &PathBuf) -> Option<Option<u64>> {
+        if self.is_excluded(path) {
+            debug!("Excluding '{}'", path.display());
+            return None;
+        }
+
         let count = self.count.fetch_add(1, atomic::Ordering::SeqCst) + 1;
 
         if count % 16 == 0 {
@@ -110,25 +221,45 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
             }
         }
 
-        match FileEntry::new(path.clone(), parent.clone()) {
+        match FileEntry::new(path.clone(), parent.clone(), &self.open_files) {
             Err(e) => {
                 println!("Skipping '{}': {}", path.display(), e);
             }
-            Ok(file_entry) => {
-                if file_entry.is_symlink() {
-                    return None;
-                }
+            Ok(mut file_entry) => {
+                // Symlinks are recorded with their target and never followed.
+                let mut has_data = !file_entry.is_directory() && !file_entry.is_symlink();
                 let is_directory = file_entry.is_directory();
+
+                // Only the first path of a hardlinked file stores its data; the others refer to
+                // it. The inode map stays locked until the first path has its id.
+                let mut inodes = None;
+                if has_data && file_entry.metadata.nlink() > 1 {
+                    let inode = (file_entry.metadata.dev(), file_entry.metadata.ino());
+                    let guard = self.inodes.lock().unwrap();
+                    let first_id = guard.get(&inode).cloned();
+                    match first_id {
+                        Some(id) => {
+                            file_entry.key_entry.hardlink_of = Some(id);
+                            file_entry.key_entry.data_length = None;
+                            file_entry.key_entry.holes = None;
+                            has_data = false;
+                        }
+                        None => inodes = Some((inode, guard)),
+                    }
+                }
+
                 let local_root = path.clone();
                 let full_path = file_entry.full_path.clone();
+                let open_files = self.open_files.clone();
 
                 let ks = self.key_store.lock().unwrap();
                 match ks.send_reply(key::Msg::Insert(file_entry.key_entry,
-                                                     if is_directory {
+                                                     if !has_data {
                                                          None
                                                      } else {
                                                          Some(Box::new(move |()| {
-                        match FileIterator::new(&full_path) {
+                        let permit = open_files.as_ref().map(|s| s.acquire());
+                        match FileIterator::with_permit(&full_path, permit) {
                             Err(e) => {
                                 println!("Skipping '{}': {}", local_root.display(), e.to_string());
                                 None
@@ -136,8 +267,14 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
                             Ok(it) => Some(it),
                         }
                     }))
-                                                     })) {
+                                                     },
+                                                     self.progress.lock().unwrap().clone(),
+                                                     key::ChunkProfile::default(),
+                                                     None)) {
                     Ok(key::Reply::Id(id)) => {
+                        if let Some((inode, mut guard)) = inodes {
+                            guard.insert(inode, id);
+                        }
                         if is_directory {
                             return Some(Some(id));
                         }
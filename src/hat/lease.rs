@@ -0,0 +1,66 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Track the snapshots in progress, so that gc can spare the data they stored before commit.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use hash;
+
+
+/// The snapshots in progress of the families of a `Hat`, by family name. Each holds a lease on the
+/// hashes reserved since it began storing data, given by the hash ID it started at. Older hashes
+/// they reuse are pinned in the hash index (see `hash::HashIndex::delete_unpinned`) until no
+/// snapshot is in progress.
+///
+/// A lease is taken when a family first stores an entry for a new snapshot, and released when the
+/// snapshot is committed or aborted. Leases are only kept in memory: the data of a snapshot
+/// whose process is gone is reclaimed like any other unreferenced data.
+pub struct SnapshotLeases {
+    hash_index: Arc<hash::HashIndex>,
+    started: Mutex<HashMap<String, i64>>,
+}
+
+impl SnapshotLeases {
+    pub fn new(hash_index: Arc<hash::HashIndex>) -> SnapshotLeases {
+        SnapshotLeases {
+            hash_index: hash_index,
+            started: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a lease for the snapshot in progress of `family`, unless it already holds one.
+    pub fn begin(&self, family: &str) {
+        let mut started = self.started.lock().unwrap();
+        if !started.contains_key(family) {
+            started.insert(family.to_owned(), self.hash_index.last_id() + 1);
+        }
+    }
+
+    /// Release the lease of `family`, once its snapshot is committed or aborted.
+    pub fn end(&self, family: &str) {
+        let mut started = self.started.lock().unwrap();
+        started.remove(family);
+        if started.is_empty() {
+            self.hash_index.unpin_all();
+        }
+    }
+
+    /// The first hash ID leased by any snapshot in progress.
+    pub fn oldest(&self) -> Option<i64> {
+        self.started.lock().unwrap().values().cloned().min()
+    }
+}
@@ -12,28 +12,65 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
 use std::fs;
-use std::path::PathBuf;
-use std::str;
-use std::sync::{Arc, mpsc};
+use std::io::Write;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
+use std::time::{Duration, Instant};
 use capnp;
+use filetime;
+#[cfg(feature = "mount")]
+use fuse;
+use libc;
+use scoped_pool;
 use void::Void;
 
 use backend::StoreBackend;
 use blob;
-use errors::HatError;
+use crypto::{self, authed, kdf};
+use errors::{CancelledError, HatError};
 use gc::{self, Gc, GcRc};
 use hash;
 use key;
+use metrics::{Metrics, NoMetrics};
+use progress::{self, Progress, ProgressSender};
 use root_capnp;
 use snapshot;
 use tags;
-use util::Process;
+use util::{CancelToken, Durability, IndexOptions, Process, Semaphore};
+use util::sqlite;
+use util::sparse::SparseWriter;
+use util::tar::{self, TarWriter};
+use util::xattr;
 
+mod entries;
 mod family;
+mod file_reader;
+mod hardlinks;
 mod insert_path_handler;
-use self::family::Family;
+mod lease;
+#[cfg(feature = "mount")]
+mod mount;
+mod retention;
+mod session;
+use self::family::{DirElem, Family};
+use self::hardlinks::HardLinks;
+pub use self::entries::SnapshotEntries;
+pub use self::family::{Diff, DiffEntry, DiffKind, DirListing, SnapshotOptions};
+pub use self::file_reader::FileReader;
+pub use self::retention::RetentionPolicy;
+pub use self::session::AppendSession;
+pub use key::{ChunkProfile, ChunkSizeStats, DedupStats, Entry};
+pub use snapshot::Selector as SnapshotSelector;
+#[cfg(feature = "mount")]
+pub use self::mount::SnapshotFs;
 
 #[cfg(test)]
 mod tests;
@@ -100,6 +137,13 @@ impl gc::GcBackend for GcBackend {
         Ok(receiver)
     }
 
+    fn list_all_ids(&self) -> Result<mpsc::Receiver<i64>, Self::Err> {
+        let (sender, receiver) = mpsc::channel();
+        self.hash_index.list_ids().iter().map(|i| sender.send(*i)).last();
+
+        Ok(receiver)
+    }
+
     fn manual_commit(&mut self) -> Result<(), Self::Err> {
         self.hash_index.manual_commit();
         Ok(())
@@ -109,17 +153,293 @@ impl gc::GcBackend for GcBackend {
 
 pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     repository_root: Option<PathBuf>,
+    index_options: IndexOptions,
     snapshot_index: snapshot::SnapshotIndex,
     hash_index: Arc<hash::HashIndex>,
     backend: Arc<B>,
     blob_index: Arc<blob::BlobIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     blob_max_size: usize,
+    hasher: Arc<hash::Hasher>,
+    metrics: Arc<Metrics>,
+    chunk_size_stats: bool,
+    hash_threads: usize,
+    tree_order: usize,
+    tree_spill: Option<(PathBuf, usize)>,
+    quota: Option<u64>,
+    pre_commit_hook: Option<PreCommitHook>,
+    snapshot_leases: Arc<lease::SnapshotLeases>,
+    gc_safety_window: bool,
+    open_files: Semaphore,
+    unchanged_commit: UnchangedCommit,
+    master_key: Option<crypto::FixedKey>,
+    // Cloned into each family opened, to count those still open.
+    open_families: Arc<()>,
+    // The threads of the key stores of the families opened, which `close()` waits for.
+    key_store_threads: Mutex<Vec<thread::JoinHandle<()>>>,
     gc: G,
 }
 
 pub type HatRc<B> = Hat<B, GcRc<GcBackend>>;
 
+/// Name of the blob holding the master key derivation parameters.
+const KDF_PARAMS_NAME: &'static str = "kdf_params";
+
+/// The number of source files open at once while snapshotting, unless configured otherwise
+/// with `Hat::set_max_open_files`.
+pub const DEFAULT_MAX_OPEN_FILES: usize = 256;
+
+/// A snapshot found by `Hat::recover` to reference blobs the backend no longer has.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DanglingSnapshot {
+    pub family_name: String,
+    pub snapshot_id: i64,
+    /// The referenced blobs that are missing.
+    pub missing_blobs: Vec<Vec<u8>>,
+    /// Whether part of the snapshot's listing could not be read, so that it may reference more
+    /// missing blobs than were found.
+    pub unreadable: bool,
+}
+
+/// The outcome of `Hat::recover`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RecoverReport {
+    /// Number of snapshots recovered into the index.
+    pub recovered: usize,
+    /// Snapshots referencing missing blobs.
+    pub dangling: Vec<DanglingSnapshot>,
+    /// Number of dangling snapshots left out of the index.
+    pub quarantined: usize,
+}
+
+/// The outcome of `Hat::rebuild_index_from_backend`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RebuildReport {
+    /// Number of blobs found in the backend and entered into the blob index.
+    pub blobs: usize,
+    /// Number of chunks listed by those blobs.
+    pub chunks: usize,
+    /// Blobs whose chunk references could not be read; they are left out of the index.
+    pub unparseable: Vec<Vec<u8>>,
+    /// The snapshots recovered from the backend afterwards.
+    pub recover: RecoverReport,
+}
+
+impl RecoverReport {
+    /// Whether every snapshot was recovered with all of its data.
+    pub fn is_complete(&self) -> bool {
+        self.dangling.is_empty()
+    }
+}
+
+/// The outcome of `Hat::verify`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    /// Number of chunks read back and checked.
+    pub checked: usize,
+    /// Number of chunks that could not be read or did not match their hash.
+    pub failed: usize,
+    /// The blob id and offset of every failed chunk.
+    pub mismatches: Vec<(Vec<u8>, usize)>,
+}
+
+/// How `Hat::restore_with` restores a snapshot. The defaults are those of `Hat::restore`.
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    /// Fail with `HatError::Cancelled` once this token is cancelled. Files restored so far are
+    /// left in place; the file being written may be incomplete.
+    pub cancel: CancelToken,
+    /// Log and skip an entry that cannot be restored (e.g. because a blob holding its data is
+    /// missing or damaged) instead of failing the whole restore. A file that fails part way is
+    /// removed. The skipped entries are listed in the returned `RestoreReport`.
+    pub skip_failed: bool,
+}
+
+/// The outcome of `Hat::restore_with`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RestoreReport {
+    /// The path and error of every entry that could not be restored. The entries of a directory
+    /// whose listing could not be read are not listed on their own.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl RestoreReport {
+    /// Whether every entry of the snapshot was restored.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// The outcome of `Hat::gc_with`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    /// Number of unused hashes deleted.
+    pub hashes_deleted: i64,
+    /// Number of hashes still referencing a stored chunk.
+    pub live: i64,
+    /// Number of hash entries scanned while marking used blobs.
+    pub chunks_scanned: usize,
+    /// Total size of the deleted hashes' chunks in blobs that were deleted.
+    pub bytes_reclaimed: u64,
+    /// Number of blobs deleted because no hash references them anymore.
+    pub blobs_emptied: usize,
+    /// Number of deleted blobs holding only chunks never committed by any snapshot, e.g. left
+    /// behind by an aborted snapshot.
+    pub blobs_orphaned: usize,
+    /// Number of deleted blobs holding chunks of snapshots that have since been deregistered.
+    pub blobs_dead: usize,
+    /// Number of blobs kept because they also hold chunks of live hashes.
+    pub blobs_partially_live: usize,
+    /// Time spent finding unused hashes and marking used blobs.
+    pub mark_time: Duration,
+    /// Time spent deleting hashes and blobs.
+    pub sweep_time: Duration,
+}
+
+/// How `Hat::gc_with` collects garbage. The defaults are those of `Hat::gc`.
+#[derive(Clone)]
+pub struct GcOptions {
+    /// Mark and sweep in batches of at most this many entries, flushing progress after each
+    /// batch. A run that was interrupted is continued by the next call.
+    pub batch_size: usize,
+    /// Where to report deleted hashes and blobs.
+    pub progress: Option<ProgressSender>,
+    /// Fail with `HatError::Cancelled` once this token is cancelled. The run stops after its
+    /// current batch, and the next call continues where it left off.
+    pub cancel: CancelToken,
+    /// Find the hashes still in use with up to this many threads, walking the trees of
+    /// independent snapshots concurrently. The result is the same as with one.
+    pub workers: usize,
+}
+
+impl Default for GcOptions {
+    fn default() -> GcOptions {
+        GcOptions {
+            batch_size: usize::max_value(),
+            progress: None,
+            cancel: CancelToken::new(),
+            workers: 1,
+        }
+    }
+}
+
+/// What `Hat::gc_dry_run` found `Hat::gc` would delete.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcDryRun {
+    /// Number of unused hashes.
+    pub hashes: i64,
+    /// Total size of the chunks stored for the unused hashes.
+    pub bytes: u64,
+}
+
+/// The outcome of `Hat::compact`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompactStats {
+    /// Number of blobs whose live chunks were moved before deleting them.
+    pub blobs_compacted: usize,
+    /// Number of live chunks moved to new blobs.
+    pub chunks_moved: usize,
+    /// Total size of the moved chunks.
+    pub bytes_moved: u64,
+}
+
+/// A snapshot as listed by `Hat::list_snapshots`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SnapshotListing {
+    pub family_name: String,
+    pub snapshot_id: i64,
+    /// When the snapshot was committed, in nanoseconds since the epoch.
+    pub commit_time: Option<i64>,
+    /// The snapshot's root hash, once its tree has been written.
+    pub hash: Option<Vec<u8>>,
+    /// False while a commit, recovery or deletion of the snapshot is unfinished.
+    pub committed: bool,
+    /// The label given with `Hat::commit_with_label`, if any.
+    pub label: Option<String>,
+}
+
+/// A version of a file, as listed by `Hat::history`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileVersion {
+    pub snapshot_id: i64,
+    /// The root hash of the file's data in this snapshot. Empty for an empty file.
+    pub data_hash: hash::Hash,
+    /// The length of the file's data, if recorded.
+    pub size: Option<u64>,
+    /// The file's modification time in nanoseconds since the epoch, if recorded.
+    pub modified: Option<i64>,
+}
+
+/// What a commit is about to make a snapshot of, as given to the pre-commit hook.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SnapshotSummary {
+    /// Number of files, directories and links.
+    pub entries: u64,
+    /// Total length of the files' data.
+    pub bytes: u64,
+    /// Number of files whose data changed while it was read; see `Family::changed_entries`.
+    pub changed: u64,
+}
+
+/// Validates a snapshot before it is committed; see `Hat::set_pre_commit_hook`.
+pub type PreCommitHook = Box<FnMut(&SnapshotSummary) -> Result<(), HatError> + Send>;
+
+/// A snapshot just committed by `Hat::commit`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CommittedSnapshot {
+    /// The id to select the snapshot by, e.g. with `Hat::deregister`.
+    pub snapshot_id: i64,
+    /// The label given with `Hat::commit_with_label`.
+    pub label: Option<String>,
+    /// Whether nothing changed since the family's previous committed snapshot, i.e. both have the
+    /// same root hash. With `UnchangedCommit::Skip`, this is the previous snapshot.
+    pub unchanged: bool,
+}
+
+/// What committing a snapshot does when nothing changed since the family's previous committed
+/// snapshot; see `Hat::set_unchanged_commit`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnchangedCommit {
+    /// Commit a new snapshot all the same, as an alias of the previous one: it shares all of its
+    /// data, so no new blobs are stored. The default.
+    Alias,
+    /// Commit nothing, and return the previous snapshot instead. The new snapshot's metadata is
+    /// written to compare its root hash before anything is reserved for it; when unchanged, it
+    /// is the previous snapshot's metadata, so nothing new is stored.
+    Skip,
+}
+
+/// A snapshot written by `Hat::prepare_commit`, but not yet committed. Pass it on to
+/// `Hat::finalize_commit` to commit it, or to `Hat::abort_commit` to drop it.
+#[derive(Debug)]
+pub struct PreparedCommit {
+    family_name: String,
+    // The reserved snapshot and its root hash, or `None` if it is skipped as unchanged, in which
+    // case `committed` is the previous snapshot.
+    reserved: Option<(snapshot::Info, hash::Hash)>,
+    committed: CommittedSnapshot,
+}
+
+impl PreparedCommit {
+    /// The snapshot that `Hat::finalize_commit` will commit.
+    pub fn snapshot(&self) -> &CommittedSnapshot {
+        &self.committed
+    }
+}
+
+// Fail unless `prepared` was prepared for `family`.
+fn check_prepared_family<B: StoreBackend>(family: &Family<B>,
+                                          prepared: &PreparedCommit)
+                                          -> Result<(), HatError> {
+    if prepared.family_name != family.name {
+        return Err(From::from(format!("Snapshot prepared for family {} cannot be committed \
+                                       to family {}",
+                                      prepared.family_name,
+                                      family.name)));
+    }
+    Ok(())
+}
+
 fn concat_filename(mut a: PathBuf, b: &str) -> String {
     a.push(b);
     a.into_os_string().into_string().unwrap()
@@ -137,6 +457,68 @@ fn hash_index_name(root: PathBuf) -> String {
     concat_filename(root, "hash_index.sqlite3")
 }
 
+fn nanos_to_filetime(ts: i64) -> filetime::FileTime {
+    let secs = ts.wrapping_div(1_000_000_000);
+    let nanos = ts.wrapping_rem(1_000_000_000);
+    if nanos < 0 {
+        filetime::FileTime::from_seconds_since_1970((secs - 1) as u64,
+                                                    (nanos + 1_000_000_000) as u32)
+    } else {
+        filetime::FileTime::from_seconds_since_1970(secs as u64, nanos as u32)
+    }
+}
+
+/// Reapply the recorded metadata of `entry` to the restored file at `path`.
+// Fail unless `name` is a plain name within its directory, so that a damaged or forged listing
+// cannot make a restore write outside of its output directory.
+fn check_entry_name(name: &[u8]) -> Result<(), HatError> {
+    if name.is_empty() || name == &b"."[..] || name == &b".."[..] || name.contains(&b'/') {
+        return Err(From::from(format!("Invalid entry name in listing: {:?}",
+                                      String::from_utf8_lossy(name))));
+    }
+    Ok(())
+}
+
+fn restore_metadata(path: &Path, entry: &key::Entry) -> Result<(), HatError> {
+    if entry.user_id.is_some() || entry.group_id.is_some() {
+        let md = try!(fs::symlink_metadata(path));
+        let uid = entry.user_id.map(|id| id as u32).unwrap_or(md.uid());
+        let gid = entry.group_id.map(|id| id as u32).unwrap_or(md.gid());
+        if uid != md.uid() || gid != md.gid() {
+            let c_path = try!(CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| "Path contains a NUL byte"));
+            if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+                // Changing owner usually requires privileges we may not have.
+                warn!("Could not restore owner of '{}'", path.display());
+            }
+        }
+    }
+
+    // Before permissions, as a read-only mode would prevent setting attributes.
+    if let Some(ref xattrs) = entry.xattrs {
+        for (name, value) in xattrs.iter() {
+            if let Err(e) = xattr::set(path, &name[..], &value[..]) {
+                // Namespaces like "security." and "trusted." may require privileges.
+                warn!("Could not restore extended attribute {} of '{}': {}",
+                      String::from_utf8_lossy(&name[..]),
+                      path.display(),
+                      e);
+            }
+        }
+    }
+
+    if let Some(mode) = entry.permissions {
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(mode as u32 & 0o7777)));
+    }
+
+    if let Some(mtime) = entry.modified {
+        let atime = entry.accessed.unwrap_or(mtime);
+        try!(filetime::set_file_times(path, nanos_to_filetime(atime), nanos_to_filetime(mtime)));
+    }
+
+    Ok(())
+}
+
 struct SnapshotLister<'a, B: StoreBackend> {
     backend: &'a key::HashStoreBackend<B>,
     family: &'a Family<B>,
@@ -147,7 +529,16 @@ struct SnapshotLister<'a, B: StoreBackend> {
 impl<'a, B: StoreBackend> SnapshotLister<'a, B> {
     fn fetch(&mut self, hash: &hash::Hash, chunk: blob::ChunkRef) -> Result<(), HatError> {
         let res = try!(self.family.fetch_dir_data(hash, chunk, self.backend.clone()));
-        for (entry, hash, pref) in res.into_iter().rev() {
+        for (entry, content) in res.into_iter().rev() {
+            // Symlinks have no hashes.
+            let (hash, pref) = match content {
+                Some(content) => content,
+                None => continue,
+            };
+            // Empty files have no data to list.
+            if hash.bytes.is_empty() {
+                continue;
+            }
             if entry.data_hash.is_some() {
                 self.queue.push((hash, None));
             } else {
@@ -191,17 +582,87 @@ fn list_snapshot<'a, B: StoreBackend>(backend: &'a key::HashStoreBackend<B>,
     }
 }
 
+// Whether the chunk at `pref` can be read back and matches `hash`.
+fn verify_chunk<B: StoreBackend>(blob_store: &blob::BlobStore<B>,
+                                 hash: &hash::Hash,
+                                 pref: &blob::ChunkRef)
+                                 -> bool {
+    match blob_store.retrieve_uncached(hash, pref) {
+        Ok(data) => hash.verify(&data[..]),
+        Err(blob::BlobError::NotFound(_)) => false,
+        Err(e) => {
+            warn!("Could not read chunk from blob {:?}: {}", pref.blob_id, e);
+            false
+        }
+    }
+}
+
+// See `Hat::derive_master_key()`; the parameters are only stored if `writable`.
+fn derive_master_key_with<B: StoreBackend>(blob_store: &blob::BlobStore<B>,
+                                           writable: bool,
+                                           passphrase: &[u8],
+                                           params: kdf::Params)
+                                           -> Result<authed::desc::Key, HatError> {
+    let params = match try!(blob_store.retrieve_named_unsealed(KDF_PARAMS_NAME)
+        .map_err(HatError::from_blob_error)) {
+        Some(bytes) => try!(kdf::Params::from_bytes(&bytes[..])),
+        None => {
+            if !writable {
+                return Err(From::from("Repository is opened read-only"));
+            }
+            try!(blob_store.store_named_unsealed(KDF_PARAMS_NAME, &params.to_bytes()[..])
+                .map_err(HatError::from_blob_error));
+            params
+        }
+    };
+    Ok(try!(kdf::derive_key(passphrase, &params)))
+}
+
 impl<B: StoreBackend> HatRc<B> {
     pub fn open_repository(repository_root: PathBuf,
                            backend: Arc<B>,
                            max_blob_size: usize)
                            -> Result<HatRc<B>, HatError> {
+        HatRc::open_repository_with_options(repository_root,
+                                            backend,
+                                            max_blob_size,
+                                            IndexOptions::default())
+    }
+
+    /// Like `open_repository()`, but for tools that only read, such as browsing and verifying:
+    /// the local indexes are opened read-only (see `IndexOptions::read_only`), so this can run
+    /// alongside a backup writing to the same repository. Anything that would change the
+    /// repository (snapshots, commits, deregistering and gc) fails instead.
+    pub fn open_repository_read_only(repository_root: PathBuf,
+                                     backend: Arc<B>,
+                                     max_blob_size: usize)
+                                     -> Result<HatRc<B>, HatError> {
+        let options = IndexOptions { read_only: true, ..IndexOptions::default() };
+        HatRc::open_repository_with_options(repository_root, backend, max_blob_size, options)
+    }
+
+    /// Like `open_repository()`, opening the local indexes below `repository_root` with
+    /// `index_options`. The options also apply to the indexes of families opened later.
+    pub fn open_repository_with_options(repository_root: PathBuf,
+                                        backend: Arc<B>,
+                                        max_blob_size: usize,
+                                        index_options: IndexOptions)
+                                        -> Result<HatRc<B>, HatError> {
+        try!(blob::check_max_blob_size(max_blob_size));
+        if let Some(ref key) = index_options.master_key {
+            try!(sqlite::unseal_dir(&repository_root, &sqlite::index_key(key)));
+        }
         let snapshot_index_path = snapshot_index_name(repository_root.clone());
         let blob_index_path = blob_index_name(repository_root.clone());
         let hash_index_path = hash_index_name(repository_root.clone());
-        let si_p = try!(snapshot::SnapshotIndex::new(&snapshot_index_path));
-        let bi_p = Arc::new(try!(blob::BlobIndex::new(&blob_index_path)));
-        let hi_p = Arc::new(try!(hash::HashIndex::new(&hash_index_path)));
+        // Another process may hold the indexes; report it as `HatError::IndexLocked`.
+        let si_p = try!(snapshot::SnapshotIndex::with_options(&snapshot_index_path,
+                                                              &index_options)
+            .map_err(HatError::from_diesel_error));
+        let bi_p = Arc::new(try!(blob::BlobIndex::with_options(&blob_index_path, &index_options)
+            .map_err(HatError::from_diesel_error)));
+        let hi_p = Arc::new(try!(hash::HashIndex::with_options(&hash_index_path, &index_options)
+            .map_err(HatError::from_diesel_error)));
 
         let bs_p = Arc::new(blob::BlobStore::new(bi_p.clone(), backend.clone(), max_blob_size));
 
@@ -210,23 +671,45 @@ impl<B: StoreBackend> HatRc<B> {
 
         let mut hat = Hat {
             repository_root: Some(repository_root),
+            index_options: index_options,
             snapshot_index: si_p,
             hash_index: hi_p.clone(),
             backend: backend,
             blob_index: bi_p,
             blob_store: bs_p,
             blob_max_size: max_blob_size,
+            hasher: Arc::new(hash::Blake2b),
+            metrics: Arc::new(NoMetrics),
+            chunk_size_stats: false,
+            hash_threads: 0,
+            tree_order: hash::tree::DEFAULT_ORDER,
+            tree_spill: None,
+            quota: None,
+            pre_commit_hook: None,
+            snapshot_leases: Arc::new(lease::SnapshotLeases::new(hi_p)),
+            gc_safety_window: false,
+            open_files: Semaphore::new(DEFAULT_MAX_OPEN_FILES),
+            unchanged_commit: UnchangedCommit::Alias,
+            master_key: None,
+            open_families: Arc::new(()),
+            key_store_threads: Mutex::new(Vec::new()),
             gc: gc,
         };
+        if let Some(key) = hat.index_options.master_key.clone() {
+            hat.set_master_key(&key);
+        }
 
-        // Resume any unfinished commands.
-        try!(hat.resume());
+        // Resume any unfinished commands, unless they are left to whoever is writing.
+        if !hat.index_options.read_only {
+            try!(hat.resume());
+        }
 
         Ok(hat)
     }
 
     #[cfg(test)]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<HatRc<B>, HatError> {
+        try!(blob::check_max_blob_size(max_blob_size));
         let si_p = snapshot::SnapshotIndex::new_for_testing().unwrap();
         let bi_p = Arc::new(blob::BlobIndex::new_for_testing().unwrap());
         let hi_p = Arc::new(hash::HashIndex::new_for_testing().unwrap());
@@ -238,11 +721,27 @@ impl<B: StoreBackend> HatRc<B> {
 
         let mut hat = Hat {
             repository_root: None,
+            index_options: IndexOptions::default(),
             snapshot_index: si_p,
-            hash_index: hi_p,
+            hash_index: hi_p.clone(),
             blob_index: bi_p,
             blob_store: bs_p,
             blob_max_size: max_blob_size,
+            hasher: Arc::new(hash::Blake2b),
+            metrics: Arc::new(NoMetrics),
+            chunk_size_stats: false,
+            hash_threads: 0,
+            tree_order: hash::tree::DEFAULT_ORDER,
+            tree_spill: None,
+            quota: None,
+            pre_commit_hook: None,
+            snapshot_leases: Arc::new(lease::SnapshotLeases::new(hi_p)),
+            gc_safety_window: false,
+            open_files: Semaphore::new(DEFAULT_MAX_OPEN_FILES),
+            unchanged_commit: UnchangedCommit::Alias,
+            master_key: None,
+            open_families: Arc::new(()),
+            key_store_threads: Mutex::new(Vec::new()),
             backend: backend,
             gc: gc,
         };
@@ -253,11 +752,194 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(hat)
     }
 
+    /// Hash chunks stored from now on with `hasher` (BLAKE2b by default). Chunks hashed with
+    /// other algorithms remain readable, but new chunks are only deduplicated against chunks
+    /// hashed with the same algorithm. Fails for a hasher whose hashes could be mistaken for
+    /// BLAKE2b hashes (see `Hash::with_hasher`).
+    pub fn set_hasher(&mut self, hasher: Arc<hash::Hasher>) -> Result<(), HatError> {
+        try!(hash::Hash::with_hasher(&*hasher, &[]));
+        self.hasher = hasher;
+        Ok(())
+    }
+
+    /// Report the bytes stored and retrieved, chunks deduplicated and blobs deleted to `metrics`
+    /// from now on. Families opened before are not affected.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.blob_store.set_metrics(metrics.clone());
+        self.metrics = metrics;
+    }
+
+    /// Record the sizes of the chunks written by families opened from now on, for
+    /// `Family::chunk_size_stats`. Off by default.
+    pub fn set_chunk_size_stats(&mut self, enabled: bool) {
+        self.chunk_size_stats = enabled;
+    }
+
+    /// Hash the file data of families opened from now on on `threads` threads per family, while
+    /// reading ahead a bounded number of chunks. With 0 (the default) each chunk is hashed by the
+    /// thread reading it. Either way the same hashes and trees result.
+    pub fn set_hash_threads(&mut self, threads: usize) {
+        self.hash_threads = threads;
+    }
+
+    /// Write the hash-trees of families opened from now on with `order` children per node
+    /// (`hash::tree::DEFAULT_ORDER` by default). Wider trees suit very large files, as fewer tree
+    /// nodes must be read to restore them. Existing trees are read the same whatever the order.
+    pub fn set_tree_order(&mut self, order: usize) {
+        assert!(order >= 2);
+        self.tree_order = order;
+    }
+
+    /// Move the references held for each level of the hash-trees written by families opened from
+    /// now on to files in `dir`, once more than `threshold` of them are in memory. This bounds
+    /// the memory used to write trees of a large order (see `set_tree_order`). The files are
+    /// removed as each tree level is completed, or when a snapshot is aborted.
+    pub fn set_tree_spill(&mut self, dir: PathBuf, threshold: usize) {
+        assert!(threshold >= 1);
+        self.tree_spill = Some((dir, threshold));
+    }
+
+    /// Keep up to `entries` recently seen chunk hashes in memory (`hash::DEFAULT_CACHE_SIZE` by
+    /// default), so that snapshotting unchanged data mostly avoids looking its chunks up in the
+    /// hash index. Hashes not in the cache are always looked up, so this never affects which
+    /// chunks are deduplicated. 0 disables the cache.
+    pub fn set_hash_cache_size(&mut self, entries: usize) {
+        self.hash_index.set_cache_size(entries);
+    }
+
+    /// Limit the total size of the stored blobs to `quota` bytes while snapshotting families
+    /// opened from now on, or lift the limit with `None` (the default). A family opened with its
+    /// own `StoreOptions::quota` is held to the smaller of the two.
+    ///
+    /// Storing data beyond the quota fails the snapshot with `HatError::Quota`. Discard it with
+    /// `Family::abort()`, after which `gc` reclaims the data that was stored for it.
+    pub fn set_quota(&mut self, quota: Option<u64>) {
+        self.quota = quota;
+    }
+
+    /// Run `hook` on the summary of each new snapshot before it is committed, or stop with
+    /// `None`. An error from the hook aborts the commit, without reserving a snapshot; the data
+    /// already stored for it is left for `gc` to reclaim. Resumed commits are not checked again.
+    pub fn set_pre_commit_hook(&mut self, hook: Option<PreCommitHook>) {
+        self.pre_commit_hook = hook;
+    }
+
+    /// Let `gc` spare the data of snapshots still in progress, from when their family first stored
+    /// an entry for them until they are committed or aborted. Off by default, in which case only
+    /// data stored while `gc` runs is spared, and uncommitted data stored before is reclaimed.
+    ///
+    /// This makes it safe to run `gc` while families of this hat are snapshotting. Older chunks
+    /// that a snapshot in progress stores again are spared too, until no snapshot is in progress.
+    pub fn set_gc_safety_window(&mut self, enabled: bool) {
+        self.gc_safety_window = enabled;
+    }
+
+    /// Keep at most `max` source files open at once while families opened from now on snapshot
+    /// directories (`DEFAULT_MAX_OPEN_FILES` by default). The limit is shared by all these
+    /// families, whatever the number of threads reading files; files beyond it wait for another
+    /// to be closed. Keep it well below the process' limit on open files (`ulimit -n`). `max` must
+    /// be at least 1.
+    pub fn set_max_open_files(&mut self, max: usize) {
+        self.open_files = Semaphore::new(max);
+    }
+
+    /// Choose what committing a new snapshot does when nothing changed since the family's
+    /// previous committed snapshot (`UnchangedCommit::Alias` by default). Either way the returned
+    /// `CommittedSnapshot` tells if nothing changed. Snapshots reserved before their commit, i.e.
+    /// labeled or resumed ones, are always committed.
+    pub fn set_unchanged_commit(&mut self, unchanged: UnchangedCommit) {
+        self.unchanged_commit = unchanged;
+    }
+
+    /// Derive the master key from `passphrase`; pass it on to `set_master_key()` to use it.
+    ///
+    /// The salt and costs of the derivation are stored in the backend the first time (taken from
+    /// `params`) and read back from it afterwards, so that the same passphrase gives the same key
+    /// on any machine with access to the backend.
+    pub fn derive_master_key(&self,
+                             passphrase: &[u8],
+                             params: kdf::Params)
+                             -> Result<authed::desc::Key, HatError> {
+        derive_master_key_with(&self.blob_store,
+                               !self.index_options.read_only,
+                               passphrase,
+                               params)
+    }
+
+    /// Like `derive_master_key()`, but before the repository is opened, e.g. to get the
+    /// `IndexOptions::master_key` to open it with.
+    pub fn derive_master_key_from(backend: Arc<B>,
+                                  max_blob_size: usize,
+                                  passphrase: &[u8],
+                                  params: kdf::Params)
+                                  -> Result<authed::desc::Key, HatError> {
+        try!(blob::check_max_blob_size(max_blob_size));
+        // Named blobs are not listed in the blob index.
+        let blob_index = Arc::new(try!(blob::BlobIndex::new(":memory:")
+            .map_err(HatError::from_diesel_error)));
+        let blob_store = blob::BlobStore::new(blob_index, backend, max_blob_size);
+        derive_master_key_with(&blob_store, true, passphrase, params)
+    }
+
+    /// Seal the blobs stored from now on with `key` (see `derive_master_key()`) instead of the
+    /// built-in key: the footer of each blob, which holds the keys of its chunks, and the list of
+    /// snapshots. Set it right after opening the repository, as blobs sealed with one key cannot
+    /// be read with another. Families opened before are not affected.
+    pub fn set_master_key(&mut self, key: &authed::desc::Key) {
+        let key = crypto::FixedKey::from_master_key(key);
+        self.blob_store.set_master_key(key.clone());
+        self.master_key = Some(key);
+    }
+
     pub fn hash_tree_writer(&self) -> hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>> {
-        hash::tree::SimpleHashTreeWriter::new(8, self.hash_backend())
+        let writer = hash::tree::SimpleHashTreeWriter::new(self.tree_order, self.hash_backend());
+        match self.tree_spill {
+            Some((ref dir, threshold)) => writer.with_spill(dir.clone(), threshold),
+            None => writer,
+        }
     }
 
+    /// Open the family `name`.
+    ///
+    /// Several families can be snapshotted and flushed from different threads at the same time,
+    /// and each family can be shared between threads by cloning it. Their data is deduplicated
+    /// against each other through the shared hash index, which reserves a new chunk for its first
+    /// writer; later writers reuse its reference. Do not open the same family more than once at a
+    /// time (each open family has its own connection to the family's key index). Running `gc`
+    /// while snapshots are in progress deletes data that is not yet committed, unless it is made
+    /// safe with `set_gc_safety_window`.
     pub fn open_family(&self, name: String) -> Result<Family<B>, HatError> {
+        self.open_family_with_options(name, Default::default())
+    }
+
+    /// Open a family whose file data is written with the given blob store options (e.g. packing,
+    /// compression level and maximum blob size).
+    pub fn open_family_with_options(&self,
+                                    name: String,
+                                    options: blob::StoreOptions)
+                                    -> Result<Family<B>, HatError> {
+        self.open_family_impl(name, options, false)
+    }
+
+    /// Open a family whose snapshots reuse the data of files with unchanged modification time
+    /// and size, without reading them again (see `key::Store::incremental`).
+    pub fn open_incremental_family(&self, name: String) -> Result<Family<B>, HatError> {
+        self.open_family_impl(name, Default::default(), true)
+    }
+
+    fn open_family_impl(&self,
+                        name: String,
+                        options: blob::StoreOptions,
+                        incremental: bool)
+                        -> Result<Family<B>, HatError> {
+        let max_blob_size = options.max_blob_size.unwrap_or(self.blob_max_size);
+        try!(blob::check_max_blob_size(max_blob_size));
+        let mut options = options;
+        options.quota = match (options.quota, self.quota) {
+            (Some(own), Some(quota)) => Some(cmp::min(own, quota)),
+            (own, quota) => own.or(quota),
+        };
+
         // We setup a standard pipeline of processes:
         // key::Store -> key::Index
         //            -> hash::Index
@@ -268,28 +950,78 @@ impl<B: StoreBackend> HatRc<B> {
             None => ":memory:".to_string(),
         };
 
-        let ki_p = Arc::new(try!(key::KeyIndex::new(&key_index_path)));
+        let ki_p = Arc::new(try!(key::KeyIndex::with_options(&key_index_path,
+                                                             &self.index_options)));
 
         let ks = key::Store::new(ki_p.clone(),
                                  self.hash_index.clone(),
-                                 self.blob_store.clone());
+                                 self.blob_store.clone())
+            .with_hasher(self.hasher.clone())
+            .with_tree_order(self.tree_order);
+        let ks = match self.tree_spill {
+            Some((ref dir, threshold)) => ks.with_tree_spill(dir.clone(), threshold),
+            None => ks,
+        };
 
+        let dedup_stats = Arc::new(Mutex::new(key::DedupStats::default()));
+        let changed_entries = Arc::new(Mutex::new(HashSet::new()));
+        let chunk_size_stats = if self.chunk_size_stats {
+            Some(Arc::new(Mutex::new(key::ChunkSizeStats::default())))
+        } else {
+            None
+        };
+        let hash_pool = if self.hash_threads > 0 {
+            Some(Arc::new(hash::HashPool::new(self.hasher.clone(), self.hash_threads)))
+        } else {
+            None
+        };
         let mut kss = vec![];
         for _ in 0..5 {
             // To allow parallel processing, each key store gets its own dedicated blob store.
-            let bs = Arc::new(blob::BlobStore::new(self.blob_index.clone(),
-                                                   self.backend.clone(),
-                                                   self.blob_max_size));
-            kss.push(Process::new(key::Store::new(ki_p.clone(), self.hash_index.clone(), bs)));
+            let bs = Arc::new(blob::BlobStore::with_options(self.blob_index.clone(),
+                                                            self.backend.clone(),
+                                                            max_blob_size,
+                                                            options.clone()));
+            bs.set_metrics(self.metrics.clone());
+            if let Some(ref key) = self.master_key {
+                bs.set_master_key(key.clone());
+            }
+            let mut ks = key::Store::new(ki_p.clone(), self.hash_index.clone(), bs)
+                .with_hasher(self.hasher.clone())
+                .with_dedup_stats(dedup_stats.clone())
+                .with_changed_entries(changed_entries.clone())
+                .with_tree_order(self.tree_order);
+            if let Some((ref dir, threshold)) = self.tree_spill {
+                ks = ks.with_tree_spill(dir.clone(), threshold);
+            }
+            if let Some(ref stats) = chunk_size_stats {
+                ks = ks.with_chunk_size_stats(stats.clone());
+            }
+            if let Some(ref pool) = hash_pool {
+                ks = ks.with_hash_pool(pool.clone());
+            }
+            let (process, thread) =
+                Process::with_handle(if incremental { ks.incremental() } else { ks });
+            kss.push(process);
+            self.key_store_threads.lock().unwrap().push(thread);
         }
         Ok(Family {
             name: name,
             key_store: ks,
             key_store_process: kss,
+            dedup_stats: dedup_stats,
+            chunk_size_stats: chunk_size_stats,
+            changed_entries: changed_entries,
+            snapshot_leases: self.snapshot_leases.clone(),
+            open_files: self.open_files.clone(),
+            read_only: self.index_options.read_only,
+            cancel_tokens: Arc::new(Mutex::new(Vec::new())),
+            opened_from: self.open_families.clone(),
         })
     }
 
     pub fn meta_commit(&mut self) -> Result<(), HatError> {
+        try!(self.check_writable());
         let all_snapshots = self.snapshot_index.list_all();
 
         // TODO(jos): use a hash tree for this listing.
@@ -306,18 +1038,35 @@ impl<B: StoreBackend> HatRc<B> {
                 s.set_msg(&snapshot.msg.unwrap_or("".to_owned()));
                 s.set_hash(&snapshot.hash.unwrap().bytes);
                 s.set_tree_reference(&snapshot.tree_ref.unwrap());
+                match snapshot.commit_time {
+                    None => s.borrow().init_commit_time().set_unknown(()),
+                    Some(ts) => s.borrow().init_commit_time().set_timestamp(ts),
+                }
             }
         }
         let mut listing = Vec::new();
         capnp::serialize_packed::write_message(&mut listing, &message).unwrap();
 
         // TODO(jos): make sure this operation is atomic or resumable.
-        try!(self.blob_store.store_named("root", &listing[..]));
+        try!(self.blob_store.store_named("root", &listing[..]).map_err(HatError::from_blob_error));
         Ok(())
     }
 
-    pub fn recover(&mut self) -> Result<(), HatError> {
-        let root = match try!(self.blob_store.retrieve_named("root")) {
+    /// Rebuild the snapshot index from the metadata in the backend. Every blob referenced by a
+    /// snapshot is read once to check that it still exists; snapshots referencing missing blobs
+    /// are recovered all the same, but reported in the returned `RecoverReport`.
+    pub fn recover(&mut self) -> Result<RecoverReport, HatError> {
+        self.recover_with_quarantine(false)
+    }
+
+    /// Like `recover()`, but with `quarantine` set, snapshots referencing missing blobs are left
+    /// out of the index, so that they are neither restored nor counted as live. Their blobs are
+    /// left in the backend. Snapshots whose listing cannot be read are always left out.
+    pub fn recover_with_quarantine(&mut self, quarantine: bool) -> Result<RecoverReport, HatError> {
+        try!(self.check_writable());
+        let root = match try!(self.blob_store
+            .retrieve_named("root")
+            .map_err(HatError::from_blob_error)) {
             Some(r) => r,
             _ => return Err(From::from("Could not read root file")),
         };
@@ -327,22 +1076,144 @@ impl<B: StoreBackend> HatRc<B> {
                 .unwrap();
         let snapshot_list = message_reader.get_root::<root_capnp::snapshot_list::Reader>().unwrap();
 
+        let mut report = RecoverReport::default();
+        let mut present = HashSet::new();
         for s in snapshot_list.get_snapshots().unwrap().iter() {
             let tree_ref = blob::ChunkRef::from_bytes(&mut s.get_tree_reference().unwrap())
                 .unwrap();
-            self.snapshot_index
+            let commit_time = match s.get_commit_time().which().unwrap() {
+                root_capnp::snapshot::commit_time::Unknown(()) => None,
+                root_capnp::snapshot::commit_time::Timestamp(ts) => Some(ts),
+            };
+            let family_name = s.get_family_name().unwrap();
+            let hash = hash::Hash { bytes: s.get_hash().unwrap().to_owned() };
+
+            let (missing, unreadable) =
+                try!(self.find_missing_blobs(family_name, &hash, tree_ref.clone(), &mut present));
+            if !missing.is_empty() || unreadable {
+                warn!("Snapshot {} of family {} references {} missing blobs",
+                      s.get_id(),
+                      family_name,
+                      missing.len());
+                report.dangling.push(DanglingSnapshot {
+                    family_name: family_name.to_owned(),
+                    snapshot_id: s.get_id(),
+                    missing_blobs: missing,
+                    unreadable: unreadable,
+                });
+                if quarantine || unreadable {
+                    report.quarantined += 1;
+                    continue;
+                }
+            }
+
+            try!(self.snapshot_index
                 .recover(s.get_id(),
-                         s.get_family_name()
-                             .unwrap(),
-                         s.get_msg().unwrap(),
-                         s.get_hash().unwrap(),
+                         family_name,
+                         match s.get_msg().unwrap() {
+                             "" => None,
+                             label => Some(label),
+                         },
+                         &hash.bytes[..],
                          &tree_ref,
-                         Some(snapshot::WorkStatus::RecoverInProgress));
+                         commit_time,
+                         Some(snapshot::WorkStatus::RecoverInProgress)));
+            report.recovered += 1;
         }
         self.flush_snapshot_index();
         try!(self.resume());
 
-        Ok(())
+        Ok(report)
+    }
+
+    /// Rebuild the blob index from the blobs in the backend, for when the local indexes are lost
+    /// but the backend survives, then recover the snapshots with `recover()`.
+    ///
+    /// Each blob's chunk references are read from its footer; blobs that do not parse are
+    /// reported and left out. Unlike `recover()` on its own, this also indexes blobs that no
+    /// snapshot references, so that gc can reclaim them. The backend must support `list()`.
+    pub fn rebuild_index_from_backend(&mut self) -> Result<RebuildReport, HatError> {
+        try!(self.check_writable());
+        let mut report = RebuildReport::default();
+        let mut reader = blob::Blob::new(self.blob_max_size);
+        if let Some(ref key) = self.master_key {
+            reader.set_master_key(key.clone());
+        }
+        let mut names = try!(self.backend.list().map_err(HatError::from_backend_error));
+        names.sort();
+        for name in names {
+            // Named blobs are read by name and are not part of the index.
+            if &name[..] == b"root" || &name[..] == KDF_PARAMS_NAME.as_bytes() {
+                continue;
+            }
+            let data = match try!(self.backend
+                .retrieve(&name[..])
+                .map_err(HatError::from_backend_error)) {
+                Some(data) => data,
+                None => continue,  // Deleted since it was listed.
+            };
+            match reader.refs_from_bytes(&data[..]) {
+                Ok(hrefs) => {
+                    let desc = self.blob_index.recover(name);
+                    self.blob_index.set_digest(&desc, &hash::Hash::new(&data[..]).bytes[..]);
+                    report.blobs += 1;
+                    report.chunks += hrefs.len();
+                }
+                Err(e) => {
+                    warn!("Could not read chunk references of blob {:?}: {}", name, e);
+                    report.unparseable.push(name);
+                }
+            }
+        }
+        self.blob_index.flush();
+
+        report.recover = try!(self.recover());
+        Ok(report)
+    }
+
+    /// Find the blobs referenced by a snapshot that the backend does not have, and whether any of
+    /// its listing could not be read. Blobs known to be `present` are not checked again.
+    fn find_missing_blobs(&mut self,
+                          family_name: &str,
+                          hash: &hash::Hash,
+                          dir_ref: blob::ChunkRef,
+                          present: &mut HashSet<Vec<u8>>)
+                          -> Result<(Vec<Vec<u8>>, bool), HatError> {
+        let family = try!(self.open_family(family_name.to_owned()));
+        let mut registered = Vec::new();
+        let mut recovered = Vec::new();
+        let unreadable = match self.recover_dir_ref(&family,
+                                                    hash,
+                                                    dir_ref.clone(),
+                                                    &mut registered,
+                                                    &mut recovered) {
+            Ok(_) => false,
+            Err(e) => {
+                warn!("Could not read snapshot listing of family {}: {}", family_name, e);
+                true
+            }
+        };
+
+        let mut missing = Vec::new();
+        let refs = registered.into_iter()
+            .chain(recovered.into_iter())
+            .filter_map(|(_, entry)| entry.persistent_ref);
+        for pref in Some(dir_ref).into_iter().chain(refs) {
+            // Empty and inline chunks are not stored in any blob.
+            if (pref.offset == 0 && pref.length == 0) || present.contains(&pref.blob_id) ||
+               missing.contains(&pref.blob_id) {
+                continue;
+            }
+            let exists = try!(self.backend
+                .exists(&pref.blob_id[..])
+                .map_err(HatError::from_backend_error));
+            if exists {
+                present.insert(pref.blob_id);
+            } else {
+                missing.push(pref.blob_id);
+            }
+        }
+        Ok((missing, unreadable))
     }
 
     fn recover_snapshot(&mut self,
@@ -380,7 +1251,7 @@ impl<B: StoreBackend> HatRc<B> {
                 hash::ReserveResult::HashKnown(id) => id,
                 hash::ReserveResult::ReserveOk(id) => {
                     // Commit hash.
-                    hashes.commit(&entry.hash, pref);
+                    try!(hashes.commit(&entry.hash, pref));
                     id
                 }
             };
@@ -415,7 +1286,7 @@ impl<B: StoreBackend> HatRc<B> {
         });
 
         try!(self.gc.register(&info, id_receiver));
-        self.flush_blob_store();
+        try!(self.flush_blob_store());
 
         // Recover final root hash for the snapshot.
         try!(recover_entry(&self.hash_index,
@@ -432,8 +1303,7 @@ impl<B: StoreBackend> HatRc<B> {
         try!(self.gc.register_final(&info, final_id));
 
         try!(self.commit_finalize(&family, info, hash));
-
-        Ok(())
+        self.sync_committed()
     }
 
     fn recover_dir_ref(&mut self,
@@ -459,8 +1329,15 @@ impl<B: StoreBackend> HatRc<B> {
                 }
             }
         }
-        for (file, hash, pref) in
+        for (file, content) in
             try!(family.fetch_dir_data(dir_hash, dir_ref.clone(), self.hash_backend())) {
+            let (hash, pref) = match content {
+                Some(content) => content,
+                None => continue,  // Symlinks have no data to recover.
+            };
+            if hash.bytes.is_empty() {
+                continue;  // Neither have empty files.
+            }
             let (childs, level) = match file.data_hash {
                 Some(..) => {
                     // Entry is a data leaf. Read the hash tree.
@@ -489,11 +1366,13 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     pub fn resume(&mut self) -> Result<(), HatError> {
+        try!(self.check_writable());
         let need_work = self.snapshot_index.list_not_done();
 
         for snapshot in need_work.into_iter() {
             match snapshot.status {
                 snapshot::WorkStatus::CommitInProgress |
+                snapshot::WorkStatus::CommitPrepared |
                 snapshot::WorkStatus::RecoverInProgress => {
                     let done_hash_opt = match &snapshot.hash {
                         &None => None,
@@ -522,6 +1401,11 @@ impl<B: StoreBackend> HatRc<B> {
                             println!("Resuming commit of: {}", snapshot.family_name);
                             try!(self.commit_by_name(snapshot.family_name, Some(snapshot.info)))
                         }
+                        (done_hash, snapshot::WorkStatus::CommitPrepared) => {
+                            try!(self.abort_prepared_by_name(snapshot.family_name,
+                                                             snapshot.info,
+                                                             done_hash.is_some()))
+                        }
                         (None, snapshot::WorkStatus::RecoverInProgress) => {
                             println!("Resuming recovery of: {}", snapshot.family_name);
                             let hash = try!(snapshot.hash
@@ -608,55 +1492,270 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Like `commit()`, but labels the new snapshot (see `SnapshotSelector::Label`). Labels must be
+    /// unique within a family.
+    pub fn commit_with_label(&mut self,
+                             family: &Family<B>,
+                             label: &str)
+                             -> Result<CommittedSnapshot, HatError> {
+        try!(self.check_writable());
+        if self.snapshot_index.label_exists(&family.name, label) {
+            return Err(From::from(format!("Family {} already has a snapshot labeled {:?}",
+                                          family.name,
+                                          label)));
+        }
+        try!(self.run_pre_commit_hook(family));
+        let info = self.snapshot_index.reserve_with_label(family.name.clone(), Some(label));
+        self.commit(family, Some(info))
+    }
+
+    /// Commit the entries snapshotted in `family` as its next snapshot, or finish the commit
+    /// described by `resume_info`. Returns the id and label of the committed snapshot.
     pub fn commit(&mut self,
                   family: &Family<B>,
                   resume_info: Option<snapshot::Info>)
-                  -> Result<(), HatError> {
+                  -> Result<CommittedSnapshot, HatError> {
+        let committed = try!(self.commit_unsynced(family, resume_info));
+        try!(self.sync_committed());
+        Ok(committed)
+    }
+
+    /// Like `commit()`, but packs and encrypts the snapshot's metadata (its directory listings
+    /// and their hash-trees) as given by `policy` instead of as the store options say. Together
+    /// with `SnapshotOptions::policy` for its file data, a single snapshot can be stored e.g.
+    /// without compression or encryption.
+    pub fn commit_with_policy(&mut self,
+                              family: &Family<B>,
+                              policy: blob::ChunkPolicy)
+                              -> Result<CommittedSnapshot, HatError> {
+        let prepared = try!(self.prepare_commit_with_info(family, None, Some(policy), false));
+        self.finalize_commit(family, prepared)
+    }
+
+    /// Open a session to append several snapshots to family `family_name`, which are only synced
+    /// to stable storage when the session is committed (see `AppendSession`).
+    pub fn append_session(&mut self, family_name: String) -> Result<AppendSession<B>, HatError> {
+        try!(self.check_writable());
+        let family = try!(self.open_family(family_name));
+        Ok(AppendSession::new(self, family))
+    }
+
+    /// Write the snapshot of `family` like `commit()`, but stop short of committing it, so that
+    /// the snapshots of several families can be committed together: prepare all of them first,
+    /// and only finalize them with `finalize_commits()` once every one was prepared. A prepared
+    /// snapshot is not listed as committed until it is finalized; if preparing another snapshot
+    /// fails, drop it with `abort_commit()`.
+    ///
+    /// Note that `resume()` rolls back the snapshots prepared by an earlier process that it did
+    /// not finalize, as with `abort_commit()`.
+    pub fn prepare_commit(&mut self, family: &Family<B>) -> Result<PreparedCommit, HatError> {
+        self.prepare_commit_with_info(family, None, None, true)
+    }
+
+    /// Commit the snapshot of `family` prepared by `prepare_commit()`.
+    pub fn finalize_commit(&mut self,
+                           family: &Family<B>,
+                           prepared: PreparedCommit)
+                           -> Result<CommittedSnapshot, HatError> {
+        let committed = try!(self.finalize_commit_unsynced(family, prepared));
+        try!(self.sync_committed());
+        Ok(committed)
+    }
+
+    /// Commit the snapshots prepared by `prepare_commit()` for several families, all or none of
+    /// them: they are marked as committed together, in a single update of the snapshot index.
+    /// If the process dies before that, `resume()` rolls all of them back, and otherwise it
+    /// completes all of them. Returns the committed snapshots in the order given.
+    pub fn finalize_commits(&mut self,
+                            prepared: &[(&Family<B>, PreparedCommit)])
+                            -> Result<Vec<CommittedSnapshot>, HatError> {
+        for &(family, ref commit) in prepared.iter() {
+            try!(check_prepared_family(family, commit));
+        }
+
+        // Flip every prepared snapshot to ready in one transaction.
+        for &(_, ref commit) in prepared.iter() {
+            if let Some((ref info, _)) = commit.reserved {
+                self.snapshot_index.ready_commit(info);
+            }
+        }
+        self.flush_snapshot_index();
+
+        let mut committed = Vec::with_capacity(prepared.len());
+        for &(family, ref commit) in prepared.iter() {
+            match commit.reserved {
+                Some((ref info, ref hash)) => {
+                    try!(self.commit_finalize(family, info.clone(), hash));
+                }
+                None => {
+                    info!("Skipping unchanged snapshot of family {}, same as snapshot {}",
+                          family.name,
+                          commit.committed.snapshot_id);
+                }
+            }
+            self.end_commit(family);
+            committed.push(commit.committed.clone());
+        }
+        try!(self.sync_committed());
+        Ok(committed)
+    }
+
+    /// Drop the snapshot of `family` prepared by `prepare_commit()`. Its data is left for the
+    /// next `gc()` to reclaim.
+    pub fn abort_commit(&mut self,
+                        family: &Family<B>,
+                        prepared: PreparedCommit)
+                        -> Result<(), HatError> {
+        try!(check_prepared_family(family, &prepared));
+        if prepared.reserved.is_some() {
+            try!(self.deregister(family, prepared.committed.snapshot_id));
+        }
+        self.end_commit(family);
+        Ok(())
+    }
+
+    // Forget the snapshot in progress in `family`, once it is committed or dropped.
+    fn end_commit(&self, family: &Family<B>) {
+        self.snapshot_leases.end(&family.name);
+        family.cancel_tokens.lock().unwrap().clear();
+        *family.dedup_stats.lock().unwrap() = key::DedupStats::default();
+    }
+
+    // Like `commit()`, but without syncing the backend and local indexes afterwards.
+    fn commit_unsynced(&mut self,
+                       family: &Family<B>,
+                       resume_info: Option<snapshot::Info>)
+                       -> Result<CommittedSnapshot, HatError> {
+        let prepared = try!(self.prepare_commit_with_info(family, resume_info, None, false));
+        self.finalize_commit_unsynced(family, prepared)
+    }
+
+    // Drop the snapshot that an earlier process prepared with `prepare_commit()`, but did not
+    // finalize. Unless its final hash was `registered`, it is prepared in full first, as
+    // `deregister()` needs all of its hashes registered with the GC.
+    fn abort_prepared_by_name(&mut self,
+                              family_name: String,
+                              snap_info: snapshot::Info,
+                              registered: bool)
+                              -> Result<(), HatError> {
+        info!("Rolling back prepared commit of family {}", family_name);
+        let family = try!(self.open_family(family_name));
+        if !registered {
+            try!(self.prepare_commit_with_info(&family, Some(snap_info.clone()), None, true));
+        }
+        try!(self.deregister(&family, snap_info.snapshot_id));
+        self.snapshot_leases.end(&family.name);
+        family.cancel_tokens.lock().unwrap().clear();
+        self.sync_committed()
+    }
+
+    fn prepare_commit_with_info(&mut self,
+                                family: &Family<B>,
+                                resume_info: Option<snapshot::Info>,
+                                policy: Option<blob::ChunkPolicy>,
+                                prepare_only: bool)
+                                -> Result<PreparedCommit, HatError> {
+        try!(self.check_writable());
+        let is_new = resume_info.is_none();
+        if is_new && family.is_cancelled() {
+            return Err(HatError::Cancelled(CancelledError));
+        }
         //  Tag 1:
         //  Reserve the snapshot and commit the reservation.
         //  Register all but the last hashes.
         //  (the last hash is a special-case, as the GC use it to save meta-data for resuming)
+        let mut listings = None;
         let snap_info = match resume_info {
             Some(info) => info,  // Resume already started commit.
             None => {
                 // Create new commit.
-                self.snapshot_index.reserve(family.name.clone())
+                try!(self.run_pre_commit_hook(family));
+                if self.unchanged_commit == UnchangedCommit::Skip {
+                    // Write the listings first, to skip an unchanged snapshot before reserving it.
+                    let (hash_sender, hash_receiver) = mpsc::channel();
+                    let mut local_family: Family<B> = (*family).clone();
+                    local_family.key_store =
+                        local_family.key_store.with_chunk_policy(policy.clone());
+                    let (hash, top_ref) = try!(local_family.commit(&hash_sender));
+                    if let Some(previous) = self.latest_committed(&family.name) {
+                        if previous.hash.as_ref() == Some(&hash) {
+                            return Ok(PreparedCommit {
+                                family_name: family.name.clone(),
+                                reserved: None,
+                                committed: CommittedSnapshot {
+                                    snapshot_id: previous.info.snapshot_id,
+                                    label: previous.msg,
+                                    unchanged: true,
+                                },
+                            });
+                        }
+                    }
+                    listings = Some((hash_receiver, hash, top_ref));
+                }
+                let info = self.snapshot_index.reserve(family.name.clone());
+                if prepare_only {
+                    // Resuming rolls it back until it is finalized.
+                    self.snapshot_index.prepare(&info);
+                }
+                info
             }
         };
         self.flush_snapshot_index();
+        info!("Committing snapshot {} of family {}", snap_info.snapshot_id, family.name);
+        let mut committed = CommittedSnapshot {
+            snapshot_id: snap_info.snapshot_id,
+            label: self.snapshot_index.label(&snap_info),
+            unchanged: false,
+        };
 
         // Prepare.
-        let (hash_sender, hash_receiver) = mpsc::channel();
         let (hash_id_sender, hash_id_receiver) = mpsc::channel();
-
         let local_hash_index = self.hash_index.clone();
-        thread::spawn(move || {
-            for hash in hash_receiver.iter() {
-                hash_id_sender.send(local_hash_index.get_id(&hash)
-                        .expect("Hash not found"))
-                    .expect("Channel failed");
+        let map_hash_ids = move |hash_receiver: mpsc::Receiver<hash::Hash>| {
+            thread::spawn(move || {
+                for hash in hash_receiver.iter() {
+                    hash_id_sender.send(local_hash_index.get_id(&hash)
+                            .expect("Hash not found"))
+                        .expect("Channel failed");
+                }
+            });
+        };
+
+        let (hash, top_ref) = match listings {
+            Some((hash_receiver, hash, top_ref)) => {
+                // Register the data-hashes sent while the listings were written.
+                map_hash_ids(hash_receiver);
+                try!(self.gc.register(&snap_info, hash_id_receiver));
+                (hash, top_ref)
             }
-        });
+            None => {
+                // Commit metadata while registering needed data-hashes (files and dirs).
+                let (hash_sender, hash_receiver) = mpsc::channel();
+                map_hash_ids(hash_receiver);
 
-        // Commit metadata while registering needed data-hashes (files and dirs).
-        let (hash, top_ref) = {
-            let mut local_family: Family<B> = (*family).clone();
-            let (s, r) = mpsc::channel();
+                let mut local_family: Family<B> = (*family).clone();
+                local_family.key_store = local_family.key_store.with_chunk_policy(policy);
+                let (s, r) = mpsc::channel();
 
-            thread::spawn(move || s.send(local_family.commit(&hash_sender)));
-            try!(self.gc.register(&snap_info, hash_id_receiver));
+                thread::spawn(move || s.send(local_family.commit(&hash_sender)));
+                try!(self.gc.register(&snap_info, hash_id_receiver));
 
-            try!(try!(r.recv()))
+                try!(try!(r.recv()))
+            }
         };
 
+        // Compare the root hash with the previous snapshot's, which is still the latest committed.
+        let previous = self.latest_committed(&family.name);
+        committed.unchanged = previous.as_ref().and_then(|s| s.hash.as_ref()) == Some(&hash);
+
         // Push any remaining data to external storage.
         // This also flushes our hashes from the memory index, so we can tag them.
-        self.flush_blob_store();
+        try!(self.flush_blob_store());
 
         // Tag 2:
         // We update the snapshot entry with the tree hash, which we then register.
         // When the GC has seen the final hash, we flush everything so far.
-        self.snapshot_index.update(&snap_info, &hash, &top_ref);
+        try!(self.snapshot_index.update(&snap_info, &hash, &top_ref));
         self.flush_snapshot_index();
 
         // Register the final hash.
@@ -666,8 +1765,57 @@ impl<B: StoreBackend> HatRc<B> {
         let hash_id = self.hash_index.get_id(&hash).expect("Hash does not exist");
         try!(self.gc.register_final(&snap_info, hash_id));
         try!(family.flush());
-        try!(self.commit_finalize(family, snap_info, &hash));
 
+        Ok(PreparedCommit {
+            family_name: family.name.clone(),
+            reserved: Some((snap_info, hash)),
+            committed: committed,
+        })
+    }
+
+    fn finalize_commit_unsynced(&mut self,
+                                family: &Family<B>,
+                                prepared: PreparedCommit)
+                                -> Result<CommittedSnapshot, HatError> {
+        try!(check_prepared_family(family, &prepared));
+        let (info, hash) = match prepared.reserved {
+            Some(reserved) => reserved,
+            None => {
+                info!("Skipping unchanged snapshot of family {}, same as snapshot {}",
+                      family.name,
+                      prepared.committed.snapshot_id);
+                let previous = prepared.committed.clone();
+                try!(self.abort_commit(family, prepared));
+                return Ok(previous);
+            }
+        };
+        try!(self.commit_finalize(family, info, &hash));
+        self.end_commit(family);
+
+        Ok(prepared.committed)
+    }
+
+    // The latest committed snapshot of `family_name`, if any.
+    fn latest_committed(&mut self, family_name: &str) -> Option<snapshot::Status> {
+        self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == family_name)
+            .filter(|s| match s.status {
+                snapshot::WorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .max_by_key(|s| s.info.snapshot_id)
+    }
+
+    fn run_pre_commit_hook(&mut self, family: &Family<B>) -> Result<(), HatError> {
+        if let Some(ref mut hook) = self.pre_commit_hook {
+            let summary = try!(family.summary());
+            if let Err(e) = (**hook)(&summary) {
+                warn!("Pre-commit hook rejected snapshot of family {}: {}", family.name, e);
+                return Err(e);
+            }
+        }
         Ok(())
     }
 
@@ -678,8 +1826,7 @@ impl<B: StoreBackend> HatRc<B> {
                                -> Result<(), HatError> {
         let family = try!(self.open_family(family_name));
         try!(self.commit_finalize(&family, snap_info, &hash));
-
-        Ok(())
+        self.sync_committed()
     }
 
     fn commit_finalize(&mut self,
@@ -698,16 +1845,114 @@ impl<B: StoreBackend> HatRc<B> {
         // Tag 0: All is done.
         self.snapshot_index.commit(&snap_info);
         self.flush_snapshot_index();
+        info!("Committed snapshot {} of family {}", snap_info.snapshot_id, family.name);
 
         Ok(())
     }
 
-    pub fn flush_snapshot_index(&mut self) {
-        self.snapshot_index.flush();
+    /// Sync the backend, and with `Durability::PerCommit` the local indexes, so that a snapshot
+    /// just committed survives a crash of the machine.
+    fn sync_committed(&self) -> Result<(), HatError> {
+        try!(self.backend.flush().map_err(HatError::from_backend_error));
+        if self.index_options.durability == Durability::PerCommit {
+            if let Some(ref root) = self.repository_root {
+                try!(sqlite::sync_dir(root));
+            }
+        }
+        Ok(())
     }
 
-    pub fn flush_blob_store(&self) {
-        self.blob_store.flush();
+    // Fail if the repository was opened read-only.
+    fn check_writable(&self) -> Result<(), HatError> {
+        if self.index_options.read_only {
+            return Err(From::from("Repository is opened read-only"));
+        }
+        Ok(())
+    }
+
+    /// Count the blobs in the local blob index with the given status. Blobs that stay
+    /// `InProgress` after a flush point at uploads that never finished.
+    pub fn count_blobs(&self, status: blob::BlobStatus) -> usize {
+        self.blob_index.count_by_status(status)
+    }
+
+    /// The total size of the blobs in the local blob index, as counted against the quota (see
+    /// `set_quota`).
+    pub fn stored_bytes(&self) -> u64 {
+        self.blob_index.stored_bytes()
+    }
+
+    /// List the names of up to `limit` blobs with the given status.
+    pub fn list_blobs(&self, status: blob::BlobStatus, limit: usize) -> Vec<Vec<u8>> {
+        self.blob_index.list_by_status(status, limit).into_iter().map(|b| b.name).collect()
+    }
+
+    /// List the snapshots in the local snapshot index, ordered by family name and snapshot id.
+    pub fn list_snapshots(&mut self) -> Vec<SnapshotListing> {
+        let mut listing: Vec<SnapshotListing> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .map(|s| {
+                SnapshotListing {
+                    family_name: s.family_name,
+                    snapshot_id: s.info.snapshot_id,
+                    commit_time: s.commit_time,
+                    hash: s.hash.map(|h| h.bytes),
+                    committed: match s.status {
+                        snapshot::WorkStatus::CommitComplete => true,
+                        _ => false,
+                    },
+                    label: s.msg,
+                }
+            })
+            .collect();
+        listing.sort_by(|a, b| {
+            (&a.family_name, a.snapshot_id).cmp(&(&b.family_name, b.snapshot_id))
+        });
+        listing
+    }
+
+    pub fn flush_snapshot_index(&mut self) {
+        self.snapshot_index.flush();
+    }
+
+    pub fn flush_blob_store(&self) -> Result<(), HatError> {
+        self.blob_store.flush().map_err(HatError::from_blob_error)
+    }
+
+    /// Flush the local indexes and close them. With a `master_key` in the index options, the
+    /// databases are then encrypted and their plaintext removed.
+    ///
+    /// All families opened from this repository (and their clones) must have been dropped first;
+    /// otherwise this fails, and the databases are left unencrypted.
+    pub fn close(mut self) -> Result<(), HatError> {
+        if Arc::strong_count(&self.open_families) > 1 {
+            return Err(From::from("Cannot close the repository while families are open"));
+        }
+        try!(self.flush_blob_store());
+        self.snapshot_index.flush();
+        self.hash_index.flush();
+        self.blob_index.flush();
+
+        // With their families dropped, the key stores shut down and release their connections.
+        let threads = mem::replace(&mut *self.key_store_threads.lock().unwrap(), Vec::new());
+        for thread in threads {
+            try!(thread.join().map_err(|_| "A key store of a family panicked"));
+        }
+
+        let key = self.index_options.master_key.take();
+        let sealing = match (self.repository_root.take(), key) {
+            // The databases of a read-only repository may still be in use by whoever is writing.
+            _ if self.index_options.read_only => None,
+            (Some(root), Some(key)) => Some((root, key)),
+            _ => None,
+        };
+        drop(self);
+
+        if let Some((root, key)) = sealing {
+            try!(sqlite::seal_dir(&root, &sqlite::index_key(&key)));
+        }
+        Ok(())
     }
 
     pub fn checkout_in_dir(&mut self,
@@ -715,51 +1960,589 @@ impl<B: StoreBackend> HatRc<B> {
                            output_dir: PathBuf)
                            -> Result<(), HatError> {
         // Extract latest snapshot info:
-        let (_info, dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
-            Some((i, h, Some(r))) => (i, h, r),
+        let snapshot_id = match self.snapshot_index.latest(&family_name) {
+            Some((i, _, Some(_))) => i.snapshot_id,
             _ => {
                 panic!("Tried to checkout family '{}' before first completed commit",
                        family_name)
             }
         };
+        self.restore_run(family_name, snapshot_id, output_dir, &CancelToken::new(), &mut None)
+    }
 
-        let family = self.open_family(family_name.clone())
-            .expect(&format!("Could not open family '{}'", family_name));
+    /// Restore snapshot `snapshot_id` of family `family_name` into `output_dir`.
+    ///
+    /// File contents are written chunk by chunk as they are read, and recorded permissions,
+    /// timestamps and ownership are reapplied where known. A missing blob is reported as an
+    /// error.
+    pub fn restore(&mut self,
+                   family_name: String,
+                   snapshot_id: i64,
+                   output_dir: PathBuf)
+                   -> Result<(), HatError> {
+        try!(self.restore_with(family_name, snapshot_id, output_dir, RestoreOptions::default()));
+        Ok(())
+    }
 
-        let mut output_dir = output_dir;
-        self.checkout_dir_ref(&family, &mut output_dir, &dir_hash, dir_ref)
+    /// Like `restore()`, but restores the snapshot as given by `options`.
+    pub fn restore_with(&mut self,
+                        family_name: String,
+                        snapshot_id: i64,
+                        output_dir: PathBuf,
+                        options: RestoreOptions)
+                        -> Result<RestoreReport, HatError> {
+        let mut report = if options.skip_failed {
+            Some(RestoreReport::default())
+        } else {
+            None
+        };
+        try!(self.restore_run(family_name, snapshot_id, output_dir, &options.cancel, &mut report));
+        Ok(report.unwrap_or_else(RestoreReport::default))
     }
 
-    fn checkout_dir_ref(&self,
-                        family: &Family<B>,
-                        output: &mut PathBuf,
-                        dir_hash: &hash::Hash,
-                        dir_ref: blob::ChunkRef)
+    /// Restore only the file or directory at `path` (names separated by `/`) in snapshot
+    /// `snapshot_id` of family `family_name` to `output`, with its metadata. Only the listings
+    /// along the path and the data below it are read. A hardlink to a file outside of `path`
+    /// is reported as an error.
+    pub fn restore_path(&mut self,
+                        family_name: String,
+                        snapshot_id: i64,
+                        path: &[u8],
+                        mut output: PathBuf)
                         -> Result<(), HatError> {
-        fs::create_dir_all(&output).unwrap();
-        for (entry, hash, pref) in
-            try!(family.fetch_dir_data(dir_hash, dir_ref, self.hash_backend())) {
-            assert!(entry.name.len() > 0);
+        let (dir_hash, dir_ref) = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((_, h, Some(r))) => (h, r),
+            _ => {
+                return Err(From::from(format!("No complete snapshot found for family {} with \
+                                               id {:?}",
+                                              family_name,
+                                              snapshot_id)));
+            }
+        };
+
+        let family = try!(self.open_family(family_name));
+        let (entry, content) = match try!(self.lookup_path(&family, dir_hash, dir_ref, path)) {
+            Some(found) => found,
+            None => {
+                return Err(From::from(format!("No such file in snapshot: {}",
+                                              String::from_utf8_lossy(path))));
+            }
+        };
 
-            output.push(str::from_utf8(&entry.name[..]).unwrap());
-            println!("{}", output.display());
+        if let Some(parent) = output.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        let mut links = HardLinks::new();
+        try!(self.restore_entry(&family,
+                                &mut output,
+                                &entry,
+                                content,
+                                &mut links,
+                                &CancelToken::new(),
+                                &mut None));
+        links.finish()
+    }
 
-            if entry.data_hash.is_some() {
-                let mut fd = fs::File::create(&output).unwrap();
+    // Restore a snapshot, recording failed entries in `report` if given, or failing on the first.
+    fn restore_run(&mut self,
+                   family_name: String,
+                   snapshot_id: i64,
+                   output_dir: PathBuf,
+                   cancel: &CancelToken,
+                   report: &mut Option<RestoreReport>)
+                   -> Result<(), HatError> {
+        let (dir_hash, dir_ref) = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((_, h, Some(r))) => (h, r),
+            _ => {
+                return Err(From::from(format!("No complete snapshot found for family {} with \
+                                               id {:?}",
+                                              family_name,
+                                              snapshot_id)));
+            }
+        };
+
+        let family = try!(self.open_family(family_name));
+
+        let mut output = output_dir.clone();
+        let mut links = HardLinks::on_demand();
+        try!(self.checkout_dir_ref(&family,
+                                   &mut output,
+                                   &dir_hash,
+                                   dir_ref.clone(),
+                                   &mut links,
+                                   cancel,
+                                   report));
+
+        // Find the files that were written before the links to them.
+        let mut unresolved = links.unresolved();
+        if !unresolved.is_empty() {
+            let backend = self.hash_backend();
+            for res in try!(SnapshotEntries::new(family, backend, &dir_hash, dir_ref)) {
+                let (path, entry) = try!(res);
+                if let Some(id) = entry.id {
+                    if entry.is_file() && unresolved.remove(&id) {
+                        links.file(id, output_dir.join(OsStr::from_bytes(&path[..])));
+                    }
+                }
+                if unresolved.is_empty() {
+                    break;
+                }
+            }
+        }
+        match *report {
+            None => links.finish(),
+            Some(ref mut report) => {
+                for (path, e) in links.finish_partial() {
+                    warn!("Could not restore '{}': {}", path.display(), e);
+                    report.failed.push((path, e.to_string()));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Write snapshot `snapshot_id` of family `family_name` to `writer` as a tar archive, with
+    /// paths relative to the snapshot's root. Directories, symlinks and hardlinks become entries
+    /// of their own; recorded permissions, ownership and modification times are kept.
+    ///
+    /// File contents are streamed chunk by chunk. Files of unknown length (from snapshots made
+    /// before lengths were recorded for all files) are read twice.
+    pub fn export_tar<W: Write>(&mut self,
+                                family_name: String,
+                                snapshot_id: i64,
+                                writer: W)
+                                -> Result<W, HatError> {
+        let (dir_hash, dir_ref) = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((_, h, Some(r))) => (h, r),
+            _ => {
+                return Err(From::from(format!("No complete snapshot found for family {} with \
+                                               id {:?}",
+                                              family_name,
+                                              snapshot_id)));
+            }
+        };
+
+        let family = try!(self.open_family(family_name));
+
+        let mut out = TarWriter::new(writer);
+        let mut files = HashMap::new();
+        let mut links = vec![];
+        try!(self.export_dir_ref(&family,
+                                 b"",
+                                 &dir_hash,
+                                 dir_ref,
+                                 &mut out,
+                                 &mut files,
+                                 &mut links));
+
+        // Hardlinks go last, as tar needs the linked file to come first.
+        for (path, id, meta) in links {
+            match files.get(&id) {
+                Some(target) => try!(out.append_hardlink(&path[..], &target[..], &meta)),
+                None => {
+                    return Err(From::from(format!("Hardlink '{}' refers to missing entry {}",
+                                                  String::from_utf8_lossy(&path[..]),
+                                                  id)))
+                }
+            }
+        }
+        Ok(try!(out.finish()))
+    }
+
+    fn export_dir_ref<W: Write>(&self,
+                                family: &Family<B>,
+                                prefix: &[u8],
+                                dir_hash: &hash::Hash,
+                                dir_ref: blob::ChunkRef,
+                                out: &mut TarWriter<W>,
+                                files: &mut HashMap<u64, Vec<u8>>,
+                                links: &mut Vec<(Vec<u8>, u64, tar::Meta)>)
+                                -> Result<(), HatError> {
+        for (entry, content) in
+            try!(family.fetch_dir_data(dir_hash, dir_ref, self.hash_backend())) {
+            let mut path = prefix.to_vec();
+            path.extend_from_slice(&entry.name[..]);
+
+            let default_mode = if entry.is_directory() { 0o755 } else { 0o644 };
+            let meta = tar::Meta {
+                mode: entry.permissions.unwrap_or(default_mode) as u32,
+                uid: entry.user_id.unwrap_or(0),
+                gid: entry.group_id.unwrap_or(0),
+                mtime: cmp::max(0, entry.modified.unwrap_or(0) / 1_000_000_000) as u64,
+            };
+
+            if let Some(ref target) = entry.link_target {
+                try!(out.append_symlink(&path[..], &target[..], &meta));
+                continue;
+            }
+            if let Some(id) = entry.hardlink_of {
+                links.push((path, id, meta));
+                continue;
+            }
+            let (hash, pref) = content.expect("files and directories have content");
+
+            if entry.is_file() {
+                let size = match entry.data_length {
+                    Some(len) => len,
+                    None => {
+                        let mut len = 0;
+                        let tree_opt =
+                            try!(hash::tree::SimpleHashTreeReader::open(self.hash_backend(),
+                                                                        &hash,
+                                                                        Some(pref.clone())));
+                        if let Some(mut tree) = tree_opt {
+                            while let Some(chunk) = try!(tree.try_next()) {
+                                len += chunk.len() as u64;
+                            }
+                        }
+                        len
+                    }
+                };
+                try!(out.begin_file(&path[..], &meta, size));
                 let tree_opt = try!(hash::tree::SimpleHashTreeReader::open(self.hash_backend(),
                                                                            &hash,
                                                                            Some(pref)));
-                if let Some(tree) = tree_opt {
-                    family.write_file_chunks(&mut fd, tree);
+                if let Some(mut tree) = tree_opt {
+                    while let Some(chunk) = try!(tree.try_next()) {
+                        try!(out.write_data(&chunk[..]));
+                    }
+                }
+                if out.remaining() > 0 {
+                    return Err(From::from(format!("File '{}' is shorter than its recorded size",
+                                                  String::from_utf8_lossy(&path[..]))));
                 }
+                files.insert(entry.id.unwrap_or(0), path);
             } else {
-                try!(self.checkout_dir_ref(family, output, &hash, pref));
+                try!(out.append_dir(&path[..], &meta));
+                path.push(b'/');
+                try!(self.export_dir_ref(family, &path[..], &hash, pref, out, files, links));
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the file at `path` (names separated by `/`) in a committed snapshot for reading.
+    /// Its contents are fetched from the backend as they are read.
+    pub fn open_file(&mut self,
+                     family_name: String,
+                     snapshot_id: i64,
+                     path: &[u8])
+                     -> Result<FileReader<key::HashStoreBackend<B>>, HatError> {
+        let (dir_hash, dir_ref) = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((_, h, Some(r))) => (h, r),
+            _ => {
+                return Err(From::from(format!("No complete snapshot found for family {} with \
+                                               id {:?}",
+                                              family_name,
+                                              snapshot_id)));
+            }
+        };
+
+        let family = try!(self.open_family(family_name));
+        let (entry, content) = match try!(self.lookup_path(&family, dir_hash, dir_ref, path)) {
+            Some(found) => found,
+            None => {
+                return Err(From::from(format!("No such file in snapshot: {}",
+                                              String::from_utf8_lossy(path))));
+            }
+        };
+        match content {
+            Some((hash, pref)) => {
+                if !entry.is_file() {
+                    return Err(From::from(format!("Not a file: {}",
+                                                  String::from_utf8_lossy(path))));
+                }
+                FileReader::new(self.hash_backend(), hash, pref).map_err(HatError::from_key_error)
+            }
+            None => {
+                Err(From::from(format!("Not a regular file or directory: {}",
+                                       String::from_utf8_lossy(path))))
+            }
+        }
+    }
+
+    /// List the versions of the file at `path` (names separated by `/`) in the committed
+    /// snapshots of `family_name` that have a file there, oldest first. Only the directory
+    /// listings along the path are read from each snapshot.
+    pub fn history(&mut self,
+                   family_name: String,
+                   path: &[u8])
+                   -> Result<Vec<FileVersion>, HatError> {
+        let ids: Vec<i64> = self.list_snapshots()
+            .into_iter()
+            .filter(|s| s.family_name == family_name && s.committed)
+            .map(|s| s.snapshot_id)
+            .collect();
+        let family = try!(self.open_family(family_name.clone()));
+
+        let mut versions = vec![];
+        for id in ids {
+            let (dir_hash, dir_ref) = match self.snapshot_index.lookup(&family_name, id) {
+                Some((_, h, Some(r))) => (h, r),
+                _ => continue,
+            };
+            let found = try!(self.lookup_path(&family, dir_hash, dir_ref, path));
+            if let Some((entry, Some((hash, _)))) = found {
+                if entry.is_file() {
+                    versions.push(FileVersion {
+                        snapshot_id: id,
+                        data_hash: hash,
+                        size: entry.data_length,
+                        modified: entry.modified,
+                    });
+                }
+            }
+        }
+        Ok(versions)
+    }
+
+    // Find the entry at `path` (names separated by `/`) below the directory `dir_hash`, reading
+    // only the listings along the path. Returns `None` if there is no such entry.
+    fn lookup_path(&self,
+                   family: &Family<B>,
+                   mut dir_hash: hash::Hash,
+                   mut dir_ref: blob::ChunkRef,
+                   path: &[u8])
+                   -> Result<Option<DirElem>, HatError> {
+        let names: Vec<&[u8]> = path.split(|b| *b == b'/').filter(|n| !n.is_empty()).collect();
+        let (last, dirs) = try!(names.split_last().ok_or("No file name given"));
+
+        for name in dirs {
+            let (entry, content) = match try!(self.find_in_dir(family, &dir_hash, dir_ref, name)) {
+                Some(found) => found,
+                None => return Ok(None),
+            };
+            if !entry.is_directory() {
+                return Ok(None);
+            }
+            match content {
+                Some((hash, pref)) => {
+                    dir_hash = hash;
+                    dir_ref = pref;
+                }
+                None => return Ok(None),
+            }
+        }
+        self.find_in_dir(family, &dir_hash, dir_ref, last)
+    }
+
+    // Find the entry called `name` in the directory `dir_hash`, reading its listing only as far
+    // as needed.
+    fn find_in_dir(&self,
+                   family: &Family<B>,
+                   dir_hash: &hash::Hash,
+                   dir_ref: blob::ChunkRef,
+                   name: &[u8])
+                   -> Result<Option<DirElem>, HatError> {
+        for elem in try!(family.dir_listing(dir_hash, dir_ref, self.hash_backend())) {
+            let (entry, content) = try!(elem);
+            if &entry.name[..] == name {
+                return Ok(Some((entry, content)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Open the data with tree root `hash` for reading, e.g. as returned by `Family::put_file`,
+    /// whichever snapshots refer to it. Its contents are fetched from the backend as they are
+    /// read; reading fails with an error if a chunk is missing. The empty hash of empty data
+    /// reads as empty.
+    pub fn get_by_hash(&self,
+                       hash: &hash::Hash)
+                       -> Result<FileReader<key::HashStoreBackend<B>>, HatError> {
+        let backend = self.hash_backend();
+        let pref = if hash.bytes.is_empty() {
+            // Empty data has no chunks, and so no reference of its own.
+            Some(blob::ChunkRef {
+                blob_id: vec![0],
+                offset: 0,
+                length: 0,
+                kind: blob::Kind::TreeLeaf,
+                packing: None,
+                key: None,
+                raw_length: None,
+                nonce: None,
+                inline: None,
+            })
+        } else {
+            hash::tree::HashTreeBackend::fetch_persistent_ref(&backend, hash)
+        };
+        let pref = try!(pref.ok_or("No data stored with this hash"));
+        FileReader::new(backend, hash.clone(), pref).map_err(HatError::from_key_error)
+    }
+
+    /// Walk the entries of snapshot `snapshot_id` of family `family_name` depth-first, with their
+    /// paths relative to the snapshot's root, e.g. to report on a snapshot without restoring it.
+    /// Directory listings are fetched as the walk reaches them.
+    pub fn snapshot_entries(&mut self,
+                            family_name: String,
+                            snapshot_id: i64)
+                            -> Result<SnapshotEntries<B>, HatError> {
+        let (dir_hash, dir_ref) = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((_, h, Some(r))) => (h, r),
+            _ => {
+                return Err(From::from(format!("No complete snapshot found for family {} with \
+                                               id {:?}",
+                                              family_name,
+                                              snapshot_id)));
+            }
+        };
+
+        let family = try!(self.open_family(family_name));
+        SnapshotEntries::new(family, self.hash_backend(), &dir_hash, dir_ref)
+    }
+
+    /// Expose a committed snapshot as a read-only filesystem, to be mounted with `fuse::mount`.
+    #[cfg(feature = "mount")]
+    pub fn snapshot_fs(&mut self,
+                       family_name: String,
+                       snapshot_id: i64)
+                       -> Result<SnapshotFs<B>, HatError> {
+        let (dir_hash, dir_ref) = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((_, h, Some(r))) => (h, r),
+            _ => {
+                return Err(From::from(format!("No complete snapshot found for family {} with \
+                                               id {:?}",
+                                              family_name,
+                                              snapshot_id)));
+            }
+        };
+
+        let family = try!(self.open_family(family_name));
+        Ok(SnapshotFs::new(family, self.hash_backend(), dir_hash, dir_ref))
+    }
+
+    /// Mount a committed snapshot read-only at `mountpoint`, until it is unmounted.
+    #[cfg(feature = "mount")]
+    pub fn mount(&mut self,
+                 family_name: String,
+                 snapshot_id: i64,
+                 mountpoint: PathBuf)
+                 -> Result<(), HatError> {
+        let fs = try!(self.snapshot_fs(family_name, snapshot_id));
+        try!(fuse::mount(fs, &mountpoint, &[OsStr::new("-o"), OsStr::new("ro")]));
+        Ok(())
+    }
+
+    /// Stream the differences between two committed snapshots of the same family, going from
+    /// `snapshot_a` to `snapshot_b`.
+    pub fn diff(&mut self,
+                family_name: String,
+                snapshot_a: i64,
+                snapshot_b: i64)
+                -> Result<Diff<B, key::HashStoreBackend<B>>, HatError> {
+        let mut roots = vec![];
+        for &snapshot_id in &[snapshot_a, snapshot_b] {
+            match self.snapshot_index.lookup(&family_name, snapshot_id) {
+                Some((_, h, Some(r))) => roots.push((h, r)),
+                _ => {
+                    return Err(From::from(format!("No complete snapshot found for family {} \
+                                                   with id {:?}",
+                                                  family_name,
+                                                  snapshot_id)));
+                }
+            }
+        }
+        let b = roots.pop().unwrap();
+        let a = roots.pop().unwrap();
+
+        let family = try!(self.open_family(family_name));
+        Ok(family.diff(self.hash_backend(), a, b))
+    }
+
+    fn checkout_dir_ref(&self,
+                        family: &Family<B>,
+                        output: &mut PathBuf,
+                        dir_hash: &hash::Hash,
+                        dir_ref: blob::ChunkRef,
+                        links: &mut HardLinks,
+                        cancel: &CancelToken,
+                        report: &mut Option<RestoreReport>)
+                        -> Result<(), HatError> {
+        try!(fs::create_dir_all(&output));
+        // Listings are read as they are walked, so that memory use is bounded by the depth of
+        // the tree rather than the number of entries.
+        for elem in try!(family.dir_listing(dir_hash, dir_ref, self.hash_backend())) {
+            let (entry, content) = try!(elem);
+            try!(cancel.check());
+
+            if let Err(e) = check_entry_name(&entry.name[..]) {
+                match *report {
+                    None => return Err(e),
+                    Some(ref mut report) => {
+                        warn!("Could not restore an entry of '{}': {}", output.display(), e);
+                        report.failed.push((output.clone(), e.to_string()));
+                        continue;
+                    }
+                }
+            }
+            output.push(OsStr::from_bytes(&entry.name[..]));
+            match self.restore_entry(family, output, &entry, content, links, cancel, report) {
+                Ok(()) => (),
+                Err(HatError::Cancelled(e)) => return Err(HatError::Cancelled(e)),
+                Err(e) => {
+                    match *report {
+                        None => return Err(e),
+                        Some(ref mut report) => {
+                            warn!("Could not restore '{}': {}", output.display(), e);
+                            if entry.is_file() {
+                                let _ = fs::remove_file(&output);
+                            }
+                            report.failed.push((output.clone(), e.to_string()));
+                        }
+                    }
+                }
             }
             output.pop();
         }
         Ok(())
     }
 
+    // Restore a single entry of a listing to `output`, and everything below it.
+    fn restore_entry(&self,
+                     family: &Family<B>,
+                     output: &mut PathBuf,
+                     entry: &key::Entry,
+                     content: Option<(hash::Hash, blob::ChunkRef)>,
+                     links: &mut HardLinks,
+                     cancel: &CancelToken,
+                     report: &mut Option<RestoreReport>)
+                     -> Result<(), HatError> {
+        if let Some(ref target) = entry.link_target {
+            // Metadata is not restored for links, as setting it would follow the link.
+            try!(unix_fs::symlink(OsStr::from_bytes(&target[..]), &output));
+            return Ok(());
+        }
+        if let Some(id) = entry.hardlink_of {
+            // The linked file carries the metadata of the shared inode.
+            links.link(output.clone(), id);
+            return Ok(());
+        }
+        let (hash, pref) = content.expect("files and directories have content");
+
+        if entry.is_file() {
+            links.file(entry.id.unwrap_or(0), output.clone());
+            let fd = try!(fs::File::create(&output));
+            let mut out = SparseWriter::new(fd, entry.holes.clone());
+            let tree_opt = try!(hash::tree::SimpleHashTreeReader::open(self.hash_backend(),
+                                                                       &hash,
+                                                                       Some(pref))
+                .map_err(HatError::from_key_error));
+            if let Some(mut tree) = tree_opt {
+                while let Some(chunk) = try!(tree.try_next().map_err(HatError::from_key_error)) {
+                    try!(cancel.check());
+                    try!(out.write_all(&chunk[..]));
+                }
+            }
+            try!(out.finish());
+        } else {
+            try!(self.checkout_dir_ref(family, output, &hash, pref, links, cancel, report));
+        }
+
+        // Metadata is applied last, as writing a directory's children touches it.
+        restore_metadata(&output, entry)
+    }
+
     pub fn deregister_by_name(&mut self,
                               family_name: String,
                               snapshot_id: i64)
@@ -770,7 +2553,68 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Find the id of the one committed snapshot of `family_name` matching `selector`.
+    pub fn resolve_snapshot(&mut self,
+                            family_name: &str,
+                            selector: &SnapshotSelector)
+                            -> Result<i64, HatError> {
+        let ids: Vec<i64> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == family_name && selector.matches(s))
+            .filter(|s| match s.status {
+                snapshot::WorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .map(|s| s.info.snapshot_id)
+            .collect();
+        match ids.len() {
+            1 => Ok(ids[0]),
+            0 => {
+                Err(From::from(format!("No complete snapshot found for family {} matching {:?}",
+                                       family_name,
+                                       selector)))
+            }
+            n => {
+                Err(From::from(format!("{} snapshots of family {} match {:?}",
+                                       n,
+                                       family_name,
+                                       selector)))
+            }
+        }
+    }
+
+    /// Like `deregister()`, but finds the snapshot by `selector` (see `resolve_snapshot()`).
+    pub fn deregister_snapshot(&mut self,
+                               family: &Family<B>,
+                               selector: &SnapshotSelector)
+                               -> Result<(), HatError> {
+        let snapshot_id = try!(self.resolve_snapshot(&family.name, selector));
+        self.deregister(family, snapshot_id)
+    }
+
+    /// Deregister the committed snapshots of `family` that `policy` does not keep, and return
+    /// their ids. The latest snapshot is never deregistered.
+    pub fn prune_with_policy(&mut self,
+                             family: &Family<B>,
+                             policy: &RetentionPolicy)
+                             -> Result<Vec<i64>, HatError> {
+        try!(self.check_writable());
+        let snapshots: Vec<(i64, i64)> = self.list_snapshots()
+            .into_iter()
+            .filter(|s| s.family_name == family.name && s.committed)
+            .filter_map(|s| s.commit_time.map(|time| (s.snapshot_id, time)))
+            .collect();
+
+        let prune = retention::to_prune(&snapshots[..], policy);
+        for id in &prune {
+            try!(self.deregister(family, *id));
+        }
+        Ok(prune)
+    }
+
     pub fn deregister(&mut self, family: &Family<B>, snapshot_id: i64) -> Result<(), HatError> {
+        try!(self.check_writable());
         let (info, dir_hash, dir_ref) = match self.snapshot_index
             .lookup(&family.name, snapshot_id) {
             Some((i, h, Some(r))) => (i, h, r),
@@ -847,35 +2691,497 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     pub fn gc(&mut self) -> Result<(i64, i64), HatError> {
-        // Remove unused hashes.
-        let mut deleted_hashes = 0;
+        let stats = try!(self.gc_with(GcOptions::default()));
+        Ok((stats.hashes_deleted, stats.live))
+    }
+
+    /// Like `gc()`, but collects garbage as given by `options`, and reports what was scanned,
+    /// deleted and kept in a `GcStats`.
+    pub fn gc_with(&mut self, options: GcOptions) -> Result<GcStats, HatError> {
+        let GcOptions { batch_size, progress, cancel, workers } = options;
+        try!(self.check_writable());
+        assert!(batch_size > 0);
+        let mut stats = GcStats::default();
+        try!(cancel.check());
+
+        // Find unused hashes. Hashes reserved from now on are spared, as are those of snapshots
+        // in progress if the safety window is enabled.
+        info!("Garbage collection: finding unused hashes");
+        let start = Instant::now();
+        let mut spare_from = self.hash_index.last_id() + 1;
+        if self.gc_safety_window {
+            if let Some(leased) = self.snapshot_leases.oldest() {
+                spare_from = cmp::min(spare_from, leased);
+            }
+        }
+        let (sender, receiver) = mpsc::channel();
+        if workers > 1 {
+            try!(self.gc.list_unused_ids_parallel(sender, workers));
+        } else {
+            try!(self.gc.list_unused_ids(sender));
+        }
+
+        // Tell the hashes of deregistered snapshots apart before their references are deleted.
+        let unused: Vec<gc::Id> = receiver.iter().filter(|&id| id < spare_from).collect();
+        let registered = try!(self.gc.list_registered_ids(&unused));
+        stats.mark_time += start.elapsed();
+
+        // Remove them, remembering how much of each blob they used.
+        let start = Instant::now();
+        let mut unused_bytes: HashMap<Vec<u8>, u64> = HashMap::new();
+        let mut dead_blobs = HashSet::new();
+        for id in unused {
+            if cancel.is_cancelled() {
+                // The remaining hashes are found again by the next run.
+                self.hash_index.flush();
+                try!(cancel.check());
+            }
+            let pref = self.hash_index.get_hash(id).and_then(|e| e.persistent_ref);
+            if self.gc_safety_window {
+                // Snapshots in progress may have stored the hash again since it was found.
+                if !self.hash_index.delete_unpinned(id) {
+                    continue;
+                }
+            } else {
+                self.hash_index.delete(id);
+            }
+            if let Some(pref) = pref {
+                if registered.contains(&id) {
+                    dead_blobs.insert(pref.blob_id.clone());
+                }
+                *unused_bytes.entry(pref.blob_id).or_insert(0) += pref.length as u64;
+            }
+            stats.hashes_deleted += 1;
+            progress::report(&progress, Progress::HashDeleted);
+            if stats.hashes_deleted as usize % batch_size == 0 {
+                self.hash_index.flush();
+            }
+        }
+        self.hash_index.flush();
+        stats.sweep_time += start.elapsed();
+        info!("Garbage collection: deleted {} unused hashes", stats.hashes_deleted);
+
+        // Mark used blobs, continuing after the last flushed batch of an interrupted run. Only
+        // stored blobs are candidates: blobs still being uploaded are in progress, and are left
+        // alone along with any blob stored from now on.
+        let start = Instant::now();
+        let mut cursor = match self.hash_index.gc_mark_cursor() {
+            Some(cursor) => cursor,
+            None => {
+                self.blob_index.retag(None, tags::Tag::Done, tags::Tag::WillDelete);
+                self.blob_index.flush();
+                0
+            }
+        };
+        // Chunks of blobs already stored may wait for earlier chunks before their hashes are
+        // written to the index. Those written meanwhile are found by the scan below.
+        for pref in self.hash_index.queued_refs() {
+            self.blob_index.retag(Some(&pref.blob_id[..]),
+                                  tags::Tag::WillDelete,
+                                  tags::Tag::Reserved);
+        }
+        loop {
+            // Marking continues from the cursor flushed after the last batch.
+            try!(cancel.check());
+            let entries = self.hash_index.list_from(cursor, batch_size);
+            if entries.is_empty() {
+                break;
+            }
+            for (id, entry) in entries {
+                cursor = id;
+                stats.chunks_scanned += 1;
+                if let Some(pref) = entry.persistent_ref {
+                    self.blob_index.retag(Some(&pref.blob_id[..]),
+                                          tags::Tag::WillDelete,
+                                          tags::Tag::Reserved);
+                }
+            }
+            self.blob_index.flush();
+            self.hash_index.set_gc_mark_cursor(Some(cursor));
+            self.hash_index.flush();
+        }
+        // Blobs that live hashes were moved out of may still be named by stored tree nodes.
+        for name in self.hash_index.superseded_blobs() {
+            self.blob_index.retag(Some(&name[..]), tags::Tag::WillDelete, tags::Tag::Reserved);
+        }
+        self.blob_index.flush();
+        stats.mark_time += start.elapsed();
+        debug!("Garbage collection: marked the blobs of {} chunks", stats.chunks_scanned);
+
+        // Any candidate still left is not referenced by any hash.
+        // It is dead if it held chunks of a deregistered snapshot, and orphaned otherwise.
+        let start = Instant::now();
+        for blob in self.blob_index.list_by_tag(tags::Tag::WillDelete, usize::max_value()) {
+            if let Some(bytes) = unused_bytes.remove(&blob.name) {
+                stats.bytes_reclaimed += bytes;
+            }
+            if dead_blobs.contains(&blob.name) {
+                stats.blobs_dead += 1;
+            } else {
+                stats.blobs_orphaned += 1;
+            }
+        }
+        // The remaining blobs still hold chunks of live hashes.
+        stats.blobs_partially_live = unused_bytes.len();
+        loop {
+            try!(cancel.check());
+            let deleted = try!(self.blob_store.delete_by_tag(tags::Tag::WillDelete, batch_size));
+            if deleted == 0 {
+                break;
+            }
+            stats.blobs_emptied += deleted;
+            self.blob_index.flush();
+            progress::report(&progress, Progress::BlobsDeleted(deleted));
+        }
+        // Blobs that could not be deleted are candidates again next time.
+        self.blob_index.retag(None, tags::Tag::Reserved, tags::Tag::Done);
+        self.blob_index.retag(None, tags::Tag::WillDelete, tags::Tag::Done);
+        try!(self.flush_blob_store());
+
+        self.hash_index.set_gc_mark_cursor(None);
+        self.hash_index.flush();
+        stats.sweep_time += start.elapsed();
+
+        stats.live = self.hash_index.count_with_persistent_ref();
+        info!("Garbage collection: deleted {} blobs ({} dead, {} orphaned); {} hashes are live",
+              stats.blobs_emptied,
+              stats.blobs_dead,
+              stats.blobs_orphaned,
+              stats.live);
+        Ok(stats)
+    }
+
+    /// Find what `gc()` would delete, without changing any index or backend state. Hashes that
+    /// `gc()` would spare, including those of snapshots in progress when the safety window is
+    /// enabled, are not counted.
+    pub fn gc_dry_run(&self) -> Result<GcDryRun, HatError> {
+        let mut result = GcDryRun::default();
+        let mut spare_from = self.hash_index.last_id() + 1;
+        if self.gc_safety_window {
+            if let Some(leased) = self.snapshot_leases.oldest() {
+                spare_from = cmp::min(spare_from, leased);
+            }
+        }
         let (sender, receiver) = mpsc::channel();
-        try!(self.gc.list_unused_ids(sender));
-        for id in receiver.iter() {
-            deleted_hashes += 1;
-            self.hash_index.delete(id);
+        try!(self.gc.list_unused_ids_dry_run(sender));
+        for id in receiver.iter().filter(|&id| id < spare_from) {
+            if self.gc_safety_window && self.hash_index.is_pinned(id) {
+                continue;
+            }
+            result.hashes += 1;
+            if let Some(pref) = self.hash_index.get_hash(id).and_then(|e| e.persistent_ref) {
+                result.bytes += pref.length as u64;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Re-encrypt every stored chunk with a fresh key. Returns the number of chunks rewritten.
+    ///
+    /// Chunks are copied to new blobs in batches, and a hash is only pointed at its new copy once
+    /// that blob has been stored. The old blobs are deleted once every chunk has been copied. An
+    /// interrupted rotation continues after its last completed batch; the old blobs of the chunks
+    /// rotated before it was interrupted are removed by `gc()`.
+    ///
+    /// Hash tree nodes and listings keep the references they were written with, and reads fall
+    /// back to the hash index when those no longer resolve. Recovering the index from the backend
+    /// alone therefore finds the snapshots taken before the rotation dangling.
+    pub fn rotate_keys(&mut self) -> Result<usize, HatError> {
+        try!(self.check_writable());
+        let batch_size = 1024;
+        let mut rotated = 0;
+        let mut old_blobs = HashSet::new();
+        let mut stores: Vec<(Option<blob::Packing>, blob::BlobStore<B>)> = vec![];
+
+        self.hash_index.flush();
+        let mut cursor = self.hash_index.key_rotation_cursor().unwrap_or(0);
+        loop {
+            let entries = self.hash_index.list_from(cursor, batch_size);
+            if entries.is_empty() {
+                break;
+            }
+            for (id, entry) in entries {
+                cursor = id;
+                let pref = match entry.persistent_ref {
+                    // Empty and inline chunks are not stored in any blob.
+                    Some(ref p) if p.offset == 0 && p.length == 0 => continue,
+                    Some(p) => p,
+                    None => continue,
+                };
+                old_blobs.insert(pref.blob_id.clone());
+                try!(self.copy_chunk(&mut stores, &entry, pref));
+                rotated += 1;
+            }
+
+            // Store the new blobs (which repoints their hashes) before recording progress.
+            for &(_, ref store) in stores.iter() {
+                try!(store.flush().map_err(HatError::from_blob_error));
+            }
+            self.blob_index.flush();
+            self.hash_index.set_key_rotation_cursor(Some(cursor));
+            self.hash_index.flush();
+        }
+
+        self.hash_index.set_key_rotation_cursor(None);
+        self.hash_index.flush();
+
+        // Every hash now points at its new copy, so nothing references the old blobs anymore.
+        for name in old_blobs.into_iter() {
+            self.blob_index.tag(&blob::BlobDesc {
+                                    id: 0,
+                                    name: name,
+                                },
+                                tags::Tag::WillDelete);
         }
+        self.blob_index.flush();
+        try!(self.blob_store
+            .delete_by_tag(tags::Tag::WillDelete, usize::max_value())
+            .map_err(HatError::from_blob_error));
+        self.blob_index.flush();
+
+        Ok(rotated)
+    }
+
+    /// Store a copy of a chunk with a fresh key in one of `stores`, and point its hash at the
+    /// copy once that has been stored. New copies keep the packing of the chunk they replace.
+    fn copy_chunk(&self,
+                  stores: &mut Vec<(Option<blob::Packing>, blob::BlobStore<B>)>,
+                  entry: &hash::Entry,
+                  pref: blob::ChunkRef)
+                  -> Result<(), HatError> {
+        let data = try!(self.blob_store
+            .retrieve(&entry.hash, &pref)
+            .map_err(HatError::from_blob_error));
+
+        let store_idx = match stores.iter().position(|&(ref p, _)| *p == pref.packing) {
+            Some(idx) => idx,
+            None => {
+                let options = blob::StoreOptions {
+                    packing: pref.packing.clone(),
+                    ..Default::default()
+                };
+                let store = blob::BlobStore::with_options(self.blob_index.clone(),
+                                                          self.backend.clone(),
+                                                          self.blob_max_size,
+                                                          options);
+                store.set_metrics(self.metrics.clone());
+                if let Some(ref key) = self.master_key {
+                    store.set_master_key(key.clone());
+                }
+                stores.push((pref.packing.clone(), store));
+                stores.len() - 1
+            }
+        };
+
+        let local_hash_index = self.hash_index.clone();
+        let callback = Box::new(move |href: hash::tree::HashRef| {
+            local_hash_index.update_persistent_ref(&href.hash, href.persistent_ref)
+                .expect("Error recording persistent reference");
+        });
+        try!(stores[store_idx].1.store(&data[..], entry.hash.clone(), pref.kind, callback));
+        Ok(())
+    }
+
+    /// Move the live chunks out of blobs holding less than `max_live_bytes` of them, and delete
+    /// those blobs, so that the backend no longer stores the chunks of deleted snapshots they
+    /// still contain. Blobs are compacted together, as their total size is not known.
+    ///
+    /// This relies on the same guarantees as `rotate_keys()`: a hash is only pointed at its new
+    /// copy once that blob has been stored, and an old blob is only deleted once no hash points
+    /// into it anymore. An old blob left behind by an interrupted run is removed by `gc()`.
+    /// Hash tree nodes and listings stored earlier still name the old blobs; reads of those
+    /// references fall back to the hash index. Like `gc()`, this must not run while snapshots
+    /// are being taken.
+    pub fn compact(&mut self, max_live_bytes: u64) -> Result<CompactStats, HatError> {
+        try!(self.check_writable());
+        let batch_size = 1024;
+        let mut stats = CompactStats::default();
+
+        // Find the blobs with few live chunks.
         self.hash_index.flush();
-        // Mark used blobs.
-        let entries = self.hash_index.list();
-        self.blob_store.tag_all(tags::Tag::InProgress);
+        let mut live_bytes: HashMap<Vec<u8>, u64> = HashMap::new();
+        for entry in self.hash_index.list() {
+            match entry.persistent_ref {
+                // Empty and inline chunks are not stored in any blob.
+                Some(ref p) if p.offset == 0 && p.length == 0 => (),
+                Some(p) => *live_bytes.entry(p.blob_id).or_insert(0) += p.length as u64,
+                None => (),
+            }
+        }
+        let sparse: HashSet<Vec<u8>> = live_bytes.into_iter()
+            .filter(|&(_, bytes)| bytes < max_live_bytes)
+            .map(|(name, _)| name)
+            .collect();
+        if sparse.is_empty() {
+            return Ok(stats);
+        }
+
+        // Copy their chunks to new blobs.
+        let mut stores: Vec<(Option<blob::Packing>, blob::BlobStore<B>)> = vec![];
+        let mut cursor = 0;
+        loop {
+            let entries = self.hash_index.list_from(cursor, batch_size);
+            if entries.is_empty() {
+                break;
+            }
+            for (id, entry) in entries {
+                cursor = id;
+                let pref = match entry.persistent_ref {
+                    Some(ref p) if p.offset == 0 && p.length == 0 => continue,
+                    Some(ref p) if !sparse.contains(&p.blob_id) => continue,
+                    Some(p) => p,
+                    None => continue,
+                };
+                stats.chunks_moved += 1;
+                stats.bytes_moved += pref.length as u64;
+                try!(self.copy_chunk(&mut stores, &entry, pref));
+            }
+
+            // Store the new blobs (which repoints their hashes) before moving on.
+            for &(_, ref store) in stores.iter() {
+                try!(store.flush().map_err(HatError::from_blob_error));
+            }
+            self.blob_index.flush();
+            self.hash_index.flush();
+        }
+
+        // Nothing references the old blobs anymore.
+        for name in sparse.into_iter() {
+            self.blob_index.tag(&blob::BlobDesc {
+                                    id: 0,
+                                    name: name,
+                                },
+                                tags::Tag::WillDelete);
+        }
+        self.blob_index.flush();
+        stats.blobs_compacted = try!(self.blob_store
+            .delete_by_tag(tags::Tag::WillDelete, usize::max_value())
+            .map_err(HatError::from_blob_error));
+        self.blob_index.flush();
+
+        Ok(stats)
+    }
+
+    /// Read back every chunk referenced by the hash index and check it against its hash.
+    pub fn verify(&mut self) -> Result<VerifyReport, HatError> {
+        self.verify_parallel(1)
+    }
+
+    /// Like `verify()`, but reads and checks up to `workers` chunks concurrently. The report is
+    /// the same as for `verify()`.
+    pub fn verify_parallel(&mut self, workers: usize) -> Result<VerifyReport, HatError> {
+        let chunks = self.hash_index
+            .list()
+            .into_iter()
+            .filter_map(|entry| entry.persistent_ref.map(|pref| (entry.hash, pref)))
+            .collect();
+        Ok(self.verify_chunks(chunks, workers))
+    }
+
+    /// Read back every chunk reachable from a single snapshot and check it against its hash.
+    pub fn verify_snapshot(&mut self,
+                           family_name: String,
+                           snapshot_id: i64)
+                           -> Result<VerifyReport, HatError> {
+        self.verify_snapshot_parallel(family_name, snapshot_id, 1)
+    }
+
+    /// Like `verify_snapshot()`, but reads and checks up to `workers` chunks concurrently.
+    pub fn verify_snapshot_parallel(&mut self,
+                                    family_name: String,
+                                    snapshot_id: i64,
+                                    workers: usize)
+                                    -> Result<VerifyReport, HatError> {
+        let (dir_hash, dir_ref) = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((_, h, Some(r))) => (h, r),
+            _ => {
+                return Err(From::from(format!("No complete snapshot found for family {} with \
+                                               id {:?}",
+                                              family_name,
+                                              snapshot_id)));
+            }
+        };
+        let family = try!(self.open_family(family_name));
+
+        // Collect the top hashes of all files and directories. A listing that cannot be read is
+        // skipped here; its chunks are still checked (and reported) below.
+        let mut queue = vec![];
+        {
+            let hash_backend = self.hash_backend();
+            for hash in list_snapshot(&hash_backend, &family, dir_hash, dir_ref) {
+                match hash {
+                    Ok(h) => queue.extend(self.hash_index.get_id(&h)),
+                    Err(e) => warn!("Could not list snapshot entry: {}", e),
+                }
+            }
+        }
 
-        let mut live_blobs = 0;
-        for entry in entries.into_iter() {
-            if let Some(pref) = entry.persistent_ref {
-                live_blobs += 1;
-                self.blob_store.tag(pref, tags::Tag::Reserved);
+        // Expand every top hash to all the chunks of its tree.
+        let mut chunks = vec![];
+        let mut seen = HashSet::new();
+        while let Some(id) = queue.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let entry = try!(self.hash_index
+                .get_hash(id)
+                .ok_or("Snapshot references an unknown hash"));
+            if let Some(childs) = entry.childs {
+                queue.extend(childs);
             }
+            match entry.persistent_ref {
+                Some(pref) => chunks.push((entry.hash, pref)),
+                None => return Err(From::from("Snapshot references a hash without data")),
+            }
+        }
+
+        Ok(self.verify_chunks(chunks, workers))
+    }
+
+    // Check `chunks` on up to `workers` threads, each taking the next unchecked chunk.
+    fn verify_chunks(&self,
+                     chunks: Vec<(hash::Hash, blob::ChunkRef)>,
+                     workers: usize)
+                     -> VerifyReport {
+        let workers = cmp::max(workers, 1);
+        let report = Mutex::new(VerifyReport::default());
+        let todo = Mutex::new(chunks.into_iter());
+        {
+            let blob_store = &self.blob_store;
+            let (report, todo) = (&report, &todo);
+            let pool = scoped_pool::Pool::new(workers);
+            pool.scoped(|scope| {
+                for _ in 0..workers {
+                    scope.execute(move || {
+                        loop {
+                            let next = todo.lock().unwrap().next();
+                            match next {
+                                Some((hash, pref)) => {
+                                    let ok = verify_chunk(blob_store, &hash, &pref);
+                                    let mut report = report.lock().unwrap();
+                                    report.checked += 1;
+                                    if !ok {
+                                        report.failed += 1;
+                                        report.mismatches.push((pref.blob_id, pref.offset));
+                                    }
+                                }
+                                None => break,
+                            }
+                        }
+                    });
+                }
+            });
+            pool.shutdown();
         }
-        // Anything still marked "in progress" is not referenced by any hash.
-        try!(self.blob_store.delete_by_tag(tags::Tag::InProgress));
-        self.blob_store.tag_all(tags::Tag::Done);
-        self.blob_store.flush();
 
-        Ok((deleted_hashes, live_blobs))
+        // Chunks finish in any order; report the failures in a stable one.
+        let mut report = report.into_inner().unwrap();
+        report.mismatches.sort();
+        report
     }
 
     fn hash_backend(&self) -> key::HashStoreBackend<B> {
         key::HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone())
+            .with_hasher(self.hasher.clone())
     }
 }
@@ -0,0 +1,374 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Expose a committed snapshot as a read-only FUSE filesystem.
+//!
+//! Directories are listed from the stored tree when first visited, and file contents are fetched
+//! from the backend as they are read.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use fuse::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+           ReplyEntry, ReplyOpen, Request};
+use libc;
+use time::Timespec;
+
+use backend::StoreBackend;
+use blob;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::file_reader::FileReader;
+use key;
+
+
+const ROOT_INODE: u64 = 1;
+
+// The snapshot never changes, so the kernel may cache everything for long.
+const TTL: Timespec = Timespec {
+    sec: 3600,
+    nsec: 0,
+};
+
+fn nanos_to_timespec(ts: Option<i64>) -> Timespec {
+    let ts = ts.unwrap_or(0);
+    let (sec, nsec) = (ts.wrapping_div(1_000_000_000), ts.wrapping_rem(1_000_000_000));
+    if nsec < 0 {
+        Timespec::new(sec - 1, (nsec + 1_000_000_000) as i32)
+    } else {
+        Timespec::new(sec, nsec as i32)
+    }
+}
+
+struct Node {
+    entry: key::Entry,
+    content: Option<(hash::Hash, blob::ChunkRef)>,
+    // Inodes of the entries in a directory, once listed.
+    children: Option<Vec<u64>>,
+}
+
+impl Node {
+    fn kind(&self) -> FileType {
+        if self.entry.link_target.is_some() {
+            FileType::Symlink
+        } else if self.entry.data_hash.is_some() || self.entry.hardlink_of.is_some() {
+            FileType::RegularFile
+        } else {
+            FileType::Directory
+        }
+    }
+}
+
+/// A committed snapshot, to be mounted with `fuse::mount`.
+pub struct SnapshotFs<B: StoreBackend> {
+    family: Family<B>,
+    backend: key::HashStoreBackend<B>,
+    // Node of inode `i` is at index `i - 1`.
+    nodes: Vec<Node>,
+    // Inode holding the data of each entry id, for resolving hardlinks.
+    data_inodes: HashMap<u64, u64>,
+    files: HashMap<u64, FileReader<key::HashStoreBackend<B>>>,
+    next_fh: u64,
+}
+
+impl<B: StoreBackend> SnapshotFs<B> {
+    pub fn new(family: Family<B>,
+               backend: key::HashStoreBackend<B>,
+               dir_hash: hash::Hash,
+               dir_ref: blob::ChunkRef)
+               -> SnapshotFs<B> {
+        let root = key::Entry {
+            id: None,
+            parent_id: None,
+            name: vec![],
+            created: None,
+            modified: None,
+            accessed: None,
+            permissions: Some(0o555),
+            user_id: None,
+            group_id: None,
+            data_hash: None,
+            data_length: None,
+            link_target: None,
+            hardlink_of: None,
+            xattrs: None,
+            holes: None,
+        };
+        SnapshotFs {
+            family: family,
+            backend: backend,
+            nodes: vec![Node {
+                            entry: root,
+                            content: Some((dir_hash, dir_ref)),
+                            children: None,
+                        }],
+            data_inodes: HashMap::new(),
+            files: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        if ino == 0 {
+            return None;
+        }
+        self.nodes.get(ino as usize - 1)
+    }
+
+    fn children(&mut self, ino: u64) -> Result<Vec<u64>, HatError> {
+        let (hash, pref) = match self.node(ino) {
+            Some(&Node { children: Some(ref children), .. }) => return Ok(children.clone()),
+            Some(node) if node.kind() == FileType::Directory => {
+                node.content.clone().expect("directories have content")
+            }
+            _ => return Err(From::from("Not a directory")),
+        };
+
+        let mut children = vec![];
+        for (entry, content) in try!(self.family
+            .fetch_dir_data(&hash, pref, self.backend.clone())) {
+            let child = self.nodes.len() as u64 + 1;
+            if let (Some(id), true) = (entry.id, entry.data_hash.is_some()) {
+                self.data_inodes.insert(id, child);
+            }
+            self.nodes.push(Node {
+                entry: entry,
+                content: content,
+                children: None,
+            });
+            children.push(child);
+        }
+        self.nodes[ino as usize - 1].children = Some(children.clone());
+        Ok(children)
+    }
+
+    /// The inode holding the data of `ino`, which differs from `ino` for hardlinks.
+    fn data_inode(&mut self, ino: u64) -> Result<u64, HatError> {
+        let id = match self.node(ino).and_then(|n| n.entry.hardlink_of) {
+            None => return Ok(ino),
+            Some(id) => id,
+        };
+        // The linked entry may live in a directory not visited yet.
+        let mut dir = ROOT_INODE;
+        while !self.data_inodes.contains_key(&id) && dir <= self.nodes.len() as u64 {
+            if self.node(dir).map(|n| n.kind()) == Some(FileType::Directory) {
+                try!(self.children(dir));
+            }
+            dir += 1;
+        }
+        self.data_inodes.get(&id).cloned().ok_or(From::from("Hardlinked entry not found"))
+    }
+
+    fn attr(&mut self, ino: u64) -> Result<FileAttr, HatError> {
+        let data_ino = try!(self.data_inode(ino));
+        let size = {
+            let node = try!(self.node(data_ino).ok_or("No such inode"));
+            match node.kind() {
+                FileType::Symlink => node.entry.link_target.as_ref().map_or(0, |t| t.len() as u64),
+                FileType::RegularFile => {
+                    match node.entry.data_length {
+                        Some(len) => len,
+                        None => {
+                            // Snapshots made before sizes were recorded; count the data.
+                            let mut len = 0;
+                            let mut buf = vec![0; key::MAX_CHUNK_LEN];
+                            let mut reader = try!(self.open_reader(data_ino));
+                            loop {
+                                match try!(reader.read(&mut buf[..])) {
+                                    0 => break,
+                                    n => len += n as u64,
+                                }
+                            }
+                            len
+                        }
+                    }
+                }
+                _ => 0,
+            }
+        };
+        self.nodes[data_ino as usize - 1].entry.data_length = Some(size);
+
+        let node = try!(self.node(data_ino).ok_or("No such inode"));
+        let kind = node.kind();
+        let entry = &node.entry;
+        let perm = match (entry.permissions, kind) {
+            (Some(mode), _) => mode as u16 & 0o7777,
+            (None, FileType::Directory) => 0o555,
+            (None, _) => 0o444,
+        };
+        Ok(FileAttr {
+            ino: ino,
+            size: size,
+            blocks: (size + 511) / 512,
+            atime: nanos_to_timespec(entry.accessed.or(entry.modified)),
+            mtime: nanos_to_timespec(entry.modified),
+            ctime: nanos_to_timespec(entry.modified),
+            crtime: nanos_to_timespec(entry.created),
+            kind: kind,
+            // Writing is not possible, whatever the stored mode says.
+            perm: perm & !0o222,
+            nlink: 1,
+            uid: entry.user_id.unwrap_or(0) as u32,
+            gid: entry.group_id.unwrap_or(0) as u32,
+            rdev: 0,
+            flags: 0,
+        })
+    }
+
+    fn open_reader(&self, ino: u64) -> Result<FileReader<key::HashStoreBackend<B>>, HatError> {
+        match self.node(ino) {
+            Some(&Node { content: Some((ref hash, ref pref)), ref entry, .. })
+                if entry.data_hash.is_some() => {
+                FileReader::new(self.backend.clone(), hash.clone(), pref.clone())
+                    .map_err(HatError::from_key_error)
+            }
+            _ => Err(From::from("Not a regular file")),
+        }
+    }
+
+    fn lookup_child(&mut self, parent: u64, name: &[u8]) -> Result<Option<u64>, HatError> {
+        let children = try!(self.children(parent));
+        Ok(children.into_iter().find(|c| self.nodes[*c as usize - 1].entry.name == name))
+    }
+}
+
+fn errno(e: HatError) -> libc::c_int {
+    warn!("Snapshot filesystem operation failed: {}", e);
+    libc::EIO
+}
+
+impl<B: StoreBackend> Filesystem for SnapshotFs<B> {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
+        match self.lookup_child(parent, name.as_os_str().as_bytes()) {
+            Ok(Some(ino)) => {
+                match self.attr(ino) {
+                    Ok(attr) => reply.entry(&TTL, &attr, 0),
+                    Err(e) => reply.error(errno(e)),
+                }
+            }
+            Ok(None) => reply.error(libc::ENOENT),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        if self.node(ino).is_none() {
+            return reply.error(libc::ENOENT);
+        }
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.node(ino).and_then(|n| n.entry.link_target.as_ref()) {
+            Some(target) => reply.data(&target[..]),
+            None => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32 {
+            return reply.error(libc::EROFS);
+        }
+        let reader = match self.data_inode(ino).and_then(|data_ino| self.open_reader(data_ino)) {
+            Ok(reader) => reader,
+            Err(e) => return reply.error(errno(e)),
+        };
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.files.insert(fh, reader);
+        reply.opened(fh, 0);
+    }
+
+    fn read(&mut self,
+            _req: &Request,
+            _ino: u64,
+            fh: u64,
+            offset: u64,
+            size: u32,
+            reply: ReplyData) {
+        let reader = match self.files.get_mut(&fh) {
+            Some(reader) => reader,
+            None => return reply.error(libc::EBADF),
+        };
+        // Sequential reads continue where the previous one stopped, without seeking.
+        if let Err(e) = reader.seek(SeekFrom::Start(offset)) {
+            return reply.error(errno(From::from(e)));
+        }
+        let mut data = vec![0; size as usize];
+        let mut len = 0;
+        while len < data.len() {
+            match reader.read(&mut data[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) => return reply.error(errno(From::from(e))),
+            }
+        }
+        reply.data(&data[..len]);
+    }
+
+    fn release(&mut self,
+               _req: &Request,
+               _ino: u64,
+               fh: u64,
+               _flags: u32,
+               _lock_owner: u64,
+               _flush: bool,
+               reply: ReplyEmpty) {
+        self.files.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(&mut self,
+               _req: &Request,
+               ino: u64,
+               _fh: u64,
+               offset: u64,
+               mut reply: ReplyDirectory) {
+        let children = match self.children(ino) {
+            Ok(children) => children,
+            Err(e) => return reply.error(errno(e)),
+        };
+        let parent = if ino == ROOT_INODE {
+            ROOT_INODE
+        } else {
+            // Parents are always listed before their children.
+            let nodes = &self.nodes;
+            (1..ino)
+                .find(|p| {
+                    nodes[*p as usize - 1].children.as_ref().map_or(false, |c| c.contains(&ino))
+                })
+                .unwrap_or(ROOT_INODE)
+        };
+
+        let mut entries = vec![(ino, FileType::Directory, OsStr::new(".")),
+                               (parent, FileType::Directory, OsStr::new(".."))];
+        for child in children.iter() {
+            let node = &self.nodes[*child as usize - 1];
+            entries.push((*child, node.kind(), OsStr::from_bytes(&node.entry.name[..])));
+        }
+        for (i, &(child, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            // The offset passed along is that of the next entry.
+            if reply.add(child, i as u64 + 1, kind, Path::new(name)) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decide which snapshots to keep by their commit time.
+
+use std::collections::HashSet;
+
+
+const NANOS_PER_DAY: i64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// A grandfather-father-son retention policy: keep the newest snapshot of each of the `daily`
+/// most recent days, `weekly` most recent weeks and `monthly` most recent months that have
+/// snapshots. Days, weeks (starting on Monday) and months are in UTC.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RetentionPolicy {
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+fn day(nanos: i64) -> i64 {
+    let days = nanos / NANOS_PER_DAY;
+    if nanos % NANOS_PER_DAY < 0 { days - 1 } else { days }
+}
+
+fn week(nanos: i64) -> i64 {
+    // The epoch was a Thursday.
+    let days = day(nanos) + 3;
+    if days < 0 { (days - 6) / 7 } else { days / 7 }
+}
+
+fn month(nanos: i64) -> i64 {
+    // Convert days since the epoch to a civil date (see "chrono-Compatible Low-Level Date
+    // Algorithms" by Howard Hinnant).
+    let z = day(nanos) + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    y * 12 + m - 1
+}
+
+/// Keep the newest snapshot in each of the `count` most recent periods.
+fn keep_per_period<F: Fn(i64) -> i64>(newest_first: &[(i64, i64)],
+                                      count: usize,
+                                      period: F,
+                                      keep: &mut HashSet<i64>) {
+    let mut last = None;
+    let mut periods = 0;
+    for &(id, time) in newest_first {
+        if periods == count {
+            break;
+        }
+        let p = period(time);
+        if last != Some(p) {
+            last = Some(p);
+            periods += 1;
+            keep.insert(id);
+        }
+    }
+}
+
+/// The ids of the `(id, commit time)` snapshots that `policy` does not keep. The latest
+/// snapshot is always kept.
+pub fn to_prune(snapshots: &[(i64, i64)], policy: &RetentionPolicy) -> Vec<i64> {
+    let mut newest_first = snapshots.to_vec();
+    newest_first.sort_by(|a, b| (b.1, b.0).cmp(&(a.1, a.0)));
+
+    let mut keep = HashSet::new();
+    if let Some(&(id, _)) = newest_first.first() {
+        keep.insert(id);
+    }
+    keep_per_period(&newest_first, policy.daily, day, &mut keep);
+    keep_per_period(&newest_first, policy.weekly, week, &mut keep);
+    keep_per_period(&newest_first, policy.monthly, month, &mut keep);
+
+    let mut prune: Vec<i64> =
+        newest_first.into_iter().map(|(id, _)| id).filter(|id| !keep.contains(id)).collect();
+    prune.sort();
+    prune
+}
@@ -0,0 +1,67 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Append several snapshots to a family, syncing them to stable storage only once.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hat::{CommittedSnapshot, HatRc};
+use hat::family::Family;
+
+
+/// A session appending snapshots to one family, opened with `Hat::append_session`.
+///
+/// Each `append()` commits the entries snapshotted in `family()` since the previous one as a new
+/// snapshot, with the same steps as `Hat::commit`, except that the backend and local indexes are
+/// not synced. That is left to `commit()`, which syncs once for all snapshots appended. Until
+/// then, a crash of the machine may lose the appended snapshots.
+///
+/// The family stays open for the whole session, so the entries and data already seen are
+/// deduplicated against by later snapshots.
+pub struct AppendSession<'a, B: StoreBackend> {
+    hat: &'a mut HatRc<B>,
+    family: Family<B>,
+    appended: Vec<CommittedSnapshot>,
+}
+
+impl<'a, B: StoreBackend> AppendSession<'a, B> {
+    pub fn new(hat: &'a mut HatRc<B>, family: Family<B>) -> AppendSession<'a, B> {
+        AppendSession {
+            hat: hat,
+            family: family,
+            appended: vec![],
+        }
+    }
+
+    /// The family to snapshot entries into before each `append()`.
+    pub fn family(&self) -> &Family<B> {
+        &self.family
+    }
+
+    /// Commit the entries snapshotted since the previous append as the family's next snapshot,
+    /// without syncing it.
+    pub fn append(&mut self) -> Result<CommittedSnapshot, HatError> {
+        try!(self.family.flush());
+        let committed = try!(self.hat.commit_unsynced(&self.family, None));
+        self.appended.push(committed.clone());
+        Ok(committed)
+    }
+
+    /// Sync the snapshots appended in this session, and return them in the order appended.
+    pub fn commit(self) -> Result<Vec<CommittedSnapshot>, HatError> {
+        try!(self.hat.sync_committed());
+        Ok(self.appended)
+    }
+}
@@ -12,14 +12,38 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use log;
+use rand;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use std::str;
+use std::sync::{Arc, Mutex, ONCE_INIT, Once, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
-use backend::{MemoryBackend, StoreBackend};
-use errors::HatError;
-use hat::HatRc;
+use backend::{BackendError, FileBackend, MemoryBackend, StoreBackend};
+use backend::tests::{mock_http_backend, mock_s3_backend};
+use blob;
+use crypto::{CipherText, FixedKey, kdf};
+use errors::{self, HatError};
+use hash;
+use hash::tree::HashTreeBackend;
+use hat::{CommittedSnapshot, DiffEntry, DiffKind, FileReader, GcDryRun, GcOptions, HatRc,
+          RestoreOptions, RetentionPolicy, SnapshotOptions, SnapshotSelector, SnapshotSummary,
+          UnchangedCommit};
 use hat::family::Family;
 use key;
-use util::FileIterator;
+use metrics::MemoryMetrics;
+use progress::Progress;
+use tags;
+use util::{CancelToken, Durability, FileIterator, IndexOptions};
+use util::tar::{self, TarWriter};
+use util::xattr;
 
 
 pub fn setup_hat<B: StoreBackend>(backend: Arc<B>) -> HatRc<B> {
@@ -27,8 +51,7 @@ pub fn setup_hat<B: StoreBackend>(backend: Arc<B>) -> HatRc<B> {
     HatRc::new_for_testing(backend, max_blob_size).unwrap()
 }
 
-fn setup_family() -> (Arc<MemoryBackend>, HatRc<MemoryBackend>, Family<MemoryBackend>) {
-    let backend = Arc::new(MemoryBackend::new());
+fn setup_family<B: StoreBackend>(backend: Arc<B>) -> (Arc<B>, HatRc<B>, Family<B>) {
     let hat = setup_hat(backend.clone());
 
     let family = "familyname".to_string();
@@ -50,6 +73,10 @@ pub fn entry(name: Vec<u8>) -> key::Entry {
         group_id: None,
         data_hash: None,
         data_length: None,
+        link_target: None,
+        hardlink_of: None,
+        xattrs: None,
+        holes: None,
     }
 }
 
@@ -64,9 +91,8 @@ fn snapshot_files<B: StoreBackend>(family: &Family<B>,
     Ok(())
 }
 
-#[test]
-fn snapshot_commit() {
-    let (_, mut hat, fam) = setup_family();
+fn snapshot_commit<B: StoreBackend>(backend: Arc<B>) {
+    let (_, mut hat, fam) = setup_family(backend);
 
     snapshot_files(&fam,
                    vec![("name1", vec![0; 1000000]),
@@ -83,9 +109,8 @@ fn snapshot_commit() {
     assert!(live > 0);
 }
 
-#[test]
-fn snapshot_commit_many_empty_files() {
-    let (_, mut hat, fam) = setup_family();
+fn snapshot_commit_many_empty_files<B: StoreBackend>(backend: Arc<B>) {
+    let (_, mut hat, fam) = setup_family(backend);
 
     let names: Vec<String> = (0..3000).map(|i| format!("name-{}", i)).collect();
     snapshot_files(&fam, names.iter().map(|n| (n.as_str(), vec![])).collect()).unwrap();
@@ -104,9 +129,70 @@ fn snapshot_commit_many_empty_files() {
     assert_eq!(live, 0);
 }
 
-#[test]
-fn snapshot_commit_many_empty_directories() {
-    let (_, mut hat, fam) = setup_family();
+fn snapshot_gc_parallel<B: StoreBackend>(backend: Arc<B>) {
+    // Run the many-empty-files scenario through serial and parallel GC.
+    let mut totals = vec![];
+    for &workers in [1, 4].iter() {
+        let (_, mut hat, fam) = setup_family(backend.clone());
+
+        let names: Vec<String> = (0..3000).map(|i| format!("name-{}", i)).collect();
+        snapshot_files(&fam, names.iter().map(|n| (n.as_str(), vec![])).collect()).unwrap();
+
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+        hat.meta_commit().unwrap();
+
+        let options = GcOptions { workers: workers, ..GcOptions::default() };
+        let committed = hat.gc_with(options.clone()).unwrap();
+        assert_eq!(committed.hashes_deleted, 0);
+        hat.deregister(&fam, 1).unwrap();
+        let deregistered = hat.gc_with(options).unwrap();
+        assert_eq!(deregistered.live, 0);
+        totals.push((committed.hashes_deleted,
+                     committed.live,
+                     deregistered.hashes_deleted,
+                     deregistered.live));
+    }
+    assert_eq!(totals[0], totals[1]);
+}
+
+fn snapshot_gc_incremental<B: StoreBackend>(backend: Arc<B>) {
+    // Run the same scenario through single-shot and incremental GC.
+    let mut totals = vec![];
+    for &batch_size in [None, Some(7)].iter() {
+        let (_, mut hat, fam) = setup_family(backend.clone());
+        let gc = |hat: &mut HatRc<B>| match batch_size {
+            None => hat.gc().unwrap(),
+            Some(n) => {
+                let stats = hat.gc_with(GcOptions { batch_size: n, ..GcOptions::default() })
+                    .unwrap();
+                (stats.hashes_deleted, stats.live)
+            }
+        };
+
+        let names: Vec<String> = (0..3000).map(|i| format!("name-{}", i)).collect();
+        snapshot_files(&fam, names.iter().map(|n| (n.as_str(), vec![])).collect()).unwrap();
+
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+        hat.meta_commit().unwrap();
+
+        let committed = gc(&mut hat);
+        hat.deregister(&fam, 1).unwrap();
+        let deregistered = gc(&mut hat);
+        totals.push((committed, deregistered));
+    }
+
+    assert_eq!(totals[0], totals[1]);
+    let ((deleted, live), (deleted2, live2)) = totals[1];
+    assert_eq!(deleted, 0);
+    assert!(live > 0);
+    assert!(deleted2 > 0);
+    assert_eq!(live2, 0);
+}
+
+fn snapshot_commit_many_empty_directories<B: StoreBackend>(backend: Arc<B>) {
+    let (_, mut hat, fam) = setup_family(backend);
 
     for i in 0..3000 {
         fam.snapshot_direct(entry(format!("name-{}", i).bytes().collect()), true, None)
@@ -127,9 +213,8 @@ fn snapshot_commit_many_empty_directories() {
     assert_eq!(live, 0);
 }
 
-#[test]
-fn snapshot_reuse_index() {
-    let (_, mut hat, fam) = setup_family();
+fn snapshot_reuse_index<B: StoreBackend>(backend: Arc<B>) {
+    let (_, mut hat, fam) = setup_family(backend);
 
     let files = vec![("file1", "block1".bytes().collect()),
                      ("file2", "block2".bytes().collect()),
@@ -175,9 +260,8 @@ fn snapshot_reuse_index() {
     assert_eq!(live, 0);
 }
 
-#[test]
-fn snapshot_gc() {
-    let (_, mut hat, fam) = setup_family();
+fn snapshot_gc<B: StoreBackend>(backend: Arc<B>) {
+    let (_, mut hat, fam) = setup_family(backend);
 
     snapshot_files(&fam,
                    vec![("name1", vec![0; 1000000]),
@@ -193,10 +277,39 @@ fn snapshot_gc() {
     assert_eq!(live, 0);
 }
 
-#[test]
-fn recover() {
+fn snapshot_abort<B: StoreBackend>(backend: Arc<B>) {
+    let (_, mut hat, fam) = setup_family(backend);
+
+    snapshot_files(&fam,
+                   vec![("name1", vec![0; 1000000]),
+                        ("name2", vec![1; 1000000]),
+                        ("name3", vec![2; 1000000])])
+        .unwrap();
+
+    fam.flush().unwrap();
+    fam.abort().unwrap();
+    assert!(fam.list_from_key_store(None).unwrap().is_empty());
+
+    // The aborted data is reclaimed as if it was never committed.
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live, 0);
+
+    // Aborting twice is harmless, and the family can still be committed afterwards.
+    fam.abort().unwrap();
+    snapshot_files(&fam, vec![("name4", vec![3; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let (deleted, live) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert!(live > 0);
+}
+
+fn recover<B: StoreBackend>(backend: Arc<B>) {
     // Prepare a snapshot.
-    let (backend, mut hat, fam) = setup_family();
+    let (backend, mut hat, fam) = setup_family(backend);
 
     snapshot_files(&fam,
                    vec![("name1", vec![0; 1000000]),
@@ -215,7 +328,7 @@ fn recover() {
     let mut hat2 = setup_hat(backend);
 
     // Recover index states.
-    hat2.recover().unwrap();
+    assert!(hat2.recover().unwrap().is_complete());
 
     // Check that we now reference all the blobs.
     let (deleted, live2) = hat2.gc().unwrap();
@@ -229,3 +342,3108 @@ fn recover() {
     assert!(deleted > 0);
     assert_eq!(live3, 0);
 }
+
+#[test]
+fn rebuild_index_from_backend() {
+    let (backend, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam,
+                   vec![("name1", vec![0; 1000000]), ("name2", vec![1; 1000])])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    backend.store(b"not a blob", &CipherText::new(vec![1; 100])).unwrap();
+
+    // Start over with empty indexes.
+    let mut hat2 = setup_hat(backend);
+    let report = hat2.rebuild_index_from_backend().unwrap();
+    assert!(report.blobs > 0);
+    assert!(report.chunks > 0);
+    assert_eq!(report.unparseable, vec![b"not a blob".to_vec()]);
+    assert!(report.recover.is_complete());
+    assert_eq!(report.recover.recovered, 1);
+
+    let mut read = Vec::new();
+    hat2.open_file(fam.name.clone(), 1, b"name1").unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec![0; 1000000]);
+
+    // Every indexed blob is referenced by the recovered snapshot.
+    let (deleted, _) = hat2.gc().unwrap();
+    assert_eq!(deleted, 0);
+}
+
+#[test]
+fn recover_reports_missing_blobs() {
+    let (backend, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam,
+                   vec![("name1", vec![0; 1000]), ("name2", vec![1; 1000])])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    // Lose the blob holding the file data.
+    let data_blob = hat.hash_index
+        .fetch_persistent_ref(&hash::Hash::new(&[0; 1000]))
+        .unwrap()
+        .unwrap()
+        .blob_id;
+    backend.delete(&data_blob[..]).unwrap();
+
+    let mut hat2 = setup_hat(backend.clone());
+    let report = hat2.recover().unwrap();
+    assert!(!report.is_complete());
+    assert_eq!(report.recovered, 1);
+    assert_eq!(report.quarantined, 0);
+    assert_eq!(report.dangling.len(), 1);
+    assert_eq!(report.dangling[0].family_name, fam.name);
+    assert_eq!(report.dangling[0].missing_blobs, vec![data_blob]);
+    assert!(!report.dangling[0].unreadable);
+
+    // Quarantined snapshots are left out of the index.
+    let mut hat3 = setup_hat(backend);
+    let report = hat3.recover_with_quarantine(true).unwrap();
+    assert_eq!(report.recovered, 0);
+    assert_eq!(report.quarantined, 1);
+    assert!(hat3.list_snapshots().is_empty());
+}
+
+fn restore_dir() -> PathBuf {
+    let mut dir = env::temp_dir();
+    dir.push(format!("hat-restore-{:x}", rand::random::<u64>()));
+    dir
+}
+
+fn snapshot_restore<B: StoreBackend>(backend: Arc<B>) {
+    let (_, mut hat, fam) = setup_family(backend);
+
+    let mtime = 1234567890;
+    let files = vec![("name1", vec![0; 1000000], 0o644),
+                     ("name2", vec![1; 1000], 0o600),
+                     ("name3", vec![2; 10], 0o755)];
+    for &(name, ref contents, mode) in files.iter() {
+        let mut e = entry(name.bytes().collect());
+        e.permissions = Some(mode);
+        e.modified = Some(mtime * 1_000_000_000);
+        fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(contents.clone())))
+            .unwrap();
+    }
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    // Unknown snapshots are reported.
+    let output = restore_dir();
+    assert!(hat.restore(fam.name.clone(), 2, output.clone()).is_err());
+
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+    for (name, contents, mode) in files {
+        let path = output.join(name);
+
+        let mut restored = Vec::new();
+        fs::File::open(&path).unwrap().read_to_end(&mut restored).unwrap();
+        assert_eq!(contents, restored);
+
+        let md = fs::metadata(&path).unwrap();
+        assert_eq!(mode as u32, md.permissions().mode() & 0o7777);
+        assert_eq!(mtime, md.mtime());
+    }
+
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn snapshot_restore_empty_file_and_directory() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    fam.snapshot_direct(entry(b"empty_file".to_vec()),
+                         false,
+                         Some(FileIterator::from_bytes(vec![])))
+        .unwrap();
+    fam.snapshot_direct(entry(b"empty_dir".to_vec()), true, None).unwrap();
+    // A file without a data source is still a file.
+    fam.snapshot_direct(entry(b"no_data".to_vec()), false, None).unwrap();
+    fam.flush().unwrap();
+
+    let mut ls = fam.list_from_key_store(None).unwrap();
+    ls.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    let kinds: Vec<(bool, bool)> =
+        ls.iter().map(|&(ref e, _, _)| (e.is_file(), e.is_directory())).collect();
+    assert_eq!(kinds, vec![(false, true), (true, false), (true, false)]);
+
+    hat.commit(&fam, None).unwrap();
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+
+    for name in &["empty_file", "no_data"] {
+        let md = fs::symlink_metadata(output.join(name)).unwrap();
+        assert!(md.is_file());
+        assert_eq!(md.len(), 0);
+    }
+    let md = fs::symlink_metadata(output.join("empty_dir")).unwrap();
+    assert!(md.is_dir());
+    assert_eq!(fs::read_dir(output.join("empty_dir")).unwrap().count(), 0);
+
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn snapshot_commit_concurrent_uploads() {
+    // Small blobs make every file span many uploads.
+    let mut hat = HatRc::new_for_testing(Arc::new(MemoryBackend::new()), 256 * 1024).unwrap();
+    let options = blob::StoreOptions { upload_concurrency: Some(8), ..Default::default() };
+    let fam = hat.open_family_with_options("familyname".to_string(), options).unwrap();
+
+    let files: Vec<(&str, Vec<u8>)> = vec![("name1", (0..1000000).map(|i| i as u8).collect()),
+                                           ("name2", (0..600000).map(|i| (i / 7) as u8).collect()),
+                                           ("name3", vec![3; 50000])];
+    snapshot_files(&fam, files.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let report = hat.verify_snapshot(fam.name.clone(), 1).unwrap();
+    assert!(report.checked > 0);
+    assert_eq!(report.failed, 0);
+
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+    for (name, contents) in files {
+        let mut restored = Vec::new();
+        fs::File::open(output.join(name)).unwrap().read_to_end(&mut restored).unwrap();
+        assert_eq!(contents, restored);
+    }
+
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn concurrent_families_share_data() {
+    let files: Vec<(String, Vec<u8>)> = (0..50)
+        .map(|i| (format!("name{}", i), (0..20000).map(|j| ((i * j) % 251) as u8).collect()))
+        .collect();
+
+    fn snapshot(fam: &Family<MemoryBackend>, files: &[(String, Vec<u8>)]) {
+        for &(ref name, ref contents) in files {
+            fam.snapshot_direct(entry(name.bytes().collect()),
+                                false,
+                                Some(FileIterator::from_bytes(contents.clone())))
+                .unwrap();
+        }
+        fam.flush().unwrap();
+    }
+
+    // Snapshot two families with the same files at the same time.
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let fams = vec![hat.open_family("family1".to_string()).unwrap(),
+                    hat.open_family("family2".to_string()).unwrap()];
+    let threads: Vec<_> = fams.iter()
+        .map(|fam| {
+            let fam = fam.clone();
+            let files = files.clone();
+            thread::spawn(move || snapshot(&fam, &files[..]))
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    for fam in &fams {
+        hat.commit(fam, None).unwrap();
+    }
+    let (deleted, live) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+
+    // The same snapshots taken one after the other give the same hashes.
+    let mut hat2 = setup_hat(Arc::new(MemoryBackend::new()));
+    for name in vec!["family1", "family2"] {
+        let fam = hat2.open_family(name.to_string()).unwrap();
+        snapshot(&fam, &files[..]);
+        hat2.commit(&fam, None).unwrap();
+    }
+    assert_eq!(hat2.gc().unwrap(), (0, live));
+
+    // Everything can be read back and deleted.
+    for fam in &fams {
+        let mut read = Vec::new();
+        hat.open_file(fam.name.clone(), 1, b"name7").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, files[7].1);
+        hat.deregister(fam, 1).unwrap();
+    }
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live, 0);
+}
+
+#[test]
+fn snapshot_resume_after_interruption() {
+    let backend = Arc::new(MemoryBackend::new());
+    let root = restore_dir();
+    fs::create_dir_all(&root).unwrap();
+
+    let names: Vec<String> = (0..6).map(|i| format!("name{}", i)).collect();
+    let contents = |i: usize| vec![i as u8; 100000];
+
+    {
+        let hat = HatRc::open_repository(root.clone(), backend.clone(), 4 * 1024 * 1024).unwrap();
+        let fam = hat.open_family("familyname".to_string()).unwrap();
+
+        // Get through the first four files, then die before flushing.
+        snapshot_files(&fam, (0..4).map(|i| (names[i].as_str(), contents(i))).collect())
+            .unwrap();
+        fam.checkpoint().unwrap();
+    }
+
+    let hat = HatRc::open_repository(root.clone(), backend, 4 * 1024 * 1024).unwrap();
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+
+    let mut receivers = vec![];
+    for (i, name) in names.iter().enumerate() {
+        let (sender, receiver) = mpsc::channel();
+        fam.snapshot_direct_with(entry(name.bytes().collect()),
+                                 false,
+                                 Some(FileIterator::from_bytes(contents(i))),
+                                 SnapshotOptions { progress: Some(sender), ..Default::default() })
+            .unwrap();
+        receivers.push(receiver);
+    }
+    fam.flush().unwrap();
+
+    let read: Vec<bool> = receivers.iter()
+        .map(|r| r.iter().any(|p| match p {
+            Progress::BytesRead(_) => true,
+            _ => false,
+        }))
+        .collect();
+    assert_eq!(read, vec![false, false, false, false, true, true]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn reopen_repository_with_wal() {
+    let backend = Arc::new(MemoryBackend::new());
+    let root = restore_dir();
+    fs::create_dir_all(&root).unwrap();
+    let options = IndexOptions {
+        wal: true,
+        busy_timeout_ms: Some(5000),
+        master_key: None,
+        durability: Durability::PerBlob,
+        read_only: false,
+    };
+    let open = |backend: Arc<MemoryBackend>| {
+        HatRc::open_repository_with_options(root.clone(), backend, 4 * 1024 * 1024, options.clone())
+            .unwrap()
+    };
+
+    {
+        let mut hat = open(backend.clone());
+        let fam = hat.open_family("familyname".to_string()).unwrap();
+        snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+        hat.meta_commit().unwrap();
+
+        // The indexes write to a log next to the database while open.
+        assert!(root.join("snapshot_index.sqlite3-wal").exists());
+    }
+
+    let mut hat = open(backend);
+    let listing = hat.list_snapshots();
+    assert_eq!(listing.len(), 1);
+    assert_eq!(listing[0].family_name, "familyname");
+    assert_eq!(listing[0].snapshot_id, 1);
+    assert!(listing[0].committed);
+
+    let mut read = Vec::new();
+    hat.open_file("familyname".to_string(), 1, b"name1").unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec![1; 1000]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn read_only_repository() {
+    let backend = Arc::new(MemoryBackend::new());
+    let root = restore_dir();
+    fs::create_dir_all(&root).unwrap();
+    let options = IndexOptions { wal: true, ..IndexOptions::default() };
+    let mut hat =
+        HatRc::open_repository_with_options(root.clone(), backend.clone(), 4 * 1024 * 1024, options)
+            .unwrap();
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    // Open the repository a second time, read-only, while the first is still in use.
+    let mut reader =
+        HatRc::open_repository_read_only(root.clone(), backend.clone(), 4 * 1024 * 1024).unwrap();
+    let listing = reader.list_snapshots();
+    assert_eq!(listing.len(), 1);
+    assert!(listing[0].committed);
+
+    let output = restore_dir();
+    reader.restore("familyname".to_string(), 1, output.clone()).unwrap();
+    let mut restored = Vec::new();
+    fs::File::open(output.join("name1")).unwrap().read_to_end(&mut restored).unwrap();
+    assert_eq!(restored, vec![1; 1000]);
+
+    let read_only_fam = reader.open_family("familyname".to_string()).unwrap();
+    assert!(snapshot_files(&read_only_fam, vec![("name2", vec![2; 1000])]).is_err());
+    assert!(read_only_fam.flush().is_err());
+    assert!(reader.commit(&read_only_fam, None).is_err());
+    assert!(reader.deregister(&read_only_fam, 1).is_err());
+    assert!(reader.gc().is_err());
+    assert_eq!(reader.list_snapshots().len(), 1);
+
+    // The writer is not held up by the reader.
+    snapshot_files(&fam, vec![("name2", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    assert_eq!(hat.list_snapshots().len(), 2);
+
+    fs::remove_dir_all(&root).unwrap();
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn reopen_repository_with_per_commit_durability() {
+    let root = restore_dir();
+    let blobs = root.join("blobs");
+    fs::create_dir_all(&blobs).unwrap();
+    let backend = || {
+        Arc::new(FileBackend::new(blobs.clone()).with_durability(Durability::PerCommit))
+    };
+    let options = IndexOptions { durability: Durability::PerCommit, ..IndexOptions::default() };
+
+    {
+        let mut hat = HatRc::open_repository_with_options(root.clone(),
+                                                          backend(),
+                                                          4 * 1024 * 1024,
+                                                          options.clone())
+            .unwrap();
+        let fam = hat.open_family("familyname".to_string()).unwrap();
+        snapshot_files(&fam, vec![("name1", vec![1; 1000]), ("name2", vec![2; 300000])])
+            .unwrap();
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+        hat.meta_commit().unwrap();
+        drop(fam);
+        hat.close().unwrap();
+    }
+
+    let mut hat =
+        HatRc::open_repository_with_options(root.clone(), backend(), 4 * 1024 * 1024, options)
+            .unwrap();
+    let listing = hat.list_snapshots();
+    assert_eq!(listing.len(), 1);
+    assert!(listing[0].committed);
+
+    for &(name, byte, len) in &[(&b"name1"[..], 1, 1000), (&b"name2"[..], 2, 300000)] {
+        let mut read = Vec::new();
+        hat.open_file("familyname".to_string(), 1, name).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, vec![byte; len]);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn encrypted_indexes_need_the_key() {
+    let backend = Arc::new(MemoryBackend::new());
+    let root = restore_dir();
+    fs::create_dir_all(&root).unwrap();
+    let options = |key| IndexOptions { master_key: Some(key), ..IndexOptions::default() };
+    let params = kdf::Params::new(kdf::OPSLIMIT_MIN, 1024 * 1024);
+    let key = |passphrase: &[u8]| {
+        HatRc::derive_master_key_from(backend.clone(), 4 * 1024 * 1024, passphrase, params.clone())
+            .unwrap()
+    };
+    let open = |passphrase: &[u8]| {
+        HatRc::open_repository_with_options(root.clone(),
+                                            backend.clone(),
+                                            4 * 1024 * 1024,
+                                            options(key(passphrase)))
+    };
+    fs::File::create(root.join("notes")).unwrap().write_all(b"not an index").unwrap();
+
+    {
+        let mut hat = open(b"passphrase").unwrap();
+        let fam = hat.open_family("familyname".to_string()).unwrap();
+        snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+        hat.meta_commit().unwrap();
+
+        // An open family still uses the databases.
+        let fam2 = fam.clone();
+        drop(fam);
+        assert!(hat.close().is_err());
+        assert!(root.join("familyname").exists());
+        drop(fam2);
+
+        let hat = open(b"passphrase").unwrap();
+        hat.close().unwrap();
+    }
+
+    // Only the encrypted databases are left behind, next to the files that are not indexes.
+    assert!(root.join("snapshot_index.sqlite3.sealed").exists());
+    assert!(root.join("familyname.sealed").exists());
+    assert!(!root.join("snapshot_index.sqlite3").exists());
+    assert!(!root.join("familyname").exists());
+    assert!(root.join("notes").exists());
+    assert!(!root.join("notes.sealed").exists());
+
+    match open(b"other passphrase") {
+        Err(HatError::IO(_)) => (),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Opened the indexes with the wrong key"),
+    }
+    assert!(!root.join("snapshot_index.sqlite3").exists());
+
+    let mut hat = open(b"passphrase").unwrap();
+    let listing = hat.list_snapshots();
+    assert_eq!(listing.len(), 1);
+    assert_eq!(listing[0].family_name, "familyname");
+    assert!(listing[0].committed);
+
+    let mut read = Vec::new();
+    hat.open_file("familyname".to_string(), 1, b"name1").unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec![1; 1000]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn snapshot_incremental_skips_unchanged() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let fam = hat.open_incremental_family("familyname".to_string()).unwrap();
+
+    let file = |accessed, contents: Vec<u8>| {
+        let mut e = entry(b"name1".to_vec());
+        e.modified = Some(1234567890);
+        e.accessed = Some(accessed);
+        e.data_length = Some(contents.len() as u64);
+        e
+    };
+    let snapshot = |accessed, contents: Vec<u8>| {
+        let (sender, receiver) = mpsc::channel();
+        fam.snapshot_direct_with(file(accessed, contents.clone()),
+                                 false,
+                                 Some(FileIterator::from_bytes(contents)),
+                                 SnapshotOptions { progress: Some(sender), ..Default::default() })
+            .unwrap();
+        fam.flush().unwrap();
+        receiver.iter().collect::<Vec<_>>()
+    };
+
+    let events = snapshot(1, vec![1; 100000]);
+    assert!(events.iter().any(|e| *e == Progress::ChunkStored));
+    hat.commit(&fam, None).unwrap();
+
+    // Same modification time and size, so the contents are not read again.
+    let events = snapshot(2, vec![2; 100000]);
+    assert_eq!(events, vec![Progress::FileDone]);
+    hat.commit(&fam, None).unwrap();
+}
+
+#[test]
+fn open_file_streams_contents() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let contents: Vec<u8> = (0..1000000).map(|i| (i % 251) as u8).collect();
+    snapshot_files(&fam, vec![("name1", vec![1; 1000]), ("name2", contents.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let mut file = hat.open_file(fam.name.clone(), 1, b"name2").unwrap();
+    let mut read = Vec::new();
+    file.read_to_end(&mut read).unwrap();
+    assert_eq!(contents, read);
+
+    // Seek past several chunks, and then back into the first one.
+    for &offset in &[700000, 12345] {
+        assert_eq!(file.seek(SeekFrom::Start(offset)).unwrap(), offset);
+        let mut read = vec![0; 1000];
+        file.read_exact(&mut read).unwrap();
+        assert_eq!(&contents[offset as usize..offset as usize + 1000], &read[..]);
+    }
+
+    assert!(hat.open_file(fam.name.clone(), 1, b"name3").is_err());
+}
+
+#[test]
+fn history_lists_file_versions() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    snapshot_files(&fam, vec![("name1", vec![2; 2000]), ("name2", vec![3; 10])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let history = hat.history(fam.name.clone(), b"name1").unwrap();
+    assert_eq!(history.iter().map(|v| v.snapshot_id).collect::<Vec<_>>(), vec![1, 2]);
+    assert!(history[0].data_hash != history[1].data_hash);
+    assert_eq!(history[0].size, Some(1000));
+    assert_eq!(history[1].size, Some(2000));
+
+    // Each version can be read back by its hash.
+    let mut read = Vec::new();
+    hat.get_by_hash(&history[0].data_hash).unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec![1; 1000]);
+
+    let history = hat.history(fam.name.clone(), b"name2").unwrap();
+    assert_eq!(history.iter().map(|v| v.snapshot_id).collect::<Vec<_>>(), vec![2]);
+    assert!(hat.history(fam.name.clone(), b"name3").unwrap().is_empty());
+}
+
+#[test]
+fn snapshot_over_quota() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    hat.set_quota(Some(256 * 1024));
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+
+    // Random data is not deduplicated, so all of it counts against the quota.
+    let contents: Vec<u8> = (0..1000000).map(|_| rand::random::<u8>()).collect();
+    match snapshot_files(&fam, vec![("name1", contents)]).and_then(|()| fam.flush()) {
+        Err(HatError::Quota(e)) => assert_eq!(e.quota, 256 * 1024),
+        other => panic!("Expected a quota error, got {:?}", other),
+    }
+
+    // The data stored before reaching the quota is reclaimed once the snapshot is discarded.
+    fam.abort().unwrap();
+    assert!(hat.stored_bytes() > 0);
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live, 0);
+    assert_eq!(hat.stored_bytes(), 0);
+
+    // Snapshots within the quota still succeed.
+    snapshot_files(&fam, vec![("name2", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    assert!(hat.stored_bytes() > 0 && hat.stored_bytes() <= 256 * 1024);
+}
+
+#[test]
+fn snapshot_with_chunk_profiles() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    let contents: Vec<u8> = (0..1000000).map(|_| rand::random::<u8>()).collect();
+    let chunks = |fam: &Family<MemoryBackend>| {
+        let stats = fam.dedup_stats();
+        stats.chunks_stored + stats.chunks_deduplicated
+    };
+
+    fam.snapshot_direct_with(entry(b"large".to_vec()),
+                             false,
+                             Some(FileIterator::from_bytes(contents.clone())),
+                             SnapshotOptions::default())
+        .unwrap();
+    fam.flush().unwrap();
+    let large = chunks(&fam);
+    assert_eq!(large, 8);
+
+    let profile = key::ChunkProfile::content_defined(2048, 8192, 32768);
+    fam.snapshot_direct_with(entry(b"small".to_vec()),
+                             false,
+                             Some(FileIterator::from_bytes(contents.clone())),
+                             SnapshotOptions { profile: profile, ..Default::default() })
+        .unwrap();
+    fam.flush().unwrap();
+    let small = chunks(&fam) - large;
+    assert!(small > 50 && small < 200, "{} chunks", small);
+    hat.commit(&fam, None).unwrap();
+
+    for name in &[&b"large"[..], &b"small"[..]] {
+        let mut file = hat.open_file(fam.name.clone(), 1, name).unwrap();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(contents, read);
+
+        // Seeking skips chunks by their recorded lengths.
+        for &offset in &[700000, 12345] {
+            assert_eq!(file.seek(SeekFrom::Start(offset)).unwrap(), offset);
+            let mut read = vec![0; 1000];
+            file.read_exact(&mut read).unwrap();
+            assert_eq!(&contents[offset as usize..offset as usize + 1000], &read[..]);
+        }
+    }
+}
+
+#[test]
+fn snapshot_stream_of_unknown_length() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let contents: Vec<u8> = (0..1500000).map(|_| rand::random::<u8>()).collect();
+    fam.snapshot_stream(b"dump".to_vec(), io::Cursor::new(contents.clone())).unwrap();
+    fam.flush().unwrap();
+
+    // The length is recorded once the stream has been read.
+    let ls = fam.list_from_key_store(None).unwrap();
+    assert_eq!(ls.len(), 1);
+    assert!(ls[0].0.is_file());
+    assert_eq!(ls[0].0.data_length, Some(contents.len() as u64));
+
+    hat.commit(&fam, None).unwrap();
+    let mut read = Vec::new();
+    hat.open_file(fam.name.clone(), 1, b"dump").unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(contents, read);
+}
+
+#[test]
+fn put_file_returns_root_hash() {
+    let (_, hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let contents: Vec<u8> = (0..1500000).map(|_| rand::random::<u8>()).collect();
+    let hash = fam.put_file(entry(b"data".to_vec()), io::Cursor::new(contents.clone())).unwrap();
+
+    // The data is read back through its hash alone, without committing a snapshot.
+    let backend = hat.hash_backend();
+    let pref = backend.fetch_persistent_ref(&hash).unwrap();
+    let mut read = Vec::new();
+    FileReader::new(backend, hash.clone(), pref).unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(contents, read);
+
+    // The same bytes have the same hash under another name.
+    let copy = fam.put_file(entry(b"copy".to_vec()), io::Cursor::new(contents)).unwrap();
+    assert_eq!(hash, copy);
+}
+
+#[test]
+fn get_by_hash_after_recover() {
+    let (backend, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let contents: Vec<u8> = (0..1500000).map(|_| rand::random::<u8>()).collect();
+    let hash = fam.put_file(entry(b"data".to_vec()), io::Cursor::new(contents.clone())).unwrap();
+    let mut read = Vec::new();
+    hat.get_by_hash(&hash).unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(contents, read);
+
+    // Empty data has the empty hash, which reads as empty.
+    let empty = fam.put_file(entry(b"empty".to_vec()), io::empty()).unwrap();
+    assert!(empty.bytes.is_empty());
+    let mut read = Vec::new();
+    hat.get_by_hash(&empty).unwrap().read_to_end(&mut read).unwrap();
+    assert!(read.is_empty());
+
+    // The data is kept by the snapshot holding the file.
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    // Create a new hat to wipe the index states, and recover them.
+    let mut hat2 = setup_hat(backend.clone());
+    assert!(hat2.recover().unwrap().is_complete());
+
+    let mut read = Vec::new();
+    hat2.get_by_hash(&hash).unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(contents, read);
+
+    // Unknown hashes and lost chunks are reported as errors.
+    assert!(hat2.get_by_hash(&hash::Hash::new(b"unknown")).is_err());
+
+    // The blob is deleted through the store, which would otherwise read it from its cache.
+    let pref = hat2.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap();
+    hat2.blob_store.tag(pref, tags::Tag::WillDelete);
+    hat2.blob_store.delete_by_tag(tags::Tag::WillDelete, 10).unwrap();
+    let mut read = Vec::new();
+    let failed = match hat2.get_by_hash(&hash) {
+        Ok(mut reader) => reader.read_to_end(&mut read).is_err(),
+        Err(_) => true,
+    };
+    assert!(failed);
+}
+
+#[test]
+fn tiny_files_are_stored_inline() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend.clone());
+    let options = blob::StoreOptions { inline_threshold: Some(1024), ..Default::default() };
+    let fam = hat.open_family_with_options("familyname".to_string(), options).unwrap();
+
+    let contents = b"0123456789".to_vec();
+    snapshot_files(&fam, vec![("tiny", contents.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let pref = hat.hash_index
+        .fetch_persistent_ref(&hash::Hash::new(&contents[..]))
+        .unwrap()
+        .unwrap();
+    assert_eq!(pref.inline, Some(contents.clone()));
+    assert_eq!((pref.offset, pref.length), (0, 0));
+
+    // Only the file data is inline; the directory listing holding it is sealed in a blob.
+    let listing = hat.list_snapshots();
+    let listing_hash = hash::Hash { bytes: listing[0].hash.clone().unwrap() };
+    let listing_ref = hat.hash_index.fetch_persistent_ref(&listing_hash).unwrap().unwrap();
+    assert_eq!(listing_ref.inline, None);
+    assert!(listing_ref.key.is_some());
+    assert!(!backend.list().unwrap().is_empty());
+
+    let dir = restore_dir();
+    hat.restore(fam.name.clone(), 1, dir.clone()).unwrap();
+    let mut read = Vec::new();
+    fs::File::open(dir.join("tiny")).unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, contents);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn missing_blob_is_reported_as_not_found() {
+    let (backend, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let hash = fam.put_file(entry(b"data".to_vec()), io::Cursor::new(vec![1; 1000])).unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let blob_id = hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap().blob_id;
+    backend.delete(&blob_id[..]).unwrap();
+
+    match hat.get_by_hash(&hash) {
+        Err(HatError::BlobNotFound(e)) => assert_eq!(e.blob_id, blob_id),
+        Err(e) => panic!("Expected a missing blob, got {}", e),
+        Ok(_) => panic!("Read a chunk of a deleted blob"),
+    }
+
+    let dir = restore_dir();
+    match hat.restore(fam.name.clone(), 1, dir.clone()) {
+        Err(HatError::BlobNotFound(e)) => assert_eq!(e.blob_id, blob_id),
+        res => panic!("Expected a missing blob, got {:?}", res),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn blob_errors_convert_to_their_own_variants() {
+    fn convert(res: Result<(), blob::BlobError>) -> Result<(), HatError> {
+        try!(res);
+        Ok(())
+    }
+
+    let missing = blob::BlobError::NotFound(errors::BlobNotFoundError { blob_id: vec![1] });
+    match convert(Err(missing)) {
+        Err(HatError::BlobNotFound(e)) => assert_eq!(e.blob_id, vec![1]),
+        res => panic!("Expected a missing blob, got {:?}", res),
+    }
+    let unavailable = blob::BlobError::Backend(BackendError::Retry(errors::RetryError));
+    match convert(Err(unavailable)) {
+        Err(HatError::BackendUnavailable(_)) => (),
+        res => panic!("Expected an unavailable backend, got {:?}", res),
+    }
+}
+
+/// Records the messages logged at info level or above, by all tests of this process.
+struct CaptureLogger {
+    records: Arc<Mutex<Vec<(log::LogLevel, String)>>>,
+}
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, metadata: &log::LogMetadata) -> bool {
+        metadata.level() <= log::LogLevel::Info
+    }
+
+    fn log(&self, record: &log::LogRecord) {
+        if self.enabled(record.metadata()) {
+            self.records.lock().unwrap().push((record.level(), format!("{}", record.args())));
+        }
+    }
+}
+
+fn captured_logs() -> Arc<Mutex<Vec<(log::LogLevel, String)>>> {
+    // There can only be one logger per process.
+    static INIT: Once = ONCE_INIT;
+    static mut RECORDS: *const Arc<Mutex<Vec<(log::LogLevel, String)>>> =
+        0 as *const Arc<Mutex<Vec<(log::LogLevel, String)>>>;
+    unsafe {
+        INIT.call_once(|| {
+            let records = Arc::new(Mutex::new(Vec::new()));
+            RECORDS = Box::into_raw(Box::new(records.clone()));
+            log::set_logger(|max_level| {
+                    max_level.set(log::LogLevelFilter::Info);
+                    Box::new(CaptureLogger { records: records })
+                })
+                .unwrap();
+        });
+        (*RECORDS).clone()
+    }
+}
+
+#[test]
+fn snapshot_and_commit_log_info_events() {
+    let logs = captured_logs();
+    let family = format!("logged-{:x}", rand::random::<u64>());
+    let dir = restore_dir();
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join("name1")).unwrap().write_all(&[1; 1000]).unwrap();
+
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let fam = hat.open_family(family.clone()).unwrap();
+    fam.snapshot_dir(dir.clone(), &[]);
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    // The start and end of both the snapshot and its commit are logged.
+    let expected = vec![format!("Snapshotting {} into family {}", dir.display(), family),
+                        format!("Finished snapshotting {} into family {}", dir.display(), family),
+                        format!("Committing snapshot 1 of family {}", family),
+                        format!("Committed snapshot 1 of family {}", family)];
+    let records = logs.lock().unwrap();
+    for line in expected {
+        assert!(records.iter().any(|&(level, ref msg)| {
+                    level == log::LogLevel::Info && *msg == line
+                }),
+                "Missing log line: {}",
+                line);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshot_large_stream() {
+    /// Generates `left` pseudo-random bytes without holding them in memory.
+    struct Generated {
+        state: u32,
+        left: usize,
+    }
+    impl Read for Generated {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = cmp::min(buf.len(), self.left);
+            for b in buf[..n].iter_mut() {
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 17;
+                self.state ^= self.state << 5;
+                *b = self.state as u8;
+            }
+            self.left -= n;
+            Ok(n)
+        }
+    }
+
+    let mut spill_dir = env::temp_dir();
+    spill_dir.push(format!("hat-tree-spill-{:x}", rand::random::<u64>()));
+    fs::create_dir(&spill_dir).unwrap();
+
+    // A narrow tree is deep, so that many of its nodes are stored before the stream ends. A wide
+    // tree holds many references per level, which are moved to the spill directory.
+    for &(order, spill) in &[(2, false), (64, true)] {
+        let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+        hat.set_tree_order(order);
+        if spill {
+            hat.set_tree_spill(spill_dir.clone(), 4);
+        }
+        let fam = hat.open_family("familyname".to_string()).unwrap();
+
+        let len = 8 * 1024 * 1024;
+        fam.snapshot_stream(b"big".to_vec(), Generated { state: 1, left: len }).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 0);
+
+        // Compare piecewise, without holding the whole file in memory either.
+        let mut expected = Generated { state: 1, left: len };
+        let mut file = hat.open_file(fam.name.clone(), 1, b"big").unwrap();
+        let (mut read, mut generated) = (vec![0; 65536], vec![0; 65536]);
+        let mut total = 0;
+        loop {
+            let n = file.read(&mut read).unwrap();
+            if n == 0 {
+                break;
+            }
+            expected.read_exact(&mut generated[..n]).unwrap();
+            assert!(read[..n] == generated[..n]);
+            total += n;
+        }
+        assert_eq!(total, len);
+    }
+    fs::remove_dir(&spill_dir).unwrap();
+}
+
+#[test]
+fn snapshot_cancelled_partway() {
+    /// Cancels `cancel` after `left` bytes have been read.
+    struct CancelAfter {
+        inner: io::Cursor<Vec<u8>>,
+        left: usize,
+        cancel: CancelToken,
+    }
+    impl Read for CancelAfter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.left == 0 {
+                self.cancel.cancel();
+            }
+            let n = try!(self.inner.read(&mut buf[..cmp::min(buf.len(), self.left)]));
+            self.left -= n;
+            Ok(n)
+        }
+    }
+
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    let cancel = CancelToken::new();
+
+    let contents: Vec<u8> = (0..3000000).map(|_| rand::random::<u8>()).collect();
+    let reader = CancelAfter {
+        inner: io::Cursor::new(contents),
+        left: 1500000,
+        cancel: cancel.clone(),
+    };
+    let options = SnapshotOptions { cancel: Some(cancel.clone()), ..Default::default() };
+    fam.snapshot_direct_with(entry(b"big".to_vec()),
+                             false,
+                             Some(FileIterator::from_reader(Box::new(reader))),
+                             options.clone())
+        .unwrap();
+    // The cut-short contents are not stored, and the snapshot cannot be committed.
+    match fam.flush() {
+        Err(HatError::Cancelled(_)) => (),
+        other => panic!("Expected the read to be cancelled, got: {:?}", other),
+    }
+    assert!(cancel.is_cancelled());
+    let stored = fam.key_store.lookup(None, b"big".to_vec()).unwrap().unwrap();
+    assert_eq!(stored.data_hash, None);
+    match hat.commit(&fam, None) {
+        Err(HatError::Cancelled(_)) => (),
+        other => panic!("Expected the commit to be rejected, got: {:?}", other),
+    }
+
+    match fam.snapshot_direct_with(entry(b"next".to_vec()),
+                                   false,
+                                   Some(FileIterator::from_bytes(vec![1; 1000])),
+                                   options) {
+        Err(HatError::Cancelled(_)) => (),
+        other => panic!("Expected the snapshot to be cancelled, got: {:?}", other),
+    }
+    match hat.gc_with(GcOptions { batch_size: 16, cancel: cancel, ..GcOptions::default() }) {
+        Err(HatError::Cancelled(_)) => (),
+        other => panic!("Expected gc to be cancelled, got: {:?}", other),
+    }
+
+    // The partial snapshot is discarded, and its stored chunks are reclaimed.
+    fam.abort().unwrap();
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live, 0);
+    assert_eq!(hat.count_blobs(blob::BlobStatus::Committed), 0);
+}
+
+#[test]
+fn custom_max_blob_size() {
+    assert!(HatRc::new_for_testing(Arc::new(MemoryBackend::new()), 0).is_err());
+    assert!(HatRc::new_for_testing(Arc::new(MemoryBackend::new()), blob::MAX_BLOB_SIZE + 1)
+        .is_err());
+
+    let leaf_blobs = |hat: &HatRc<MemoryBackend>| {
+        hat.hash_index
+            .list_from(0, usize::max_value())
+            .into_iter()
+            .filter(|&(_, ref e)| e.level == 0)
+            .filter_map(|(_, e)| e.persistent_ref.map(|r| r.blob_id))
+            .collect::<HashSet<_>>()
+    };
+    let random_files = || {
+        ["name1", "name2", "name3", "name4", "name5", "name6", "name7", "name8"]
+            .iter()
+            .map(|name| (*name, (0..100000).map(|_| rand::random::<u8>()).collect()))
+            .collect::<Vec<(&str, Vec<u8>)>>()
+    };
+
+    let hat = HatRc::new_for_testing(Arc::new(MemoryBackend::new()), 2 * 1024 * 1024).unwrap();
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(&fam, random_files()).unwrap();
+    fam.flush().unwrap();
+
+    // All chunks fit in a single blob.
+    let blobs = leaf_blobs(&hat);
+    assert_eq!(blobs.len(), 1);
+
+    // A family can use smaller blobs.
+    let options = blob::StoreOptions {
+        max_blob_size: Some(blob::MIN_BLOB_SIZE),
+        ..Default::default()
+    };
+    let small = hat.open_family_with_options("small".to_string(), options).unwrap();
+    snapshot_files(&small, random_files()).unwrap();
+    small.flush().unwrap();
+    assert!(leaf_blobs(&hat).len() - blobs.len() >= 4);
+
+    let options = blob::StoreOptions { max_blob_size: Some(0), ..Default::default() };
+    assert!(hat.open_family_with_options("invalid".to_string(), options).is_err());
+}
+
+#[test]
+fn derive_master_key() {
+    let params = kdf::Params::new(kdf::OPSLIMIT_MIN, 1024 * 1024);
+    let key = kdf::derive_key(b"passphrase", &params).unwrap();
+    assert_eq!(key, kdf::derive_key(b"passphrase", &params).unwrap());
+    assert!(key != kdf::derive_key(b"other passphrase", &params).unwrap());
+
+    let other_salt = kdf::Params { salt: vec![0; kdf::SALTBYTES], ..params.clone() };
+    assert!(key != kdf::derive_key(b"passphrase", &other_salt).unwrap());
+
+    // The parameters are persisted, so later derivations use the original salt and costs.
+    let backend = Arc::new(MemoryBackend::new());
+    let hat = setup_hat(backend.clone());
+    let key = hat.derive_master_key(b"passphrase", params.clone()).unwrap();
+    assert_eq!(key, kdf::derive_key(b"passphrase", &params).unwrap());
+
+    let hat = setup_hat(backend);
+    let other_params = kdf::Params::new(kdf::OPSLIMIT_MIN, 2 * 1024 * 1024);
+    assert_eq!(key, hat.derive_master_key(b"passphrase", other_params).unwrap());
+}
+
+#[test]
+fn master_key_seals_blobs() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend.clone());
+    let params = kdf::Params::new(kdf::OPSLIMIT_MIN, 1024 * 1024);
+    let key = hat.derive_master_key(b"passphrase", params).unwrap();
+    hat.set_master_key(&key);
+
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    // Only the key derivation parameters open without the derived key.
+    let mut sealed = blob::Blob::new(4 * 1024 * 1024);
+    sealed.set_master_key(FixedKey::from_master_key(&key));
+    let unsealed = blob::Blob::new(4 * 1024 * 1024);
+    for name in backend.list().unwrap() {
+        let data = backend.retrieve(&name[..]).unwrap().unwrap();
+        if &name[..] == b"kdf_params" {
+            assert!(unsealed.refs_from_bytes(&data[..]).is_ok());
+        } else {
+            assert!(unsealed.refs_from_bytes(&data[..]).is_err());
+            assert!(sealed.refs_from_bytes(&data[..]).is_ok());
+        }
+    }
+
+    let mut read = Vec::new();
+    hat.open_file(fam.name.clone(), 1, b"name1").unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec![1; 1000]);
+}
+
+#[test]
+fn hashers_deduplicate_separately() {
+    let contents: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+
+    let blake2b = hash::Hash::with_hasher(&hash::Blake2b, &contents[..]).unwrap();
+    let sha256 = hash::Hash::with_hasher(&hash::Sha256, &contents[..]).unwrap();
+    assert_eq!(blake2b, hash::Hash::new(&contents[..]));
+    assert!(blake2b != sha256);
+    assert_eq!(blake2b.hasher_id(), hash::BLAKE2B_ID);
+    assert_eq!(sha256.hasher_id(), hash::SHA256_ID);
+    assert!(blake2b.verify(&contents[..]) && sha256.verify(&contents[..]));
+
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let fam1 = hat.open_family("family1".to_string()).unwrap();
+    snapshot_files(&fam1, vec![("name1", contents.clone())]).unwrap();
+    fam1.flush().unwrap();
+    let count = hat.hash_index.list_ids().len();
+
+    // The same data hashed with the same algorithm is deduplicated.
+    snapshot_files(&fam1, vec![("name2", contents.clone())]).unwrap();
+    fam1.flush().unwrap();
+    assert_eq!(hat.hash_index.list_ids().len(), count);
+
+    // The same data hashed with another algorithm is stored again.
+    hat.set_hasher(Arc::new(hash::Sha256)).unwrap();
+    let fam2 = hat.open_family("family2".to_string()).unwrap();
+    snapshot_files(&fam2, vec![("name1", contents.clone())]).unwrap();
+    fam2.flush().unwrap();
+    let count2 = hat.hash_index.list_ids().len();
+    assert!(count2 > count);
+
+    snapshot_files(&fam2, vec![("name2", contents.clone())]).unwrap();
+    fam2.flush().unwrap();
+    assert_eq!(hat.hash_index.list_ids().len(), count2);
+
+    // Both are readable from the mixed store.
+    hat.commit(&fam1, None).unwrap();
+    hat.commit(&fam2, None).unwrap();
+    for name in vec![fam1.name.clone(), fam2.name.clone()] {
+        let mut read = Vec::new();
+        hat.open_file(name, 1, b"name2").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(contents, read);
+    }
+}
+
+#[test]
+fn hasher_with_ambiguous_digest_length_fails() {
+    // Prefixed by its id, a digest of 63 bytes would read as a BLAKE2b hash.
+    struct Truncated;
+    impl hash::Hasher for Truncated {
+        fn id(&self) -> u8 {
+            200
+        }
+        fn digest(&self, text: &[u8]) -> Vec<u8> {
+            let mut digest = hash::Hash::new(text).bytes;
+            digest.truncate(63);
+            digest
+        }
+    }
+    assert!(hash::Hash::with_hasher(&Truncated, b"data").is_err());
+
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    match hat.set_hasher(Arc::new(Truncated)) {
+        Err(HatError::Hasher(e)) => assert_eq!(e.digest_len, 63),
+        _ => panic!("Expected a hasher error"),
+    }
+}
+
+#[cfg(feature = "mount")]
+#[test]
+fn mount_snapshot() {
+    use fuse;
+    use std::path::Path;
+
+    if !Path::new("/dev/fuse").exists() {
+        return;
+    }
+
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let contents: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+    let mut e = entry(b"name1".to_vec());
+    e.permissions = Some(0o640);
+    e.modified = Some(1234567890 * 1_000_000_000);
+    e.data_length = Some(contents.len() as u64);
+    fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(contents.clone()))).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let mountpoint = restore_dir();
+    fs::create_dir_all(&mountpoint).unwrap();
+    let snapshot_fs = hat.snapshot_fs(fam.name.clone(), 1).unwrap();
+    let session = match unsafe { fuse::spawn_mount(snapshot_fs, &mountpoint, &[]) } {
+        Ok(session) => session,
+        // Mounting may need privileges we do not have.
+        Err(_) => return,
+    };
+
+    let path = mountpoint.join("name1");
+    let md = fs::metadata(&path).unwrap();
+    assert_eq!(md.len(), contents.len() as u64);
+    assert_eq!(md.mtime(), 1234567890);
+    // Stored modes are shown without write permissions.
+    assert_eq!(md.permissions().mode() & 0o7777, 0o440);
+
+    let mut read = Vec::new();
+    fs::File::open(&path).unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(contents, read);
+
+    drop(session);
+    fs::remove_dir_all(&mountpoint).unwrap();
+}
+
+#[test]
+fn deregister_by_selector() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    let commit_time = hat.list_snapshots()[0].commit_time.unwrap();
+
+    let missing = SnapshotSelector::CommitTime(commit_time + 1);
+    assert!(hat.deregister_snapshot(&fam, &missing).is_err());
+
+    snapshot_files(&fam, vec![("name2", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    // Unlabeled snapshots match no label.
+    let label = SnapshotSelector::Label("anonymous".to_string());
+    assert!(hat.deregister_snapshot(&fam, &label).is_err());
+
+    hat.deregister_snapshot(&fam, &SnapshotSelector::CommitTime(commit_time)).unwrap();
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert!(live > 0);
+
+    // Only the second snapshot is left.
+    hat.deregister_snapshot(&fam, &SnapshotSelector::Id(2)).unwrap();
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live, 0);
+}
+
+#[test]
+fn commit_returns_snapshot_id() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    let first = hat.commit(&fam, None).unwrap();
+    assert_eq!(first,
+               CommittedSnapshot {
+                   snapshot_id: 1,
+                   label: None,
+                   unchanged: false,
+               });
+
+    snapshot_files(&fam, vec![("name2", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    let second = hat.commit_with_label(&fam, "weekly").unwrap();
+    assert_eq!(second,
+               CommittedSnapshot {
+                   snapshot_id: 2,
+                   label: Some("weekly".to_string()),
+                   unchanged: false,
+               });
+
+    // The returned ids are the ones to deregister by.
+    hat.deregister(&fam, first.snapshot_id).unwrap();
+    let ids: Vec<_> = hat.list_snapshots().into_iter().map(|s| s.snapshot_id).collect();
+    assert_eq!(ids, vec![second.snapshot_id]);
+    hat.deregister(&fam, second.snapshot_id).unwrap();
+    assert!(hat.list_snapshots().is_empty());
+}
+
+#[test]
+fn unchanged_commit_stores_no_new_blobs() {
+    let (backend, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    let files = || vec![("name1", vec![1; 1000]), ("name2", vec![2; 100000])];
+
+    snapshot_files(&fam, files()).unwrap();
+    fam.flush().unwrap();
+    let first = hat.commit(&fam, None).unwrap();
+    assert!(!first.unchanged);
+    let blobs = backend.list().unwrap().len();
+
+    // Committing the same content again shares all of the first snapshot's data.
+    snapshot_files(&fam, files()).unwrap();
+    fam.flush().unwrap();
+    let second = hat.commit(&fam, None).unwrap();
+    assert_eq!(second.snapshot_id, 2);
+    assert!(second.unchanged);
+    assert_eq!(backend.list().unwrap().len(), blobs);
+
+    // When skipped, the previous snapshot is returned instead.
+    hat.set_unchanged_commit(UnchangedCommit::Skip);
+    let third = hat.commit(&fam, None).unwrap();
+    assert_eq!(third,
+               CommittedSnapshot {
+                   snapshot_id: 2,
+                   label: None,
+                   unchanged: true,
+               });
+    assert_eq!(backend.list().unwrap().len(), blobs);
+    let ids: Vec<_> = hat.list_snapshots().into_iter().map(|s| s.snapshot_id).collect();
+    assert_eq!(ids, vec![1, 2]);
+    // Nothing was reserved for the skipped snapshot.
+    assert_eq!(hat.snapshot_index.list_all().len(), 2);
+
+    // A changed snapshot is committed as usual.
+    snapshot_files(&fam, vec![("name3", vec![3; 1000])]).unwrap();
+    fam.flush().unwrap();
+    let fourth = hat.commit(&fam, None).unwrap();
+    assert_eq!(fourth.snapshot_id, 3);
+    assert!(!fourth.unchanged);
+}
+
+#[test]
+fn prepared_commits_are_committed_together() {
+    let (_, mut hat, fam1) = setup_family(Arc::new(MemoryBackend::new()));
+    let fam2 = hat.open_family("other".to_string()).unwrap();
+    hat.set_pre_commit_hook(Some(Box::new(|summary: &SnapshotSummary| {
+        if summary.bytes > 10000 {
+            return Err(HatError::from("Snapshot too large"));
+        }
+        Ok(())
+    })));
+
+    snapshot_files(&fam1, vec![("small", vec![1; 1000])]).unwrap();
+    fam1.flush().unwrap();
+    snapshot_files(&fam2, vec![("large", vec![2; 100000])]).unwrap();
+    fam2.flush().unwrap();
+
+    // The second family fails to prepare, so the first is dropped and neither is listed.
+    let prepared = hat.prepare_commit(&fam1).unwrap();
+    assert_eq!(prepared.snapshot().snapshot_id, 1);
+    assert!(hat.prepare_commit(&fam2).is_err());
+    hat.abort_commit(&fam1, prepared).unwrap();
+    assert!(hat.list_snapshots().is_empty());
+
+    // Once both prepare, both are committed.
+    fam2.abort().unwrap();
+    snapshot_files(&fam2, vec![("small", vec![2; 1000])]).unwrap();
+    fam2.flush().unwrap();
+    let prepared1 = hat.prepare_commit(&fam1).unwrap();
+    let prepared2 = hat.prepare_commit(&fam2).unwrap();
+    assert!(hat.list_snapshots().iter().all(|s| !s.committed));
+    hat.finalize_commit(&fam1, prepared1).unwrap();
+    hat.finalize_commit(&fam2, prepared2).unwrap();
+    let committed: Vec<_> = hat.list_snapshots()
+        .into_iter()
+        .filter(|s| s.committed)
+        .map(|s| s.family_name)
+        .collect();
+    assert_eq!(committed, vec!["familyname".to_string(), "other".to_string()]);
+}
+
+#[test]
+fn resume_rolls_back_unfinalized_commits() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let fam1 = hat.open_family("familyname".to_string()).unwrap();
+    let fam2 = hat.open_family("other".to_string()).unwrap();
+    snapshot_files(&fam1, vec![("one", vec![1; 1000])]).unwrap();
+    fam1.flush().unwrap();
+    snapshot_files(&fam2, vec![("two", vec![2; 1000])]).unwrap();
+    fam2.flush().unwrap();
+
+    // As if the process died after preparing the first family: it is not committed alone.
+    hat.prepare_commit(&fam1).unwrap();
+    hat.resume().unwrap();
+    assert!(hat.list_snapshots().is_empty());
+
+    // Only the snapshot finalized before dying is kept.
+    let prepared1 = hat.prepare_commit(&fam1).unwrap();
+    hat.prepare_commit(&fam2).unwrap();
+    hat.finalize_commit(&fam1, prepared1).unwrap();
+    hat.resume().unwrap();
+    let listing = hat.list_snapshots();
+    assert_eq!(listing.len(), 1);
+    assert_eq!(listing[0].family_name, "familyname");
+    assert!(listing[0].committed);
+
+    // Snapshots prepared together and not finalized are all rolled back.
+    hat.prepare_commit(&fam1).unwrap();
+    hat.prepare_commit(&fam2).unwrap();
+    hat.resume().unwrap();
+    assert_eq!(hat.list_snapshots().len(), 1);
+
+    // Once finalized together, all of them are kept.
+    let prepared1 = hat.prepare_commit(&fam1).unwrap();
+    let prepared2 = hat.prepare_commit(&fam2).unwrap();
+    let ids = vec![prepared1.snapshot().snapshot_id, prepared2.snapshot().snapshot_id];
+    let committed = hat.finalize_commits(&[(&fam1, prepared1), (&fam2, prepared2)]).unwrap();
+    assert_eq!(committed.iter().map(|c| c.snapshot_id).collect::<Vec<_>>(), ids);
+    hat.resume().unwrap();
+    let listing = hat.list_snapshots();
+    assert_eq!(listing.len(), 3);
+    assert!(listing.iter().all(|s| s.committed));
+    assert_eq!(listing.iter().filter(|s| s.family_name == "other").count(), 1);
+}
+
+#[test]
+fn snapshot_policy_overrides_store_options() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let options = blob::StoreOptions {
+        packing: Some(blob::Packing::Zstd),
+        cipher: blob::Cipher::XSalsa20Poly1305,
+        ..Default::default()
+    };
+    let fam = hat.open_family_with_options("familyname".to_string(), options).unwrap();
+
+    // The first snapshot is stored neither packed nor encrypted.
+    let policy = blob::ChunkPolicy {
+        packing: None,
+        cipher: blob::Cipher::Unencrypted,
+    };
+    fam.snapshot_direct_with(entry(b"plain".to_vec()),
+                             false,
+                             Some(FileIterator::from_bytes(vec![1; 10000])),
+                             SnapshotOptions { policy: Some(policy.clone()), ..Default::default() })
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit_with_policy(&fam, policy).unwrap();
+
+    // The second one is packed and encrypted as the store options say.
+    snapshot_files(&fam, vec![("packed", vec![2; 10000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    {
+        let data_ref = |name: &[u8]| {
+            let entry = fam.key_store.lookup(None, name.to_vec()).unwrap().unwrap();
+            let hash = hash::Hash { bytes: entry.data_hash.unwrap() };
+            hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap()
+        };
+        let plain = data_ref(b"plain");
+        assert_eq!(plain.packing, None);
+        assert!(plain.key.is_none());
+        let packed = data_ref(b"packed");
+        assert_eq!(packed.packing, Some(blob::Packing::Zstd));
+        assert!(packed.key.is_some());
+    }
+    let first_tree = hat.snapshot_index.lookup("familyname", 1).unwrap().2.unwrap();
+    assert!(first_tree.key.is_none());
+    let second_tree = hat.snapshot_index.lookup("familyname", 2).unwrap().2.unwrap();
+    assert!(second_tree.key.is_some());
+
+    // Both snapshots read back alike.
+    for id in 1..3 {
+        let output = restore_dir();
+        hat.restore(fam.name.clone(), id, output.clone()).unwrap();
+        let mut contents = vec![];
+        fs::File::open(output.join("plain")).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![1; 10000]);
+        fs::remove_dir_all(&output).unwrap();
+    }
+}
+
+#[test]
+fn encrypted_snapshot_does_not_reuse_unencrypted_chunks() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+
+    let policy = blob::ChunkPolicy {
+        packing: None,
+        cipher: blob::Cipher::Unencrypted,
+    };
+    fam.snapshot_direct_with(entry(b"plain".to_vec()),
+                             false,
+                             Some(FileIterator::from_bytes(vec![1; 10000])),
+                             SnapshotOptions { policy: Some(policy.clone()), ..Default::default() })
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit_with_policy(&fam, policy).unwrap();
+
+    let hash = {
+        let entry = fam.key_store.lookup(None, b"plain".to_vec()).unwrap().unwrap();
+        hash::Hash { bytes: entry.data_hash.unwrap() }
+    };
+    assert!(hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap().key.is_none());
+
+    // The same data in an encrypted snapshot is stored again, encrypted.
+    snapshot_files(&fam, vec![("copy", vec![1; 10000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    assert!(hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap().key.is_some());
+    assert!(!hat.hash_index.superseded_blobs().is_empty());
+
+    // The first snapshot still reads its own copy.
+    assert_eq!(hat.gc_with(GcOptions::default()).unwrap().blobs_emptied, 0);
+    for &(id, name) in &[(1, &b"plain"[..]), (2, &b"copy"[..])] {
+        let mut read = Vec::new();
+        hat.open_file(fam.name.clone(), id, name).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, vec![1; 10000]);
+    }
+}
+
+#[test]
+fn pre_commit_hook_rejects_large_snapshot() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    let seen = Arc::new(Mutex::new(vec![]));
+    let local_seen = seen.clone();
+    hat.set_pre_commit_hook(Some(Box::new(move |summary: &SnapshotSummary| {
+        local_seen.lock().unwrap().push(*summary);
+        if summary.bytes > 10000 {
+            return Err(HatError::from("Snapshot too large"));
+        }
+        Ok(())
+    })));
+
+    fam.snapshot_direct(entry(b"dir".to_vec()), true, None).unwrap();
+    snapshot_files(&fam, vec![("small", vec![1; 1000]), ("large", vec![2; 100000])]).unwrap();
+    fam.flush().unwrap();
+    assert!(hat.commit(&fam, None).is_err());
+    assert!(hat.commit_with_label(&fam, "large").is_err());
+    assert!(hat.list_snapshots().is_empty());
+    assert_eq!(seen.lock().unwrap()[0],
+               SnapshotSummary {
+                   entries: 3,
+                   bytes: 101000,
+                   changed: 0,
+               });
+
+    // Without the large file, the snapshot is accepted.
+    fam.abort().unwrap();
+    snapshot_files(&fam, vec![("small", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    assert_eq!(hat.commit(&fam, None).unwrap().snapshot_id, 1);
+    assert_eq!(seen.lock().unwrap().len(), 3);
+    assert_eq!(seen.lock().unwrap()[2],
+               SnapshotSummary {
+                   entries: 1,
+                   bytes: 1000,
+                   changed: 0,
+               });
+}
+
+#[test]
+fn snapshot_flags_file_changed_while_read() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    // More data is read than the size recorded up front, as if the file grew meanwhile.
+    let mut grown = entry(b"grown".to_vec());
+    grown.data_length = Some(1000);
+    fam.snapshot_direct(grown, false, Some(FileIterator::from_bytes(vec![1; 2000]))).unwrap();
+    let mut steady = entry(b"steady".to_vec());
+    steady.data_length = Some(1000);
+    fam.snapshot_direct(steady, false, Some(FileIterator::from_bytes(vec![2; 1000]))).unwrap();
+    fam.flush().unwrap();
+
+    let changed = fam.changed_entries().unwrap();
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0].name, b"grown".to_vec());
+    assert_eq!(fam.summary().unwrap().changed, 1);
+
+    // Reading the file again without a change clears the flag.
+    let mut grown = entry(b"grown".to_vec());
+    grown.data_length = Some(2000);
+    grown.modified = Some(1);
+    fam.snapshot_direct(grown, false, Some(FileIterator::from_bytes(vec![1; 2000]))).unwrap();
+    fam.flush().unwrap();
+
+    assert!(fam.changed_entries().unwrap().is_empty());
+    assert_eq!(fam.summary().unwrap().changed, 0);
+    hat.commit(&fam, None).unwrap();
+}
+
+#[test]
+fn labeled_snapshot_survives_recover() {
+    let (backend, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit_with_label(&fam, "nightly-2024-06-01").unwrap();
+
+    // Labels are unique within a family.
+    snapshot_files(&fam, vec![("name2", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    assert!(hat.commit_with_label(&fam, "nightly-2024-06-01").is_err());
+    hat.commit_with_label(&fam, "nightly-2024-06-02").unwrap();
+    hat.meta_commit().unwrap();
+
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    let labels: Vec<_> = hat2.list_snapshots().into_iter().map(|s| s.label).collect();
+    assert_eq!(labels,
+               vec![Some("nightly-2024-06-01".to_string()),
+                    Some("nightly-2024-06-02".to_string())]);
+
+    let label = SnapshotSelector::Label("nightly-2024-06-01".to_string());
+    assert_eq!(hat2.resolve_snapshot(&fam.name, &label).unwrap(), 1);
+    hat2.deregister_snapshot(&fam, &label).unwrap();
+}
+
+#[test]
+fn retention_policy() {
+    use hat::retention::to_prune;
+
+    // Two snapshots a day at 06:00 and 18:00 (UTC), from Wednesday 2024-05-01 to Monday
+    // 2024-06-10; snapshot ids count up from 1.
+    let day = 24 * 60 * 60 * 1_000_000_000i64;
+    let may_first = 19844 * day;
+    let snapshots: Vec<(i64, i64)> = (0..82)
+        .map(|i| (i + 1, may_first + (i / 2) * day + (6 + 12 * (i % 2)) * day / 24))
+        .collect();
+    let id_on = |days_after_may_first: i64, evening: bool| {
+        days_after_may_first * 2 + if evening { 2 } else { 1 }
+    };
+
+    let policy = RetentionPolicy {
+        daily: 3,
+        weekly: 2,
+        monthly: 2,
+    };
+    let prune = to_prune(&snapshots[..], &policy);
+    let mut kept: Vec<i64> = snapshots.iter()
+        .map(|&(id, _)| id)
+        .filter(|id| !prune.contains(id))
+        .collect();
+    kept.sort();
+
+    // Daily: June 10, 9 and 8. Weekly: Monday June 10 and Sunday June 9. Monthly: June 10 and
+    // May 31.
+    assert_eq!(kept,
+               vec![id_on(30, true), id_on(38, true), id_on(39, true), id_on(40, true)]);
+
+    // The latest snapshot is kept even if the policy keeps nothing.
+    assert_eq!(to_prune(&snapshots[..], &Default::default()).len(), 81);
+    assert!(to_prune(&[(1, 0)], &Default::default()).is_empty());
+}
+
+#[test]
+fn prune_with_policy() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    for i in 0..3 {
+        snapshot_files(&fam, vec![("name", vec![i; 1000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+    }
+
+    // All snapshots are from today, so keeping one day keeps the latest snapshot only.
+    let policy = RetentionPolicy { daily: 1, ..Default::default() };
+    assert_eq!(hat.prune_with_policy(&fam, &policy).unwrap(), vec![1, 2]);
+    assert_eq!(hat.prune_with_policy(&fam, &policy).unwrap(), Vec::<i64>::new());
+
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert!(live > 0);
+}
+
+#[test]
+fn dedup_stats() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    assert_eq!(fam.dedup_stats().ratio(), 1.0);
+
+    let files = vec![("file1", vec![1; 10000]),
+                     ("file2", vec![2; 10000]),
+                     ("file3", vec![1; 10000]),
+                     ("file4", vec![2; 10000]),
+                     ("file5", vec![3; 10000])];
+    snapshot_files(&fam, files).unwrap();
+    fam.flush().unwrap();
+
+    let stats = fam.dedup_stats();
+    assert_eq!(stats.logical_bytes, 50000);
+    assert_eq!(stats.unique_bytes, 30000);
+    assert_eq!(stats.chunks_stored, 3);
+    assert_eq!(stats.chunks_deduplicated, 2);
+    assert!(stats.unique_bytes < stats.logical_bytes);
+    assert!(stats.ratio() > 1.6 && stats.ratio() < 1.7);
+
+    // The next snapshot starts over.
+    hat.commit(&fam, None).unwrap();
+    assert_eq!(fam.dedup_stats(), key::DedupStats::default());
+    snapshot_files(&fam, vec![("file6", vec![3; 10000])]).unwrap();
+    fam.flush().unwrap();
+    let stats = fam.dedup_stats();
+    assert_eq!(stats.logical_bytes, 10000);
+    assert_eq!(stats.chunks_deduplicated, 1);
+}
+
+#[test]
+fn families_deduplicate_against_each_other() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend.clone());
+    let files = || {
+        vec![("file1", (0..300000).map(|i| (i % 251) as u8).collect()), ("file2", vec![2; 10000])]
+    };
+
+    let first = hat.open_family("first".to_string()).unwrap();
+    snapshot_files(&first, files()).unwrap();
+    first.flush().unwrap();
+    hat.flush_blob_store().unwrap();
+    hat.commit(&first, None).unwrap();
+    let stored = backend.total_bytes();
+
+    // The second family finds all of its data chunks already stored by the first.
+    let second = hat.open_family("second".to_string()).unwrap();
+    snapshot_files(&second, files()).unwrap();
+    second.flush().unwrap();
+    hat.flush_blob_store().unwrap();
+    let stats = second.dedup_stats();
+    assert_eq!(stats.chunks_stored, 0);
+    assert!(stats.chunks_deduplicated > 0);
+    assert_eq!(backend.total_bytes(), stored);
+    hat.commit(&second, None).unwrap();
+
+    // Each family keeps its own references, so dropping one snapshot keeps the shared data.
+    hat.deregister(&first, 1).unwrap();
+    hat.gc().unwrap();
+    let mut read = Vec::new();
+    hat.open_file("second".to_string(), 1, b"file1").unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, files()[0].1);
+}
+
+#[test]
+fn dedup_with_and_without_hash_cache() {
+    let files = || {
+        vec![("file1", (0..300000).map(|i| (i % 251) as u8).collect()),
+             ("file2", vec![2; 10000]),
+             ("file3", vec![2; 10000])]
+    };
+
+    let mut results = vec![];
+    for &cache_size in [0, hash::DEFAULT_CACHE_SIZE].iter() {
+        let backend = Arc::new(MemoryBackend::new());
+        let mut hat = setup_hat(backend.clone());
+        hat.set_hash_cache_size(cache_size);
+
+        // The second family sees all of its chunks again.
+        let mut stats = vec![];
+        for name in &["first", "second"] {
+            let fam = hat.open_family(name.to_string()).unwrap();
+            snapshot_files(&fam, files()).unwrap();
+            fam.flush().unwrap();
+            let s = fam.dedup_stats();
+            stats.push((s.chunks_stored, s.chunks_deduplicated, s.unique_bytes));
+            hat.commit(&fam, None).unwrap();
+        }
+        assert_eq!(stats[1].0, 0);
+        hat.flush_blob_store().unwrap();
+        results.push((stats, backend.total_bytes(), hat.gc().unwrap()));
+    }
+    assert_eq!(results[0], results[1]);
+}
+
+#[test]
+fn identical_small_files_share_chunk() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let names: Vec<String> = (0..100).map(|i| format!("template-{}", i)).collect();
+    snapshot_files(&fam, names.iter().map(|n| (n.as_str(), vec![7; 10])).collect()).unwrap();
+    fam.flush().unwrap();
+
+    let stats = fam.dedup_stats();
+    assert_eq!(stats.chunks_stored, 1);
+    assert_eq!(stats.chunks_deduplicated, 99);
+    assert_eq!(stats.unique_bytes, 10);
+
+    // Every entry refers to the same chunk, without a tree above it.
+    let ls = fam.list_from_key_store(None).unwrap();
+    assert_eq!(ls.len(), 100);
+    let (ref first, ref first_ref, _) = ls[0];
+    assert_eq!(first.data_hash, Some(hash::Hash::new(&[7; 10]).bytes));
+    for &(ref e, ref pref, _) in &ls {
+        assert_eq!(e.data_hash, first.data_hash);
+        assert_eq!(pref, first_ref);
+    }
+
+    hat.commit(&fam, None).unwrap();
+    let mut read = Vec::new();
+    hat.open_file(fam.name.clone(), 1, b"template-42").unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec![7; 10]);
+}
+
+#[test]
+fn chunk_size_stats() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    assert!(hat.open_family("plain".to_string()).unwrap().chunk_size_stats().is_none());
+
+    hat.set_chunk_size_stats(true);
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+    let size = 5000000;
+    let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    snapshot_files(&fam, vec![("large", data)]).unwrap();
+    fam.flush().unwrap();
+
+    let stats = fam.chunk_size_stats().unwrap();
+    let total = stats.histogram.iter().fold(0, |a, b| a + b);
+    assert_eq!(total, stats.leaf_chunks);
+    let estimate = total as f64 * stats.average_leaf_size();
+    assert!((estimate - size as f64).abs() < size as f64 * 0.01);
+
+    // All chunks but the last are full, and the tree has a level above them.
+    assert_eq!(stats.histogram[18], size / key::MAX_CHUNK_LEN as u64);
+    assert!(stats.branch_chunks > 0);
+    assert!(stats.branch_bytes > 0);
+}
+
+#[test]
+fn parallel_hashing_matches_serial() {
+    let contents: Vec<u8> = (0..3000000).map(|_| rand::random::<u8>()).collect();
+
+    let mut hashes = vec![];
+    for &threads in &[0, 4] {
+        let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+        hat.set_hash_threads(threads);
+        let fam = hat.open_family("familyname".to_string()).unwrap();
+        snapshot_files(&fam, vec![("large", contents.clone()), ("small", vec![1; 10])]).unwrap();
+        fam.flush().unwrap();
+
+        let mut ls = fam.list_from_key_store(None).unwrap();
+        ls.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        hashes.push(ls.into_iter().map(|(entry, _, _)| entry.data_hash).collect::<Vec<_>>());
+
+        hat.commit(&fam, None).unwrap();
+        let mut read = Vec::new();
+        hat.open_file(fam.name.clone(), 1, b"large").unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(contents, read);
+    }
+    assert_eq!(hashes[0], hashes[1]);
+}
+
+#[test]
+fn metrics_are_consistent() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let metrics = Arc::new(MemoryMetrics::new());
+    hat.set_metrics(metrics.clone());
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+
+    snapshot_files(&fam,
+                   vec![("name1", vec![1; 100000]),
+                        ("name2", vec![2; 100000]),
+                        ("name3", vec![1; 100000])])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.gc().unwrap();
+
+    let live_bytes: usize = hat.hash_index
+        .list()
+        .into_iter()
+        .filter_map(|e| e.persistent_ref.map(|r| r.length))
+        .fold(0, |a, b| a + b);
+    let counts = metrics.counts();
+    assert!(live_bytes > 0);
+    assert!(counts.bytes_stored >= live_bytes);
+    assert!(counts.chunks_deduplicated >= 1);
+    assert_eq!(counts.blobs_deleted, 0);
+
+    let mut read = Vec::new();
+    hat.open_file(fam.name.clone(), 1, b"name2").unwrap().read_to_end(&mut read).unwrap();
+    assert!(metrics.counts().bytes_retrieved > 0);
+
+    hat.deregister(&fam, 1).unwrap();
+    hat.gc().unwrap();
+    assert!(metrics.counts().blobs_deleted > 0);
+}
+
+#[test]
+fn empty_files_store_no_blobs() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let metrics = Arc::new(MemoryMetrics::new());
+    hat.set_metrics(metrics.clone());
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+
+    let names: Vec<String> = (0..1000).map(|i| format!("name-{}", i)).collect();
+    snapshot_files(&fam, names.iter().map(|n| (n.as_str(), vec![])).collect()).unwrap();
+    fam.flush().unwrap();
+
+    // Neither data nor hashes are stored for the empty files.
+    assert_eq!(metrics.counts().bytes_stored, 0);
+    assert_eq!(metrics.counts().chunks_deduplicated, 0);
+    assert_eq!(hat.hash_index.list().len(), 0);
+
+    // Only the listing is stored on commit, and the files restore as empty.
+    hat.commit(&fam, None).unwrap();
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+    for name in names.iter() {
+        assert_eq!(fs::metadata(output.join(name)).unwrap().len(), 0);
+    }
+    let mut read = Vec::new();
+    hat.open_file(fam.name.clone(), 1, b"name-0").unwrap().read_to_end(&mut read).unwrap();
+    assert!(read.is_empty());
+
+    hat.deregister(&fam, 1).unwrap();
+    let (_, live) = hat.gc().unwrap();
+    assert_eq!(live, 0);
+}
+
+#[test]
+fn compact_partially_dead_blob() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend.clone());
+    let fam1 = hat.open_family("family1".to_string()).unwrap();
+    let fam2 = hat.open_family("family2".to_string()).unwrap();
+
+    // Both files end up in the same blob; only the second one is kept alive by another family.
+    let dead: Vec<u8> = (0..500000).map(|_| rand::random::<u8>()).collect();
+    let live: Vec<u8> = (0..500000).map(|_| rand::random::<u8>()).collect();
+    snapshot_files(&fam1, vec![("name1", dead), ("name2", live.clone())]).unwrap();
+    fam1.flush().unwrap();
+    hat.commit(&fam1, None).unwrap();
+
+    snapshot_files(&fam2, vec![("name2", live.clone())]).unwrap();
+    fam2.flush().unwrap();
+    hat.commit(&fam2, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    hat.deregister(&fam1, 1).unwrap();
+    let stats = hat.gc_with(GcOptions::default()).unwrap();
+    assert!(stats.blobs_partially_live > 0);
+
+    let before = backend.total_bytes();
+    let compacted = hat.compact(2 * 1024 * 1024).unwrap();
+    assert!(compacted.blobs_compacted > 0);
+    assert!(compacted.chunks_moved > 0);
+    assert!(backend.total_bytes() + 400000 < before);
+
+    // All live chunks are still readable, also through the snapshot's tree, which still names
+    // the old blob, and nothing more is left for gc.
+    let report = hat.verify().unwrap();
+    assert!(report.checked > 0);
+    assert_eq!(report.failed, 0);
+
+    let mut read = Vec::new();
+    hat.open_file(fam2.name.clone(), 1, b"name2").unwrap().read_to_end(&mut read).unwrap();
+    assert!(read == live);
+
+    let (deleted, _) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+}
+
+#[test]
+fn gc_dry_run() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam,
+                   vec![("name1", vec![0; 1000000]),
+                        ("name2", vec![1; 1000000]),
+                        ("name3", vec![2; 1000000])])
+        .unwrap();
+    fam.flush().unwrap();
+
+    // No commit so everything would be deleted.
+    let dry = hat.gc_dry_run().unwrap();
+    assert!(dry.hashes > 0);
+    assert!(dry.bytes > 0);
+
+    // Nothing was changed by the dry run.
+    assert_eq!(dry, hat.gc_dry_run().unwrap());
+
+    let (deleted, live) = hat.gc().unwrap();
+    assert_eq!(deleted, dry.hashes);
+    assert_eq!(live, 0);
+    assert_eq!(hat.gc_dry_run().unwrap().hashes, 0);
+}
+
+#[test]
+fn gc_dry_run_spares_snapshot_in_progress() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    hat.set_gc_safety_window(true);
+
+    snapshot_files(&fam, vec![("name1", vec![0; 1000000])]).unwrap();
+    fam.flush().unwrap();
+
+    // Like gc, the dry run spares the snapshot until it is aborted.
+    assert_eq!(hat.gc_dry_run().unwrap(), GcDryRun::default());
+    assert_eq!(hat.gc_with(GcOptions::default()).unwrap().hashes_deleted, 0);
+
+    fam.abort().unwrap();
+    let dry = hat.gc_dry_run().unwrap();
+    assert!(dry.hashes > 0);
+    assert_eq!(hat.gc_with(GcOptions::default()).unwrap().hashes_deleted, dry.hashes);
+}
+
+#[test]
+fn gc_with_stats() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam,
+                   vec![("name1", vec![0; 1000000]),
+                        ("name2", vec![1; 1000000]),
+                        ("name3", vec![2; 1000000])])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let stats = hat.gc_with(GcOptions::default()).unwrap();
+    assert_eq!(stats.hashes_deleted, 0);
+    assert_eq!(stats.bytes_reclaimed, 0);
+    assert_eq!(stats.blobs_emptied, 0);
+    assert!(stats.chunks_scanned > 0);
+    assert!(stats.live > 0);
+
+    hat.deregister(&fam, 1).unwrap();
+
+    let stats = hat.gc_with(GcOptions::default()).unwrap();
+    assert!(stats.hashes_deleted > 0);
+    assert!(stats.bytes_reclaimed > 0);
+    assert!(stats.blobs_emptied > 0);
+    assert_eq!(stats.live, 0);
+}
+
+#[test]
+fn gc_stats_orphaned_and_dead_blobs() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam, vec![("name1", vec![0; 1000000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    // Flushed but never committed, like the data of an aborted snapshot.
+    snapshot_files(&fam, vec![("name2", vec![1; 1000000])]).unwrap();
+    fam.flush().unwrap();
+
+    let stats = hat.gc_with(GcOptions::default()).unwrap();
+    assert!(stats.blobs_orphaned > 0);
+    assert_eq!(stats.blobs_dead, 0);
+    assert_eq!(stats.blobs_emptied, stats.blobs_orphaned);
+
+    hat.deregister(&fam, 1).unwrap();
+
+    let stats = hat.gc_with(GcOptions::default()).unwrap();
+    assert!(stats.blobs_dead > 0);
+    assert_eq!(stats.blobs_orphaned, 0);
+    assert_eq!(stats.blobs_emptied, stats.blobs_dead);
+    assert_eq!(stats.live, 0);
+}
+
+#[test]
+fn gc_spares_snapshot_in_progress() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    hat.set_gc_safety_window(true);
+
+    let files: Vec<(String, Vec<u8>)> =
+        (0..20).map(|i| (format!("name{}", i), vec![i as u8; 300000])).collect();
+
+    // Snapshot in another thread while gc runs.
+    let local_fam = fam.clone();
+    let local_files = files.clone();
+    let snapshotter = thread::spawn(move || {
+        for (name, contents) in local_files {
+            local_fam.snapshot_direct(entry(name.into_bytes()),
+                                      false,
+                                      Some(FileIterator::from_bytes(contents)))
+                .unwrap();
+        }
+        local_fam.flush().unwrap();
+    });
+    for _ in 0..5 {
+        hat.gc_with(GcOptions::default()).unwrap();
+    }
+    snapshotter.join().unwrap();
+
+    // Nothing of the snapshot is collected before it is committed.
+    let stats = hat.gc_with(GcOptions::default()).unwrap();
+    assert_eq!(stats.hashes_deleted, 0);
+    assert_eq!(stats.blobs_emptied, 0);
+
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    let report = hat.verify().unwrap();
+    assert!(report.checked > 0);
+    assert_eq!(report.failed, 0);
+    for (name, contents) in files {
+        let mut read = Vec::new();
+        hat.open_file(fam.name.clone(), 1, name.as_bytes())
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(read == contents);
+    }
+
+    // Once aborted, a snapshot is no longer spared.
+    snapshot_files(&fam, vec![("aborted", vec![99; 100000])]).unwrap();
+    fam.flush().unwrap();
+    assert_eq!(hat.gc_with(GcOptions::default()).unwrap().hashes_deleted, 0);
+    fam.abort().unwrap();
+    assert!(hat.gc_with(GcOptions::default()).unwrap().hashes_deleted > 0);
+}
+
+#[test]
+fn gc_spares_older_data_reused_in_progress() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    hat.set_gc_safety_window(true);
+
+    // The data of an aborted snapshot is unreferenced, until another snapshot stores it again.
+    let contents = vec![7; 300000];
+    snapshot_files(&fam, vec![("aborted", contents.clone())]).unwrap();
+    fam.flush().unwrap();
+    fam.abort().unwrap();
+
+    snapshot_files(&fam, vec![("name1", contents.clone())]).unwrap();
+    fam.flush().unwrap();
+    assert_eq!(hat.gc_with(GcOptions::default()).unwrap().hashes_deleted, 0);
+
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    assert_eq!(hat.verify().unwrap().failed, 0);
+
+    let mut read = Vec::new();
+    hat.open_file(fam.name.clone(), 1, b"name1").unwrap().read_to_end(&mut read).unwrap();
+    assert!(read == contents);
+}
+
+#[test]
+fn blob_status_after_commit() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    assert_eq!(hat.count_blobs(blob::BlobStatus::Committed), 0);
+
+    snapshot_files(&fam, vec![("name1", vec![0; 1000000]), ("name2", vec![1; 1000000])])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    // Every blob referenced by a hash has finished uploading.
+    let committed: HashSet<Vec<u8>> =
+        hat.list_blobs(blob::BlobStatus::Committed, usize::max_value()).into_iter().collect();
+    assert_eq!(committed.len(), hat.count_blobs(blob::BlobStatus::Committed));
+    let mut referenced = 0;
+    for entry in hat.hash_index.list() {
+        if let Some(pref) = entry.persistent_ref {
+            if pref.length > 0 {
+                assert!(committed.contains(&pref.blob_id));
+                referenced += 1;
+            }
+        }
+    }
+    assert!(referenced > 0);
+    assert_eq!(hat.count_blobs(blob::BlobStatus::InProgress), 0);
+
+    // A blob whose upload started but never finished stays in progress.
+    let pending = hat.blob_index.reserve();
+    hat.blob_index.in_air(&pending);
+    assert_eq!(hat.list_blobs(blob::BlobStatus::InProgress, 10), vec![pending.name.clone()]);
+    assert_eq!(hat.blob_index.status(&pending.name[..]),
+               Some(blob::BlobStatus::InProgress));
+    assert_eq!(hat.count_blobs(blob::BlobStatus::Committed), committed.len());
+}
+
+#[test]
+fn verify_reports_corrupted_chunk() {
+    let backend = Arc::new(MemoryBackend::new());
+    let (_, mut hat, fam) = setup_family(backend.clone());
+
+    snapshot_files(&fam,
+                   vec![("name1", "unique contents of the first file".bytes().collect()),
+                        ("name2", vec![1; 1000000]),
+                        ("name3", vec![2; 1000000])])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let report = hat.verify().unwrap();
+    assert!(report.checked > 0);
+    assert_eq!(report.failed, 0);
+    assert_eq!(hat.verify_snapshot(fam.name.clone(), 1).unwrap().failed, 0);
+
+    // Corrupt the chunk holding the first file.
+    let hash = hash::Hash::new(b"unique contents of the first file");
+    let pref = hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap();
+    backend.modify(&pref.blob_id[..], |blob| blob[pref.offset] ^= 1);
+
+    let expected = vec![(pref.blob_id.clone(), pref.offset)];
+    let report = hat.verify().unwrap();
+    assert_eq!(report.failed, 1);
+    assert_eq!(report.mismatches, expected);
+
+    let report = hat.verify_snapshot(fam.name.clone(), 1).unwrap();
+    assert_eq!(report.failed, 1);
+    assert_eq!(report.mismatches, expected);
+}
+
+#[test]
+fn verify_parallel_detects_corrupted_chunk() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = HatRc::new_for_testing(backend.clone(), blob::MIN_BLOB_SIZE).unwrap();
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+
+    let mut files: Vec<(&str, Vec<u8>)> =
+        vec![("small", "unique contents of the small file".bytes().collect())];
+    for name in ["name1", "name2", "name3", "name4", "name5", "name6"].iter() {
+        files.push((*name, (0..200000).map(|_| rand::random::<u8>()).collect()));
+    }
+    snapshot_files(&fam, files).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let blobs = hat.hash_index
+        .list()
+        .into_iter()
+        .filter_map(|e| e.persistent_ref.map(|r| r.blob_id))
+        .collect::<HashSet<_>>();
+    assert!(blobs.len() >= 4);
+
+    let serial = hat.verify().unwrap();
+    assert_eq!(serial.failed, 0);
+    assert_eq!(hat.verify_parallel(4).unwrap(), serial);
+    let snapshot = hat.verify_snapshot(fam.name.clone(), 1).unwrap();
+    assert_eq!(hat.verify_snapshot_parallel(fam.name.clone(), 1, 4).unwrap(), snapshot);
+
+    // Corrupt the chunk holding the small file.
+    let hash = hash::Hash::new(b"unique contents of the small file");
+    let pref = hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap();
+    backend.modify(&pref.blob_id[..], |blob| blob[pref.offset] ^= 1);
+
+    let expected = vec![(pref.blob_id.clone(), pref.offset)];
+    let report = hat.verify_parallel(4).unwrap();
+    assert_eq!(report.checked, serial.checked);
+    assert_eq!(report.failed, 1);
+    assert_eq!(report.mismatches, expected);
+
+    let report = hat.verify_snapshot_parallel(fam.name.clone(), 1, 4).unwrap();
+    assert_eq!(report.checked, snapshot.checked);
+    assert_eq!(report.failed, 1);
+    assert_eq!(report.mismatches, expected);
+}
+
+#[test]
+fn restore_partial_skips_damaged_file() {
+    let backend = Arc::new(MemoryBackend::new());
+    let (_, mut hat, fam) = setup_family(backend.clone());
+
+    // The damaged file gets a blob of its own, as a damaged blob fails all of its chunks.
+    snapshot_files(&fam, vec![("bad", "unique contents of the bad file".bytes().collect())])
+        .unwrap();
+    fam.flush().unwrap();
+    let files = vec![("name1", vec![1; 1000000]), ("name2", vec![2; 1000]), ("name3", vec![])];
+    snapshot_files(&fam, files.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let hash = hash::Hash::new(b"unique contents of the bad file");
+    let pref = hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap();
+    backend.modify(&pref.blob_id[..], |blob| blob[pref.offset] ^= 1);
+
+    // By default, the damaged file fails the restore.
+    assert!(hat.restore(fam.name.clone(), 1, restore_dir()).is_err());
+
+    let output = restore_dir();
+    let options = RestoreOptions { skip_failed: true, ..RestoreOptions::default() };
+    let report = hat.restore_with(fam.name.clone(), 1, output.clone(), options).unwrap();
+    assert!(!report.is_complete());
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, output.join("bad"));
+    assert!(!output.join("bad").exists());
+
+    for (name, contents) in files {
+        let mut restored = Vec::new();
+        fs::File::open(output.join(name)).unwrap().read_to_end(&mut restored).unwrap();
+        assert_eq!(contents, restored);
+    }
+}
+
+#[test]
+fn restore_rejects_unsafe_names() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    let escaped = format!("hat-escaped-{:x}", rand::random::<u64>());
+    snapshot_files(&fam,
+                   vec![(&format!("../{}", escaped)[..], vec![1; 10]),
+                        ("..", vec![2; 10]),
+                        ("name1", vec![3; 10])])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    // Neither the restore nor the checkout writes outside of its output directory.
+    let output = restore_dir();
+    assert!(hat.restore(fam.name.clone(), 1, output.clone()).is_err());
+    assert!(hat.checkout_in_dir(fam.name.clone(), output.clone()).is_err());
+    assert!(!env::temp_dir().join(&escaped).exists());
+
+    // The other entries are restored all the same.
+    let options = RestoreOptions { skip_failed: true, ..RestoreOptions::default() };
+    let report = hat.restore_with(fam.name.clone(), 1, output.clone(), options).unwrap();
+    assert_eq!(report.failed.len(), 2);
+    assert!(!env::temp_dir().join(&escaped).exists());
+    let mut restored = Vec::new();
+    fs::File::open(output.join("name1")).unwrap().read_to_end(&mut restored).unwrap();
+    assert_eq!(restored, vec![3; 10]);
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn restore_single_path() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let mut e = entry(b"name2".to_vec());
+    e.permissions = Some(0o600);
+    fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(vec![2; 100000]))).unwrap();
+    snapshot_files(&fam, vec![("name1", vec![1; 1000]), ("name3", vec![3; 10])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let output = restore_dir();
+    let restored = output.join("restored");
+    hat.restore_path(fam.name.clone(), 1, b"name2", restored.clone()).unwrap();
+
+    let mut read = Vec::new();
+    fs::File::open(&restored).unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec![2; 100000]);
+    assert_eq!(fs::metadata(&restored).unwrap().permissions().mode() & 0o7777, 0o600);
+
+    // Nothing else was written.
+    let written: Vec<PathBuf> =
+        fs::read_dir(&output).unwrap().map(|e| e.unwrap().path()).collect();
+    assert_eq!(written, vec![restored]);
+
+    assert!(hat.restore_path(fam.name.clone(), 1, b"name4", output.join("name4")).is_err());
+    assert!(!output.join("name4").exists());
+}
+
+#[test]
+fn append_session_commits_once() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    {
+        let mut session = hat.append_session("familyname".to_string()).unwrap();
+        for &(name, byte) in &[("name1", 1), ("name2", 2), ("name3", 3)] {
+            snapshot_files(session.family(), vec![(name, vec![byte; 1000])]).unwrap();
+            session.append().unwrap();
+        }
+        let committed = session.commit().unwrap();
+        assert_eq!(committed.iter().map(|c| c.snapshot_id).collect::<Vec<_>>(),
+                   vec![1, 2, 3]);
+    }
+
+    let listing = hat.list_snapshots();
+    assert_eq!(listing.iter().map(|s| s.snapshot_id).collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert!(listing.iter().all(|s| s.committed && s.family_name == "familyname"));
+
+    // Later snapshots still hold the entries of earlier ones.
+    let mut read = Vec::new();
+    hat.open_file("familyname".to_string(), 3, b"name1").unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec![1; 1000]);
+}
+
+#[test]
+fn list_snapshots() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+    assert_eq!(hat.list_snapshots(), vec![]);
+
+    snapshot_files(&fam, vec![("name1", vec![1, 2, 3])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    snapshot_files(&fam, vec![("name2", vec![4, 5, 6])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let listing = hat.list_snapshots();
+    assert_eq!(listing.len(), 2);
+    assert_eq!(listing.iter().map(|s| s.snapshot_id).collect::<Vec<_>>(), vec![1, 2]);
+    for s in listing.iter() {
+        assert_eq!(s.family_name, fam.name);
+        assert!(s.committed);
+        assert!(s.hash.is_some());
+        assert!(s.commit_time.is_some());
+    }
+    assert!(listing[0].hash != listing[1].hash);
+    assert!(listing[0].commit_time <= listing[1].commit_time);
+}
+
+#[test]
+fn snapshot_dir_exclude() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let input = restore_dir();
+    for dir in &["target/debug", "sub/target", "sub/src"] {
+        fs::create_dir_all(input.join(dir)).unwrap();
+    }
+    let files = ["keep", "skip.tmp", "target/debug/out", "sub/target/out", "sub/src/keep",
+                 "sub/src/skip.tmp"];
+    for name in &files {
+        fs::File::create(input.join(name)).unwrap().write_all(name.as_bytes()).unwrap();
+    }
+
+    fam.snapshot_dir(input.clone(),
+                     &["**/target/**".to_owned(), "*.tmp".to_owned()]);
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+
+    for name in &["keep", "sub", "sub/src", "sub/src/keep"] {
+        assert!(output.join(name).exists(), "missing {}", name);
+    }
+    for name in &["skip.tmp", "target", "sub/target", "sub/src/skip.tmp"] {
+        assert!(!output.join(name).exists(), "not excluded: {}", name);
+    }
+
+    fs::remove_dir_all(&input).unwrap();
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn snapshot_dir_prescan_reports_total() {
+    let (_, _, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let input = restore_dir();
+    fs::create_dir_all(input.join("sub")).unwrap();
+    let files = vec![("a", 1000), ("sub/b", 200000), ("sub/c", 0), ("skip.tmp", 500)];
+    for &(name, len) in files.iter() {
+        fs::File::create(input.join(name)).unwrap().write_all(&vec![1; len][..]).unwrap();
+    }
+    unix_fs::symlink("a", input.join("link")).unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    fam.snapshot_dir_with_progress(input.clone(), &["*.tmp".to_owned()], Some(sender), true);
+    fam.flush().unwrap();
+
+    let events: Vec<Progress> = receiver.iter().collect();
+    // Everything but the excluded file: "a", "sub", "sub/b", "sub/c" and "link".
+    let expected_bytes = 1000 + 200000;
+    assert_eq!(events[0],
+               Progress::Total {
+                   entries: 5,
+                   bytes: expected_bytes,
+               });
+    let read: u64 = events.iter()
+        .map(|e| match *e {
+            Progress::BytesRead(n) => n,
+            _ => 0,
+        })
+        .sum();
+    assert_eq!(read, expected_bytes);
+    assert_eq!(events.iter().filter(|e| **e == Progress::FileDone).count(), 5);
+
+    fs::remove_dir_all(&input).unwrap();
+}
+
+#[test]
+fn snapshot_restore_symlink() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let input = restore_dir();
+    fs::create_dir_all(input.join("dir")).unwrap();
+    fs::File::create(input.join("dir/file")).unwrap().write_all(b"contents").unwrap();
+    unix_fs::symlink("dir/file", input.join("link")).unwrap();
+    unix_fs::symlink("dir", input.join("dir-link")).unwrap();
+    unix_fs::symlink("/does/not/exist", input.join("dangling")).unwrap();
+
+    fam.snapshot_dir(input.clone(), &[]);
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+
+    for &(name, target) in &[("link", "dir/file"),
+                             ("dir-link", "dir"),
+                             ("dangling", "/does/not/exist")] {
+        let md = fs::symlink_metadata(output.join(name)).unwrap();
+        assert!(md.file_type().is_symlink(), "{} is not a symlink", name);
+        assert_eq!(fs::read_link(output.join(name)).unwrap(), PathBuf::from(target));
+    }
+
+    let mut contents = vec![];
+    fs::File::open(output.join("dir/file")).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"contents".to_vec());
+
+    fs::remove_dir_all(&input).unwrap();
+    fs::remove_dir_all(&output).unwrap();
+}
+
+/// Read the entries of a tar archive as (path, type, mode, mtime, data or link target).
+fn read_tar(tar: &[u8]) -> Vec<(String, u8, u32, u64, Vec<u8>)> {
+    fn field(block: &[u8]) -> &[u8] {
+        block.split(|&b| b == 0).next().unwrap()
+    }
+    fn number(block: &[u8]) -> u64 {
+        let digits = str::from_utf8(field(block)).unwrap().trim();
+        u64::from_str_radix(digits, 8).unwrap()
+    }
+
+    let mut entries = vec![];
+    let mut long_name = None;
+    let mut pos = 0;
+    while tar[pos..pos + 512].iter().any(|&b| b != 0) {
+        let header = &tar[pos..pos + 512];
+        let checksum = header.iter()
+            .enumerate()
+            .fold(0, |sum, (i, &b)| sum + if i >= 148 && i < 156 { 32 } else { b as u64 });
+        assert_eq!(number(&header[148..156]), checksum);
+        assert_eq!(&header[257..263], b"ustar\0");
+
+        let size = number(&header[124..136]) as usize;
+        let data = tar[pos + 512..pos + 512 + size].to_vec();
+        pos += 512 + (size + 511) / 512 * 512;
+
+        let name = String::from_utf8(field(&header[..100]).to_vec()).unwrap();
+        match header[156] {
+            b'L' => long_name = Some(String::from_utf8(field(&data).to_vec()).unwrap()),
+            kind => {
+                let link = field(&header[157..257]).to_vec();
+                entries.push((long_name.take().unwrap_or(name),
+                              kind,
+                              number(&header[100..108]) as u32,
+                              number(&header[136..148]),
+                              if kind == b'0' { data } else { link }));
+            }
+        }
+    }
+    entries
+}
+
+#[test]
+fn export_tar() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let large: Vec<u8> = (0..1500000).map(|_| rand::random::<u8>()).collect();
+    let long_name: String = (0..150).map(|_| 'n').collect();
+    let input = restore_dir();
+    fs::create_dir_all(input.join("dir/empty")).unwrap();
+    fs::File::create(input.join("dir/large")).unwrap().write_all(&large[..]).unwrap();
+    fs::File::create(input.join("small")).unwrap().write_all(b"contents").unwrap();
+    fs::File::create(input.join(&long_name)).unwrap();
+    fs::set_permissions(input.join("small"), fs::Permissions::from_mode(0o600)).unwrap();
+    unix_fs::symlink("dir/large", input.join("link")).unwrap();
+    fs::hard_link(input.join("small"), input.join("dir/hardlink")).unwrap();
+    let mtime = fs::metadata(input.join("small")).unwrap().mtime() as u64;
+
+    fam.snapshot_dir(input.clone(), &[]);
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    assert!(hat.export_tar(fam.name.clone(), 2, vec![]).is_err());
+    let tar = hat.export_tar(fam.name.clone(), 1, vec![]).unwrap();
+    assert_eq!(tar.len() % 512, 0);
+
+    let entries = read_tar(&tar[..]);
+    let find = |path: &str| entries.iter().find(|e| e.0 == path).unwrap().clone();
+    assert_eq!(entries.len(), 7);
+
+    assert_eq!(find("dir/").1, b'5');
+    assert_eq!(find("dir/empty/").1, b'5');
+    let (_, kind, _, _, data) = find("dir/large");
+    assert_eq!(kind, b'0');
+    assert!(data == large);
+    assert_eq!(find(&long_name).4, Vec::<u8>::new());
+    let (_, kind, _, _, target) = find("link");
+    assert_eq!(kind, b'2');
+    assert_eq!(target, b"dir/large".to_vec());
+
+    // One of the two paths of the hardlinked file holds the data, the other links to it.
+    let (small, hardlink) = (find("small"), find("dir/hardlink"));
+    let (file, link) = if small.1 == b'0' { (small, hardlink) } else { (hardlink, small) };
+    assert_eq!(file.1, b'0');
+    assert_eq!(file.2, 0o600);
+    assert_eq!(file.3, mtime);
+    assert_eq!(file.4, b"contents".to_vec());
+    assert_eq!(link.1, b'1');
+    assert_eq!(link.4, file.0.into_bytes());
+
+    fs::remove_dir_all(&input).unwrap();
+}
+
+#[test]
+fn snapshot_entries_visit_each_path_once() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let input = restore_dir();
+    fs::create_dir_all(input.join("a/b/c")).unwrap();
+    fs::create_dir_all(input.join("many")).unwrap();
+    fs::create_dir_all(input.join("empty")).unwrap();
+    let mut expected: HashSet<String> =
+        vec!["a", "a/b", "a/b/c", "many", "empty"].into_iter().map(String::from).collect();
+    for name in vec!["top", "a/one", "a/b/two", "a/b/c/three"] {
+        fs::File::create(input.join(name)).unwrap().write_all(name.as_bytes()).unwrap();
+        expected.insert(name.to_string());
+    }
+    for i in 0..200 {
+        let name = format!("many/file{}", i);
+        fs::File::create(input.join(&name)).unwrap();
+        expected.insert(name);
+    }
+    unix_fs::symlink("top", input.join("a/link")).unwrap();
+    expected.insert("a/link".to_string());
+
+    fam.snapshot_dir(input.clone(), &[]);
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    assert!(hat.snapshot_entries(fam.name.clone(), 2).is_err());
+
+    let mut seen = HashSet::new();
+    let mut dirs = HashMap::new();
+    for res in hat.snapshot_entries(fam.name.clone(), 1).unwrap() {
+        let (path, entry) = res.unwrap();
+        let path = String::from_utf8(path).unwrap();
+        assert!(seen.insert(path.clone()), "visited twice: {}", path);
+
+        // A directory is visited before anything below it.
+        match path.rfind('/') {
+            None => assert_eq!(entry.parent_id, None),
+            Some(i) => assert_eq!(entry.parent_id, Some(dirs[&path[..i]])),
+        }
+        if entry.is_directory() {
+            dirs.insert(path.clone(), entry.id.unwrap());
+        }
+        if path == "a/b/two" {
+            assert!(entry.is_file());
+            assert_eq!(entry.data_length, Some(7));
+        }
+        if path == "a/link" {
+            assert_eq!(entry.link_target, Some(b"top".to_vec()));
+        }
+    }
+    assert_eq!(seen, expected);
+
+    fs::remove_dir_all(&input).unwrap();
+}
+
+#[test]
+fn import_tar() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let large: Vec<u8> = (0..1500000).map(|_| rand::random::<u8>()).collect();
+    let meta = |mode| {
+        tar::Meta {
+            mode: mode,
+            uid: 0,
+            gid: 0,
+            mtime: 1234567890,
+        }
+    };
+    let mut archive = TarWriter::new(vec![]);
+    archive.append_dir(b"./dir", &meta(0o750)).unwrap();
+    archive.begin_file(b"./dir/large", &meta(0o644), large.len() as u64).unwrap();
+    for chunk in large.chunks(100000) {
+        archive.write_data(chunk).unwrap();
+    }
+    archive.begin_file(b"./small", &meta(0o600), 8).unwrap();
+    archive.write_data(b"contents").unwrap();
+    archive.append_symlink(b"./link", b"dir/large", &meta(0o777)).unwrap();
+    archive.append_hardlink(b"./other/hardlink", b"./small", &meta(0o600)).unwrap();
+    let archive = archive.finish().unwrap();
+
+    fam.import_tar(&archive[..]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+
+    let md = fs::metadata(output.join("dir")).unwrap();
+    assert!(md.is_dir());
+    assert_eq!(md.permissions().mode() & 0o7777, 0o750);
+    assert_eq!(md.mtime(), 1234567890);
+
+    let mut restored = Vec::new();
+    fs::File::open(output.join("dir/large")).unwrap().read_to_end(&mut restored).unwrap();
+    assert!(restored == large);
+
+    let md = fs::metadata(output.join("small")).unwrap();
+    assert_eq!(md.permissions().mode() & 0o7777, 0o600);
+    assert_eq!(md.mtime(), 1234567890);
+    let mut restored = Vec::new();
+    fs::File::open(output.join("small")).unwrap().read_to_end(&mut restored).unwrap();
+    assert_eq!(restored, b"contents".to_vec());
+
+    assert_eq!(fs::read_link(output.join("link")).unwrap(), PathBuf::from("dir/large"));
+    // The hardlink's directory was not in the archive, and is created for it.
+    assert!(fs::metadata(output.join("other")).unwrap().is_dir());
+    assert_eq!(fs::metadata(output.join("other/hardlink")).unwrap().ino(), md.ino());
+
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn snapshot_restore_xattrs() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let input = restore_dir();
+    fs::create_dir_all(&input).unwrap();
+    fs::File::create(input.join("file")).unwrap().write_all(b"contents").unwrap();
+    fs::File::create(input.join("plain")).unwrap();
+    if xattr::set(&input.join("file"), b"user.hat.test", b"value").is_err() {
+        // The temporary directory does not support user attributes.
+        fs::remove_dir_all(&input).unwrap();
+        return;
+    }
+
+    fam.snapshot_dir(input.clone(), &[]);
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+
+    let attrs = xattr::list(&output.join("file")).unwrap();
+    assert_eq!(attrs.get(&b"user.hat.test"[..]), Some(&b"value".to_vec()));
+    assert!(xattr::list(&output.join("plain")).unwrap().is_empty());
+
+    fs::remove_dir_all(&input).unwrap();
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn snapshot_restore_sparse_file() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let input = restore_dir();
+    fs::create_dir_all(&input).unwrap();
+    let hole_len = 16 * 1024 * 1024;
+    {
+        let mut fd = fs::File::create(input.join("sparse")).unwrap();
+        fd.write_all(b"head").unwrap();
+        fd.seek(SeekFrom::Start(4 + hole_len)).unwrap();
+        fd.write_all(b"tail").unwrap();
+    }
+    let md = fs::metadata(input.join("sparse")).unwrap();
+    if md.blocks() * 512 >= md.len() {
+        // The temporary directory does not support sparse files.
+        fs::remove_dir_all(&input).unwrap();
+        return;
+    }
+
+    fam.snapshot_dir(input.clone(), &[]);
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+
+    let md = fs::metadata(output.join("sparse")).unwrap();
+    assert_eq!(md.len(), hole_len + 8);
+    assert!(md.blocks() * 512 < md.len());
+
+    let mut contents = Vec::new();
+    fs::File::open(output.join("sparse")).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(&contents[..4], b"head");
+    assert_eq!(&contents[contents.len() - 4..], b"tail");
+    assert!(contents[4..contents.len() - 4].iter().all(|&b| b == 0));
+
+    fs::remove_dir_all(&input).unwrap();
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn snapshot_restore_hardlinks() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let input = restore_dir();
+    fs::create_dir_all(input.join("dir")).unwrap();
+    fs::File::create(input.join("first")).unwrap().write_all(b"shared").unwrap();
+    fs::hard_link(input.join("first"), input.join("second")).unwrap();
+    fs::hard_link(input.join("first"), input.join("dir/third")).unwrap();
+    fs::File::create(input.join("other")).unwrap().write_all(b"shared").unwrap();
+
+    fam.snapshot_dir(input.clone(), &[]);
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+
+    let inode = |name: &str| fs::metadata(output.join(name)).unwrap().ino();
+    assert_eq!(inode("first"), inode("second"));
+    assert_eq!(inode("first"), inode("dir/third"));
+    assert!(inode("first") != inode("other"));
+    assert_eq!(fs::metadata(output.join("first")).unwrap().nlink(), 3);
+
+    for name in &["first", "second", "dir/third", "other"] {
+        let mut contents = vec![];
+        fs::File::open(output.join(name)).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"shared".to_vec());
+    }
+
+    fs::remove_dir_all(&input).unwrap();
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn snapshot_dir_with_few_open_files() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_max_open_files(2);
+    let fam = hat.open_family("familyname".to_string()).unwrap();
+
+    let input = restore_dir();
+    for d in 0..4 {
+        fs::create_dir_all(input.join(format!("dir{}", d))).unwrap();
+        for f in 0..100 {
+            let name = input.join(format!("dir{}/file{}", d, f));
+            fs::File::create(name).unwrap().write_all(format!("{}-{}", d, f).as_bytes()).unwrap();
+        }
+    }
+
+    // Files beyond the limit wait for a permit, and all of them are stored.
+    fam.snapshot_dir(input.clone(), &[]);
+    fam.flush().unwrap();
+    assert_eq!(fam.open_files.available(), 2);
+    hat.commit(&fam, None).unwrap();
+
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+    for d in 0..4 {
+        for f in 0..100 {
+            let mut contents = String::new();
+            fs::File::open(output.join(format!("dir{}/file{}", d, f)))
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            assert_eq!(contents, format!("{}-{}", d, f));
+        }
+    }
+
+    fs::remove_dir_all(&input).unwrap();
+    fs::remove_dir_all(&output).unwrap();
+}
+
+/// Counts the chunks fetched through a hash backend.
+#[derive(Clone)]
+struct CountingFetches {
+    inner: key::HashStoreBackend<MemoryBackend>,
+    fetches: Arc<AtomicUsize>,
+}
+
+impl HashTreeBackend for CountingFetches {
+    type Err = key::MsgError;
+
+    fn fetch_chunk(&self,
+                   hash: &hash::Hash,
+                   cref: Option<blob::ChunkRef>)
+                   -> Result<Option<Vec<u8>>, key::MsgError> {
+        self.fetches.fetch_add(1, Ordering::SeqCst);
+        self.inner.fetch_chunk(hash, cref)
+    }
+    fn fetch_childs(&self, hash: &hash::Hash) -> Option<Vec<i64>> {
+        self.inner.fetch_childs(hash)
+    }
+    fn fetch_persistent_ref(&self, hash: &hash::Hash) -> Option<blob::ChunkRef> {
+        self.inner.fetch_persistent_ref(hash)
+    }
+    fn insert_chunk(&self,
+                    hash: &hash::Hash,
+                    level: i64,
+                    childs: Option<Vec<i64>>,
+                    chunk: &[u8])
+                    -> Result<(i64, hash::tree::HashRef), key::MsgError> {
+        self.inner.insert_chunk(hash, level, childs, chunk)
+    }
+}
+
+#[test]
+fn restore_deep_and_wide_tree() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let input = restore_dir();
+    let mut deep = input.clone();
+    for i in 0..40 {
+        deep.push("d");
+        fs::create_dir_all(&deep).unwrap();
+        fs::File::create(deep.join("f")).unwrap().write_all(&[i as u8]).unwrap();
+    }
+    fs::create_dir_all(input.join("wide")).unwrap();
+    for i in 0..3000 {
+        fs::File::create(input.join(format!("wide/{}", i))).unwrap();
+    }
+
+    fam.snapshot_dir(input.clone(), &[]);
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let output = restore_dir();
+    hat.restore(fam.name.clone(), 1, output.clone()).unwrap();
+    assert_eq!(fs::read_dir(output.join("wide")).unwrap().count(), 3000);
+    let mut deep = output.clone();
+    for i in 0..40 {
+        deep.push("d");
+        let mut contents = vec![];
+        fs::File::open(deep.join("f")).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![i as u8]);
+    }
+
+    // The wide directory's listing spans several chunks, which are fetched as they are read.
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let backend = CountingFetches {
+        inner: hat.hash_backend(),
+        fetches: fetches.clone(),
+    };
+    let (root_hash, root_ref) = match hat.snapshot_index.lookup(&fam.name, 1) {
+        Some((_, hash, Some(cref))) => (hash, cref),
+        _ => panic!("Snapshot not found"),
+    };
+    let (_, wide) = fam.fetch_dir_data(&root_hash, root_ref, hat.hash_backend())
+        .unwrap()
+        .into_iter()
+        .find(|&(ref entry, _)| entry.name == b"wide")
+        .unwrap();
+    let (wide_hash, wide_ref) = wide.unwrap();
+    let mut listing = fam.dir_listing(&wide_hash, wide_ref, backend).unwrap();
+    listing.next().unwrap().unwrap();
+    let fetched_first = fetches.load(Ordering::SeqCst);
+    assert_eq!(listing.map(|elem| elem.unwrap()).count(), 2999);
+    assert!(fetched_first < fetches.load(Ordering::SeqCst));
+
+    fs::remove_dir_all(&input).unwrap();
+    fs::remove_dir_all(&output).unwrap();
+}
+
+#[test]
+fn diff_snapshots() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam,
+                   vec![("name1", vec![1, 2, 3]), ("name2", vec![4, 5, 6])])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    // Give the changed file a new timestamp, so the key store notices the new contents.
+    let mut changed = entry(b"name2".to_vec());
+    changed.modified = Some(1);
+    fam.snapshot_direct(changed, false, Some(FileIterator::from_bytes(vec![7, 8, 9])))
+        .unwrap();
+    snapshot_files(&fam, vec![("name3", vec![10])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let diff = |hat: &mut HatRc<_>, a, b| -> Vec<DiffEntry> {
+        hat.diff(fam.name.clone(), a, b).unwrap().map(|d| d.unwrap()).collect()
+    };
+    let change = |path: &str, kind| {
+        DiffEntry {
+            path: path.bytes().collect(),
+            kind: kind,
+        }
+    };
+
+    assert_eq!(diff(&mut hat, 1, 1), vec![]);
+    assert_eq!(diff(&mut hat, 1, 2),
+               vec![change("name2", DiffKind::Modified), change("name3", DiffKind::Added)]);
+    assert_eq!(diff(&mut hat, 2, 1),
+               vec![change("name2", DiffKind::Modified), change("name3", DiffKind::Removed)]);
+
+    assert!(hat.diff(fam.name.clone(), 1, 3).is_err());
+}
+
+#[test]
+fn rotate_keys() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    snapshot_files(&fam,
+                   vec![("name1", vec![0; 1000000]),
+                        ("name2", vec![1; 1000000]),
+                        ("name3", "block".bytes().collect())])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let before: Vec<_> = hat.hash_index
+        .list()
+        .into_iter()
+        .filter(|e| e.persistent_ref.as_ref().map_or(false, |p| p.length > 0))
+        .collect();
+    assert!(!before.is_empty());
+
+    assert_eq!(hat.rotate_keys().unwrap(), before.len());
+
+    // The old blobs are deleted, and no hash was left behind for GC.
+    let (deleted, live) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert!(live > 0);
+
+    // Every chunk decrypts with its new key...
+    let report = hat.verify().unwrap();
+    assert_eq!(report.checked, live as usize);
+    assert_eq!(report.failed, 0);
+
+    // ...and none with its old one.
+    for old in before {
+        let old_ref = old.persistent_ref.unwrap();
+        let new_ref = hat.hash_index.fetch_persistent_ref(&old.hash).unwrap().unwrap();
+        assert!(new_ref.key != old_ref.key);
+        assert!(new_ref.blob_id != old_ref.blob_id);
+        match hat.blob_store.retrieve(&old.hash, &old_ref) {
+            Err(blob::BlobError::NotFound(e)) => assert_eq!(e.blob_id, old_ref.blob_id),
+            res => panic!("Expected the old blob to be gone, got {:?}", res),
+        }
+
+        let with_old_key = blob::ChunkRef { key: old_ref.key, ..new_ref };
+        assert!(hat.blob_store.retrieve(&old.hash, &with_old_key).is_err());
+    }
+
+    // Snapshot listings still reference the old blobs, but can be read through the hash index.
+    let dir = restore_dir();
+    hat.restore(fam.name.clone(), 1, dir.clone()).unwrap();
+    let mut contents = vec![];
+    fs::File::open(dir.join("name3")).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"block".to_vec());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn progress_events() {
+    let (_, mut hat, fam) = setup_family(Arc::new(MemoryBackend::new()));
+
+    let files = vec![("file1", "block1".bytes().collect::<Vec<u8>>()),
+                     ("file2", "block2".bytes().collect()),
+                     ("file3", "block1".bytes().collect()),
+                     ("file4", vec![])];
+    let (sender, receiver) = mpsc::channel();
+    for &(name, ref contents) in files.iter() {
+        let options = SnapshotOptions { progress: Some(sender.clone()), ..Default::default() };
+        fam.snapshot_direct_with(entry(name.bytes().collect()),
+                                 false,
+                                 Some(FileIterator::from_bytes(contents.clone())),
+                                 options)
+            .unwrap();
+    }
+    fam.flush().unwrap();
+    drop(sender);
+
+    let (mut bytes, mut files_done, mut stored, mut deduplicated) = (0, 0, 0, 0);
+    for event in receiver.iter() {
+        match event {
+            Progress::BytesRead(n) => bytes += n,
+            Progress::FileDone => files_done += 1,
+            Progress::ChunkStored => stored += 1,
+            Progress::ChunkDeduplicated => deduplicated += 1,
+            other => panic!("Unexpected event during snapshot: {:?}", other),
+        }
+    }
+    assert_eq!(files_done, files.len());
+    assert_eq!(bytes,
+               files.iter().map(|&(_, ref contents)| contents.len() as u64).sum::<u64>());
+    assert!(stored > 0);
+    assert!(deduplicated > 0);
+
+    // No commit, so GC reports deleting every hash.
+    let (sender, receiver) = mpsc::channel();
+    let stats = hat.gc_with(GcOptions {
+            batch_size: 2,
+            progress: Some(sender),
+            ..GcOptions::default()
+        })
+        .unwrap();
+    assert_eq!(stats.live, 0);
+    let hashes_deleted = receiver.iter().filter(|e| *e == Progress::HashDeleted).count();
+    assert_eq!(hashes_deleted as i64, stats.hashes_deleted);
+}
+
+// Run the snapshot/commit/gc flows against each backend.
+macro_rules! backend_tests {
+    ($($name:ident => $backend:expr;)*) => {
+        $(
+            mod $name {
+                use super::*;
+
+                #[test]
+                fn snapshot_commit() {
+                    super::snapshot_commit(Arc::new($backend));
+                }
+
+                #[test]
+                fn snapshot_commit_many_empty_files() {
+                    super::snapshot_commit_many_empty_files(Arc::new($backend));
+                }
+
+                #[test]
+                fn snapshot_gc_parallel() {
+                    super::snapshot_gc_parallel(Arc::new($backend));
+                }
+
+                #[test]
+                fn snapshot_gc_incremental() {
+                    super::snapshot_gc_incremental(Arc::new($backend));
+                }
+
+                #[test]
+                fn snapshot_commit_many_empty_directories() {
+                    super::snapshot_commit_many_empty_directories(Arc::new($backend));
+                }
+
+                #[test]
+                fn snapshot_reuse_index() {
+                    super::snapshot_reuse_index(Arc::new($backend));
+                }
+
+                #[test]
+                fn snapshot_gc() {
+                    super::snapshot_gc(Arc::new($backend));
+                }
+
+                #[test]
+                fn snapshot_abort() {
+                    super::snapshot_abort(Arc::new($backend));
+                }
+
+                #[test]
+                fn recover() {
+                    super::recover(Arc::new($backend));
+                }
+
+                #[test]
+                fn snapshot_restore() {
+                    super::snapshot_restore(Arc::new($backend));
+                }
+            }
+        )*
+    }
+}
+
+backend_tests! {
+    memory => MemoryBackend::new();
+    s3 => mock_s3_backend();
+    http => mock_http_backend();
+}
@@ -46,11 +46,18 @@ fn insert_1_key_x_128000_zeros(bench: &mut Bencher) {
                 permissions: None,
                 data_hash: None,
                 data_length: None,
+                link_target: None,
+                hardlink_of: None,
+                xattrs: None,
+                holes: None,
             },
         };
 
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |()| Some(entry))),
+                                    None,
+                                    ChunkProfile::default(),
+                                    None))
             .unwrap();
     });
 
@@ -89,11 +96,18 @@ fn insert_1_key_x_128000_unique(bench: &mut Bencher) {
                 permissions: None,
                 data_hash: None,
                 data_length: None,
+                link_target: None,
+                hardlink_of: None,
+                xattrs: None,
+                holes: None,
             },
         };
 
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |()| Some(entry))),
+                                    None,
+                                    ChunkProfile::default(),
+                                    None))
             .unwrap();
     });
 
@@ -123,10 +137,17 @@ fn insert_1_key_x_16_x_128000_zeros(bench: &mut Bencher) {
                 permissions: None,
                 data_hash: None,
                 data_length: None,
+                link_target: None,
+                hardlink_of: None,
+                xattrs: None,
+                holes: None,
             },
         };
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |()| Some(entry))),
+                                    None,
+                                    ChunkProfile::default(),
+                                    None))
             .unwrap();
 
         match ks_p.send_reply(Msg::Flush).unwrap() {
@@ -176,11 +197,18 @@ fn insert_1_key_x_16_x_128000_unique(bench: &mut Bencher) {
                 permissions: None,
                 data_hash: None,
                 data_length: None,
+                link_target: None,
+                hardlink_of: None,
+                xattrs: None,
+                holes: None,
             },
         };
 
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
-                                    Some(Box::new(move |()| Some(entry)))))
+                                    Some(Box::new(move |()| Some(entry))),
+                                    None,
+                                    ChunkProfile::default(),
+                                    None))
             .unwrap();
 
         match ks_p.send_reply(Msg::Flush).unwrap() {
@@ -213,9 +241,18 @@ fn insert_1_key_unchanged_empty(bench: &mut Bencher) {
                 permissions: None,
                 data_hash: None,
                 data_length: None,
+                link_target: None,
+                hardlink_of: None,
+                xattrs: None,
+                holes: None,
             },
         };
-        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None)).unwrap();
+        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
+                                    None,
+                                    None,
+                                    ChunkProfile::default(),
+                                    None))
+            .unwrap();
     });
 }
 
@@ -242,9 +279,18 @@ fn insert_1_key_updated_empty(bench: &mut Bencher) {
                 permissions: None,
                 data_hash: None,
                 data_length: None,
+                link_target: None,
+                hardlink_of: None,
+                xattrs: None,
+                holes: None,
             },
         };
-        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None)).unwrap();
+        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
+                                    None,
+                                    None,
+                                    ChunkProfile::default(),
+                                    None))
+            .unwrap();
     });
 }
 
@@ -271,8 +317,17 @@ fn insert_1_key_unique_empty(bench: &mut Bencher) {
                 permissions: None,
                 data_hash: None,
                 data_length: None,
+                link_target: None,
+                hardlink_of: None,
+                xattrs: None,
+                holes: None,
             },
         };
-        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None)).unwrap();
+        ks_p.send_reply(Msg::Insert(entry.key_entry.clone(),
+                                    None,
+                                    None,
+                                    ChunkProfile::default(),
+                                    None))
+            .unwrap();
     });
 }
@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use std::f64;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use backend::StoreBackend;
 use blob;
@@ -20,16 +22,103 @@ use errors::RetryError;
 use hash;
 use key::MsgError;
 use hash::tree::HashTreeBackend;
+use progress::{self, Progress, ProgressSender};
+use util::FnBox;
+
+/// How well the file data stored was deduplicated against data stored before.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DedupStats {
+    /// Size of all data chunks, including those already stored.
+    pub logical_bytes: u64,
+    /// Size of the data chunks that were not stored before.
+    pub unique_bytes: u64,
+    /// Number of data chunks that were not stored before.
+    pub chunks_stored: u64,
+    /// Number of data chunks that reused a stored chunk.
+    pub chunks_deduplicated: u64,
+}
+
+impl DedupStats {
+    /// The logical size divided by the unique size; infinite if all data was already stored, and
+    /// 1 if there is no data.
+    pub fn ratio(&self) -> f64 {
+        match (self.logical_bytes, self.unique_bytes) {
+            (0, _) => 1.0,
+            (_, 0) => f64::INFINITY,
+            (logical, unique) => logical as f64 / unique as f64,
+        }
+    }
+}
+
+/// Sizes of the chunks written to hash trees, to help tune chunking.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChunkSizeStats {
+    /// Number of data chunks by size: `histogram[i]` counts chunks of `2^(i-1)` up to `2^i - 1`
+    /// bytes, and `histogram[0]` counts empty chunks.
+    pub histogram: Vec<u64>,
+    /// Number of data chunks (`Kind::TreeLeaf`).
+    pub leaf_chunks: u64,
+    /// Total size of the data chunks.
+    pub leaf_bytes: u64,
+    /// Number of chunks listing other chunks (`Kind::TreeBranch`).
+    pub branch_chunks: u64,
+    /// Total size of the chunks listing other chunks.
+    pub branch_bytes: u64,
+}
+
+impl ChunkSizeStats {
+    /// Average size of a data chunk; 0 if there are none.
+    pub fn average_leaf_size(&self) -> f64 {
+        if self.leaf_chunks == 0 {
+            0.0
+        } else {
+            self.leaf_bytes as f64 / self.leaf_chunks as f64
+        }
+    }
+
+    fn add(&mut self, kind: blob::Kind, len: usize) {
+        match kind {
+            blob::Kind::TreeLeaf => {
+                // The number of significant bits in the length.
+                let bucket = (0usize.leading_zeros() - len.leading_zeros()) as usize;
+                if self.histogram.len() <= bucket {
+                    self.histogram.resize(bucket + 1, 0);
+                }
+                self.histogram[bucket] += 1;
+                self.leaf_chunks += 1;
+                self.leaf_bytes += len as u64;
+            }
+            blob::Kind::TreeBranch => {
+                self.branch_chunks += 1;
+                self.branch_bytes += len as u64;
+            }
+        }
+    }
+}
 
 pub struct HashStoreBackend<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
+    progress: Option<ProgressSender>,
+    hasher: Arc<hash::Hasher>,
+    dedup_stats: Option<Arc<Mutex<DedupStats>>>,
+    chunk_size_stats: Option<Arc<Mutex<ChunkSizeStats>>>,
+    record_raw_lengths: bool,
+    policy: Option<blob::ChunkPolicy>,
+    file_data: bool,
 }
 impl<B> Clone for HashStoreBackend<B> {
     fn clone(&self) -> HashStoreBackend<B> {
         HashStoreBackend {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
+            progress: self.progress.clone(),
+            hasher: self.hasher.clone(),
+            dedup_stats: self.dedup_stats.clone(),
+            chunk_size_stats: self.chunk_size_stats.clone(),
+            record_raw_lengths: self.record_raw_lengths,
+            policy: self.policy.clone(),
+            file_data: self.file_data,
         }
     }
 }
@@ -38,9 +127,121 @@ impl<B: StoreBackend> HashStoreBackend<B> {
     pub fn new(hash_index: Arc<hash::HashIndex>,
                blob_store: Arc<blob::BlobStore<B>>)
                -> HashStoreBackend<B> {
+        HashStoreBackend::with_progress(hash_index, blob_store, None)
+    }
+
+    /// Like `new()`, but reports inserted chunks to `progress`.
+    pub fn with_progress(hash_index: Arc<hash::HashIndex>,
+                         blob_store: Arc<blob::BlobStore<B>>,
+                         progress: Option<ProgressSender>)
+                         -> HashStoreBackend<B> {
         HashStoreBackend {
             hash_index: hash_index,
             blob_store: blob_store,
+            progress: progress,
+            hasher: Arc::new(hash::Blake2b),
+            dedup_stats: None,
+            chunk_size_stats: None,
+            record_raw_lengths: false,
+            policy: None,
+            file_data: false,
+        }
+    }
+
+    /// Hash inserted chunks with `hasher` instead of the default.
+    pub fn with_hasher(mut self, hasher: Arc<hash::Hasher>) -> HashStoreBackend<B> {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Count inserted data chunks (leaves of the hash tree) in `stats`.
+    pub fn with_dedup_stats(mut self,
+                            stats: Option<Arc<Mutex<DedupStats>>>)
+                            -> HashStoreBackend<B> {
+        self.dedup_stats = stats;
+        self
+    }
+
+    /// Record the size of every inserted chunk, stored or not, in `stats`.
+    pub fn with_chunk_size_stats(mut self,
+                                 stats: Option<Arc<Mutex<ChunkSizeStats>>>)
+                                 -> HashStoreBackend<B> {
+        self.chunk_size_stats = stats;
+        self
+    }
+
+    /// Record the length of each data chunk in the references to it returned by `insert_chunk`,
+    /// so that readers can skip chunks that are not `key::MAX_CHUNK_LEN` bytes long.
+    pub fn with_raw_lengths(mut self, record: bool) -> HashStoreBackend<B> {
+        self.record_raw_lengths = record;
+        self
+    }
+
+    /// Pack and encrypt the chunks stored as given by `policy`, instead of as the blob store's
+    /// options say.
+    pub fn with_policy(mut self, policy: Option<blob::ChunkPolicy>) -> HashStoreBackend<B> {
+        self.policy = policy;
+        self
+    }
+
+    /// Whether the hash trees written hold file data, whose leaves may then be kept in their
+    /// references (see `blob::BlobStore::store_file_data`).
+    pub fn with_file_data(mut self, file_data: bool) -> HashStoreBackend<B> {
+        self.file_data = file_data;
+        self
+    }
+
+    fn with_raw_length(&self,
+                       level: i64,
+                       len: usize,
+                       mut href: hash::tree::HashRef)
+                       -> hash::tree::HashRef {
+        if self.record_raw_lengths && level == 0 {
+            href.persistent_ref.raw_length = Some(len);
+        }
+        href
+    }
+
+    // Store `chunk` as given by the policy, calling `callback` once it is stored.
+    fn store_chunk(&self,
+                   hash: &hash::Hash,
+                   level: i64,
+                   chunk: &[u8],
+                   callback: Box<FnBox<hash::tree::HashRef, ()>>)
+                   -> Result<hash::tree::HashRef, blob::BlobError> {
+        let policy = self.policy.as_ref();
+        if self.file_data && level == 0 {
+            return self.blob_store.store_file_data(&chunk, hash.clone(), policy, callback);
+        }
+        let kind = if level == 0 {
+            blob::Kind::TreeLeaf
+        } else {
+            blob::Kind::TreeBranch
+        };
+        self.blob_store.store_with_policy(&chunk, hash.clone(), kind, policy, callback)
+    }
+
+    fn count_chunk(&self, level: i64, len: usize, stored: bool) {
+        if let Some(ref stats) = self.chunk_size_stats {
+            let kind = if level == 0 {
+                blob::Kind::TreeLeaf
+            } else {
+                blob::Kind::TreeBranch
+            };
+            stats.lock().unwrap().add(kind, len);
+        }
+        if level != 0 {
+            return;
+        }
+        if let Some(ref stats) = self.dedup_stats {
+            let mut stats = stats.lock().unwrap();
+            stats.logical_bytes += len as u64;
+            if stored {
+                stats.unique_bytes += len as u64;
+                stats.chunks_stored += 1;
+            } else {
+                stats.chunks_deduplicated += 1;
+            }
         }
     }
 
@@ -56,8 +257,7 @@ impl<B: StoreBackend> HashStoreBackend<B> {
                                        hash: &hash::Hash,
                                        cref: &blob::ChunkRef)
                                        -> Result<Option<Vec<u8>>, MsgError> {
-        let res = try!(self.blob_store.retrieve(&hash, &cref));
-        Ok(res)
+        Ok(Some(try!(self.blob_store.retrieve(&hash, &cref))))
     }
 }
 
@@ -71,19 +271,33 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
         assert!(!hash.bytes.is_empty());
 
         let data_opt = if let Some(r) = persistent_ref {
-            try!(self.fetch_chunk_from_persistent_ref(&hash, &r))
+            match self.fetch_chunk_from_persistent_ref(&hash, &r) {
+                Ok(Some(data)) => Some(data),
+                // The chunk may have been rewritten since this reference was recorded (e.g. by
+                // key rotation). If so, the hash index knows where it is now.
+                res => {
+                    match try!(self.hash_index.fetch_persistent_ref(hash)) {
+                        Some(ref current) if *current != r => {
+                            try!(self.fetch_chunk_from_persistent_ref(&hash, current))
+                        }
+                        _ => try!(res),
+                    }
+                }
+            }
         } else {
             try!(self.fetch_chunk_from_hash(&hash))
         };
 
         Ok(data_opt.and_then(|data| {
-            let actual_hash = hash::Hash::new(&data[..]);
-            if *hash == actual_hash {
+            let ok = if hash.hasher_id() == self.hasher.id() {
+                hash::Hash::with_hasher(&*self.hasher, &data[..]).ok().as_ref() == Some(hash)
+            } else {
+                hash.verify(&data[..])
+            };
+            if ok {
                 Some(data)
             } else {
-                error!("Data hash does not match expectation: {:?} instead of {:?}",
-                       actual_hash,
-                       hash);
+                error!("Data hash does not match expectation: {:?}", hash);
                 None
             }
         }))
@@ -95,7 +309,8 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
             match self.hash_index.fetch_persistent_ref(hash) {
                 Ok(Some(r)) => return Some(r), // done
                 Ok(None) => return None, // done
-                Err(RetryError) => (),  // continue loop
+                // The chunk is being stored by another writer; let it finish.
+                Err(RetryError) => thread::yield_now(),
             }
         }
     }
@@ -107,6 +322,10 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
         }
     }
 
+    fn hash(&self, chunk: &[u8]) -> Result<hash::Hash, MsgError> {
+        Ok(try!(hash::Hash::with_hasher(&*self.hasher, chunk)))
+    }
+
     fn insert_chunk(&self,
                     hash: &hash::Hash,
                     level: i64,
@@ -124,29 +343,53 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
 
         match self.hash_index.reserve(&hash_entry) {
             hash::ReserveResult::HashKnown(id) => {
+                let persistent_ref = self.fetch_persistent_ref(hash)
+                    .expect("Could not find persistent_ref for known chunk.");
+                if self.blob_store.needs_encrypted_copy(&persistent_ref, self.policy.as_ref()) {
+                    // Someone stored it unencrypted with their own policy, which must not apply
+                    // to our data: store it again, and have the hash point at the new copy.
+                    let local_hash_index = self.hash_index.clone();
+                    let callback = Box::new(move |href: hash::tree::HashRef| {
+                        local_hash_index.upgrade_persistent_ref(&href.hash, href.persistent_ref)
+                            .expect("Error recording persistent reference");
+                    });
+                    let href = try!(self.store_chunk(hash, level, chunk, callback));
+                    progress::report(&self.progress, Progress::ChunkStored);
+                    self.count_chunk(level, chunk.len(), true);
+                    return Ok((id, self.with_raw_length(level, chunk.len(), href)));
+                }
+
                 // Someone came before us: piggyback on their result.
-                Ok((id,
-                    hash::tree::HashRef {
+                progress::report(&self.progress, Progress::ChunkDeduplicated);
+                self.blob_store.metrics().chunk_deduplicated();
+                self.count_chunk(level, chunk.len(), false);
+                let href = hash::tree::HashRef {
                     hash: hash.clone(),
-                    persistent_ref: self.fetch_persistent_ref(hash)
-                        .expect("Could not find persistent_ref for known chunk."),
-                }))
+                    persistent_ref: persistent_ref,
+                };
+                Ok((id, self.with_raw_length(level, chunk.len(), href)))
             }
             hash::ReserveResult::ReserveOk(id) => {
                 // We came first: this data-chunk is ours to process.
                 let local_hash_index = self.hash_index.clone();
 
                 let callback = Box::new(move |href: hash::tree::HashRef| {
-                    local_hash_index.commit(&href.hash, href.persistent_ref);
+                    local_hash_index.commit(&href.hash, href.persistent_ref)
+                        .expect("Error recording persistent reference");
                 });
-                let kind = if level == 0 {
-                    blob::Kind::TreeLeaf
-                } else {
-                    blob::Kind::TreeBranch
+                let href = match self.store_chunk(hash, level, chunk, callback) {
+                    Ok(href) => href,
+                    Err(e) => {
+                        // Nothing was stored, so later writers must not wait for this chunk.
+                        try!(self.hash_index.unreserve(hash));
+                        return Err(From::from(e));
+                    }
                 };
-                let href = self.blob_store.store(&chunk, hash.clone(), kind, callback);
+                let href = self.with_raw_length(level, chunk.len(), href);
                 hash_entry.persistent_ref = Some(href.persistent_ref.clone());
                 self.hash_index.update_reserved(hash_entry);
+                progress::report(&self.progress, Progress::ChunkStored);
+                self.count_chunk(level, chunk.len(), true);
                 Ok((id, href))
             }
         }
@@ -18,15 +18,19 @@ use diesel;
 use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
 
+use std::collections::BTreeMap;
 use std::sync::{Mutex, MutexGuard};
 use time::Duration;
 
 use blob;
 use errors::DieselError;
 use hash;
-use util::{InfoWriter, PeriodicTimer};
 
-use super::schema;
+use capnp;
+use root_capnp;
+use util::{self, IndexOptions, InfoWriter, PeriodicTimer};
+
+use super::{MsgError, schema};
 
 #[derive(Clone, Debug)]
 pub struct Entry {
@@ -45,6 +49,94 @@ pub struct Entry {
 
     pub data_hash: Option<Vec<u8>>,
     pub data_length: Option<u64>,
+
+    /// Target of a symbolic link. Symlinks have no data, so `data_hash` and `data_length` are
+    /// absent for them.
+    pub link_target: Option<Vec<u8>>,
+
+    /// Id of an entry sharing this entry's inode. Only the first path of a hardlinked file
+    /// stores data, so `data_hash` and `data_length` are absent for the others.
+    pub hardlink_of: Option<u64>,
+
+    /// Extended attributes by name. Absent when none were recorded.
+    pub xattrs: Option<BTreeMap<Vec<u8>, Vec<u8>>>,
+
+    /// Holes of a sparse file as `(offset, length)`, in ascending order. The data stored for the
+    /// file still includes their zeros. Absent for files without holes.
+    pub holes: Option<Vec<(u64, u64)>>,
+}
+
+impl Entry {
+    /// Whether this entry is a directory. Directories are the entries without data, link target
+    /// or hardlink; a regular file always has a data hash. The hash of an empty file is itself
+    /// empty, as no data is stored for it.
+    pub fn is_directory(&self) -> bool {
+        self.data_hash.is_none() && self.link_target.is_none() && self.hardlink_of.is_none()
+    }
+
+    /// Whether this entry is a regular file with its own data (possibly empty).
+    pub fn is_file(&self) -> bool {
+        self.data_hash.is_some()
+    }
+}
+
+/// Serialize extended attributes for storage in the index.
+fn xattrs_as_bytes(xattrs: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let root = message.init_root::<root_capnp::extended_attribute_list::Builder>();
+        let mut list = root.init_attributes(xattrs.len() as u32);
+        for (i, (name, value)) in xattrs.iter().enumerate() {
+            let mut attr = list.borrow().get(i as u32);
+            attr.set_name(&name[..]);
+            attr.set_value(&value[..]);
+        }
+    }
+
+    let mut out = Vec::new();
+    capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+
+    out
+}
+
+fn xattrs_from_bytes(mut bytes: &[u8]) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, capnp::Error> {
+    let reader = try!(capnp::serialize_packed::read_message(&mut bytes,
+                                                           capnp::message::ReaderOptions::new()));
+    let root = try!(reader.get_root::<root_capnp::extended_attribute_list::Reader>());
+
+    let mut out = BTreeMap::new();
+    for attr in try!(root.get_attributes()).iter() {
+        out.insert(try!(attr.get_name()).to_owned(),
+                   try!(attr.get_value()).to_owned());
+    }
+    Ok(out)
+}
+
+/// Serialize the holes of a sparse file for storage in the index.
+fn holes_as_bytes(holes: &[(u64, u64)]) -> Vec<u8> {
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let root = message.init_root::<root_capnp::hole_list::Builder>();
+        let mut list = root.init_holes(holes.len() as u32);
+        for (i, &(offset, length)) in holes.iter().enumerate() {
+            let mut hole = list.borrow().get(i as u32);
+            hole.set_offset(offset);
+            hole.set_length(length);
+        }
+    }
+
+    let mut out = Vec::new();
+    capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+
+    out
+}
+
+fn holes_from_bytes(mut bytes: &[u8]) -> Result<Vec<(u64, u64)>, capnp::Error> {
+    let reader = try!(capnp::serialize_packed::read_message(&mut bytes,
+                                                           capnp::message::ReaderOptions::new()));
+    let root = try!(reader.get_root::<root_capnp::hole_list::Reader>());
+
+    Ok(try!(root.get_holes()).iter().map(|h| (h.get_offset(), h.get_length())).collect())
 }
 
 pub struct KeyIndex(Mutex<InternalKeyIndex>);
@@ -56,8 +148,8 @@ pub struct InternalKeyIndex {
 
 
 impl InternalKeyIndex {
-    fn new(path: &str) -> Result<InternalKeyIndex, DieselError> {
-        let conn = try!(SqliteConnection::establish(path));
+    fn new(path: &str, options: &IndexOptions) -> Result<InternalKeyIndex, DieselError> {
+        let conn = try!(util::sqlite::establish(path, options));
 
         let ki = InternalKeyIndex {
             conn: conn,
@@ -107,13 +199,23 @@ impl InternalKeyIndex {
                           name.eq(&entry.name[..]),
                           created.eq(entry.created),
                           modified.eq(entry.modified),
-                          accessed.eq(entry.accessed)))
+                          accessed.eq(entry.accessed),
+                          permissions.eq(entry.permissions.map(|x| x as i64)),
+                          user_id.eq(entry.user_id.map(|x| x as i64)),
+                          group_id.eq(entry.group_id.map(|x| x as i64)),
+                          link_target.eq(entry.link_target.as_ref().map(|t| &t[..])),
+                          hardlink_of.eq(entry.hardlink_of.map(|x| x as i64)),
+                          xattrs.eq(entry.xattrs.as_ref().map(xattrs_as_bytes)),
+                          data_length.eq(entry.data_length.map(|x| x as i64)),
+                          holes.eq(entry.holes.as_ref().map(|h| holes_as_bytes(&h[..])))))
                     .execute(&self.conn));
                 entry
             }
             None => {
                 // Insert new entry.
                 {
+                    let xattrs_bytes = entry.xattrs.as_ref().map(xattrs_as_bytes);
+                    let holes_bytes = entry.holes.as_ref().map(|h| holes_as_bytes(&h[..]));
                     let new = schema::NewKey {
                         parent: entry.parent_id.map(|x| x as i64),
                         name: &entry.name[..],
@@ -125,6 +227,11 @@ impl InternalKeyIndex {
                         user_id: entry.user_id.map(|x| x as i64),
                         hash: None,
                         persistent_ref: None,
+                        link_target: entry.link_target.as_ref().map(|t| &t[..]),
+                        hardlink_of: entry.hardlink_of.map(|x| x as i64),
+                        xattrs: xattrs_bytes.as_ref().map(|x| &x[..]),
+                        data_length: entry.data_length.map(|x| x as i64),
+                        holes: holes_bytes.as_ref().map(|x| &x[..]),
                     };
 
                     try!(diesel::insert(&new)
@@ -175,7 +282,11 @@ impl InternalKeyIndex {
                 user_id: row.user_id.map(|x| x as u64),
                 group_id: row.group_id.map(|x| x as u64),
                 data_hash: row.hash,
-                data_length: None,
+                data_length: row.data_length.map(|x| x as u64),
+                link_target: row.link_target,
+                hardlink_of: row.hardlink_of.map(|x| x as u64),
+                xattrs: row.xattrs.map(|x| xattrs_from_bytes(&x[..]).unwrap()),
+                holes: row.holes.map(|x| holes_from_bytes(&x[..]).unwrap()),
             }))
         } else {
             Ok(None)
@@ -190,14 +301,19 @@ impl InternalKeyIndex {
                         last_modified: Option<i64>,
                         hash_opt: Option<hash::Hash>,
                         persistent_ref_opt: Option<blob::ChunkRef>)
-                        -> Result<(), DieselError> {
+                        -> Result<(), MsgError> {
         use super::schema::keys::dsl::*;
 
         let id_ = id_ as i64;
-        assert!(hash_opt.is_some() == persistent_ref_opt.is_some());
+        // Only the empty hash of an empty file comes without a reference.
+        assert_eq!(hash_opt.as_ref().map_or(false, |h| !h.bytes.is_empty()),
+                   persistent_ref_opt.is_some());
 
         let hash_bytes = hash_opt.map(|h| h.bytes);
-        let persistent_ref_bytes = persistent_ref_opt.map(|p| p.as_bytes());
+        let persistent_ref_bytes = match persistent_ref_opt {
+            Some(p) => Some(try!(p.as_bytes())),
+            None => None,
+        };
 
         if last_modified.is_some() {
             try!(diesel::update(keys.find(id_)
@@ -211,9 +327,64 @@ impl InternalKeyIndex {
                 .execute(&self.conn));
         }
 
+        Ok(try!(self.maybe_flush()))
+    }
+
+    fn update_data_length(&mut self, id_: u64, length: u64) -> Result<(), DieselError> {
+        use super::schema::keys::dsl::*;
+
+        try!(diesel::update(keys.find(id_ as i64))
+            .set(data_length.eq(Some(length as i64)))
+            .execute(&self.conn));
+
         self.maybe_flush()
     }
 
+    /// Record entries whose data has been stored, so that an interrupted snapshot can skip them.
+    fn checkpoint(&mut self, entries: &[(u64, Option<i64>)]) -> Result<(), DieselError> {
+        use super::schema::key_checkpoint::dsl::*;
+
+        for &(id_, modified_) in entries.iter() {
+            try!(diesel::delete(key_checkpoint.find(id_ as i64)).execute(&self.conn));
+            let new = schema::NewCheckpoint {
+                id: id_ as i64,
+                modified: modified_,
+            };
+            try!(diesel::insert(&new)
+                .into(key_checkpoint)
+                .execute(&self.conn));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the entry was checkpointed with the given modification time.
+    fn is_checkpointed(&mut self, id_: u64, modified_: Option<i64>) -> Result<bool, DieselError> {
+        use super::schema::key_checkpoint::dsl::*;
+
+        let row = try!(key_checkpoint.find(id_ as i64)
+            .select(modified)
+            .first::<Option<i64>>(&self.conn)
+            .optional());
+        Ok(row == Some(modified_))
+    }
+
+    fn clear_checkpoint(&mut self) -> Result<(), DieselError> {
+        use super::schema::key_checkpoint::dsl::*;
+
+        try!(diesel::delete(key_checkpoint).execute(&self.conn));
+        Ok(())
+    }
+
+    /// Remove all entries and the checkpoint, e.g. to discard an uncommitted snapshot.
+    fn clear(&mut self) -> Result<(), DieselError> {
+        use super::schema::keys::dsl::*;
+
+        try!(self.clear_checkpoint());
+        try!(diesel::delete(keys).execute(&self.conn));
+        Ok(())
+    }
+
     /// List a directory (aka. `level`) in the index.
     /// Returns `ListResult` with all the entries under the given parent.
     fn list_dir(&mut self,
@@ -246,7 +417,11 @@ impl InternalKeyIndex {
                     user_id: r.user_id.map(|x| x as u64),
                     group_id: r.group_id.map(|x| x as u64),
                     data_hash: r.hash,
-                    data_length: None,
+                    data_length: r.data_length.map(|x| x as u64),
+                    link_target: r.link_target,
+                    hardlink_of: r.hardlink_of.map(|x| x as u64),
+                    xattrs: r.xattrs.as_ref().map(|x| xattrs_from_bytes(&x[..]).unwrap()),
+                    holes: r.holes.as_ref().map(|x| holes_from_bytes(&x[..]).unwrap()),
                 },
                  r.persistent_ref
                     .as_mut()
@@ -258,7 +433,11 @@ impl InternalKeyIndex {
 
 impl KeyIndex {
     pub fn new(path: &str) -> Result<KeyIndex, DieselError> {
-        InternalKeyIndex::new(path).map(|index| KeyIndex(Mutex::new(index)))
+        KeyIndex::with_options(path, &IndexOptions::default())
+    }
+
+    pub fn with_options(path: &str, options: &IndexOptions) -> Result<KeyIndex, DieselError> {
+        InternalKeyIndex::new(path, options).map(|index| KeyIndex(Mutex::new(index)))
     }
 
     #[cfg(test)]
@@ -286,16 +465,37 @@ impl KeyIndex {
                             last_modified: Option<i64>,
                             hash_opt: Option<hash::Hash>,
                             persistent_ref_opt: Option<blob::ChunkRef>)
-                            -> Result<(), DieselError> {
+                            -> Result<(), MsgError> {
         self.lock().update_data_hash(id, last_modified, hash_opt, persistent_ref_opt)
     }
 
+    /// Record the length of an entry's data once it is known (e.g. after reading a stream).
+    pub fn update_data_length(&self, id: u64, length: u64) -> Result<(), DieselError> {
+        self.lock().update_data_length(id, length)
+    }
+
     pub fn list_dir(&self,
                     parent_opt: Option<u64>)
                     -> Result<Vec<(Entry, Option<blob::ChunkRef>)>, DieselError> {
         self.lock().list_dir(parent_opt)
     }
 
+    pub fn checkpoint(&self, entries: &[(u64, Option<i64>)]) -> Result<(), DieselError> {
+        self.lock().checkpoint(entries)
+    }
+
+    pub fn is_checkpointed(&self, id: u64, modified: Option<i64>) -> Result<bool, DieselError> {
+        self.lock().is_checkpointed(id, modified)
+    }
+
+    pub fn clear_checkpoint(&self) -> Result<(), DieselError> {
+        self.lock().clear_checkpoint()
+    }
+
+    pub fn clear(&self) -> Result<(), DieselError> {
+        self.lock().clear()
+    }
+
     pub fn flush(&self) -> Result<(), DieselError> {
         self.lock().flush()
     }
@@ -14,17 +14,25 @@
 
 //! External API for creating and manipulating snapshots.
 
+use std::cmp;
+use std::collections::HashSet;
 use std::io;
-use std::sync::Arc;
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::borrow::Cow;
 
+use capnp;
+
 use backend::StoreBackend;
 use blob;
 use hash;
 use hash::tree::{ReaderResult, SimpleHashTreeReader, SimpleHashTreeWriter};
+use progress::{self, Progress, ProgressSender};
 
-use util::{FnBox, MsgHandler, Process};
-use errors::{DieselError, RetryError};
+use util::{self, FileIterator, FnBox, MsgHandler, Process};
+use errors::{CancelledError, DieselError, HasherError, RetryError};
 
 mod schema;
 mod index;
@@ -35,10 +43,101 @@ mod tests;
 #[cfg(all(test, feature = "benchmarks"))]
 mod benchmarks;
 
-pub use self::hash_store_backend::HashStoreBackend;
+pub use self::hash_store_backend::{ChunkSizeStats, DedupStats, HashStoreBackend};
 pub use self::index::{Entry, KeyIndex};
 
 
+/// Number of stored entries between checkpoints of an unfinished snapshot.
+const CHECKPOINT_INTERVAL: usize = 1000;
+
+/// File contents are split into chunks of this many bytes; only the last chunk of a file (or one
+/// cut short by a read error) is smaller.
+pub const MAX_CHUNK_LEN: usize = 128 * 1024;
+
+
+/// How file contents are split into chunks, e.g. large chunks for databases and small ones for
+/// source trees. Chunking only matters when data is written: chunks of any profile are read back
+/// alike, but only deduplicate against data chunked the same way.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChunkProfile {
+    min_len: usize,
+    avg_len: usize,
+    max_len: usize,
+}
+
+impl Default for ChunkProfile {
+    /// Fixed chunks of `MAX_CHUNK_LEN` bytes.
+    fn default() -> ChunkProfile {
+        ChunkProfile::fixed(MAX_CHUNK_LEN)
+    }
+}
+
+impl ChunkProfile {
+    /// Chunks of `len` bytes, which must be positive and at most `MAX_CHUNK_LEN`.
+    pub fn fixed(len: usize) -> ChunkProfile {
+        ChunkProfile::content_defined(len, len, len)
+    }
+
+    /// Chunks between `min_len` and `max_len` bytes long, cut where a rolling hash of the data
+    /// matches, so that inserting data only changes the chunks around it. Chunks average about
+    /// `min_len` plus `avg_len` rounded up to a power of two.
+    ///
+    /// Requires `0 < min_len <= avg_len <= max_len <= MAX_CHUNK_LEN`.
+    pub fn content_defined(min_len: usize, avg_len: usize, max_len: usize) -> ChunkProfile {
+        assert!(0 < min_len && min_len <= avg_len && avg_len <= max_len &&
+                max_len <= MAX_CHUNK_LEN,
+                "Invalid chunk profile");
+        ChunkProfile {
+            min_len: min_len,
+            avg_len: avg_len,
+            max_len: max_len,
+        }
+    }
+
+    /// Whether all chunks are `MAX_CHUNK_LEN` bytes, except for the last of a file.
+    fn is_default(&self) -> bool {
+        *self == ChunkProfile::default()
+    }
+
+    /// The length of the next chunk at the start of `data`, which holds `max_len` bytes unless
+    /// the file ends sooner.
+    fn cut(&self, data: &[u8], gear: &[u64; 256]) -> usize {
+        if data.len() <= self.min_len || self.min_len == self.max_len {
+            return cmp::min(data.len(), self.max_len);
+        }
+        // Cut where the top `bits` bits of the hash are zero, once every `2^bits` bytes on
+        // average. They depend on the last 64 bytes, so hashing starts just in time for `min_len`.
+        let bits = self.avg_len.next_power_of_two().trailing_zeros();
+        let mask = if bits == 0 { 0 } else { !0u64 << (64 - bits) };
+        let end = cmp::min(data.len(), self.max_len);
+        let mut hash = 0u64;
+        for i in self.min_len.saturating_sub(64)..end {
+            hash = (hash << 1).wrapping_add(gear[data[i] as usize]);
+            if i + 1 >= self.min_len && hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Random values for the rolling hash of `ChunkProfile::cut`, fixed so that the same data is
+/// always cut the same way.
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x9e3779b97f4a7c15u64;
+    for value in table.iter_mut() {
+        // SplitMix64.
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        *value = z ^ (z >> 31);
+    }
+    table
+}
+
+
 error_type! {
     #[derive(Debug)]
     pub enum MsgError {
@@ -55,6 +154,15 @@ error_type! {
         },
         Blob(blob::BlobError) {
             cause;
+        },
+        DataSerialization(capnp::Error) {
+            cause;
+        },
+        Hasher(HasherError) {
+            cause;
+        },
+        Cancelled(CancelledError) {
+            cause;
         }
      }
 }
@@ -69,21 +177,50 @@ pub struct HashTreeReaderInitializer<B> {
     persistent_ref: Option<blob::ChunkRef>,
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
+    hasher: Arc<hash::Hasher>,
 }
 
 impl<B: StoreBackend> HashTreeReaderInitializer<B> {
     pub fn init(self) -> Result<Option<ReaderResult<HashStoreBackend<B>>>, MsgError> {
-        let backend = HashStoreBackend::new(self.hash_index, self.blob_store);
+        let backend = HashStoreBackend::new(self.hash_index, self.blob_store)
+            .with_hasher(self.hasher);
         SimpleHashTreeReader::open(backend, &self.hash, self.persistent_ref)
     }
 }
 
+/// The data of an entry given to `Msg::Insert`.
+pub trait DataSource: io::Read {
+    /// The current modification time of the data's source in nanoseconds since the epoch, if it
+    /// has one. Compared to the entry's after reading, to tell if the data changed meanwhile.
+    fn modified(&self) -> Option<i64> {
+        None
+    }
+}
+
+impl DataSource for FileIterator {
+    fn modified(&self) -> Option<i64> {
+        match *self {
+            FileIterator::File(ref f, _) => {
+                f.get_ref().metadata().ok().map(|md| md.mtime() * 1_000_000_000 + md.mtime_nsec())
+            }
+            _ => None,
+        }
+    }
+}
+
 // Public structs
 pub enum Msg<IT> {
     /// Insert a key into the index. If this key has associated data a "chunk-iterator creator"
     /// can be passed along with it. If the data turns out to be unreadable, this iterator proc
-    /// can return `None`. Returns `Id` with the new entry ID.
-    Insert(Entry, Option<Box<FnBox<(), Option<IT>>>>),
+    /// can return `None`. Progress of storing the data is reported to the optional listener, the
+    /// data is split into chunks as given by the profile, and the chunks are packed and encrypted
+    /// as given by the policy, if any.
+    /// Returns `Id` with the new entry ID.
+    Insert(Entry,
+           Option<Box<FnBox<(), Option<IT>>>>,
+           Option<ProgressSender>,
+           ChunkProfile,
+           Option<blob::ChunkPolicy>),
 
     /// List a "directory" (aka. a `level`) in the index.
     /// Returns `ListResult` with all the entries under the given parent.
@@ -92,6 +229,16 @@ pub enum Msg<IT> {
     /// Flush this key store and its dependencies.
     /// Returns `FlushOk`.
     Flush,
+
+    /// Make the entries stored so far durable and record them in the checkpoint, so that an
+    /// interrupted snapshot does not store them again.
+    /// Returns `FlushOk`.
+    Checkpoint,
+
+    /// Wait for the data stored so far and then forget all entries, so that the snapshot in
+    /// progress is never committed from this index.
+    /// Returns `FlushOk`.
+    Abort,
 }
 
 pub enum Reply<B> {
@@ -104,6 +251,19 @@ pub struct Store<B> {
     index: Arc<index::KeyIndex>,
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
+    // Stored entries not yet in the checkpoint, with their modification time.
+    unchecked: Vec<(u64, Option<i64>)>,
+    incremental: bool,
+    hasher: Arc<hash::Hasher>,
+    dedup_stats: Option<Arc<Mutex<DedupStats>>>,
+    chunk_size_stats: Option<Arc<Mutex<ChunkSizeStats>>>,
+    changed_entries: Option<Arc<Mutex<HashSet<u64>>>>,
+    hash_pool: Option<Arc<hash::HashPool>>,
+    tree_order: usize,
+    tree_spill: Option<(PathBuf, usize)>,
+    chunk_policy: Option<blob::ChunkPolicy>,
+    // An error to report in reply to the next message.
+    failed: Option<MsgError>,
 }
 impl<B> Clone for Store<B> {
     fn clone(&self) -> Store<B> {
@@ -111,6 +271,17 @@ impl<B> Clone for Store<B> {
             index: self.index.clone(),
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
+            unchecked: Vec::new(),
+            incremental: self.incremental,
+            hasher: self.hasher.clone(),
+            dedup_stats: self.dedup_stats.clone(),
+            chunk_size_stats: self.chunk_size_stats.clone(),
+            changed_entries: self.changed_entries.clone(),
+            hash_pool: self.hash_pool.clone(),
+            tree_order: self.tree_order,
+            tree_spill: self.tree_spill.clone(),
+            chunk_policy: self.chunk_policy.clone(),
+            failed: None,
         }
     }
 }
@@ -125,9 +296,83 @@ impl<B: StoreBackend> Store<B> {
             index: index,
             hash_index: hash_index,
             blob_store: blob_store,
+            unchecked: Vec::new(),
+            incremental: false,
+            hasher: Arc::new(hash::Blake2b),
+            dedup_stats: None,
+            chunk_size_stats: None,
+            changed_entries: None,
+            hash_pool: None,
+            tree_order: hash::tree::DEFAULT_ORDER,
+            tree_spill: None,
+            chunk_policy: None,
+            failed: None,
         }
     }
 
+    /// Reuse the stored data of files whose modification time and size are unchanged, without
+    /// reading them again. Other metadata changes are still recorded.
+    pub fn incremental(mut self) -> Store<B> {
+        self.incremental = true;
+        self
+    }
+
+    /// Hash new chunks with `hasher` instead of the default. Chunks are only deduplicated against
+    /// chunks hashed with the same algorithm.
+    pub fn with_hasher(mut self, hasher: Arc<hash::Hasher>) -> Store<B> {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Count the file data stored by this key store in `stats`.
+    pub fn with_dedup_stats(mut self, stats: Arc<Mutex<DedupStats>>) -> Store<B> {
+        self.dedup_stats = Some(stats);
+        self
+    }
+
+    /// Record the size of the chunks written by this key store in `stats`.
+    pub fn with_chunk_size_stats(mut self, stats: Arc<Mutex<ChunkSizeStats>>) -> Store<B> {
+        self.chunk_size_stats = Some(stats);
+        self
+    }
+
+    /// Record in `changed` the IDs of entries whose data changed while it was read, i.e. whose
+    /// size or modification time after reading differs from the entry's. An ID is removed again
+    /// once its data is read without changing.
+    pub fn with_changed_entries(mut self, changed: Arc<Mutex<HashSet<u64>>>) -> Store<B> {
+        self.changed_entries = Some(changed);
+        self
+    }
+
+    /// Hash file data on `pool` while reading ahead, instead of hashing each chunk in turn. The
+    /// pool must use the same hasher as this key store.
+    pub fn with_hash_pool(mut self, pool: Arc<hash::HashPool>) -> Store<B> {
+        self.hash_pool = Some(pool);
+        self
+    }
+
+    /// Write hash-trees with `order` children per node instead of `DEFAULT_ORDER`. Wider trees
+    /// are shallower, so huge files need fewer tree nodes read on restore.
+    pub fn with_tree_order(mut self, order: usize) -> Store<B> {
+        assert!(order >= 2);
+        self.tree_order = order;
+        self
+    }
+
+    /// Move the references held for a level of the hash-trees written to files in `dir` once
+    /// more than `threshold` of them are in memory (see `SimpleHashTreeWriter::with_spill`).
+    pub fn with_tree_spill(mut self, dir: PathBuf, threshold: usize) -> Store<B> {
+        self.tree_spill = Some((dir, threshold));
+        self
+    }
+
+    /// Pack and encrypt the chunks of the hash-trees written by `hash_tree_writer()` as given by
+    /// `policy`, instead of as the blob store's options say.
+    pub fn with_chunk_policy(mut self, policy: Option<blob::ChunkPolicy>) -> Store<B> {
+        self.chunk_policy = policy;
+        self
+    }
+
     #[cfg(test)]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<Store<B>, DieselError> {
         let ki_p = Arc::new(try!(index::KeyIndex::new_for_testing()));
@@ -138,21 +383,111 @@ impl<B: StoreBackend> Store<B> {
             index: ki_p,
             hash_index: hi_p,
             blob_store: bs_p,
+            unchecked: Vec::new(),
+            incremental: false,
+            hasher: Arc::new(hash::Blake2b),
+            dedup_stats: None,
+            chunk_size_stats: None,
+            changed_entries: None,
+            hash_pool: None,
+            tree_order: hash::tree::DEFAULT_ORDER,
+            tree_spill: None,
+            chunk_policy: None,
+            failed: None,
         })
     }
 
     pub fn flush(&mut self) -> Result<(), MsgError> {
-        self.blob_store.flush();
+        try!(self.blob_store.flush());
         self.hash_index.flush();
+        // The snapshot data is complete, so there is nothing left to resume.
+        self.unchecked.clear();
+        try!(self.index.clear_checkpoint());
         try!(self.index.flush());
 
         Ok(())
     }
 
+    pub fn checkpoint(&mut self) -> Result<(), MsgError> {
+        // The data must be durable before the checkpoint claims it is.
+        try!(self.blob_store.flush());
+        self.hash_index.flush();
+        try!(self.index.checkpoint(&self.unchecked[..]));
+        self.unchecked.clear();
+        try!(self.index.flush());
+
+        Ok(())
+    }
+
+    pub fn abort(&mut self) -> Result<(), MsgError> {
+        // Let pending writes finish so that nothing refers to the entries once they are gone.
+        try!(self.blob_store.flush());
+        self.hash_index.flush();
+        self.unchecked.clear();
+        if let Some(ref changed) = self.changed_entries {
+            changed.lock().unwrap().clear();
+        }
+        try!(self.index.clear());
+        try!(self.index.flush());
+
+        Ok(())
+    }
+
+    /// Look up the entry called `name` in the directory `parent`, as stored so far.
+    pub fn lookup(&self, parent: Option<u64>, name: Vec<u8>) -> Result<Option<Entry>, MsgError> {
+        Ok(try!(self.index.lookup(parent, name)))
+    }
+
+    fn set_changed(&self, id: u64, changed: bool) {
+        if let Some(ref entries) = self.changed_entries {
+            let mut entries = entries.lock().unwrap();
+            if changed {
+                entries.insert(id);
+            } else {
+                entries.remove(&id);
+            }
+        }
+    }
+
+    fn stored(&mut self, entry: &Entry) -> Result<(), MsgError> {
+        self.unchecked.push((entry.id.unwrap(), entry.modified));
+        if self.unchecked.len() >= CHECKPOINT_INTERVAL {
+            try!(self.checkpoint());
+        }
+        Ok(())
+    }
+
     pub fn hash_tree_writer(&mut self) -> SimpleHashTreeWriter<HashStoreBackend<B>> {
-        let backend = HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone());
-        SimpleHashTreeWriter::new(8, backend)
+        let policy = self.chunk_policy.clone();
+        self.hash_tree_writer_with_progress(None, false, false, policy)
     }
+
+    fn hash_tree_writer_with_progress(&mut self,
+                                      progress: Option<ProgressSender>,
+                                      file_data: bool,
+                                      raw_lengths: bool,
+                                      policy: Option<blob::ChunkPolicy>)
+                                      -> SimpleHashTreeWriter<HashStoreBackend<B>> {
+        let backend = HashStoreBackend::with_progress(self.hash_index.clone(),
+                                                      self.blob_store.clone(),
+                                                      progress)
+            .with_hasher(self.hasher.clone())
+            .with_dedup_stats(self.dedup_stats.clone())
+            .with_chunk_size_stats(self.chunk_size_stats.clone())
+            .with_raw_lengths(raw_lengths)
+            .with_policy(policy)
+            .with_file_data(file_data);
+        let writer = SimpleHashTreeWriter::new(self.tree_order, backend);
+        match self.tree_spill {
+            Some((ref dir, threshold)) => writer.with_spill(dir.clone(), threshold),
+            None => writer,
+        }
+    }
+}
+
+/// The data hash of an empty file. It is empty itself, as there are no chunks to refer to.
+fn empty_data_hash() -> hash::Hash {
+    hash::Hash { bytes: vec![] }
 }
 
 fn file_size_warning(name: &[u8], wanted: u64, got: u64) {
@@ -169,13 +504,50 @@ fn file_size_warning(name: &[u8], wanted: u64, got: u64) {
     }
 }
 
-impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
+impl<IT: DataSource, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
     type Err = MsgError;
 
     fn handle<F: FnOnce(Result<Reply<B>, MsgError>)>(&mut self,
                                                      msg: Msg<IT>,
                                                      reply: F)
                                                      -> Result<(), MsgError> {
+        // An error after replying is fatal to the process, but running out of quota or being
+        // cancelled is not: it is reported in reply to the next message instead.
+        if let Some(e) = self.failed.take() {
+            reply(Err(e));
+            return Ok(());
+        }
+        let mut replied = false;
+        let res = self.handle_msg(msg, |r| {
+            replied = true;
+            reply(r)
+        });
+        let e = match res {
+            Err(MsgError::Blob(blob::BlobError::Quota(e))) => {
+                MsgError::Blob(blob::BlobError::Quota(e))
+            }
+            Err(MsgError::Cancelled(e)) => MsgError::Cancelled(e),
+            res => return res,
+        };
+        if replied {
+            self.failed = Some(e);
+            Ok(())
+        } else {
+            Err(e)
+        }
+    }
+}
+
+impl<B: StoreBackend> Store<B> {
+    /// Whether the data with this top hash is stored. Empty files store nothing.
+    fn has_data(&self, hash: &hash::Hash) -> bool {
+        hash.bytes.is_empty() || self.hash_index.hash_exists(hash)
+    }
+
+    fn handle_msg<IT, F>(&mut self, msg: Msg<IT>, reply: F) -> Result<(), MsgError>
+        where IT: DataSource,
+              F: FnOnce(Result<Reply<B>, MsgError>)
+    {
         macro_rules! reply_ok(($x:expr) => {{
             reply(Ok($x));
             Ok(())
@@ -192,6 +564,16 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                 reply_ok!(Reply::FlushOk)
             }
 
+            Msg::Checkpoint => {
+                try!(self.checkpoint());
+                reply_ok!(Reply::FlushOk)
+            }
+
+            Msg::Abort => {
+                try!(self.abort());
+                reply_ok!(Reply::FlushOk)
+            }
+
             Msg::ListDir(parent) => {
                 match self.index.list_dir(parent) {
                     Ok(entries) => {
@@ -203,6 +585,7 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                                     persistent_ref: persistent_ref.clone(),
                                     hash_index: self.hash_index.clone(),
                                     blob_store: self.blob_store.clone(),
+                                    hasher: self.hasher.clone(),
                                 }
                             });
 
@@ -214,25 +597,57 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                 }
             }
 
-            Msg::Insert(org_entry, chunk_it_opt) => {
-                let entry = match try!(self.index
-                    .lookup(org_entry.parent_id, org_entry.name.clone())) {
+            Msg::Insert(org_entry, chunk_it_opt, progress, profile, policy) => {
+                let existing = try!(self.index.lookup(org_entry.parent_id, org_entry.name.clone()));
+                if let Some(ref entry) = existing {
+                    if try!(self.index.is_checkpointed(entry.id.unwrap(), org_entry.modified)) {
+                        // Short-circuit: Stored before the snapshot was interrupted.
+                        progress::report(&progress, Progress::FileDone);
+                        return reply_ok!(Reply::Id(entry.id.unwrap()));
+                    }
+                }
+
+                let entry = match existing {
                     Some(ref entry) if org_entry.accessed == entry.accessed &&
                                        org_entry.modified == entry.modified &&
-                                       org_entry.created == entry.created => {
+                                       org_entry.created == entry.created &&
+                                       org_entry.link_target == entry.link_target &&
+                                       org_entry.hardlink_of == entry.hardlink_of &&
+                                       org_entry.xattrs == entry.xattrs => {
                         if chunk_it_opt.is_some() && entry.data_hash.is_some() {
                             let hash = hash::Hash { bytes: entry.data_hash.clone().unwrap() };
-                            if self.hash_index.hash_exists(&hash) {
+                            if self.has_data(&hash) {
                                 // Short-circuit: We have the data.
+                                progress::report(&progress, Progress::FileDone);
                                 return reply_ok!(Reply::Id(entry.id.unwrap()));
                             }
                         } else if chunk_it_opt.is_none() && entry.data_hash.is_none() {
                             // Short-circuit: No data needed.
+                            progress::report(&progress, Progress::FileDone);
                             return reply_ok!(Reply::Id(entry.id.unwrap()));
                         }
                         // Our stored entry is incomplete.
                         Entry { id: entry.id, ..org_entry }
                     }
+                    Some(ref entry) if self.incremental && chunk_it_opt.is_some() &&
+                                       entry.data_hash.is_some() &&
+                                       org_entry.modified.is_some() &&
+                                       org_entry.data_length.is_some() &&
+                                       org_entry.modified == entry.modified &&
+                                       org_entry.data_length == entry.data_length &&
+                                       org_entry.link_target == entry.link_target &&
+                                       org_entry.hardlink_of == entry.hardlink_of => {
+                        let hash = hash::Hash { bytes: entry.data_hash.clone().unwrap() };
+                        if self.has_data(&hash) {
+                            // Short-circuit: The data is unchanged; only update the metadata.
+                            let entry = Entry { id: entry.id, ..org_entry };
+                            let entry = try!(self.index.insert(entry));
+                            reply(Ok(Reply::Id(entry.id.unwrap())));
+                            progress::report(&progress, Progress::FileDone);
+                            return self.stored(&entry);
+                        }
+                        Entry { id: entry.id, ..org_entry }
+                    }
                     Some(entry) => Entry { id: entry.id, ..org_entry },
                     None => org_entry,
                 };
@@ -245,52 +660,116 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                 reply(Ok(Reply::Id(entry.id.unwrap())));
 
 
-                // Setup hash tree structure
-                let mut tree = self.hash_tree_writer();
+                // Setup hash tree structure. Readers skip chunks by their length, which must be
+                // recorded unless all chunks have the default length.
+                let policy = policy.or(self.chunk_policy.clone());
+                let mut tree = self.hash_tree_writer_with_progress(progress.clone(),
+                                                                   true,
+                                                                   !profile.is_default(),
+                                                                   policy);
 
                 // Check if we have an data source:
+                let is_file = chunk_it_opt.is_some();
                 let it_opt = chunk_it_opt.and_then(|open| open.call(()));
                 if it_opt.is_none() {
-                    // No data is associated with this entry.
+                    // A file whose data could not be opened is stored as empty, so that it is not
+                    // mistaken for a directory. Other entries have no data.
+                    let hash_opt = if is_file { Some(empty_data_hash()) } else { None };
                     try!(self.index.update_data_hash(
                         entry.id.unwrap(),
                         entry.modified,
-                        None,
+                        hash_opt,
                         None
                     ));
+                    self.set_changed(entry.id.unwrap(), false);
+                    progress::report(&progress, Progress::FileDone);
                     // Bail out before storing data that does not exist:
-                    return Ok(());
+                    return self.stored(&entry);
                 }
 
                 // Read and insert all file chunks:
                 // (see HashStoreBackend::insert_chunk above)
-                let max_chunk_len = 128 * 1024;
-                let mut chunk = vec![0; max_chunk_len];
+                let max_chunk_len = profile.max_len;
+                let gear = gear_table();
+                let mut buf = vec![0; max_chunk_len];
+                let mut buf_len = 0;
+                let mut eof = false;
                 let mut reader = it_opt.unwrap();
                 let mut file_len = 0u64;
+                let mut hash_queue = self.hash_pool.as_ref().map(|pool| pool.queue());
                 loop {
-                    let mut chunk_len = 0;
-                    while chunk_len < max_chunk_len {
-                        chunk_len += match reader.read(&mut chunk[chunk_len..]) {
+                    while !eof && buf_len < max_chunk_len {
+                        buf_len += match reader.read(&mut buf[buf_len..]) {
                             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                            Ok(0) | Err(_) => break,
+                            // The data is cut short; leave the entry without it.
+                            Err(ref e) if util::is_cancelled(e) => {
+                                return Err(From::from(CancelledError));
+                            }
+                            Ok(0) | Err(_) => {
+                                eof = true;
+                                break;
+                            }
                             Ok(size) => size,
                         }
                     }
-                    if chunk_len == 0 {
+                    if buf_len == 0 {
                         break;
                     }
+                    let chunk_len = profile.cut(&buf[..buf_len], &gear);
                     file_len += chunk_len as u64;
-                    try!(tree.append(&chunk[..chunk_len]))
+                    progress::report(&progress, Progress::BytesRead(chunk_len as u64));
+                    match hash_queue {
+                        Some(ref mut queue) => {
+                            let chunk = if chunk_len == buf_len {
+                                let mut full = mem::replace(&mut buf, vec![0; max_chunk_len]);
+                                full.truncate(chunk_len);
+                                full
+                            } else {
+                                buf[..chunk_len].to_vec()
+                            };
+                            if let Some((data, hash)) = queue.push(chunk) {
+                                try!(tree.append_hashed(&data[..], try!(hash)));
+                            }
+                        }
+                        None => try!(tree.append(&buf[..chunk_len])),
+                    }
+                    // Keep the rest for the next chunk.
+                    let rest = buf[chunk_len..buf_len].to_vec();
+                    buf[..rest.len()].copy_from_slice(&rest[..]);
+                    buf_len = rest.len();
+                }
+                // Append the chunks still being hashed, in order.
+                if let Some(ref mut queue) = hash_queue {
+                    while let Some((data, hash)) = queue.pop() {
+                        try!(tree.append_hashed(&data[..], try!(hash)));
+                    }
                 }
 
-                // Warn the user if we did not read the expected size:
-                entry.data_length.map(|s| {
-                    file_size_warning(&entry.name, s, file_len);
-                });
-
-                // Get top tree hash:
-                let (hash, persistent_ref) = try!(tree.hash());
+                // Warn the user if we did not read the expected size, or record the size if it
+                // was not known up front:
+                match entry.data_length {
+                    Some(s) => file_size_warning(&entry.name, s, file_len),
+                    None => try!(self.index.update_data_length(entry.id.unwrap(), file_len)),
+                }
+                let modified = match (entry.modified, reader.modified()) {
+                    (Some(before), Some(after)) if before != after => {
+                        warn!("File was modified while reading it: {:?}", entry.name);
+                        true
+                    }
+                    _ => false,
+                };
+                self.set_changed(entry.id.unwrap(),
+                                 modified || entry.data_length.map_or(false, |s| s != file_len));
+
+                // Get top tree hash. For a file of at most one chunk this is the chunk's own hash
+                // and reference, so identical small files share the stored chunk. An empty file
+                // has no chunks at all, and stores nothing:
+                let (hash, persistent_ref_opt) = if file_len == 0 {
+                    (empty_data_hash(), None)
+                } else {
+                    let (hash, persistent_ref) = try!(tree.hash());
+                    (hash, Some(persistent_ref))
+                };
 
                 // Update hash in key index.
                 // It is OK that this has is not yet valid, as we check hashes at snapshot time.
@@ -298,10 +777,11 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                     entry.id.unwrap(),
                     entry.modified,
                     Some(hash),
-                    Some(persistent_ref)
+                    persistent_ref_opt
                 ));
+                progress::report(&progress, Progress::FileDone);
 
-                Ok(())
+                self.stored(&entry)
             }
         }
     }
@@ -30,6 +30,19 @@ table! {
 
         hash -> Nullable<Binary>,
         persistent_ref -> Nullable<Binary>,
+
+        link_target -> Nullable<Binary>,
+        hardlink_of -> Nullable<BigInt>,
+        xattrs -> Nullable<Binary>,
+        data_length -> Nullable<BigInt>,
+        holes -> Nullable<Binary>,
+    }
+}
+
+table! {
+    key_checkpoint {
+        id -> BigInt,
+        modified -> Nullable<BigInt>,
     }
 }
 
@@ -52,6 +65,12 @@ pub struct Key {
 
     pub hash: Option<Vec<u8>>,
     pub persistent_ref: Option<Vec<u8>>,
+
+    pub link_target: Option<Vec<u8>>,
+    pub hardlink_of: Option<i64>,
+    pub xattrs: Option<Vec<u8>>,
+    pub data_length: Option<i64>,
+    pub holes: Option<Vec<u8>>,
 }
 
 #[insertable_into(keys)]
@@ -69,4 +88,16 @@ pub struct NewKey<'a> {
 
     pub hash: Option<&'a [u8]>,
     pub persistent_ref: Option<&'a [u8]>,
+
+    pub link_target: Option<&'a [u8]>,
+    pub hardlink_of: Option<i64>,
+    pub xattrs: Option<&'a [u8]>,
+    pub data_length: Option<i64>,
+    pub holes: Option<&'a [u8]>,
+}
+
+#[insertable_into(key_checkpoint)]
+pub struct NewCheckpoint {
+    pub id: i64,
+    pub modified: Option<i64>,
 }
@@ -52,6 +52,8 @@ impl io::Read for EntryStub {
     }
 }
 
+impl DataSource for EntryStub {}
+
 #[derive(Clone, Debug)]
 struct FileSystem {
     file: EntryStub,
@@ -89,6 +91,10 @@ fn rng_filesystem(size: usize) -> FileSystem {
                     name: random_ascii_bytes(),
                     data_hash: None,
                     data_length: None,
+                    link_target: None,
+                    hardlink_of: None,
+                    xattrs: None,
+                    holes: None,
 
                     created: thread_rng().gen(),
                     modified: thread_rng().gen(),
@@ -117,6 +123,10 @@ fn rng_filesystem(size: usize) -> FileSystem {
             name: b"root".to_vec(),
             data_hash: None,
             data_length: None,
+            link_target: None,
+            hardlink_of: None,
+            xattrs: None,
+            holes: None,
             created: thread_rng().gen(),
             modified: thread_rng().gen(),
             accessed: thread_rng().gen(),
@@ -139,7 +149,10 @@ fn insert_and_update_fs<B: StoreBackend>(fs: &mut FileSystem, ks_p: &StoreProces
                                     Some(Box::new(move |()| Some(local_file)))
                                 } else {
                                     None
-                                }))
+                                },
+                                None,
+                                ChunkProfile::default(),
+                                None))
         .unwrap() {
         Reply::Id(id) => id,
         _ => panic!("unexpected reply from key store"),
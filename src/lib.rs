@@ -28,11 +28,19 @@ extern crate time;
 // Rust crates.
 extern crate byteorder;
 extern crate capnp;
+extern crate filetime;
+extern crate flate2;
+#[cfg(feature = "mount")]
+extern crate fuse;
+extern crate hyper;
+extern crate libc;
 extern crate sodiumoxide;
 extern crate libsodium_sys;
 extern crate rustc_serialize;
 extern crate scoped_pool;
+extern crate snap;
 extern crate void;
+extern crate zstd;
 
 // Error definition macros.
 #[macro_use]
@@ -55,6 +63,8 @@ mod gc;
 mod hash;
 pub mod hat;
 mod key;
+mod metrics;
+mod progress;
 mod snapshot;
 mod tags;
 mod util;
@@ -62,6 +72,28 @@ mod util;
 // Re-export the main type
 pub use hat::Hat;
 
+// Re-export the options for snapshotting, restoring and collecting garbage
+pub use hat::{GcOptions, RestoreOptions, SnapshotOptions};
+
+// Re-export the types needed to configure blob packing and encryption
+pub use blob::{BlobStatus, ChunkPolicy, Cipher, CompressionLevel, Packing, StoreOptions};
+
+// Re-export the events reported to progress listeners
+pub use progress::Progress;
+
+// Re-export the options for opening the local indexes and syncing stored data
+pub use util::{Durability, IndexOptions};
+
+// Re-export the token for cancelling long operations
+pub use util::CancelToken;
+
+// Re-export the errors, so that callers can tell failures apart
+pub use errors::{BlobNotFoundError, CancelledError, DieselError, HatError, IntegrityError,
+                 QuotaError, RetryError};
+
+// Re-export the counters for observing backend traffic
+pub use metrics::{MemoryMetrics, Metrics, MetricsCounts, NoMetrics};
+
 // The capnp module generated by build.rs and used internally
 #[allow(dead_code)]
 mod root_capnp {
@@ -44,6 +44,21 @@ fn license() {
     println!(include_str!("../LICENSE-CLAP"));
 }
 
+#[cfg(feature = "mount")]
+fn mount(name: String, id: i64, path: PathBuf) {
+    let backend = Arc::new(backend::FileBackend::new(blob_dir()));
+    let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
+        .unwrap();
+
+    hat.mount(name, id, path).unwrap();
+}
+
+#[cfg(not(feature = "mount"))]
+fn mount(_name: String, _id: i64, _path: PathBuf) {
+    println!("This hat was built without FUSE support; rebuild with --features mount");
+    std::process::exit(1);
+}
+
 
 fn main() {
     env_logger::init().unwrap();
@@ -61,16 +76,45 @@ fn main() {
         .arg_from_usage("--license 'Display the license'")
         .subcommand(SubCommand::with_name("snapshot")
             .about("Create a snapshot")
-            .args_from_usage(arg_template))
+            .args_from_usage(arg_template)
+            .arg_from_usage("-e --exclude [PATTERN]... 'Skip entries matching PATTERN (e.g. \
+                             **/target/** or *.tmp)'")
+            .arg_from_usage("-i --incremental 'Do not re-read files whose modification time and \
+                             size are unchanged'")
+            .arg_from_usage("--max-open-files [N] 'Keep at most N files open at once while \
+                             reading them'"))
         .subcommand(SubCommand::with_name("checkout")
             .about("Checkout a snapshot")
             .args_from_usage(arg_template))
+        .subcommand(SubCommand::with_name("restore")
+            .about("Restore a committed snapshot")
+            .args_from_usage("<NAME> 'Name of the snapshot family'
+                              <ID> 'The snapshot id to restore'
+                              <PATH> 'The path to restore into'")
+            .arg_from_usage("-k --keep-going 'Skip entries that cannot be restored, and list \
+                             them at the end'"))
+        .subcommand(SubCommand::with_name("mount")
+            .about("Mount a committed snapshot as a read-only filesystem")
+            .args_from_usage("<NAME> 'Name of the snapshot family'
+                              <ID> 'The snapshot id to mount'
+                              <PATH> 'Where to mount the snapshot'"))
+        .subcommand(SubCommand::with_name("diff")
+            .about("List differences between two committed snapshots")
+            .args_from_usage("<NAME> 'Name of the snapshot family'
+                              <FROM> 'The older snapshot id'
+                              <TO> 'The newer snapshot id'"))
         .subcommand(SubCommand::with_name("commit")
             .about("Commit a snapshot")
-            .arg_from_usage("<NAME> 'Name of the snapshot'"))
+            .arg_from_usage("<NAME> 'Name of the snapshot'")
+            .arg_from_usage("-l --label [LABEL] 'Label the snapshot (unique within the family)'")
+            .arg_from_usage("--skip-unchanged 'Do not commit a snapshot if nothing changed since \
+                             the previous one'"))
         .subcommand(SubCommand::with_name("meta-commit")
             .about("Commit snapshot metadata (required for recover command"))
-        .subcommand(SubCommand::with_name("recover").about("Recover list of commit'ed snapshots"))
+        .subcommand(SubCommand::with_name("recover")
+            .about("Recover list of commit'ed snapshots")
+            .args_from_usage("-q --quarantine 'Leave out snapshots referencing missing data'"))
+        .subcommand(SubCommand::with_name("list").about("List known snapshots"))
         .subcommand(SubCommand::with_name("delete")
             .about("Delete a snapshot")
             .args_from_usage("<NAME> 'Name of the snapshot family'
@@ -79,6 +123,18 @@ fn main() {
         .subcommand(SubCommand::with_name("gc")
             .about("Garbage collect: identify and remove unused data blocks.")
             .args_from_usage("-p --pretend 'Do not modify any data'"))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Verify that stored data can be read back and matches its hashes")
+            .args_from_usage("[NAME] 'Name of the snapshot family to verify'
+                              [ID] 'The snapshot id to verify'
+                              -j --jobs [JOBS] 'Number of chunks to check concurrently \
+                              (default: 1)'"))
+        .subcommand(SubCommand::with_name("compact")
+            .about("Move live data out of mostly unused blobs and delete those (run after gc)")
+            .args_from_usage("[LIVE_BYTES] 'Compact blobs with less live data than this \
+                              (default: half the blob size)'"))
+        .subcommand(SubCommand::with_name("rotate-keys")
+            .about("Re-encrypt all stored data with fresh keys and delete the old blobs"))
         .subcommand(SubCommand::with_name("resume").about("Resume previous failed command."))
         .get_matches();
 
@@ -101,16 +157,42 @@ fn main() {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let path = cmd.value_of("PATH").unwrap();
 
+            let max_open_files = cmd.value_of("max-open-files").map(|max| {
+                match max.parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        println!("--max-open-files must be a positive number, not {:?}", max);
+                        std::process::exit(1);
+                    }
+                }
+            });
+
             let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
+            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
                 .unwrap();
+            if let Some(max) = max_open_files {
+                hat.set_max_open_files(max);
+            }
 
-            let family = hat.open_family(name.clone())
+            let family = if cmd.is_present("incremental") {
+                    hat.open_incremental_family(name.clone())
+                } else {
+                    hat.open_family(name.clone())
+                }
                 .expect(&format!("Could not open family '{}'", name));
 
-            family.snapshot_dir(PathBuf::from(path));
+            let exclude: Vec<String> = match cmd.values_of("exclude") {
+                Some(patterns) => patterns.map(|p| p.to_owned()).collect(),
+                None => vec![],
+            };
+
+            family.snapshot_dir(PathBuf::from(path), &exclude[..]);
             family.flush().unwrap();
 
+            for entry in family.changed_entries().unwrap() {
+                println!("Changed while reading: {}", String::from_utf8_lossy(&entry.name));
+            }
+
             println!("Waiting for final flush...");
         }
         ("checkout", Some(cmd)) => {
@@ -123,6 +205,50 @@ fn main() {
 
             hat.checkout_in_dir(name, PathBuf::from(path)).unwrap();
         }
+        ("restore", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let id = cmd.value_of("ID").unwrap().to_owned();
+            let path = cmd.value_of("PATH").unwrap();
+
+            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
+            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
+                .unwrap();
+
+            let id = id.parse::<i64>().unwrap();
+            if cmd.is_present("keep-going") {
+                let options = hat::RestoreOptions { skip_failed: true, ..Default::default() };
+                let report = hat.restore_with(name, id, PathBuf::from(path), options).unwrap();
+                for (path, e) in report.failed.iter() {
+                    println!("Could not restore {}: {}", path.display(), e);
+                }
+                if !report.is_complete() {
+                    std::process::exit(1);
+                }
+            } else {
+                hat.restore(name, id, PathBuf::from(path)).unwrap();
+            }
+        }
+        ("mount", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let id = cmd.value_of("ID").unwrap().parse::<i64>().unwrap();
+            let path = cmd.value_of("PATH").unwrap();
+
+            mount(name, id, PathBuf::from(path));
+        }
+        ("diff", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let from = cmd.value_of("FROM").unwrap().parse::<i64>().unwrap();
+            let to = cmd.value_of("TO").unwrap().parse::<i64>().unwrap();
+
+            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
+            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
+                .unwrap();
+
+            for d in hat.diff(name, from, to).unwrap() {
+                let d = d.unwrap();
+                println!("{:?} {}", d.kind, String::from_utf8_lossy(&d.path[..]));
+            }
+        }
         ("meta-commit", Some(_cmd)) => {
             let backend = Arc::new(backend::FileBackend::new(blob_dir()));
             let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
@@ -130,12 +256,30 @@ fn main() {
 
             hat.meta_commit().unwrap();
         }
-        ("recover", Some(_cmd)) => {
+        ("recover", Some(cmd)) => {
             let backend = Arc::new(backend::FileBackend::new(blob_dir()));
             let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
                 .unwrap();
 
-            hat.recover().unwrap();
+            let report = hat.recover_with_quarantine(cmd.is_present("quarantine")).unwrap();
+            println!("Recovered snapshots: {:?}", report.recovered);
+            for dangling in report.dangling.iter() {
+                println!("Snapshot {} #{} references {} missing blobs{}",
+                         dangling.family_name,
+                         dangling.snapshot_id,
+                         dangling.missing_blobs.len(),
+                         if dangling.unreadable {
+                             " and could not be read"
+                         } else {
+                             ""
+                         });
+            }
+            if report.quarantined > 0 {
+                println!("Quarantined snapshots: {:?}", report.quarantined);
+            }
+            if !report.is_complete() {
+                std::process::exit(1);
+            }
         }
         ("commit", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
@@ -143,8 +287,33 @@ fn main() {
             let backend = Arc::new(backend::FileBackend::new(blob_dir()));
             let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
                 .unwrap();
+            if cmd.is_present("skip-unchanged") {
+                hat.set_unchanged_commit(hat::hat::UnchangedCommit::Skip);
+            }
 
-            hat.commit_by_name(name, None).unwrap();
+            let family = hat.open_family(name).unwrap();
+            let committed = match cmd.value_of("label") {
+                None => hat.commit(&family, None).unwrap(),
+                Some(label) => hat.commit_with_label(&family, label).unwrap(),
+            };
+            if committed.unchanged {
+                println!("No changes since the previous snapshot");
+            }
+            println!("Committed snapshot #{}", committed.snapshot_id);
+        }
+        ("list", Some(_cmd)) => {
+            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
+            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
+                .unwrap();
+
+            for s in hat.list_snapshots() {
+                println!("{} #{} committed: {} time: {:?} label: {:?}",
+                         s.family_name,
+                         s.snapshot_id,
+                         s.committed,
+                         s.commit_time,
+                         s.label);
+            }
         }
         ("delete", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
@@ -165,6 +334,51 @@ fn main() {
             println!("Live data blobs after deletion: {:?}", live_blobs);
 
         }
+        ("verify", Some(cmd)) => {
+            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
+            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
+                .unwrap();
+
+            let jobs = cmd.value_of("jobs").map_or(1, |j| j.parse::<usize>().unwrap());
+            let report = match (cmd.value_of("NAME"), cmd.value_of("ID")) {
+                (Some(name), Some(id)) => {
+                    hat.verify_snapshot_parallel(name.to_owned(), id.parse::<i64>().unwrap(), jobs)
+                        .unwrap()
+                }
+                (None, None) => hat.verify_parallel(jobs).unwrap(),
+                _ => {
+                    println!("Both NAME and ID are needed to verify a single snapshot");
+                    std::process::exit(1);
+                }
+            };
+            println!("Checked chunks: {:?}", report.checked);
+            println!("Failed chunks: {:?}", report.failed);
+            for (blob_id, offset) in report.mismatches {
+                println!("Bad chunk in blob {:?} at offset {}", blob_id, offset);
+            }
+            if report.failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        ("compact", Some(cmd)) => {
+            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
+            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
+                .unwrap();
+
+            let live_bytes = cmd.value_of("LIVE_BYTES")
+                .map_or(MAX_BLOB_SIZE as u64 / 2, |b| b.parse::<u64>().unwrap());
+            let stats = hat.compact(live_bytes).unwrap();
+            println!("Compacted blobs: {:?}", stats.blobs_compacted);
+            println!("Moved chunks: {:?} ({} bytes)", stats.chunks_moved, stats.bytes_moved);
+        }
+        ("rotate-keys", Some(_cmd)) => {
+            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
+            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
+                .unwrap();
+
+            let rotated = hat.rotate_keys().unwrap();
+            println!("Re-encrypted chunks: {:?}", rotated);
+        }
         _ => {
             println!("No subcommand specified\n{}\nFor more information re-run with --help",
                      matches.usage());
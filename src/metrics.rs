@@ -0,0 +1,92 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Counters for observing the traffic to and from the backend.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Receives counts as data is stored, retrieved and deleted. All methods default to doing nothing.
+///
+/// The methods are called from the threads doing the work, so they should return quickly.
+pub trait Metrics: Send + Sync {
+    /// A blob of `bytes` bytes was stored in the backend.
+    fn bytes_stored(&self, _bytes: usize) {}
+
+    /// A blob of `bytes` bytes was retrieved from the backend.
+    fn bytes_retrieved(&self, _bytes: usize) {}
+
+    /// A chunk was already stored, so its existing copy is reused.
+    fn chunk_deduplicated(&self) {}
+
+    /// `count` blobs were deleted from the backend.
+    fn blobs_deleted(&self, _count: usize) {}
+}
+
+/// Ignores all counts.
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// The totals collected by `MemoryMetrics`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MetricsCounts {
+    pub bytes_stored: usize,
+    pub bytes_retrieved: usize,
+    pub chunks_deduplicated: usize,
+    pub blobs_deleted: usize,
+}
+
+/// Adds up all counts in memory.
+#[derive(Default)]
+pub struct MemoryMetrics {
+    bytes_stored: AtomicUsize,
+    bytes_retrieved: AtomicUsize,
+    chunks_deduplicated: AtomicUsize,
+    blobs_deleted: AtomicUsize,
+}
+
+impl MemoryMetrics {
+    pub fn new() -> MemoryMetrics {
+        Default::default()
+    }
+
+    /// The totals so far.
+    pub fn counts(&self) -> MetricsCounts {
+        MetricsCounts {
+            bytes_stored: self.bytes_stored.load(Ordering::SeqCst),
+            bytes_retrieved: self.bytes_retrieved.load(Ordering::SeqCst),
+            chunks_deduplicated: self.chunks_deduplicated.load(Ordering::SeqCst),
+            blobs_deleted: self.blobs_deleted.load(Ordering::SeqCst),
+        }
+    }
+}
+
+impl Metrics for MemoryMetrics {
+    fn bytes_stored(&self, bytes: usize) {
+        self.bytes_stored.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn bytes_retrieved(&self, bytes: usize) {
+        self.bytes_retrieved.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn chunk_deduplicated(&self) {
+        self.chunks_deduplicated.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn blobs_deleted(&self, count: usize) {
+        self.blobs_deleted.fetch_add(count, Ordering::SeqCst);
+    }
+}
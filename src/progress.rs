@@ -0,0 +1,47 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Progress events reported by long-running operations.
+
+use std::sync::mpsc;
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Progress {
+    /// A snapshot is about to store `entries` files, directories and links, holding `bytes`
+    /// bytes of file data. Reported first, when the snapshot was asked to scan ahead.
+    Total { entries: u64, bytes: u64 },
+    /// File data has been read during a snapshot.
+    BytesRead(u64),
+    /// A file or directory entry has been snapshotted.
+    FileDone,
+    /// A chunk was already known, so its existing copy is reused.
+    ChunkDeduplicated,
+    /// A new chunk has been handed to the blob store.
+    ChunkStored,
+    /// GC deleted an unused hash.
+    HashDeleted,
+    /// GC deleted a batch of unreferenced blobs.
+    BlobsDeleted(usize),
+}
+
+/// Progress is reported over a channel, so a slow consumer never blocks the reporting thread.
+pub type ProgressSender = mpsc::Sender<Progress>;
+
+/// Report `event` if someone is listening. A receiver that went away is not an error.
+pub fn report(progress: &Option<ProgressSender>, event: Progress) {
+    if let Some(ref sender) = *progress {
+        let _ = sender.send(event);
+    }
+}
@@ -19,9 +19,11 @@ use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
 
 use blob;
+use capnp;
 use errors::DieselError;
 use hash;
 use tags;
+use time;
 use util;
 
 mod schema;
@@ -41,6 +43,28 @@ pub enum WorkStatus {
     DeleteInProgress,
     DeleteComplete,
     RecoverInProgress,
+    /// Written by `Hat::prepare_commit`, but not finalized. Resuming rolls it back.
+    CommitPrepared,
+}
+
+/// Identifies a snapshot within its family.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Selector {
+    Id(i64),
+    /// The label (message) recorded with the snapshot when it was committed.
+    Label(String),
+    /// When the snapshot was committed, in nanoseconds since the epoch.
+    CommitTime(i64),
+}
+
+impl Selector {
+    pub fn matches(&self, status: &Status) -> bool {
+        match *self {
+            Selector::Id(id) => status.info.snapshot_id == id,
+            Selector::Label(ref label) => status.msg.as_ref() == Some(label),
+            Selector::CommitTime(time) => status.commit_time == Some(time),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -50,6 +74,8 @@ pub struct Status {
     pub hash: Option<hash::Hash>,
     pub msg: Option<String>,
     pub tree_ref: Option<Vec<u8>>,
+    /// When the snapshot's tree was committed, in nanoseconds since the epoch.
+    pub commit_time: Option<i64>,
     pub status: WorkStatus,
 }
 
@@ -66,6 +92,7 @@ fn tag_to_work_status(tag: tags::Tag) -> WorkStatus {
         tags::Tag::ReadyDelete |
         tags::Tag::DeleteComplete => WorkStatus::DeleteComplete,
         tags::Tag::RecoverInProgress => WorkStatus::RecoverInProgress,
+        tags::Tag::Prepared => WorkStatus::CommitPrepared,
     }
 }
 
@@ -76,12 +103,19 @@ fn work_status_to_tag(status: WorkStatus) -> tags::Tag {
         WorkStatus::DeleteInProgress => tags::Tag::WillDelete,
         WorkStatus::DeleteComplete => tags::Tag::DeleteComplete,
         WorkStatus::RecoverInProgress => tags::Tag::RecoverInProgress,
+        WorkStatus::CommitPrepared => tags::Tag::Prepared,
     }
 }
 
 impl SnapshotIndex {
     pub fn new(path: &str) -> Result<SnapshotIndex, DieselError> {
-        let conn = try!(SqliteConnection::establish(path));
+        SnapshotIndex::with_options(path, &util::IndexOptions::default())
+    }
+
+    pub fn with_options(path: &str,
+                        options: &util::IndexOptions)
+                        -> Result<SnapshotIndex, DieselError> {
+        let conn = try!(util::sqlite::establish(path, options));
 
         let si = SnapshotIndex { conn: conn };
 
@@ -168,7 +202,7 @@ impl SnapshotIndex {
         let row_opt = snapshots.inner_join(family)
             .filter(name.eq(family_name_))
             .filter(snapshot_id.eq(snapshot_id_))
-            .select((id, tag, family_id, snapshot_id, msg, hash, tree_ref))
+            .select((id, tag, family_id, snapshot_id, msg, hash, tree_ref, commit_time))
             .first::<self::schema::Snapshot>(&self.conn)
             .optional()
             .expect("Error reading snapshot info");
@@ -185,6 +219,12 @@ impl SnapshotIndex {
     }
 
     pub fn reserve(&mut self, family_: String) -> Info {
+        self.reserve_with_label(family_, None)
+    }
+
+    /// Like `reserve()`, but labels the snapshot (see `Selector::Label`). The label is recorded
+    /// right away, so that it is kept when an interrupted commit is resumed.
+    pub fn reserve_with_label(&mut self, family_: String, label: Option<&str>) -> Info {
         use self::schema::snapshots::dsl::*;
 
         let family_id_ = self.get_or_create_family_id(&family_);
@@ -194,9 +234,10 @@ impl SnapshotIndex {
             family_id: family_id_,
             snapshot_id: snapshot_id_,
             tag: tags::Tag::Reserved as i32,
-            msg: None,
+            msg: label,
             hash: None,
             tree_ref: None,
+            commit_time: None,
         };
 
         diesel::insert(&new)
@@ -213,24 +254,25 @@ impl SnapshotIndex {
         }
     }
 
-    fn update_internal(&mut self,
-                       snapshot_: &Info,
-                       msg_: &str,
-                       hash_: &hash::Hash,
-                       tree_ref_: &blob::ChunkRef) {
+    /// Update existing snapshot. Its label, if any, was given when reserving it.
+    pub fn update(&mut self,
+                  snapshot_: &Info,
+                  hash_: &hash::Hash,
+                  tree_ref_: &blob::ChunkRef)
+                  -> Result<(), capnp::Error> {
         use self::schema::snapshots::dsl::*;
 
+        let now = time::get_time();
+        let now_nanos = now.sec * 1_000_000_000 + now.nsec as i64;
+
+        let tree_bytes = try!(tree_ref_.as_bytes());
         diesel::update(snapshots.find(snapshot_.unique_id))
-            .set((msg.eq(Some(msg_)),
-                  hash.eq(Some(&hash_.bytes)),
-                  tree_ref.eq(Some(tree_ref_.as_bytes()))))
+            .set((hash.eq(Some(&hash_.bytes)),
+                  tree_ref.eq(Some(tree_bytes)),
+                  commit_time.eq(Some(now_nanos))))
             .execute(&self.conn)
             .expect("Error updating snapshot");
-    }
-
-    /// Update existing snapshot.
-    pub fn update(&mut self, snapshot: &Info, hash: &hash::Hash, tree_ref: &blob::ChunkRef) {
-        self.update_internal(snapshot, "anonymous", hash, tree_ref);
+        Ok(())
     }
 
     fn set_tag(&mut self, snapshot_: &Info, tag_: tags::Tag) {
@@ -242,6 +284,11 @@ impl SnapshotIndex {
             .expect("Error updating snapshot");
     }
 
+    /// The snapshot is only prepared; it is rolled back unless it reaches `ready_commit`.
+    pub fn prepare(&mut self, snapshot: &Info) {
+        self.set_tag(snapshot, tags::Tag::Prepared)
+    }
+
     /// ReadyCommit.
     pub fn ready_commit(&mut self, snapshot: &Info) {
         self.set_tag(snapshot, tags::Tag::Complete)
@@ -319,6 +366,7 @@ impl SnapshotIndex {
                     msg: snap.msg,
                     hash: hash_,
                     tree_ref: snap.tree_ref,
+                    commit_time: snap.commit_time,
                     status: status,
                     info: Info {
                         unique_id: snap.id,
@@ -340,14 +388,45 @@ impl SnapshotIndex {
         self.list(None)
     }
 
+    /// The label of a snapshot, if it has one.
+    pub fn label(&mut self, snapshot: &Info) -> Option<String> {
+        use self::schema::snapshots::dsl::*;
+
+        snapshots.find(snapshot.unique_id)
+            .select(msg)
+            .first::<Option<String>>(&self.conn)
+            .optional()
+            .expect("Error reading snapshot label")
+            .and_then(|x| x)
+    }
+
+    /// Whether a snapshot of `family` is labeled `label`.
+    pub fn label_exists(&mut self, family: &str, label: &str) -> bool {
+        match self.get_family_id(family) {
+            None => false,
+            Some(family_id_) => {
+                use self::schema::snapshots::dsl::*;
+                snapshots.filter(family_id.eq(family_id_))
+                    .filter(msg.eq(Some(label)))
+                    .select(id)
+                    .first::<i64>(&self.conn)
+                    .optional()
+                    .expect("Error reading snapshot labels")
+                    .is_some()
+            }
+        }
+    }
+
     /// Recover snapshot information.
     pub fn recover(&mut self,
                    snapshot_id_: i64,
                    family: &str,
-                   msg_: &str,
+                   msg_: Option<&str>,
                    hash_: &[u8],
                    tree_ref_: &blob::ChunkRef,
-                   work_opt_: Option<WorkStatus>) {
+                   commit_time_: Option<i64>,
+                   work_opt_: Option<WorkStatus>)
+                   -> Result<(), capnp::Error> {
         let family_id_ = self.get_or_create_family_id(&family);
         let insert = match self.lookup(family, snapshot_id_) {
             Some((_info, h, r)) => {
@@ -361,13 +440,14 @@ impl SnapshotIndex {
         if insert {
             use self::schema::snapshots::dsl::*;
 
-            let tree_bytes = tree_ref_.as_bytes();
+            let tree_bytes = try!(tree_ref_.as_bytes());
             let new = self::schema::NewSnapshot {
                 family_id: family_id_,
                 snapshot_id: snapshot_id_,
-                msg: Some(msg_),
+                msg: msg_,
                 hash: Some(hash_),
                 tree_ref: Some(&tree_bytes[..]),
+                commit_time: commit_time_,
                 tag: work_opt_.map_or(tags::Tag::Done, work_status_to_tag) as i32,
             };
 
@@ -376,6 +456,7 @@ impl SnapshotIndex {
                 .execute(&self.conn)
                 .expect("Error inserting new snapshot");
         }
+        Ok(())
     }
 
     /// Flush the hash index to clear internal buffers and commit the underlying database.
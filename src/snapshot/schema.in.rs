@@ -33,12 +33,13 @@ table! {
         msg -> Nullable<VarChar>,
         hash -> Nullable<Binary>,
         tree_ref -> Nullable<Binary>,
+        commit_time -> Nullable<BigInt>,
     }
 }
 
 joinable!(snapshots -> family (family_id));
 select_column_workaround!(snapshots -> family (id, tag, family_id, snapshot_id, msg,
-                                               hash, tree_ref));
+                                               hash, tree_ref, commit_time));
 select_column_workaround!(family -> snapshots (id, name));
 
 
@@ -65,6 +66,7 @@ pub struct Snapshot {
     pub msg: Option<String>,
     pub hash: Option<Vec<u8>>,
     pub tree_ref: Option<Vec<u8>>,
+    pub commit_time: Option<i64>,
 }
 
 #[insertable_into(snapshots)]
@@ -75,4 +77,5 @@ pub struct NewSnapshot<'a> {
     pub msg: Option<&'a str>,
     pub hash: Option<&'a [u8]>,
     pub tree_ref: Option<&'a [u8]>,
+    pub commit_time: Option<i64>,
 }
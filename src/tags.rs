@@ -25,6 +25,8 @@ pub enum Tag {
     DeleteComplete = 6,
 
     RecoverInProgress = 7,
+
+    Prepared = 8,
 }
 
 pub fn tag_from_num(n: i64) -> Option<Tag> {
@@ -40,6 +42,8 @@ pub fn tag_from_num(n: i64) -> Option<Tag> {
 
         7 => Some(Tag::RecoverInProgress),
 
+        8 => Some(Tag::Prepared),
+
         _ => None,
     }
 }
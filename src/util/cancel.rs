@@ -0,0 +1,81 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ask long-running operations to stop early.
+
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use errors::CancelledError;
+
+
+/// Shared flag for cancelling operations, e.g. on shutdown. Clones refer to the same flag, so one
+/// clone can be handed to the operation and another kept to cancel it from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Ask the operations given this token to stop at their next safe point. This cannot be
+    /// undone.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fail with `CancelledError` once cancelled.
+    pub fn check(&self) -> Result<(), CancelledError> {
+        if self.is_cancelled() {
+            Err(CancelledError)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A reader that fails once its token is cancelled, so that whoever reads it stops early.
+pub struct CancelReader<R> {
+    inner: R,
+    cancel: CancelToken,
+}
+
+impl<R: Read> CancelReader<R> {
+    pub fn new(inner: R, cancel: CancelToken) -> CancelReader<R> {
+        CancelReader {
+            inner: inner,
+            cancel: cancel,
+        }
+    }
+}
+
+impl<R: Read> Read for CancelReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::Other, CancelledError));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Whether `e` was returned by a `CancelReader` whose token was cancelled.
+pub fn is_cancelled(e: &io::Error) -> bool {
+    e.get_ref().map_or(false, |inner| inner.is::<CancelledError>())
+}
@@ -26,4 +26,9 @@ impl Counter {
         self.previous += 1;
         self.previous
     }
+
+    /// The value last returned by `next()`, or the initial value.
+    pub fn current(&self) -> i64 {
+        self.previous
+    }
 }
@@ -0,0 +1,34 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// When data written to local disk is synced, i.e. forced out of the operating system's caches.
+///
+/// Weaker settings trade safety against a crash of the machine (not just of the process) for
+/// throughput. A process that exits, cleanly or not, loses nothing either way.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Durability {
+    /// Sync each blob as it is stored and each index transaction as it is committed.
+    PerBlob,
+    /// Sync only when a snapshot is committed. A machine crash may lose the data written since
+    /// the last commit, and may leave the local indexes to be rebuilt with `Hat::recover`.
+    PerCommit,
+    /// Never sync, and leave it to the operating system to write data out eventually.
+    None,
+}
+
+impl Default for Durability {
+    fn default() -> Durability {
+        Durability::PerBlob
+    }
+}
@@ -17,18 +17,26 @@ use std::io;
 use std::io::Read;
 use std::path::PathBuf;
 
+use util::Permit;
+
 pub enum FileIterator {
-    File(io::BufReader<fs::File>),
+    /// An open file, with the permit it was opened under (see `with_permit`).
+    File(io::BufReader<fs::File>, Option<Permit>),
     #[cfg(test)]
     Buf(Vec<u8>, usize),
-    #[cfg(all(test, feature = "benchmarks"))]
     Reader(Box<Read + Send>),
 }
 
 impl FileIterator {
     pub fn new(path: &PathBuf) -> io::Result<FileIterator> {
+        FileIterator::with_permit(path, None)
+    }
+
+    /// Like `new()`, but holds on to `permit` until the file is closed, so that a `Semaphore`
+    /// bounds the files open at once.
+    pub fn with_permit(path: &PathBuf, permit: Option<Permit>) -> io::Result<FileIterator> {
         match fs::File::open(path) {
-            Ok(f) => Ok(FileIterator::File(io::BufReader::new(f))),
+            Ok(f) => Ok(FileIterator::File(io::BufReader::new(f), permit)),
             Err(e) => Err(e),
         }
     }
@@ -37,7 +45,7 @@ impl FileIterator {
         FileIterator::Buf(contents, 0)
     }
 
-    #[cfg(all(test, feature = "benchmarks"))]
+    /// Read the contents from a stream, e.g. one without a backing file.
     pub fn from_reader<R>(r: Box<R>) -> FileIterator
         where R: Read + Send + 'static
     {
@@ -48,7 +56,7 @@ impl FileIterator {
 impl Read for FileIterator {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
-            &mut FileIterator::File(ref mut f) => f.read(buf),
+            &mut FileIterator::File(ref mut f, _) => f.read(buf),
             #[cfg(test)]
             &mut FileIterator::Buf(ref vec, ref mut pos) => {
                 use std::cmp;
@@ -61,7 +69,6 @@ impl Read for FileIterator {
                     Ok(next.len())
                 }
             }
-            #[cfg(all(test, feature = "benchmarks"))]
             &mut FileIterator::Reader(ref mut r) => r.read(buf),
         }
     }
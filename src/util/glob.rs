@@ -0,0 +1,102 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shell-style patterns for excluding paths from a snapshot.
+//!
+//! A pattern is matched against a path relative to the snapshot root, one `/`-separated
+//! component at a time. Within a component, `*` matches any run of bytes and `?` matches a single
+//! byte. A `**` component matches any number of components, including none. A pattern without a
+//! `/` matches the last component only, so `*.tmp` excludes such files at every depth.
+
+#[derive(Clone, Debug)]
+pub struct Glob {
+    components: Vec<Vec<u8>>,
+}
+
+fn match_component(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&b'*') => (0..name.len() + 1).any(|i| match_component(&pattern[1..], &name[i..])),
+        Some(&b'?') => !name.is_empty() && match_component(&pattern[1..], &name[1..]),
+        Some(c) => name.first() == Some(c) && match_component(&pattern[1..], &name[1..]),
+    }
+}
+
+fn match_components(pattern: &[Vec<u8>], path: &[&[u8]]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(p) if &p[..] == b"**" => {
+            (0..path.len() + 1).any(|i| match_components(&pattern[1..], &path[i..]))
+        }
+        Some(p) => {
+            !path.is_empty() && match_component(&p[..], path[0]) &&
+            match_components(&pattern[1..], &path[1..])
+        }
+    }
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        let mut components: Vec<Vec<u8>> = pattern.split('/')
+            .filter(|c| !c.is_empty())
+            .map(|c| c.bytes().collect())
+            .collect();
+        if components.len() == 1 {
+            components.insert(0, b"**".to_vec());
+        }
+        Glob { components: components }
+    }
+
+    /// Check whether the relative `path` (components separated by `/`) matches this pattern.
+    pub fn matches(&self, path: &[u8]) -> bool {
+        let path: Vec<&[u8]> = path.split(|&c| c == b'/').filter(|c| !c.is_empty()).collect();
+        match_components(&self.components[..], &path[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_component_matches_at_any_depth() {
+        let glob = Glob::new("*.tmp");
+        assert!(glob.matches(b"a.tmp"));
+        assert!(glob.matches(b"x/y/.tmp"));
+        assert!(!glob.matches(b"a.tmp/b"));
+        assert!(!glob.matches(b"a.tmpx"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_byte() {
+        let glob = Glob::new("file?");
+        assert!(glob.matches(b"file1"));
+        assert!(!glob.matches(b"file"));
+        assert!(!glob.matches(b"file12"));
+    }
+
+    #[test]
+    fn double_star_spans_components() {
+        let glob = Glob::new("**/target/**");
+        assert!(glob.matches(b"target"));
+        assert!(glob.matches(b"a/b/target"));
+        assert!(glob.matches(b"a/target/debug/x"));
+        assert!(!glob.matches(b"a/targets"));
+
+        let glob = Glob::new("src/*.rs");
+        assert!(glob.matches(b"src/lib.rs"));
+        assert!(!glob.matches(b"src/a/lib.rs"));
+        assert!(!glob.matches(b"lib.rs"));
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+
+/// A map holding at most `capacity` entries, evicting the least recently used one to make room.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (u64, V)>,
+    // The keys by the tick of their last use.
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity: capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Change the capacity, evicting entries as needed. A capacity of 0 disables the cache.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn get(&mut self, k: &K) -> Option<&V> {
+        self.tick += 1;
+        match self.entries.get_mut(k) {
+            None => None,
+            Some(&mut (ref mut used, ref v)) => {
+                self.order.remove(used);
+                *used = self.tick;
+                self.order.insert(self.tick, k.clone());
+                Some(v)
+            }
+        }
+    }
+
+    pub fn insert(&mut self, k: K, v: V) {
+        self.tick += 1;
+        if let Some((used, _)) = self.entries.insert(k.clone(), (self.tick, v)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(self.tick, k);
+        self.evict();
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        self.entries.remove(k).map(|(used, v)| {
+            self.order.remove(&used);
+            v
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest = *self.order.keys().next().expect("order must match entries");
+            let k = self.order.remove(&oldest).expect("oldest must exist");
+            self.entries.remove(&k);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, 'a');
+        cache.insert(2, 'b');
+        assert_eq!(cache.get(&1), Some(&'a'));
+
+        cache.insert(3, 'c');
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&'a'));
+        assert_eq!(cache.get(&3), Some(&'c'));
+
+        cache.insert(1, 'd');
+        cache.insert(4, 'e');
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.get(&1), Some(&'d'));
+    }
+
+    #[test]
+    fn zero_capacity_holds_nothing() {
+        let mut cache = LruCache::new(1);
+        cache.insert(1, 'a');
+        cache.set_capacity(0);
+        assert_eq!(cache.get(&1), None);
+        cache.insert(2, 'b');
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.remove(&2), None);
+    }
+}
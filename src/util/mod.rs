@@ -12,23 +12,38 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod cancel;
 mod counter;
+mod durability;
 mod file_iterator;
 mod fnbox;
+mod glob;
 mod infowriter;
 mod listdir;
+mod lru_cache;
 mod sync_pool;
 mod ordered_collection;
 mod periodic_timer;
 mod process;
+mod semaphore;
 mod unique_priority_queue;
+pub mod sparse;
+pub mod sqlite;
+pub mod tar;
+pub mod xattr;
 
+pub use self::cancel::{CancelReader, CancelToken, is_cancelled};
 pub use self::counter::Counter;
+pub use self::durability::Durability;
 pub use self::file_iterator::FileIterator;
 pub use self::fnbox::FnBox;
+pub use self::glob::Glob;
 pub use self::infowriter::InfoWriter;
 pub use self::listdir::{HasPath, PathHandler};
+pub use self::lru_cache::LruCache;
 pub use self::sync_pool::{SyncPool, SyncPoolGuard};
 pub use self::periodic_timer::PeriodicTimer;
 pub use self::process::{MsgHandler, Process};
+pub use self::semaphore::{Permit, Semaphore};
+pub use self::sqlite::IndexOptions;
 pub use self::unique_priority_queue::UniquePriorityQueue;
@@ -82,12 +82,20 @@ impl<Msg, Reply, E> Process<Msg, Reply, E>
           E: 'static + Send + fmt::Debug
 {
     /// Create and start a new process using `handler`.
-    pub fn new<H>(mut handler: H) -> Process<Msg, Reply, E>
+    pub fn new<H>(handler: H) -> Process<Msg, Reply, E>
+        where H: MsgHandler<Msg, Reply, Err = E>
+    {
+        Process::with_handle(handler).0
+    }
+
+    /// Like `new()`, but also return the handle of the thread. The thread drops `handler` and
+    /// finishes once the process and all its clones have been dropped.
+    pub fn with_handle<H>(mut handler: H) -> (Process<Msg, Reply, E>, thread::JoinHandle<()>)
         where H: MsgHandler<Msg, Reply, Err = E>
     {
         let (sender, receiver) = mpsc::sync_channel::<(Msg, mpsc::Sender<Result<Reply, E>>)>(10);
 
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             while let Ok((msg, rep)) = receiver.recv() {
                 let mut did_reply = false;
                 let ret = handler.handle(msg, |r| {
@@ -103,7 +111,7 @@ impl<Msg, Reply, E> Process<Msg, Reply, E>
             }
         });
 
-        Process { sender: sender }
+        (Process { sender: sender }, handle)
     }
 
     /// Synchronous send.
@@ -0,0 +1,103 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bound the resources in use at once across threads, e.g. open files.
+
+use std::sync::{Arc, Condvar, Mutex};
+
+
+/// A counting semaphore. Clones share the same permits.
+#[derive(Clone)]
+pub struct Semaphore {
+    // The number of permits not taken.
+    state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+/// A permit taken from a `Semaphore`. It is returned to the semaphore when dropped.
+pub struct Permit {
+    state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        assert!(permits > 0);
+        Semaphore { state: Arc::new((Mutex::new(permits), Condvar::new())) }
+    }
+
+    /// Take a permit, waiting for one to be returned if all of them are taken.
+    pub fn acquire(&self) -> Permit {
+        let &(ref available, ref returned) = &*self.state;
+        let mut available = available.lock().unwrap();
+        while *available == 0 {
+            available = returned.wait(available).unwrap();
+        }
+        *available -= 1;
+        Permit { state: self.state.clone() }
+    }
+
+    /// The number of permits not taken.
+    pub fn available(&self) -> usize {
+        *self.state.0.lock().unwrap()
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let &(ref available, ref returned) = &*self.state;
+        *available.lock().unwrap() += 1;
+        returned.notify_one();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn permits_are_returned() {
+        let sem = Semaphore::new(2);
+        let first = sem.acquire();
+        let second = sem.clone().acquire();
+        assert_eq!(sem.available(), 0);
+        drop(first);
+        assert_eq!(sem.available(), 1);
+        drop(second);
+        assert_eq!(sem.available(), 2);
+    }
+
+    #[test]
+    fn acquire_waits_for_permit() {
+        let sem = Semaphore::new(1);
+        let permit = sem.acquire();
+
+        let (sender, receiver) = mpsc::channel();
+        let local_sem = sem.clone();
+        let waiter = thread::spawn(move || {
+            let _permit = local_sem.acquire();
+            sender.send(()).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(receiver.try_recv().is_err());
+        drop(permit);
+        receiver.recv().unwrap();
+        waiter.join().unwrap();
+        assert_eq!(sem.available(), 1);
+    }
+}
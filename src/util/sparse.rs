@@ -0,0 +1,150 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Find the holes of sparse files, and write files with holes.
+
+use std::cmp;
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use libc::{self, c_int, off_t};
+    use std::cmp;
+    use std::fs;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    #[cfg(target_os = "linux")]
+    const SEEK_DATA: c_int = 3;
+    #[cfg(target_os = "linux")]
+    const SEEK_HOLE: c_int = 4;
+
+    #[cfg(target_os = "macos")]
+    const SEEK_HOLE: c_int = 3;
+    #[cfg(target_os = "macos")]
+    const SEEK_DATA: c_int = 4;
+
+    /// Seek to the next hole or data at or after `pos`. Returns `None` if there is none.
+    fn seek(file: &fs::File, pos: u64, whence: c_int) -> io::Result<Option<u64>> {
+        match unsafe { libc::lseek(file.as_raw_fd(), pos as off_t, whence) } {
+            n if n >= 0 => Ok(Some(n as u64)),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() == Some(libc::ENXIO) {
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    pub fn holes(file: &fs::File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        let mut holes = vec![];
+        let mut pos = 0;
+        while pos < len {
+            let hole = match try!(seek(file, pos, SEEK_HOLE)) {
+                Some(hole) if hole < len => hole,
+                // There is always an implicit hole at the end of the file.
+                _ => break,
+            };
+            let data = try!(seek(file, hole, SEEK_DATA)).map_or(len, |data| cmp::min(data, len));
+            holes.push((hole, data - hole));
+            pos = data;
+        }
+        Ok(holes)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use std::fs;
+    use std::io;
+
+    pub fn holes(_file: &fs::File, _len: u64) -> io::Result<Vec<(u64, u64)>> {
+        Ok(vec![])
+    }
+}
+
+/// Find the holes in the first `len` bytes of a file, as `(offset, length)` in ascending order.
+/// Returns `None` if there are none, or if the platform or filesystem cannot tell. This moves the
+/// file's offset.
+pub fn holes(file: &fs::File, len: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    match sys::holes(file, len) {
+        Ok(ref holes) if holes.is_empty() => Ok(None),
+        Ok(holes) => Ok(Some(holes)),
+        // The filesystem does not support finding holes.
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a file from its start, seeking over the given holes instead of writing their zeros.
+/// Data that falls in a hole but is not all zeros is written regardless, so the holes only have
+/// to be a hint.
+pub struct SparseWriter {
+    file: fs::File,
+    holes: Vec<(u64, u64)>,
+    next_hole: usize,
+    pos: u64,
+}
+
+impl SparseWriter {
+    pub fn new(file: fs::File, holes: Option<Vec<(u64, u64)>>) -> SparseWriter {
+        SparseWriter {
+            file: file,
+            holes: holes.unwrap_or_else(Vec::new),
+            next_hole: 0,
+            pos: 0,
+        }
+    }
+
+    /// Set the file length to what was written, which creates any hole at the end of the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        try!(self.file.flush());
+        self.file.set_len(self.pos)
+    }
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Skip the holes that are behind us.
+        while self.holes
+            .get(self.next_hole)
+            .map_or(false, |&(offset, length)| offset + length <= self.pos) {
+            self.next_hole += 1;
+        }
+
+        let (in_hole, len) = match self.holes.get(self.next_hole) {
+            Some(&(offset, length)) if offset <= self.pos => (true, offset + length - self.pos),
+            Some(&(offset, _)) => (false, offset - self.pos),
+            None => (false, buf.len() as u64),
+        };
+        let len = cmp::min(len, buf.len() as u64) as usize;
+
+        let written = if in_hole && buf[..len].iter().all(|&b| b == 0) {
+            try!(self.file.seek(SeekFrom::Current(len as i64)));
+            len
+        } else {
+            try!(self.file.write(&buf[..len]))
+        };
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
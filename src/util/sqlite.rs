@@ -0,0 +1,205 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Open the SQLite databases of the local indexes.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use sodiumoxide::crypto::auth::hmacsha512256;
+
+use crypto::authed;
+use errors::DieselError;
+use util::Durability;
+
+
+/// How to open the SQLite databases of the local indexes.
+#[derive(Clone, Debug, Default)]
+pub struct IndexOptions {
+    /// Journal to a write-ahead log, so that readers are not blocked while another connection
+    /// writes. This is recorded in the database file and ignored for in-memory databases.
+    pub wal: bool,
+    /// How long to wait for a lock held by another connection, in milliseconds, before failing
+    /// with "database is locked". By default this fails right away.
+    pub busy_timeout_ms: Option<u32>,
+    /// The master key of the repository (see `Hat::derive_master_key_from()`). The repository
+    /// seals its blobs with it (as with `Hat::set_master_key()`), and keeps the index databases
+    /// encrypted with a key derived from it while closed: they are decrypted when the repository
+    /// is opened and encrypted again by `Hat::close()`, so the plaintext is only on disk while
+    /// the repository is open (or after a crash).
+    pub master_key: Option<authed::desc::Key>,
+    /// When the databases are synced to disk. Unless this is `Durability::PerBlob`, SQLite does
+    /// not sync its transactions, and the databases are only synced by `sync_dir`.
+    pub durability: Durability,
+    /// Open the databases read-only: every write fails, and no write lock is ever taken, so
+    /// that another process can keep writing to them (best with `wal`, as then readers do not
+    /// hold up writers either). The databases must already exist and be up to date.
+    pub read_only: bool,
+}
+
+/// Suffix of the encrypted copy of an index database.
+pub const SEALED_SUFFIX: &'static str = ".sealed";
+
+// What the index databases are derived from the master key for; see `index_key()`.
+const INDEX_KEY_CONTEXT: &'static [u8] = b"hat index databases";
+
+// Every SQLite database file starts with this.
+const SQLITE_HEADER: &'static [u8] = b"SQLite format 3\0";
+
+// SQLite's temporary files live next to the database and go away when it is closed cleanly.
+const TRANSIENT_SUFFIXES: [&'static str; 3] = ["-wal", "-shm", "-journal"];
+
+/// Connect to the database at `path` (or `":memory:"`), configured by `options`.
+pub fn establish(path: &str, options: &IndexOptions) -> Result<SqliteConnection, DieselError> {
+    let conn = try!(SqliteConnection::establish(path));
+    if let Some(ms) = options.busy_timeout_ms {
+        try!(conn.batch_execute(&format!("PRAGMA busy_timeout = {};", ms)));
+    }
+    if options.wal && !options.read_only {
+        // Must be set outside of a transaction.
+        try!(conn.batch_execute("PRAGMA journal_mode = WAL;"));
+    }
+    if options.read_only {
+        try!(conn.batch_execute("PRAGMA query_only = ON;"));
+    }
+    if options.durability != Durability::PerBlob {
+        try!(conn.batch_execute("PRAGMA synchronous = OFF;"));
+    }
+    Ok(conn)
+}
+
+/// Sync the databases in `dir` (and their journals) to disk, e.g. after a commit when SQLite does
+/// not sync by itself.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        if !try!(entry.file_type()).is_file() {
+            continue;
+        }
+        match fs::File::open(entry.path()) {
+            Ok(file) => try!(file.sync_all()),
+            // Journals come and go as the databases are used.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// The key the index databases are sealed with, derived from the master key of the repository
+/// (see `IndexOptions::master_key`), so that the two keys are never the same.
+pub fn index_key(master_key: &authed::desc::Key) -> authed::desc::Key {
+    let key = hmacsha512256::Key::from_slice(&master_key.0[..]).unwrap();
+    let tag = hmacsha512256::authenticate(INDEX_KEY_CONTEXT, &key);
+    authed::desc::Key::from_slice(&tag.0[..]).unwrap()
+}
+
+/// Decrypt each sealed database in `dir` that has no plaintext next to it. A plaintext left
+/// behind by a crash is newer than its sealed copy, and is kept.
+pub fn unseal_dir(dir: &Path, key: &authed::desc::Key) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let sealed_path = try!(entry).path();
+        let plain_path = match sealed_path.to_str() {
+            Some(p) if p.ends_with(SEALED_SUFFIX) => {
+                Path::new(&p[..p.len() - SEALED_SUFFIX.len()]).to_path_buf()
+            }
+            _ => continue,
+        };
+        if plain_path.exists() {
+            continue;
+        }
+
+        let mut sealed = vec![];
+        try!(try!(fs::File::open(&sealed_path)).read_to_end(&mut sealed));
+        let plain = try!(open(&sealed[..], key).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("Could not decrypt index {}: wrong key or corrupt file",
+                                   sealed_path.display()))
+        }));
+        try!(write_atomically(&plain_path, &plain[..]));
+    }
+    Ok(())
+}
+
+/// Encrypt each database in `dir` to its sealed copy and remove the plaintext. The databases
+/// must be closed. Other files, which are told apart by the SQLite header, are left alone.
+pub fn seal_dir(dir: &Path, key: &authed::desc::Key) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        if !try!(entry.file_type()).is_file() {
+            continue;
+        }
+        let plain_path = entry.path();
+        let name = match plain_path.to_str() {
+            Some(p) => p.to_owned(),
+            None => continue,
+        };
+        if name.ends_with(SEALED_SUFFIX) ||
+           name.ends_with(".tmp") ||
+           TRANSIENT_SUFFIXES.iter().any(|s| name.ends_with(s)) {
+            continue;
+        }
+
+        let mut plain = vec![];
+        try!(try!(fs::File::open(&plain_path)).read_to_end(&mut plain));
+        if !plain.starts_with(SQLITE_HEADER) {
+            continue;
+        }
+        let sealed_path = format!("{}{}", name, SEALED_SUFFIX);
+        try!(write_atomically(Path::new(&sealed_path), &seal(&plain[..], key)[..]));
+        try!(fs::remove_file(&plain_path));
+    }
+    Ok(())
+}
+
+// A random nonce followed by the authenticated ciphertext.
+fn seal(plain: &[u8], key: &authed::desc::Key) -> Vec<u8> {
+    let nonce = authed::imp::gen_nonce();
+    let mut sealed = nonce.0.to_vec();
+    sealed.extend_from_slice(&authed::imp::seal(plain, &nonce, key)[..]);
+    sealed
+}
+
+fn open(sealed: &[u8], key: &authed::desc::Key) -> Option<Vec<u8>> {
+    if sealed.len() < authed::desc::NONCEBYTES + authed::desc::MACBYTES {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(authed::desc::NONCEBYTES);
+    let nonce = match authed::desc::Nonce::from_slice(nonce) {
+        Some(nonce) => nonce,
+        None => return None,
+    };
+    authed::imp::open(ciphertext, &nonce, key).ok()
+}
+
+// Write through a temporary file, so that a crash never leaves a partial file behind.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path.display());
+    {
+        let mut tmp = try!(fs::File::create(&tmp_path));
+        try!(tmp.write_all(data));
+        try!(tmp.sync_all());
+    }
+    fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,411 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read and write tar archives (ustar, with GNU long names) one entry at a time.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::str;
+
+const BLOCK: usize = 512;
+
+// Long names and pax headers are read into memory; larger ones are taken for a corrupt archive.
+const MAX_METADATA_LEN: u64 = 1024 * 1024;
+
+/// Metadata shared by all kinds of tar entries.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Meta {
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// Seconds since the epoch.
+    pub mtime: u64,
+}
+
+pub struct TarWriter<W> {
+    out: W,
+    // Bytes of the current file still to be written.
+    remaining: u64,
+    // Bytes of padding after the current file.
+    padding: usize,
+}
+
+/// The number of zeros that fill up the last block of `len` bytes of data.
+fn padding(len: u64) -> usize {
+    (BLOCK - (len % BLOCK as u64) as usize) % BLOCK
+}
+
+/// Write `value` as a NUL-terminated octal number filling `field`, or in base-256 if it is too
+/// large for that.
+fn set_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if digits >= 22 || value < 1 << (3 * digits) {
+        let s = format!("{:01$o}", value, digits);
+        field[..digits].copy_from_slice(s.as_bytes());
+        field[digits] = 0;
+    } else {
+        let mut v = value;
+        for b in field.iter_mut().rev() {
+            *b = v as u8;
+            v >>= 8;
+        }
+        field[0] = 0x80;
+    }
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> TarWriter<W> {
+        TarWriter {
+            out: out,
+            remaining: 0,
+            padding: 0,
+        }
+    }
+
+    fn header(&mut self,
+              path: &[u8],
+              kind: u8,
+              meta: &Meta,
+              size: u64,
+              link: &[u8])
+              -> io::Result<()> {
+        assert_eq!(self.remaining, 0);
+        if path.len() > 100 {
+            try!(self.long_name(b'L', path));
+        }
+        if link.len() > 100 {
+            try!(self.long_name(b'K', link));
+        }
+
+        let mut h = [0u8; BLOCK];
+        let name_len = cmp::min(path.len(), 100);
+        h[..name_len].copy_from_slice(&path[..name_len]);
+        set_number(&mut h[100..108], meta.mode as u64 & 0o7777);
+        set_number(&mut h[108..116], meta.uid);
+        set_number(&mut h[116..124], meta.gid);
+        set_number(&mut h[124..136], size);
+        set_number(&mut h[136..148], meta.mtime);
+        h[156] = kind;
+        let link_len = cmp::min(link.len(), 100);
+        h[157..157 + link_len].copy_from_slice(&link[..link_len]);
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+
+        // The checksum is computed with its own field set to spaces.
+        for b in h[148..156].iter_mut() {
+            *b = b' ';
+        }
+        let sum = h.iter().fold(0u64, |sum, &b| sum + b as u64);
+        set_number(&mut h[148..155], sum);
+        h[155] = b' ';
+
+        self.out.write_all(&h[..])
+    }
+
+    /// Record a name too long for the header in an entry of its own, preceding the header.
+    fn long_name(&mut self, kind: u8, name: &[u8]) -> io::Result<()> {
+        let mut data = name.to_vec();
+        data.push(0);
+        try!(self.header(b"././@LongLink", kind, &Meta::default(), data.len() as u64, b""));
+        try!(self.out.write_all(&data[..]));
+        self.out.write_all(&[0u8; BLOCK][..padding(data.len() as u64)])
+    }
+
+    pub fn append_dir(&mut self, path: &[u8], meta: &Meta) -> io::Result<()> {
+        let mut path = path.to_vec();
+        path.push(b'/');
+        self.header(&path[..], b'5', meta, 0, b"")
+    }
+
+    pub fn append_symlink(&mut self, path: &[u8], target: &[u8], meta: &Meta) -> io::Result<()> {
+        self.header(path, b'2', meta, 0, target)
+    }
+
+    /// Add a hardlink to `target`, the path of a file added before.
+    pub fn append_hardlink(&mut self, path: &[u8], target: &[u8], meta: &Meta) -> io::Result<()> {
+        self.header(path, b'1', meta, 0, target)
+    }
+
+    /// Start a regular file of `size` bytes. Exactly that many bytes must be written before the
+    /// next entry is added.
+    pub fn begin_file(&mut self, path: &[u8], meta: &Meta, size: u64) -> io::Result<()> {
+        try!(self.header(path, b'0', meta, size, b""));
+        self.remaining = size;
+        self.padding = padding(size);
+        if size == 0 {
+            try!(self.end_file());
+        }
+        Ok(())
+    }
+
+    /// Write the next part of the current file's data.
+    pub fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() as u64 > self.remaining {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "More data than the file's recorded size"));
+        }
+        try!(self.out.write_all(data));
+        self.remaining -= data.len() as u64;
+        if self.remaining == 0 {
+            try!(self.end_file());
+        }
+        Ok(())
+    }
+
+    fn end_file(&mut self) -> io::Result<()> {
+        let padding = self.padding;
+        self.padding = 0;
+        self.out.write_all(&[0u8; BLOCK][..padding])
+    }
+
+    /// The number of bytes of the current file still to be written.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// End the archive, and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.remaining > 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Less data than the file's recorded size"));
+        }
+        try!(self.out.write_all(&[0u8; 2 * BLOCK][..]));
+        try!(self.out.flush());
+        Ok(self.out)
+    }
+}
+
+
+/// The kinds of tar entries.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    /// A hardlink to the path in `Header::link`, which came earlier in the archive.
+    Hardlink,
+    /// Devices, fifos and other entries, by their type flag.
+    Other(u8),
+}
+
+#[derive(Clone, Debug)]
+pub struct Header {
+    /// The path, without a trailing slash for directories.
+    pub path: Vec<u8>,
+    pub kind: Kind,
+    pub meta: Meta,
+    /// The length of a file's data.
+    pub size: u64,
+    /// The target of a symlink or hardlink.
+    pub link: Vec<u8>,
+}
+
+/// Reads the entries of a tar archive. The data of the current file entry is read through
+/// `Read`; whatever is left of it is skipped when moving to the next entry.
+pub struct TarReader<R> {
+    input: R,
+    remaining: u64,
+    padding: usize,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The bytes of `field` up to the first NUL.
+fn field_bytes(field: &[u8]) -> &[u8] {
+    field.split(|&b| b == 0).next().unwrap_or(field)
+}
+
+/// Parse a number written as octal (with optional padding), or in base-256.
+fn get_number(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let v = field[1..].iter().fold(field[0] as u64 & 0x7f, |v, &b| v << 8 | b as u64);
+        return Ok(v);
+    }
+    let mut v = 0u64;
+    for &b in field_bytes(field) {
+        match b {
+            b'0'...b'7' => v = v * 8 + (b - b'0') as u64,
+            b' ' => (),
+            _ => return Err(invalid("Invalid number in tar header")),
+        }
+    }
+    Ok(v)
+}
+
+/// Find the value of `key` in the records of a pax extended header.
+fn pax_value<'a>(records: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    let mut rest = records;
+    while !rest.is_empty() {
+        // Each record is "<length> <key>=<value>\n", where length counts the whole record.
+        let space = match rest.iter().position(|&b| b == b' ') {
+            Some(space) => space,
+            None => return None,
+        };
+        let len = match str::from_utf8(&rest[..space]).ok().and_then(|l| l.parse().ok()) {
+            // At least the separating space and the trailing newline.
+            Some(len) if len >= space + 2 && len <= rest.len() => len,
+            _ => return None,
+        };
+        let record = &rest[space + 1..len - 1];
+        if let Some(eq) = record.iter().position(|&b| b == b'=') {
+            if &record[..eq] == key {
+                return Some(&record[eq + 1..]);
+            }
+        }
+        rest = &rest[len..];
+    }
+    None
+}
+
+impl<R: Read> TarReader<R> {
+    pub fn new(input: R) -> TarReader<R> {
+        TarReader {
+            input: input,
+            remaining: 0,
+            padding: 0,
+        }
+    }
+
+    /// Skip the rest of the current entry's data.
+    fn skip(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; BLOCK];
+        while self.remaining > 0 {
+            let n = cmp::min(self.remaining, BLOCK as u64) as usize;
+            try!(self.input.read_exact(&mut buf[..n]));
+            self.remaining -= n as u64;
+        }
+        let padding = self.padding;
+        self.padding = 0;
+        self.input.read_exact(&mut buf[..padding])
+    }
+
+    /// Read all data of the current entry, which holds metadata of the next one.
+    fn read_data(&mut self) -> io::Result<Vec<u8>> {
+        if self.remaining > MAX_METADATA_LEN {
+            return Err(invalid("Tar metadata entry is too large"));
+        }
+        let mut data = vec![];
+        try!(self.read_to_end(&mut data));
+        Ok(data)
+    }
+
+    /// Move to the next entry. Returns `None` at the end of the archive.
+    pub fn next_entry(&mut self) -> io::Result<Option<Header>> {
+        let mut long_path = None;
+        let mut long_link = None;
+        loop {
+            try!(self.skip());
+            let mut h = [0u8; BLOCK];
+            try!(self.input.read_exact(&mut h[..]));
+            if h.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+
+            let sum = h.iter()
+                .enumerate()
+                .fold(0u64, |sum, (i, &b)| sum + if i >= 148 && i < 156 { 32 } else { b as u64 });
+            if try!(get_number(&h[148..156])) != sum {
+                return Err(invalid("Bad checksum in tar header"));
+            }
+
+            let size = try!(get_number(&h[124..136]));
+            self.remaining = size;
+            self.padding = padding(size);
+
+            let kind = match h[156] {
+                b'0' | 0 | b'7' => Kind::File,
+                b'5' => Kind::Dir,
+                b'2' => Kind::Symlink,
+                b'1' => Kind::Hardlink,
+                b'L' => {
+                    long_path = Some(field_bytes(&try!(self.read_data())[..]).to_vec());
+                    continue;
+                }
+                b'K' => {
+                    long_link = Some(field_bytes(&try!(self.read_data())[..]).to_vec());
+                    continue;
+                }
+                b'x' => {
+                    let records = try!(self.read_data());
+                    if let Some(path) = pax_value(&records[..], b"path") {
+                        long_path = Some(path.to_vec());
+                    }
+                    if let Some(link) = pax_value(&records[..], b"linkpath") {
+                        long_link = Some(link.to_vec());
+                    }
+                    continue;
+                }
+                // Global pax headers apply to the archive, not to a single entry.
+                b'g' => continue,
+                other => Kind::Other(other),
+            };
+
+            let mut path = long_path.take().unwrap_or_else(|| {
+                let prefix = field_bytes(&h[345..500]);
+                let name = field_bytes(&h[..100]);
+                if prefix.is_empty() || &h[257..262] != b"ustar" {
+                    name.to_vec()
+                } else {
+                    let mut path = prefix.to_vec();
+                    path.push(b'/');
+                    path.extend_from_slice(name);
+                    path
+                }
+            });
+            while path.last() == Some(&b'/') {
+                path.pop();
+            }
+            match kind {
+                Kind::File => (),
+                // Links, directories, devices and fifos have no data, whatever their size says.
+                Kind::Dir | Kind::Symlink | Kind::Hardlink |
+                Kind::Other(b'3') | Kind::Other(b'4') | Kind::Other(b'6') => {
+                    self.remaining = 0;
+                    self.padding = 0;
+                }
+                Kind::Other(_) => try!(self.skip()),
+            }
+
+            return Ok(Some(Header {
+                path: path,
+                kind: kind,
+                meta: Meta {
+                    mode: try!(get_number(&h[100..108])) as u32,
+                    uid: try!(get_number(&h[108..116])),
+                    gid: try!(get_number(&h[116..124])),
+                    mtime: try!(get_number(&h[136..148])),
+                },
+                size: if kind == Kind::File { size } else { 0 },
+                link: long_link.take().unwrap_or_else(|| field_bytes(&h[157..257]).to_vec()),
+            }));
+        }
+    }
+}
+
+impl<R: Read> Read for TarReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = cmp::min(self.remaining, buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        let n = try!(self.input.read(&mut buf[..n]));
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated tar archive"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
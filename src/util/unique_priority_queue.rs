@@ -69,6 +69,20 @@ impl<P: Clone + Ord, K: Clone + Ord, V> UniquePriorityQueue<P, K, V> {
         cur.0 = Status::Ready;
     }
 
+    pub fn remove_key(&mut self, k: &K) -> Option<(P, V)> {
+        self.key_to_priority.remove(k).map(|p| {
+            let (_status, _k, v) = self.priority
+                .remove(&p)
+                .expect("remove_key: Priority must exist.");
+            (p, v)
+        })
+    }
+
+    /// The values of all keys, ready or not, by priority.
+    pub fn values(&self) -> Vec<&V> {
+        self.priority.values().map(|&(_, _, ref v)| v).collect()
+    }
+
     pub fn pop_min_if_complete(&mut self) -> Option<(P, K, V)> {
         let min_opt = self.priority
             .pop_min_when(|_k, min| min.0 == Status::Ready);
@@ -142,4 +156,19 @@ mod tests {
 
         quickcheck::quickcheck(prop as fn(Vec<(i8, isize, i8)>) -> bool);
     }
+
+    #[test]
+    fn remove_unblocks_later_priorities() {
+        let mut upq = UniquePriorityQueue::new();
+        assert!(upq.put_value(1, 10, 'a').is_ok());
+        assert!(upq.put_value(2, 20, 'b').is_ok());
+        upq.set_ready(&2);
+        assert_eq!(upq.pop_min_if_complete(), None);
+
+        assert_eq!(upq.remove_key(&10), Some((1, 'a')));
+        assert_eq!(upq.remove_key(&10), None);
+        assert_eq!(upq.find_key(&10), None);
+        assert_eq!(upq.pop_min_if_complete(), Some((2, 20, 'b')));
+        assert_eq!(upq.pop_min_if_complete(), None);
+    }
 }
@@ -0,0 +1,133 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read and write extended attributes of a path, without following symlinks.
+
+use libc::{self, c_char, c_void, size_t, ssize_t};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc::{self, c_char, c_int, c_void, size_t, ssize_t};
+
+    pub unsafe fn list(path: *const c_char, buf: *mut c_char, size: size_t) -> ssize_t {
+        libc::llistxattr(path, buf, size)
+    }
+    pub unsafe fn get(path: *const c_char,
+                      name: *const c_char,
+                      buf: *mut c_void,
+                      size: size_t)
+                      -> ssize_t {
+        libc::lgetxattr(path, name, buf, size)
+    }
+    pub unsafe fn set(path: *const c_char,
+                      name: *const c_char,
+                      value: *const c_void,
+                      size: size_t)
+                      -> c_int {
+        libc::lsetxattr(path, name, value, size, 0)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use libc::{self, c_char, c_int, c_void, size_t, ssize_t};
+
+    pub unsafe fn list(path: *const c_char, buf: *mut c_char, size: size_t) -> ssize_t {
+        libc::listxattr(path, buf, size, libc::XATTR_NOFOLLOW)
+    }
+    pub unsafe fn get(path: *const c_char,
+                      name: *const c_char,
+                      buf: *mut c_void,
+                      size: size_t)
+                      -> ssize_t {
+        libc::getxattr(path, name, buf, size, 0, libc::XATTR_NOFOLLOW)
+    }
+    pub unsafe fn set(path: *const c_char,
+                      name: *const c_char,
+                      value: *const c_void,
+                      size: size_t)
+                      -> c_int {
+        libc::setxattr(path, name, value, size, 0, libc::XATTR_NOFOLLOW)
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+/// Call `f` first to learn the size of the value, and then again to fill a buffer of that size.
+fn read_sized<F>(f: F) -> io::Result<Vec<u8>>
+    where F: Fn(*mut c_void, size_t) -> ssize_t
+{
+    loop {
+        let size = f(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let read = f(buf.as_mut_ptr() as *mut c_void, buf.len() as size_t);
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+        // The value grew between the two calls; try again.
+    }
+}
+
+/// List all extended attributes of `path` with their values.
+pub fn list(path: &Path) -> io::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let path = try!(c_path(path));
+    let names = try!(read_sized(|buf, size| unsafe {
+        sys::list(path.as_ptr(), buf as *mut c_char, size)
+    }));
+
+    let mut out = BTreeMap::new();
+    for name in names.split(|&c| c == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name).unwrap();
+        let value = try!(read_sized(|buf, size| unsafe {
+            sys::get(path.as_ptr(), c_name.as_ptr(), buf, size)
+        }));
+        out.insert(name.to_vec(), value);
+    }
+    Ok(out)
+}
+
+/// Set the extended attribute `name` of `path` to `value`.
+pub fn set(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+    let path = try!(c_path(path));
+    let name = try!(CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte")));
+    let res = unsafe {
+        sys::set(path.as_ptr(),
+                 name.as_ptr(),
+                 value.as_ptr() as *const c_void,
+                 value.len() as size_t)
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}